@@ -7,10 +7,10 @@
 //! [`Config`]: struct.Config.html
 
 use std::{env, fmt, fs, io};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
 use std::io::Read;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
@@ -18,9 +18,17 @@ use clap::{App, Arg, ArgMatches, crate_version};
 #[cfg(unix)] use daemonize::Daemonize;
 use dirs::home_dir;
 use log::{LevelFilter, Log, error};
+use rpki::uri;
 #[cfg(unix)] use syslog::Facility;
 use tokio::runtime::Runtime;
+use uuid::Uuid;
 use crate::operation::Error;
+use crate::rrdp::http::{
+    DEFAULT_DELTA_WORKERS, DEFAULT_NOTIFICATION_TIMEOUT,
+    DEFAULT_POOL_IDLE_TIMEOUT, DEFAULT_POOL_MAX_IDLE, DEFAULT_RETRIES,
+    DEFAULT_RETRY_DELAY,
+};
+use crate::rrdp::server::ServerDirNames;
 
 
 //------------ Defaults for Some Values --------------------------------------
@@ -49,6 +57,21 @@ const DEFAULT_HISTORY_SIZE: usize = 10;
 /// The default RRDP HTTP User Agent header value to send.
 const DEFAULT_RRDP_USER_AGENT: &str = concat!("Routinator/", crate_version!());
 
+/// The default share of an RRDP update deadline after which to warn.
+const DEFAULT_RRDP_DEADLINE_WARNING: f64 = 0.8;
+
+/// The default window for counting RRDP session changes in seconds.
+const DEFAULT_RRDP_SESSION_WINDOW: u64 = 86400;
+
+/// The default time before retrying a broken RRDP server in seconds.
+const DEFAULT_RRDP_BROKEN_BACKOFF: u64 = 3600;
+
+/// The default maximum size of an RRDP notification file in bytes.
+const DEFAULT_RRDP_MAX_NOTIFICATION_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The default maximum number of deltas in an RRDP notification file.
+const DEFAULT_RRDP_MAX_NOTIFICATION_DELTAS: usize = 10_000;
+
 /// The default RTR TCP keepalive.
 const DEFAULT_RTR_TCP_KEEPALIVE: Option<Duration>
     = Some(Duration::from_secs(60));
@@ -140,22 +163,230 @@ pub struct Config {
     pub rrdp_timeout: Option<Option<Duration>>,
 
     /// Optional RRDP connect timeout in seconds.
+    ///
+    /// This applies to all repositories. It can’t be overridden via
+    /// `rrdp_repository_timeouts`.
     pub rrdp_connect_timeout: Option<Duration>,
 
     /// Optional RRDP local address to bind to when doing requests.
     pub rrdp_local_addr: Option<IpAddr>,
 
+    /// The address family to use for RRDP connections.
+    pub rrdp_address_family: AddressFamily,
+
     /// RRDP additional root certificates for HTTPS.
     ///
     /// These do not overide the default system root certififcates.
     pub rrdp_root_certs: Vec<PathBuf>,
 
+    /// RRDP client certificates for specific hosts.
+    ///
+    /// The keys are host names in lower case, the values the paths of PEM
+    /// files containing the certificate chain and private key.
+    pub rrdp_client_certs: HashMap<String, PathBuf>,
+
     /// RRDP HTTP proxies.
     pub rrdp_proxies: Vec<String>,
 
+    /// Additional hosts RRDP snapshots and deltas may be fetched from.
+    ///
+    /// Without these, snapshots and deltas have to be on the same host as
+    /// their notification file.
+    pub rrdp_allowed_hosts: Vec<String>,
+
     /// RRDP HTTP User Agent.
     pub rrdp_user_agent: String,
 
+    /// Additional headers to include in all RRDP requests.
+    ///
+    /// Each header is given as `name: value`.
+    pub rrdp_headers: Vec<String>,
+
+    /// Optional limit for the number of bytes RRDP may buffer at once.
+    ///
+    /// If this is set, new RRDP downloads will wait until the expected size
+    /// of all downloads currently in flight drops below this limit.
+    pub rrdp_memory_budget: Option<u64>,
+
+    /// Optional limit for the number of RRDP servers updating at once.
+    ///
+    /// If this is set, further updates wait until one of the running
+    /// updates has finished.
+    pub rrdp_max_updates: Option<usize>,
+
+    /// Optional limit for the size of a single RRDP snapshot or delta.
+    ///
+    /// This is the size of the file after its content encoding has been
+    /// removed.
+    pub rrdp_max_file_size: Option<u64>,
+
+    /// Optional limit for the bytes downloaded during one RRDP update.
+    ///
+    /// This is the size of all responses for a server as transferred.
+    pub rrdp_max_download_size: Option<u64>,
+
+    /// Optional limit for the size of an RRDP notification file.
+    ///
+    /// This is the size of the file as transferred.
+    pub rrdp_max_notification_size: Option<u64>,
+
+    /// Optional limit for the number of deltas in a notification file.
+    pub rrdp_max_notification_deltas: Option<usize>,
+
+    /// Optional limit for the bandwidth used by all RRDP downloads.
+    ///
+    /// This is in bytes per second as transferred.
+    pub rrdp_rate_limit: Option<u64>,
+
+    /// Whether to verify RRDP objects against their hash when first read.
+    pub rrdp_verify_on_read: bool,
+
+    /// Whether the RRDP cache is only read and never changed.
+    ///
+    /// This is for instances sharing a cache updated by another instance.
+    pub rrdp_read_only: bool,
+
+    /// Whether to flush changes to the RRDP cache to storage.
+    pub rrdp_fsync: bool,
+
+    /// Whether to resume interrupted RRDP snapshot downloads.
+    pub rrdp_resume_snapshots: bool,
+
+    /// Whether to log a summary record for each RRDP server update.
+    pub rrdp_log_timeline: bool,
+
+    /// Should structured events of RRDP updates be logged?
+    pub rrdp_log_events: bool,
+
+    /// How should we deal with RRDP notification files that look like a
+    /// replay of an older session?
+    ///
+    /// See the [`ReplayPolicy`] type for a description of the available
+    /// options.
+    ///
+    /// [`ReplayPolicy`]: enum.ReplayPolicy.html
+    pub rrdp_replay_policy: ReplayPolicy,
+
+    /// The digest algorithm for checking the local copy of RRDP servers.
+    ///
+    /// See the [`DigestAlgorithm`] type for the available algorithms.
+    ///
+    /// [`DigestAlgorithm`]: enum.DigestAlgorithm.html
+    pub rrdp_local_digest: DigestAlgorithm,
+
+    /// Whether the digest of RRDP local copies covers the file content.
+    pub rrdp_deep_digest: bool,
+
+    /// Whether to store RRDP objects compressed.
+    pub rrdp_compress: bool,
+
+    /// Whether to share identical RRDP objects between repositories.
+    pub rrdp_dedup: bool,
+
+    /// Whether to keep the objects of each RRDP repository in an archive.
+    pub rrdp_archive: bool,
+
+    /// Optional size of recently loaded RRDP objects kept in memory.
+    ///
+    /// This is in bytes per repository.
+    pub rrdp_load_cache: Option<u64>,
+
+    /// Optional system load above which RRDP updates are paused.
+    pub rrdp_max_load: Option<f64>,
+
+    /// Optional number of RRDP deltas after which to commit a checkpoint.
+    pub rrdp_delta_checkpoint: Option<usize>,
+
+    /// Optional number of RRDP deltas above which to use the snapshot.
+    ///
+    /// If this is `None`, deltas are used however many there are.
+    pub rrdp_max_deltas: Option<usize>,
+
+    /// Mirrors for RRDP deltas.
+    ///
+    /// Each element is a pair of a URI prefix and the prefix to replace it
+    /// with for a mirror. Mirrors are tried in the given order if fetching
+    /// a delta from its original URI fails.
+    pub rrdp_mirrors: Vec<(String, String)>,
+
+    /// Hosts to send RRDP requests to instead of the host of the URI.
+    ///
+    /// The keys are host names in lower case, the values the host names to
+    /// send requests to. URIs are still checked against the original host.
+    pub rrdp_host_map: HashMap<String, String>,
+
+    /// Optional number of raw RRDP notification files to keep per server.
+    pub rrdp_keep_notifications: Option<usize>,
+
+    /// The names of the files and directories of each RRDP server.
+    pub rrdp_dir_names: ServerDirNames,
+
+    /// Optional number of downloaded RRDP deltas to keep for reuse.
+    pub rrdp_delta_cache: Option<usize>,
+
+    /// The number of RRDP deltas of a server to download concurrently.
+    pub rrdp_delta_workers: usize,
+
+    /// The number of times to retry RRDP requests after transient errors.
+    pub rrdp_retries: usize,
+
+    /// The delay before retrying an RRDP request for the first time.
+    pub rrdp_retry_delay: Duration,
+
+    /// The time after which idle RRDP connections are closed.
+    ///
+    /// If this is `None`, idle connections are kept open indefinitely.
+    pub rrdp_pool_idle_timeout: Option<Duration>,
+
+    /// The maximum number of idle RRDP connections to keep per host.
+    pub rrdp_pool_max_idle: usize,
+
+    /// The share of an RRDP update deadline after which to warn.
+    pub rrdp_deadline_warning: f64,
+
+    /// Optional number of RRDP session changes before backing off.
+    pub rrdp_session_changes: Option<usize>,
+
+    /// The window for counting RRDP session changes.
+    pub rrdp_session_window: Duration,
+
+    /// The time before retrying an RRDP server whose copy was removed.
+    ///
+    /// A duration of zero means to retry right away.
+    pub rrdp_broken_backoff: Duration,
+
+    /// Optional timeout for fetching RRDP notification files.
+    ///
+    /// If this is `None`, the general RRDP timeout applies.
+    pub rrdp_notification_timeout: Option<Duration>,
+
+    /// Optional timeout for fetching RRDP snapshots.
+    ///
+    /// If this is `None`, the general RRDP timeout applies.
+    pub rrdp_snapshot_timeout: Option<Duration>,
+
+    /// Optional timeout for fetching RRDP deltas.
+    ///
+    /// If this is `None`, the general RRDP timeout applies.
+    pub rrdp_delta_timeout: Option<Duration>,
+
+    /// Timeouts for specific RRDP repositories.
+    ///
+    /// The keys are the notification URIs of the repositories. Any timeout
+    /// not set for a repository falls back to the global default.
+    pub rrdp_repository_timeouts: HashMap<uri::Https, RrdpTimeouts>,
+
+    /// RRDP repositories to never update via deltas.
+    ///
+    /// The elements are the notification URIs of the repositories.
+    pub rrdp_snapshot_only: HashSet<uri::Https>,
+
+    /// The sessions RRDP repositories are pinned to.
+    ///
+    /// The keys are the notification URIs of the repositories. Updates
+    /// switching to a different session are refused.
+    pub rrdp_session_pins: HashMap<uri::Https, Uuid>,
+
     /// Wether to not cleanup the repository directory after a validation run.
     ///
     /// If this is `false` and update has not been disabled otherwise, all
@@ -185,6 +416,9 @@ pub struct Config {
     /// Addresses to listen on for HTTP monitoring connectsion.
     pub http_listen: Vec<SocketAddr>,
 
+    /// Optional address to listen on for RRDP debug connections.
+    pub rrdp_debug_listen: Option<SocketAddr>,
+
     /// Whether to get the listening sockets from systemd.
     pub systemd_listen: bool,
 
@@ -261,7 +495,6 @@ impl Config {
              .help("File with local exceptions (see RFC 8416 for format)")
              .takes_value(true)
              .multiple(true)
-             .number_of_values(1)
         )
         .arg(Arg::with_name("strict")
              .long("strict")
@@ -315,13 +548,19 @@ impl Config {
             .help("Local address for outgoing RRDP connections")
             .takes_value(true)
         )
+        .arg(Arg::with_name("rrdp-address-family")
+            .long("rrdp-address-family")
+            .value_name("FAMILY")
+            .possible_values(AddressFamily::VALUES)
+            .help("The address family for outgoing RRDP connections")
+            .takes_value(true)
+        )
         .arg(Arg::with_name("rrdp-root-cert")
             .long("rrdp-root-cert")
             .value_name("PATH")
             .help("Path to trusted PEM certificate for RRDP HTTPS")
             .takes_value(true)
             .multiple(true)
-            .number_of_values(1)
         )
         .arg(Arg::with_name("rrdp-proxy")
             .long("rrdp-proxy")
@@ -329,7 +568,266 @@ impl Config {
             .help("Proxy server for RRDP (HTTP or SOCKS5)")
             .takes_value(true)
             .multiple(true)
-            .number_of_values(1)
+        )
+        .arg(Arg::with_name("rrdp-user-agent")
+            .long("rrdp-user-agent")
+            .value_name("AGENT")
+            .help("User-Agent header to send with RRDP requests")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-header")
+            .long("rrdp-header")
+            .value_name("HEADER")
+            .help("Additional header 'name: value' for RRDP requests")
+            .takes_value(true)
+            .multiple(true)
+        )
+        .arg(Arg::with_name("rrdp-allow-host")
+            .long("rrdp-allow-host")
+            .value_name("HOST")
+            .help("Additional host for RRDP snapshots and deltas")
+            .takes_value(true)
+            .multiple(true)
+        )
+        .arg(Arg::with_name("rrdp-memory-budget")
+            .long("rrdp-memory-budget")
+            .value_name("BYTES")
+            .help("Maximum bytes of concurrent RRDP downloads")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-max-updates")
+            .long("rrdp-max-updates")
+            .value_name("COUNT")
+            .help("Update at most COUNT RRDP servers at once (0 for any)")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-max-file-size")
+            .long("rrdp-max-file-size")
+            .value_name("BYTES")
+            .help("Maximum size of an RRDP snapshot or delta")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-max-download-size")
+            .long("rrdp-max-download-size")
+            .value_name("BYTES")
+            .help("Maximum bytes downloaded for one RRDP update")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-max-notification-size")
+            .long("rrdp-max-notification-size")
+            .value_name("BYTES")
+            .help("Maximum size of an RRDP notification file (0 for any)")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-max-notification-deltas")
+            .long("rrdp-max-notification-deltas")
+            .value_name("COUNT")
+            .help("Maximum deltas in an RRDP notification file (0 for any)")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-rate-limit")
+            .long("rrdp-rate-limit")
+            .value_name("BYTES")
+            .help("Maximum bytes per second downloaded via RRDP")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-verify-on-read")
+            .long("rrdp-verify-on-read")
+            .help("Verify RRDP objects against their hash when first read")
+        )
+        .arg(Arg::with_name("rrdp-read-only")
+            .long("rrdp-read-only")
+            .help("Use the RRDP cache without ever changing it")
+        )
+        .arg(Arg::with_name("rrdp-fsync")
+            .long("rrdp-fsync")
+            .help("Flush changes to the RRDP cache to storage")
+        )
+        .arg(Arg::with_name("rrdp-resume-snapshots")
+            .long("rrdp-resume-snapshots")
+            .help("Resume interrupted RRDP snapshot downloads")
+        )
+        .arg(Arg::with_name("rrdp-log-timeline")
+            .long("rrdp-log-timeline")
+            .help("Log a summary record for each RRDP server update")
+        )
+        .arg(Arg::with_name("rrdp-log-events")
+            .long("rrdp-log-events")
+            .help("Log RRDP update events as JSON records")
+        )
+        .arg(Arg::with_name("rrdp-replay-policy")
+            .long("rrdp-replay-policy")
+            .value_name("POLICY")
+            .help("The policy for handling replayed RRDP sessions")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-local-digest")
+            .long("rrdp-local-digest")
+            .value_name("ALGORITHM")
+            .possible_values(DigestAlgorithm::VALUES)
+            .help("The digest algorithm for checking local RRDP data")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-deep-digest")
+            .long("rrdp-deep-digest")
+            .help("Include file content in the digest of local RRDP data")
+        )
+        .arg(Arg::with_name("rrdp-compress")
+            .long("rrdp-compress")
+            .help("Store RRDP objects compressed")
+        )
+        .arg(Arg::with_name("rrdp-dedup")
+            .long("rrdp-dedup")
+            .help("Store identical RRDP objects only once")
+        )
+        .arg(Arg::with_name("rrdp-archive")
+            .long("rrdp-archive")
+            .help("Keep the RRDP objects of a repository in a single file")
+        )
+        .arg(Arg::with_name("rrdp-load-cache")
+            .long("rrdp-load-cache")
+            .value_name("BYTES")
+            .help("Keep up to BYTES of recently read RRDP objects in memory")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-max-load")
+            .long("rrdp-max-load")
+            .value_name("LOAD")
+            .help("Pause RRDP updates while the load average exceeds LOAD")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-delta-checkpoint")
+            .long("rrdp-delta-checkpoint")
+            .value_name("COUNT")
+            .help("Commit RRDP delta updates every COUNT deltas")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-max-deltas")
+            .long("rrdp-max-deltas")
+            .value_name("COUNT")
+            .help("Use the snapshot if there are more than COUNT deltas")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-keep-notifications")
+            .long("rrdp-keep-notifications")
+            .value_name("COUNT")
+            .help("Keep the last COUNT RRDP notification files")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-state-file")
+            .long("rrdp-state-file")
+            .value_name("NAME")
+            .help("File name of the state of each RRDP server")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-data-dir")
+            .long("rrdp-data-dir")
+            .value_name("NAME")
+            .help("Directory name of the objects of each RRDP server")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-hashes-file")
+            .long("rrdp-hashes-file")
+            .value_name("NAME")
+            .help("File name of the object hashes of each RRDP server")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-manifest-file")
+            .long("rrdp-manifest-file")
+            .value_name("NAME")
+            .help("File name of the manifest of each RRDP server")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-notification-file")
+            .long("rrdp-notification-file")
+            .value_name("NAME")
+            .help("File name of kept RRDP notification files")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-delta-cache")
+            .long("rrdp-delta-cache")
+            .value_name("COUNT")
+            .help("Keep the last COUNT RRDP deltas for reuse")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-delta-workers")
+            .long("rrdp-delta-workers")
+            .value_name("COUNT")
+            .help("Download up to COUNT RRDP deltas concurrently")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-retries")
+            .long("rrdp-retries")
+            .value_name("COUNT")
+            .help("Retry failed RRDP requests up to COUNT times")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-retry-delay")
+            .long("rrdp-retry-delay")
+            .value_name("SECONDS")
+            .help("Wait SECONDS before retrying an RRDP request")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-pool-idle-timeout")
+            .long("rrdp-pool-idle-timeout")
+            .value_name("SECONDS")
+            .help("Close idle RRDP connections after SECONDS")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-pool-max-idle")
+            .long("rrdp-pool-max-idle")
+            .value_name("COUNT")
+            .help("Keep up to COUNT idle RRDP connections per host")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-deadline-warning")
+            .long("rrdp-deadline-warning")
+            .value_name("FRACTION")
+            .help("Warn when an RRDP update uses FRACTION of its deadline")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-session-changes")
+            .long("rrdp-session-changes")
+            .value_name("COUNT")
+            .help("Back off RRDP servers changing session over COUNT times")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-session-window")
+            .long("rrdp-session-window")
+            .value_name("SECONDS")
+            .help("Count RRDP session changes over SECONDS")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-broken-backoff")
+            .long("rrdp-broken-backoff")
+            .value_name("SECONDS")
+            .help("Wait SECONDS before retrying a broken RRDP server")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-unpin-session")
+            .long("rrdp-unpin-session")
+            .value_name("URI")
+            .help("Ignore the pinned session of an RRDP repository")
+            .takes_value(true)
+            .multiple(true)
+        )
+        .arg(Arg::with_name("rrdp-notification-timeout")
+            .long("rrdp-notification-timeout")
+            .value_name("SECONDS")
+            .help("Timeout for RRDP notification files (0 for general)")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-snapshot-timeout")
+            .long("rrdp-snapshot-timeout")
+            .value_name("SECONDS")
+            .help("Timeout for RRDP snapshots")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("rrdp-delta-timeout")
+            .long("rrdp-delta-timeout")
+            .value_name("SECONDS")
+            .help("Timeout for RRDP deltas")
+            .takes_value(true)
         )
         .arg(Arg::with_name("dirty-repository")
             .long("dirty")
@@ -410,7 +908,6 @@ impl Config {
             .help("Listen on address/port for RTR")
             .takes_value(true)
             .multiple(true)
-            .number_of_values(1)
         )
         .arg(Arg::with_name("http-listen")
             .long("http")
@@ -418,7 +915,12 @@ impl Config {
             .help("Listen on address/port for HTTP")
             .takes_value(true)
             .multiple(true)
-            .number_of_values(1)
+        )
+        .arg(Arg::with_name("rrdp-debug-listen")
+            .long("rrdp-debug-listen")
+            .value_name("ADDR:PORT")
+            .help("Listen on address/port for RRDP debug requests")
+            .takes_value(true)
         )
         .arg(Arg::with_name("systemd-listen")
             .long("systemd-listen")
@@ -583,7 +1085,9 @@ impl Config {
         }
 
         // rrdp_connect_timeout
-        if let Some(value) = from_str_value_of(matches, "rrdp-timeout")? {
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-connect-timeout"
+        )? {
             self.rrdp_connect_timeout = Some(Duration::from_secs(value))
         }
 
@@ -592,6 +1096,13 @@ impl Config {
             self.rrdp_local_addr = Some(value)
         }
 
+        // rrdp_address_family
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-address-family"
+        )? {
+            self.rrdp_address_family = value
+        }
+
         // rrdp_root_certs
         if let Some(list) = matches.values_of("rrdp-root-cert") {
             self.rrdp_root_certs = Vec::new();
@@ -611,78 +1122,380 @@ impl Config {
             self.rrdp_proxies = list.map(Into::into).collect();
         }
 
-        // dirty_repository
-        if matches.is_present("dirty-repository") {
-            self.dirty_repository = true
+        // rrdp_user_agent
+        if let Some(value) = matches.value_of("rrdp-user-agent") {
+            self.rrdp_user_agent = value.into()
         }
 
-        // validation_threads
-        if let Some(value) = from_str_value_of(matches, "validation-threads")? {
-            self.validation_threads = value
+        // rrdp_headers
+        if let Some(list) = matches.values_of("rrdp-header") {
+            self.rrdp_headers = list.map(Into::into).collect();
         }
 
-        // log_level
-        match (matches.occurrences_of("verbose"),
-                                            matches.occurrences_of("quiet")) {
-            // This assumes that -v and -q are conflicting.
-            (0, 0) => { }
-            (1, 0) => self.log_level = LevelFilter::Info,
-            (_, 0) => self.log_level = LevelFilter::Debug,
-            (0, 1) => self.log_level = LevelFilter::Error,
-            (0, _) => self.log_level = LevelFilter::Off,
-            _ => { }
+        // rrdp_allowed_hosts
+        if let Some(list) = matches.values_of("rrdp-allow-host") {
+            self.rrdp_allowed_hosts = list.map(Into::into).collect();
         }
 
-        // log_target
-        self.apply_log_matches(matches, cur_dir)?;
+        // rrdp_memory_budget
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-memory-budget"
+        )? {
+            self.rrdp_memory_budget = Some(value)
+        }
 
-        Ok(())
-    }
+        // rrdp_max_updates
+        if let Some(value) = from_str_value_of(matches, "rrdp-max-updates")? {
+            self.rrdp_max_updates = nonzero_count(value)
+        }
 
-    /// Applies the logging-specific command line arguments to the config.
-    ///
-    /// This is the Unix version that also considers syslog as a valid
-    /// target.
-    #[cfg(unix)]
-    fn apply_log_matches(
-        &mut self,
-        matches: &ArgMatches,
-        cur_dir: &Path,
-    ) -> Result<(), Error> {
-        if matches.is_present("syslog") {
-            self.log_target = LogTarget::Syslog(
-                match Facility::from_str(
-                               matches.value_of("syslog-facility").unwrap()) {
-                    Ok(value) => value,
-                    Err(_) => {
-                        error!("Invalid value for syslog-facility.");
-                        return Err(Error);
-                    }
-                }
-            )
+        // rrdp_max_file_size
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-max-file-size"
+        )? {
+            self.rrdp_max_file_size = Some(value)
         }
-        else if let Some(file) = matches.value_of("logfile") {
-            if file == "-" {
-                self.log_target = LogTarget::Stderr
-            }
-            else {
-                self.log_target = LogTarget::File(cur_dir.join(file))
-            }
+
+        // rrdp_max_download_size
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-max-download-size"
+        )? {
+            self.rrdp_max_download_size = Some(value)
         }
-        Ok(())
-    }
 
-    /// Applies the logging-specific command line arguments to the config.
-    ///
-    /// This is the non-Unix version that does not use syslog.
-    #[cfg(not(unix))]
-    fn apply_log_matches(
-        &mut self,
-        matches: &ArgMatches,
-        cur_dir: &Path,
-    ) -> Result<(), Error> {
-        if let Some(file) = matches.value_of("logfile") {
-            if file == "-" {
+        // rrdp_max_notification_size
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-max-notification-size"
+        )? {
+            self.rrdp_max_notification_size = nonzero_size(value)
+        }
+
+        // rrdp_max_notification_deltas
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-max-notification-deltas"
+        )? {
+            self.rrdp_max_notification_deltas = nonzero_count(value)
+        }
+
+        // rrdp_rate_limit
+        if let Some(value) = from_str_value_of(matches, "rrdp-rate-limit")? {
+            self.rrdp_rate_limit = Some(value)
+        }
+
+        // rrdp_verify_on_read
+        if matches.is_present("rrdp-verify-on-read") {
+            self.rrdp_verify_on_read = true
+        }
+
+        // rrdp_read_only
+        if matches.is_present("rrdp-read-only") {
+            self.rrdp_read_only = true
+        }
+
+        // rrdp_fsync
+        if matches.is_present("rrdp-fsync") {
+            self.rrdp_fsync = true
+        }
+
+        // rrdp_resume_snapshots
+        if matches.is_present("rrdp-resume-snapshots") {
+            self.rrdp_resume_snapshots = true
+        }
+
+        // rrdp_log_timeline
+        if matches.is_present("rrdp-log-timeline") {
+            self.rrdp_log_timeline = true
+        }
+
+        // rrdp_log_events
+        if matches.is_present("rrdp-log-events") {
+            self.rrdp_log_events = true
+        }
+
+        // rrdp_replay_policy
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-replay-policy"
+        )? {
+            self.rrdp_replay_policy = value
+        }
+
+        // rrdp_local_digest
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-local-digest"
+        )? {
+            self.rrdp_local_digest = value
+        }
+
+        // rrdp_deep_digest
+        if matches.is_present("rrdp-deep-digest") {
+            self.rrdp_deep_digest = true
+        }
+
+        // rrdp_compress
+        if matches.is_present("rrdp-compress") {
+            self.rrdp_compress = true
+        }
+
+        // rrdp_dedup
+        if matches.is_present("rrdp-dedup") {
+            self.rrdp_dedup = true
+        }
+
+        // rrdp_archive
+        if matches.is_present("rrdp-archive") {
+            self.rrdp_archive = true
+        }
+
+        // rrdp_load_cache
+        if let Some(value) = from_str_value_of(matches, "rrdp-load-cache")? {
+            self.rrdp_load_cache = Some(value)
+        }
+
+        // rrdp_max_load
+        if let Some(value) = from_str_value_of(matches, "rrdp-max-load")? {
+            self.rrdp_max_load = Some(value)
+        }
+
+        // rrdp_delta_checkpoint
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-delta-checkpoint"
+        )? {
+            self.rrdp_delta_checkpoint = Some(value)
+        }
+
+        // rrdp_max_deltas
+        if let Some(value) = from_str_value_of(matches, "rrdp-max-deltas")? {
+            self.rrdp_max_deltas = nonzero_count(value)
+        }
+
+        // rrdp_keep_notifications
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-keep-notifications"
+        )? {
+            self.rrdp_keep_notifications = Some(value)
+        }
+
+        // rrdp_dir_names
+        if let Some(value) = matches.value_of("rrdp-state-file") {
+            self.rrdp_dir_names.state = value.into()
+        }
+        if let Some(value) = matches.value_of("rrdp-data-dir") {
+            self.rrdp_dir_names.data = value.into()
+        }
+        if let Some(value) = matches.value_of("rrdp-hashes-file") {
+            self.rrdp_dir_names.hashes = value.into()
+        }
+        if let Some(value) = matches.value_of("rrdp-manifest-file") {
+            self.rrdp_dir_names.manifest = value.into()
+        }
+        if let Some(value) = matches.value_of("rrdp-notification-file") {
+            self.rrdp_dir_names.notification = value.into()
+        }
+
+        // rrdp_delta_cache
+        if let Some(value) = from_str_value_of(matches, "rrdp-delta-cache")? {
+            self.rrdp_delta_cache = Some(value)
+        }
+
+        // rrdp_delta_workers
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-delta-workers"
+        )? {
+            self.rrdp_delta_workers = value
+        }
+
+        // rrdp_retries
+        if let Some(value) = from_str_value_of(matches, "rrdp-retries")? {
+            self.rrdp_retries = value
+        }
+
+        // rrdp_retry_delay
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-retry-delay"
+        )? {
+            self.rrdp_retry_delay = Duration::from_secs(value)
+        }
+
+        // rrdp_pool_idle_timeout
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-pool-idle-timeout"
+        )? {
+            self.rrdp_pool_idle_timeout = if value == 0 {
+                None
+            }
+            else {
+                Some(Duration::from_secs(value))
+            }
+        }
+
+        // rrdp_pool_max_idle
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-pool-max-idle"
+        )? {
+            self.rrdp_pool_max_idle = value
+        }
+
+        // rrdp_deadline_warning
+        if let Some(value) = from_str_value_of::<f64>(
+            matches, "rrdp-deadline-warning"
+        )? {
+            if !is_fraction(value) {
+                error!(
+                    "Invalid value for rrdp-deadline-warning: \
+                     expected a number greater than 0 and at most 1."
+                );
+                return Err(Error)
+            }
+            self.rrdp_deadline_warning = value
+        }
+
+        // rrdp_session_changes
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-session-changes"
+        )? {
+            self.rrdp_session_changes = Some(value)
+        }
+
+        // rrdp_session_window
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-session-window"
+        )? {
+            self.rrdp_session_window = Duration::from_secs(value)
+        }
+
+        // rrdp_broken_backoff
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-broken-backoff"
+        )? {
+            self.rrdp_broken_backoff = Duration::from_secs(value)
+        }
+
+        // rrdp_session_pins
+        if let Some(list) = matches.values_of("rrdp-unpin-session") {
+            for value in list {
+                match uri::Https::from_str(value) {
+                    Ok(uri) => {
+                        self.rrdp_session_pins.remove(&uri);
+                    }
+                    Err(_) => {
+                        error!(
+                            "Invalid URI for rrdp-unpin-session '{}'", value
+                        );
+                        return Err(Error)
+                    }
+                }
+            }
+        }
+
+        // rrdp_notification_timeout
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-notification-timeout"
+        )? {
+            self.rrdp_notification_timeout = nonzero_secs(value)
+        }
+
+        // rrdp_snapshot_timeout
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-snapshot-timeout"
+        )? {
+            self.rrdp_snapshot_timeout = nonzero_secs(value)
+        }
+
+        // rrdp_delta_timeout
+        if let Some(value) = from_str_value_of(
+            matches, "rrdp-delta-timeout"
+        )? {
+            self.rrdp_delta_timeout = nonzero_secs(value)
+        }
+
+        // dirty_repository
+        if matches.is_present("dirty-repository") {
+            self.dirty_repository = true
+        }
+
+        // validation_threads
+        if let Some(value) = from_str_value_of(matches, "validation-threads")? {
+            self.validation_threads = value
+        }
+
+        // log_level
+        match (matches.occurrences_of("verbose"),
+                                            matches.occurrences_of("quiet")) {
+            // This assumes that -v and -q are conflicting.
+            (0, 0) => { }
+            (1, 0) => self.log_level = LevelFilter::Info,
+            (_, 0) => self.log_level = LevelFilter::Debug,
+            (0, 1) => self.log_level = LevelFilter::Error,
+            (0, _) => self.log_level = LevelFilter::Off,
+            _ => { }
+        }
+
+        // log_target
+        self.apply_log_matches(matches, cur_dir)?;
+
+        self.check_rrdp_store()
+    }
+
+    /// Checks that the options for storing RRDP objects fit together.
+    ///
+    /// Deduplication hard links the files of the local copies, so it can’t
+    /// be used with archives which don’t have a file per object. The names
+    /// of the entries of the server directories must not collide.
+    fn check_rrdp_store(&self) -> Result<(), Error> {
+        if self.rrdp_dedup && self.rrdp_archive {
+            error!(
+                "The rrdp-dedup and rrdp-archive options cannot be used \
+                 together."
+            );
+            return Err(Error)
+        }
+        self.rrdp_dir_names.check()
+    }
+
+    /// Applies the logging-specific command line arguments to the config.
+    ///
+    /// This is the Unix version that also considers syslog as a valid
+    /// target.
+    #[cfg(unix)]
+    fn apply_log_matches(
+        &mut self,
+        matches: &ArgMatches,
+        cur_dir: &Path,
+    ) -> Result<(), Error> {
+        if matches.is_present("syslog") {
+            self.log_target = LogTarget::Syslog(
+                match Facility::from_str(
+                               matches.value_of("syslog-facility").unwrap()) {
+                    Ok(value) => value,
+                    Err(_) => {
+                        error!("Invalid value for syslog-facility.");
+                        return Err(Error);
+                    }
+                }
+            )
+        }
+        else if let Some(file) = matches.value_of("logfile") {
+            if file == "-" {
+                self.log_target = LogTarget::Stderr
+            }
+            else {
+                self.log_target = LogTarget::File(cur_dir.join(file))
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the logging-specific command line arguments to the config.
+    ///
+    /// This is the non-Unix version that does not use syslog.
+    #[cfg(not(unix))]
+    fn apply_log_matches(
+        &mut self,
+        matches: &ArgMatches,
+        cur_dir: &Path,
+    ) -> Result<(), Error> {
+        if let Some(file) = matches.value_of("logfile") {
+            if file == "-" {
                 self.log_target = LogTarget::Stderr
             }
             else {
@@ -749,6 +1562,11 @@ impl Config {
             }
         }
 
+        // rrdp_debug_listen
+        if let Some(value) = from_str_value_of(matches, "rrdp-debug-listen")? {
+            self.rrdp_debug_listen = Some(value)
+        }
+
         // systemd_listen
         if matches.is_present("systemd-listen") {
             self.systemd_listen = true
@@ -1013,16 +1831,171 @@ impl Config {
                 file.take_u64("rrdp-connect-timeout")?.map(Duration::from_secs)
             },
             rrdp_local_addr: file.take_from_str("rrdp-local-addr")?,
+            rrdp_address_family: {
+                file.take_from_str("rrdp-address-family")?.unwrap_or_default()
+            },
             rrdp_root_certs: {
                 file.take_from_str_array("rrdp-root-certs")?
                     .unwrap_or_else(Vec::new)
             },
+            rrdp_client_certs: {
+                file.take_rrdp_client_certs("rrdp-client-certs")?
+            },
             rrdp_proxies: {
                 file.take_string_array("rrdp-proxies")?.unwrap_or_else(
                     Vec::new
                 )
             },
-            rrdp_user_agent: DEFAULT_RRDP_USER_AGENT.to_string(),
+            rrdp_allowed_hosts: {
+                file.take_string_array("rrdp-allowed-hosts")?.unwrap_or_else(
+                    Vec::new
+                )
+            },
+            rrdp_user_agent: {
+                file.take_string("rrdp-user-agent")?.unwrap_or_else(|| {
+                    DEFAULT_RRDP_USER_AGENT.to_string()
+                })
+            },
+            rrdp_headers: {
+                file.take_string_array("rrdp-headers")?.unwrap_or_else(
+                    Vec::new
+                )
+            },
+            rrdp_memory_budget: file.take_u64("rrdp-memory-budget")?,
+            rrdp_max_updates: {
+                file.take_small_usize("rrdp-max-updates")?
+                    .and_then(nonzero_count)
+            },
+            rrdp_max_file_size: file.take_u64("rrdp-max-file-size")?,
+            rrdp_max_download_size:
+                file.take_u64("rrdp-max-download-size")?,
+            rrdp_max_notification_size: {
+                match file.take_u64("rrdp-max-notification-size")? {
+                    Some(value) => nonzero_size(value),
+                    None => Some(DEFAULT_RRDP_MAX_NOTIFICATION_SIZE)
+                }
+            },
+            rrdp_max_notification_deltas: {
+                match file.take_small_usize("rrdp-max-notification-deltas")? {
+                    Some(value) => nonzero_count(value),
+                    None => Some(DEFAULT_RRDP_MAX_NOTIFICATION_DELTAS)
+                }
+            },
+            rrdp_rate_limit: file.take_u64("rrdp-rate-limit")?,
+            rrdp_verify_on_read:
+                file.take_bool("rrdp-verify-on-read")?.unwrap_or(false),
+            rrdp_read_only:
+                file.take_bool("rrdp-read-only")?.unwrap_or(false),
+            rrdp_fsync:
+                file.take_bool("rrdp-fsync")?.unwrap_or(false),
+            rrdp_resume_snapshots:
+                file.take_bool("rrdp-resume-snapshots")?.unwrap_or(false),
+            rrdp_log_timeline:
+                file.take_bool("rrdp-log-timeline")?.unwrap_or(false),
+            rrdp_log_events:
+                file.take_bool("rrdp-log-events")?.unwrap_or(false),
+            rrdp_replay_policy: {
+                file.take_from_str("rrdp-replay-policy")?.unwrap_or_default()
+            },
+            rrdp_local_digest: {
+                file.take_from_str("rrdp-local-digest")?.unwrap_or_default()
+            },
+            rrdp_deep_digest:
+                file.take_bool("rrdp-deep-digest")?.unwrap_or(false),
+            rrdp_compress:
+                file.take_bool("rrdp-compress")?.unwrap_or(false),
+            rrdp_dedup:
+                file.take_bool("rrdp-dedup")?.unwrap_or(false),
+            rrdp_archive:
+                file.take_bool("rrdp-archive")?.unwrap_or(false),
+            rrdp_load_cache: file.take_u64("rrdp-load-cache")?,
+            rrdp_max_load: file.take_f64("rrdp-max-load")?,
+            rrdp_delta_checkpoint: {
+                file.take_small_usize("rrdp-delta-checkpoint")?
+            },
+            rrdp_max_deltas: {
+                file.take_small_usize("rrdp-max-deltas")?.and_then(
+                    nonzero_count
+                )
+            },
+            rrdp_mirrors: {
+                file.take_string_pairs("rrdp-mirrors")?.unwrap_or_default()
+            },
+            rrdp_host_map: {
+                file.take_string_map("rrdp-host-map")?.unwrap_or_default()
+                    .into_iter().map(|(host, target)| {
+                        (host.to_ascii_lowercase(), target)
+                    }).collect()
+            },
+            rrdp_keep_notifications: {
+                file.take_small_usize("rrdp-keep-notifications")?
+            },
+            rrdp_dir_names: Self::rrdp_dir_names_from_config_file(&mut file)?,
+            rrdp_delta_cache: file.take_small_usize("rrdp-delta-cache")?,
+            rrdp_delta_workers: {
+                file.take_small_usize("rrdp-delta-workers")?
+                    .unwrap_or(DEFAULT_DELTA_WORKERS)
+            },
+            rrdp_retries: {
+                file.take_small_usize("rrdp-retries")?
+                    .unwrap_or(DEFAULT_RETRIES)
+            },
+            rrdp_retry_delay: {
+                file.take_u64("rrdp-retry-delay")?.map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_RETRY_DELAY)
+            },
+            rrdp_pool_idle_timeout: {
+                match file.take_u64("rrdp-pool-idle-timeout")? {
+                    Some(0) => None,
+                    Some(value) => Some(Duration::from_secs(value)),
+                    None => Some(DEFAULT_POOL_IDLE_TIMEOUT)
+                }
+            },
+            rrdp_pool_max_idle: {
+                file.take_small_usize("rrdp-pool-max-idle")?
+                    .unwrap_or(DEFAULT_POOL_MAX_IDLE)
+            },
+            rrdp_deadline_warning: {
+                file.take_fraction("rrdp-deadline-warning")?
+                    .unwrap_or(DEFAULT_RRDP_DEADLINE_WARNING)
+            },
+            rrdp_session_changes: {
+                file.take_small_usize("rrdp-session-changes")?
+            },
+            rrdp_session_window: {
+                Duration::from_secs(
+                    file.take_u64("rrdp-session-window")?
+                        .unwrap_or(DEFAULT_RRDP_SESSION_WINDOW)
+                )
+            },
+            rrdp_broken_backoff: {
+                Duration::from_secs(
+                    file.take_u64("rrdp-broken-backoff")?
+                        .unwrap_or(DEFAULT_RRDP_BROKEN_BACKOFF)
+                )
+            },
+            rrdp_notification_timeout: {
+                match file.take_u64("rrdp-notification-timeout")? {
+                    Some(secs) => nonzero_secs(secs),
+                    None => Some(DEFAULT_NOTIFICATION_TIMEOUT),
+                }
+            },
+            rrdp_snapshot_timeout: {
+                file.take_u64("rrdp-snapshot-timeout")?.and_then(nonzero_secs)
+            },
+            rrdp_delta_timeout: {
+                file.take_u64("rrdp-delta-timeout")?.and_then(nonzero_secs)
+            },
+            rrdp_repository_timeouts: {
+                file.take_rrdp_timeouts("rrdp-repository-timeouts")?
+            },
+            rrdp_snapshot_only: {
+                file.take_from_str_array("rrdp-snapshot-only")?
+                    .unwrap_or_default().into_iter().collect()
+            },
+            rrdp_session_pins: {
+                file.take_rrdp_session_pins("rrdp-session-pins")?
+            },
             dirty_repository: file.take_bool("dirty")?.unwrap_or(false),
             validation_threads: {
                 file.take_small_usize("validation-threads")?
@@ -1055,6 +2028,7 @@ impl Config {
                 file.take_from_str_array("http-listen")?
                     .unwrap_or_else(Vec::new)
             },
+            rrdp_debug_listen: file.take_from_str("rrdp-debug-listen")?,
             systemd_listen: file.take_bool("systemd-listen")?.unwrap_or(false),
             rtr_tcp_keepalive: {
                 match file.take_from_str("rtr-tcp-keepalive")? {
@@ -1075,6 +2049,32 @@ impl Config {
             tal_labels: file.take_string_map("tal-labels")?.unwrap_or_default(),
         };
         file.check_exhausted()?;
+        res.check_rrdp_store()?;
+        Ok(res)
+    }
+
+    /// Determines the names of the RRDP server directory entries.
+    ///
+    /// Names missing from the config file keep their default.
+    fn rrdp_dir_names_from_config_file(
+        file: &mut ConfigFile
+    ) -> Result<ServerDirNames, Error> {
+        let mut res = ServerDirNames::default();
+        if let Some(name) = file.take_string("rrdp-state-file")? {
+            res.state = name.into()
+        }
+        if let Some(name) = file.take_string("rrdp-data-dir")? {
+            res.data = name.into()
+        }
+        if let Some(name) = file.take_string("rrdp-hashes-file")? {
+            res.hashes = name.into()
+        }
+        if let Some(name) = file.take_string("rrdp-manifest-file")? {
+            res.manifest = name.into()
+        }
+        if let Some(name) = file.take_string("rrdp-notification-file")? {
+            res.notification = name.into()
+        }
         Ok(res)
     }
 
@@ -1184,9 +2184,64 @@ impl Config {
             rrdp_timeout: None,
             rrdp_connect_timeout: None,
             rrdp_local_addr: None,
+            rrdp_address_family: Default::default(),
             rrdp_root_certs: Vec::new(),
+            rrdp_client_certs: HashMap::new(),
             rrdp_proxies: Vec::new(),
+            rrdp_allowed_hosts: Vec::new(),
             rrdp_user_agent: DEFAULT_RRDP_USER_AGENT.to_string(),
+            rrdp_headers: Vec::new(),
+            rrdp_memory_budget: None,
+            rrdp_max_updates: None,
+            rrdp_max_file_size: None,
+            rrdp_max_download_size: None,
+            rrdp_max_notification_size: {
+                Some(DEFAULT_RRDP_MAX_NOTIFICATION_SIZE)
+            },
+            rrdp_max_notification_deltas: {
+                Some(DEFAULT_RRDP_MAX_NOTIFICATION_DELTAS)
+            },
+            rrdp_rate_limit: None,
+            rrdp_verify_on_read: false,
+            rrdp_read_only: false,
+            rrdp_fsync: false,
+            rrdp_resume_snapshots: false,
+            rrdp_log_timeline: false,
+            rrdp_log_events: false,
+            rrdp_replay_policy: Default::default(),
+            rrdp_local_digest: Default::default(),
+            rrdp_deep_digest: false,
+            rrdp_compress: false,
+            rrdp_dedup: false,
+            rrdp_archive: false,
+            rrdp_load_cache: None,
+            rrdp_max_load: None,
+            rrdp_delta_checkpoint: None,
+            rrdp_max_deltas: None,
+            rrdp_mirrors: Vec::new(),
+            rrdp_host_map: HashMap::new(),
+            rrdp_keep_notifications: None,
+            rrdp_dir_names: ServerDirNames::default(),
+            rrdp_delta_cache: None,
+            rrdp_delta_workers: DEFAULT_DELTA_WORKERS,
+            rrdp_retries: DEFAULT_RETRIES,
+            rrdp_retry_delay: DEFAULT_RETRY_DELAY,
+            rrdp_pool_idle_timeout: Some(DEFAULT_POOL_IDLE_TIMEOUT),
+            rrdp_pool_max_idle: DEFAULT_POOL_MAX_IDLE,
+            rrdp_deadline_warning: DEFAULT_RRDP_DEADLINE_WARNING,
+            rrdp_session_changes: None,
+            rrdp_session_window: {
+                Duration::from_secs(DEFAULT_RRDP_SESSION_WINDOW)
+            },
+            rrdp_broken_backoff: {
+                Duration::from_secs(DEFAULT_RRDP_BROKEN_BACKOFF)
+            },
+            rrdp_notification_timeout: Some(DEFAULT_NOTIFICATION_TIMEOUT),
+            rrdp_snapshot_timeout: None,
+            rrdp_delta_timeout: None,
+            rrdp_repository_timeouts: HashMap::new(),
+            rrdp_snapshot_only: HashSet::new(),
+            rrdp_session_pins: HashMap::new(),
             dirty_repository: DEFAULT_DIRTY_REPOSITORY,
             validation_threads: ::num_cpus::get(),
             refresh: Duration::from_secs(DEFAULT_REFRESH),
@@ -1195,6 +2250,7 @@ impl Config {
             history_size: DEFAULT_HISTORY_SIZE,
             rtr_listen: Vec::new(),
             http_listen: Vec::new(),
+            rrdp_debug_listen: None,
             systemd_listen: false,
             rtr_tcp_keepalive: DEFAULT_RTR_TCP_KEEPALIVE,
             log_level: LevelFilter::Warn,
@@ -1361,6 +2417,10 @@ impl Config {
         if let Some(addr) = self.rrdp_local_addr {
             res.insert("rrdp-local-addr".into(), addr.to_string().into());
         }
+        res.insert(
+            "rrdp-address-family".into(),
+            format!("{}", self.rrdp_address_family).into()
+        );
         res.insert(
             "rrdp-root-certs".into(),
             toml::Value::Array(
@@ -1369,37 +2429,298 @@ impl Config {
                     .collect()
             )
         );
+        if !self.rrdp_client_certs.is_empty() {
+            res.insert(
+                "rrdp-client-certs".into(),
+                toml::Value::Array(
+                    self.rrdp_client_certs.iter().map(|(host, path)| {
+                        let mut table = toml::value::Table::new();
+                        table.insert("host".into(), host.clone().into());
+                        table.insert(
+                            "identity".into(),
+                            path.display().to_string().into()
+                        );
+                        toml::Value::Table(table)
+                    }).collect()
+                )
+            );
+        }
         res.insert(
             "rrdp-proxies".into(),
             toml::Value::Array(
                 self.rrdp_proxies.iter().map(|s| s.clone().into()).collect()
             )
         );
-        res.insert("dirty".into(), self.dirty_repository.into());
         res.insert(
-            "validation-threads".into(),
-            (self.validation_threads as i64).into()
+            "rrdp-user-agent".into(), self.rrdp_user_agent.clone().into()
         );
-        res.insert("refresh".into(), (self.refresh.as_secs() as i64).into());
-        res.insert("retry".into(), (self.retry.as_secs() as i64).into());
-        res.insert("expire".into(), (self.expire.as_secs() as i64).into());
-        res.insert("history-size".into(), (self.history_size as i64).into());
         res.insert(
-            "rtr-listen".into(),
+            "rrdp-headers".into(),
             toml::Value::Array(
-                self.rtr_listen.iter().map(|a| a.to_string().into()).collect()
+                self.rrdp_headers.iter().map(|s| s.clone().into()).collect()
             )
         );
         res.insert(
-            "http-listen".into(),
+            "rrdp-allowed-hosts".into(),
             toml::Value::Array(
-                self.http_listen.iter().map(|a| a.to_string().into()).collect()
+                self.rrdp_allowed_hosts.iter().map(|s| {
+                    s.clone().into()
+                }).collect()
             )
         );
-        res.insert("systemd-listen".into(), self.systemd_listen.into());
-        res.insert("rtr-tcp-keepalive".into(),
-            match self.rtr_tcp_keepalive {
-                Some(keep) => (keep.as_secs() as i64).into(),
+        if let Some(budget) = self.rrdp_memory_budget {
+            res.insert(
+                "rrdp-memory-budget".into(), (budget as i64).into()
+            );
+        }
+        if let Some(count) = self.rrdp_max_updates {
+            res.insert("rrdp-max-updates".into(), (count as i64).into());
+        }
+        if let Some(size) = self.rrdp_max_file_size {
+            res.insert(
+                "rrdp-max-file-size".into(), (size as i64).into()
+            );
+        }
+        if let Some(size) = self.rrdp_max_download_size {
+            res.insert(
+                "rrdp-max-download-size".into(), (size as i64).into()
+            );
+        }
+        res.insert(
+            "rrdp-max-notification-size".into(),
+            (self.rrdp_max_notification_size.unwrap_or(0) as i64).into()
+        );
+        res.insert(
+            "rrdp-max-notification-deltas".into(),
+            (self.rrdp_max_notification_deltas.unwrap_or(0) as i64).into()
+        );
+        if let Some(rate) = self.rrdp_rate_limit {
+            res.insert("rrdp-rate-limit".into(), (rate as i64).into());
+        }
+        res.insert(
+            "rrdp-verify-on-read".into(), self.rrdp_verify_on_read.into()
+        );
+        res.insert(
+            "rrdp-read-only".into(), self.rrdp_read_only.into()
+        );
+        res.insert(
+            "rrdp-fsync".into(), self.rrdp_fsync.into()
+        );
+        res.insert(
+            "rrdp-resume-snapshots".into(),
+            self.rrdp_resume_snapshots.into()
+        );
+        res.insert(
+            "rrdp-log-timeline".into(), self.rrdp_log_timeline.into()
+        );
+        res.insert(
+            "rrdp-log-events".into(), self.rrdp_log_events.into()
+        );
+        res.insert(
+            "rrdp-replay-policy".into(),
+            format!("{}", self.rrdp_replay_policy).into()
+        );
+        res.insert(
+            "rrdp-local-digest".into(),
+            format!("{}", self.rrdp_local_digest).into()
+        );
+        res.insert(
+            "rrdp-deep-digest".into(), self.rrdp_deep_digest.into()
+        );
+        res.insert("rrdp-compress".into(), self.rrdp_compress.into());
+        res.insert("rrdp-dedup".into(), self.rrdp_dedup.into());
+        res.insert("rrdp-archive".into(), self.rrdp_archive.into());
+        if let Some(size) = self.rrdp_load_cache {
+            res.insert("rrdp-load-cache".into(), (size as i64).into());
+        }
+        if let Some(load) = self.rrdp_max_load {
+            res.insert("rrdp-max-load".into(), load.into());
+        }
+        if let Some(count) = self.rrdp_delta_checkpoint {
+            res.insert(
+                "rrdp-delta-checkpoint".into(), (count as i64).into()
+            );
+        }
+        if let Some(count) = self.rrdp_max_deltas {
+            res.insert("rrdp-max-deltas".into(), (count as i64).into());
+        }
+        if let Some(count) = self.rrdp_keep_notifications {
+            res.insert(
+                "rrdp-keep-notifications".into(), (count as i64).into()
+            );
+        }
+        let names = &self.rrdp_dir_names;
+        for &(key, name) in &[
+            ("rrdp-state-file", &names.state),
+            ("rrdp-data-dir", &names.data),
+            ("rrdp-hashes-file", &names.hashes),
+            ("rrdp-manifest-file", &names.manifest),
+            ("rrdp-notification-file", &names.notification),
+        ] {
+            res.insert(
+                key.into(), name.to_string_lossy().into_owned().into()
+            );
+        }
+        if let Some(count) = self.rrdp_delta_cache {
+            res.insert("rrdp-delta-cache".into(), (count as i64).into());
+        }
+        res.insert(
+            "rrdp-delta-workers".into(),
+            (self.rrdp_delta_workers as i64).into()
+        );
+        res.insert(
+            "rrdp-retries".into(), (self.rrdp_retries as i64).into()
+        );
+        res.insert(
+            "rrdp-retry-delay".into(),
+            (self.rrdp_retry_delay.as_secs() as i64).into()
+        );
+        res.insert(
+            "rrdp-pool-idle-timeout".into(),
+            match self.rrdp_pool_idle_timeout {
+                Some(timeout) => (timeout.as_secs() as i64).into(),
+                None => 0.into()
+            }
+        );
+        res.insert(
+            "rrdp-pool-max-idle".into(),
+            (self.rrdp_pool_max_idle as i64).into()
+        );
+        res.insert(
+            "rrdp-deadline-warning".into(), self.rrdp_deadline_warning.into()
+        );
+        if let Some(count) = self.rrdp_session_changes {
+            res.insert(
+                "rrdp-session-changes".into(), (count as i64).into()
+            );
+        }
+        res.insert(
+            "rrdp-session-window".into(),
+            (self.rrdp_session_window.as_secs() as i64).into()
+        );
+        res.insert(
+            "rrdp-broken-backoff".into(),
+            (self.rrdp_broken_backoff.as_secs() as i64).into()
+        );
+        res.insert(
+            "rrdp-notification-timeout".into(),
+            (
+                self.rrdp_notification_timeout.map(|d| d.as_secs())
+                    .unwrap_or(0) as i64
+            ).into()
+        );
+        if let Some(timeout) = self.rrdp_snapshot_timeout {
+            res.insert(
+                "rrdp-snapshot-timeout".into(),
+                (timeout.as_secs() as i64).into()
+            );
+        }
+        if let Some(timeout) = self.rrdp_delta_timeout {
+            res.insert(
+                "rrdp-delta-timeout".into(),
+                (timeout.as_secs() as i64).into()
+            );
+        }
+        if !self.rrdp_snapshot_only.is_empty() {
+            res.insert(
+                "rrdp-snapshot-only".into(),
+                toml::Value::Array(
+                    self.rrdp_snapshot_only.iter().map(|uri| {
+                        uri.to_string().into()
+                    }).collect()
+                )
+            );
+        }
+        if !self.rrdp_session_pins.is_empty() {
+            res.insert(
+                "rrdp-session-pins".into(),
+                toml::Value::Array(
+                    self.rrdp_session_pins.iter().map(|(uri, session)| {
+                        toml::Value::Array(vec![
+                            uri.to_string().into(), session.to_string().into()
+                        ])
+                    }).collect()
+                )
+            );
+        }
+        if !self.rrdp_repository_timeouts.is_empty() {
+            res.insert(
+                "rrdp-repository-timeouts".into(),
+                toml::Value::Array(
+                    self.rrdp_repository_timeouts.iter().map(|(uri, value)| {
+                        let mut table = toml::value::Table::new();
+                        table.insert(
+                            "notify-uri".into(), uri.to_string().into()
+                        );
+                        if let Some(timeout) = value.timeout {
+                            table.insert(
+                                "timeout".into(),
+                                (timeout.as_secs() as i64).into()
+                            );
+                        }
+                        if let Some(deadline) = value.deadline {
+                            table.insert(
+                                "deadline".into(),
+                                (deadline.as_secs() as i64).into()
+                            );
+                        }
+                        toml::Value::Table(table)
+                    }).collect()
+                )
+            );
+        }
+        if !self.rrdp_mirrors.is_empty() {
+            res.insert(
+                "rrdp-mirrors".into(),
+                toml::Value::Array(
+                    self.rrdp_mirrors.iter().map(|(left, right)| {
+                        toml::Value::Array(vec![
+                            left.clone().into(), right.clone().into()
+                        ])
+                    }).collect()
+                )
+            );
+        }
+        if !self.rrdp_host_map.is_empty() {
+            res.insert(
+                "rrdp-host-map".into(),
+                toml::Value::Array(
+                    self.rrdp_host_map.iter().map(|(left, right)| {
+                        toml::Value::Array(vec![
+                            left.clone().into(), right.clone().into()
+                        ])
+                    }).collect()
+                )
+            );
+        }
+        res.insert("dirty".into(), self.dirty_repository.into());
+        res.insert(
+            "validation-threads".into(),
+            (self.validation_threads as i64).into()
+        );
+        res.insert("refresh".into(), (self.refresh.as_secs() as i64).into());
+        res.insert("retry".into(), (self.retry.as_secs() as i64).into());
+        res.insert("expire".into(), (self.expire.as_secs() as i64).into());
+        res.insert("history-size".into(), (self.history_size as i64).into());
+        res.insert(
+            "rtr-listen".into(),
+            toml::Value::Array(
+                self.rtr_listen.iter().map(|a| a.to_string().into()).collect()
+            )
+        );
+        res.insert(
+            "http-listen".into(),
+            toml::Value::Array(
+                self.http_listen.iter().map(|a| a.to_string().into()).collect()
+            )
+        );
+        if let Some(addr) = self.rrdp_debug_listen {
+            res.insert("rrdp-debug-listen".into(), addr.to_string().into());
+        }
+        res.insert("systemd-listen".into(), self.systemd_listen.into());
+        res.insert("rtr-tcp-keepalive".into(),
+            match self.rtr_tcp_keepalive {
+                Some(keep) => (keep.as_secs() as i64).into(),
                 None => 0.into(),
             }
         );
@@ -1626,6 +2947,195 @@ impl fmt::Display for StalePolicy {
 }
 
 
+//------------ ReplayPolicy --------------------------------------------------
+
+/// The local policy for handling RRDP session replays.
+///
+/// A notification file that switches back to a session the server has used
+/// before but doesn’t have a serial number higher than the last one we have
+/// seen for that session may be a replay of old data. This type defines
+/// what to do in this case.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReplayPolicy {
+    /// Log a warning and update from the snapshot.
+    ///
+    /// This is the default policy.
+    Snapshot,
+
+    /// Log a warning and keep the current data.
+    Refuse,
+}
+
+impl Default for ReplayPolicy {
+    fn default() -> Self {
+        ReplayPolicy::Snapshot
+    }
+}
+
+impl FromStr for ReplayPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snapshot" => Ok(ReplayPolicy::Snapshot),
+            "refuse" => Ok(ReplayPolicy::Refuse),
+            _ => Err(format!("invalid policy '{}'", s))
+        }
+    }
+}
+
+impl fmt::Display for ReplayPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ReplayPolicy::Snapshot => "snapshot",
+            ReplayPolicy::Refuse => "refuse",
+        })
+    }
+}
+
+
+//------------ DigestAlgorithm -----------------------------------------------
+
+/// The digest algorithm used for checking the local copy of RRDP servers.
+///
+/// The digest of a server’s data directory only serves to detect local
+/// changes made behind our back, not to protect against an attacker, so a
+/// faster non-cryptographic hash may be chosen instead of the default
+/// SHA-256.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DigestAlgorithm {
+    /// SHA-256.
+    ///
+    /// This is the default algorithm.
+    Sha256,
+
+    /// The 64 bit FNV-1a hash.
+    ///
+    /// This is a lot faster than SHA-256 but not collision resistant.
+    Fnv1a,
+}
+
+impl DigestAlgorithm {
+    /// The names of all algorithms.
+    pub const VALUES: &'static [&'static str] = &[
+        "sha256", "fnv1a"
+    ];
+}
+
+impl Default for DigestAlgorithm {
+    fn default() -> Self {
+        DigestAlgorithm::Sha256
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(DigestAlgorithm::Sha256),
+            "fnv1a" => Ok(DigestAlgorithm::Fnv1a),
+            _ => Err(format!("invalid digest algorithm '{}'", s))
+        }
+    }
+}
+
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            DigestAlgorithm::Sha256 => "sha256",
+            DigestAlgorithm::Fnv1a => "fnv1a",
+        })
+    }
+}
+
+
+//------------ AddressFamily -------------------------------------------------
+
+/// The address family to use for outgoing connections.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddressFamily {
+    /// Use whatever family works.
+    ///
+    /// This is the default.
+    Any,
+
+    /// Only use IPv4.
+    Ipv4,
+
+    /// Only use IPv6.
+    Ipv6,
+}
+
+impl AddressFamily {
+    /// The names of all address families.
+    pub const VALUES: &'static [&'static str] = &["any", "ipv4", "ipv6"];
+
+    /// Returns the unspecified address of the family.
+    ///
+    /// Binding to this address restricts connections to the family.
+    /// Returns `None` for `AddressFamily::Any`.
+    pub fn unspecified_addr(self) -> Option<IpAddr> {
+        match self {
+            AddressFamily::Any => None,
+            AddressFamily::Ipv4 => Some(Ipv4Addr::UNSPECIFIED.into()),
+            AddressFamily::Ipv6 => Some(Ipv6Addr::UNSPECIFIED.into()),
+        }
+    }
+
+    /// Returns whether `addr` belongs to the family.
+    pub fn contains(self, addr: IpAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::Ipv4 => addr.is_ipv4(),
+            AddressFamily::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
+impl Default for AddressFamily {
+    fn default() -> Self {
+        AddressFamily::Any
+    }
+}
+
+impl FromStr for AddressFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(AddressFamily::Any),
+            "ipv4" => Ok(AddressFamily::Ipv4),
+            "ipv6" => Ok(AddressFamily::Ipv6),
+            _ => Err(format!("invalid address family '{}'", s))
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            AddressFamily::Any => "any",
+            AddressFamily::Ipv4 => "ipv4",
+            AddressFamily::Ipv6 => "ipv6",
+        })
+    }
+}
+
+
+//------------ RrdpTimeouts --------------------------------------------------
+
+/// Timeouts overriding the defaults for a specific RRDP repository.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RrdpTimeouts {
+    /// The timeout for each individual request.
+    pub timeout: Option<Duration>,
+
+    /// The maximum time the complete update of the repository may take.
+    pub deadline: Option<Duration>,
+}
+
+
 //------------ ConfigFile ----------------------------------------------------
 
 /// The content of a config file.
@@ -1767,26 +3277,77 @@ impl ConfigFile {
         }
     }
 
-    /// Takes a small unsigned integer value from the config file.
-    ///
-    /// While the result is returned as an `usize`, it musn’t be in the
-    /// range of a `u16`.
+    /// Takes a non-negative number from the config file.
     ///
     /// The value is taken from the given `key`. Returns `Ok(None)` if there
     /// is no such key. Returns an error if the key exists but the value
-    /// isn’t an integer or if it is out of bounds.
-    fn take_small_usize(&mut self, key: &str) -> Result<Option<usize>, Error> {
-        match self.content.remove(key) {
-            Some(value) => {
-                if let toml::Value::Integer(res) = value {
-                    if res < 0 {
-                        error!(
-                            "Error in config file {}: \
-                            '{}' expected to be a positive integer.",
-                            self.path.display(), key
-                        );
-                        Err(Error)
-                    }
+    /// is neither a float nor an integer or if it is negative.
+    fn take_f64(&mut self, key: &str) -> Result<Option<f64>, Error> {
+        let res = match self.content.remove(key) {
+            Some(toml::Value::Float(res)) => res,
+            Some(toml::Value::Integer(res)) => res as f64,
+            Some(_) => {
+                error!(
+                    "Error in config file {}: \
+                     '{}' expected to be a number.",
+                    self.path.display(), key
+                );
+                return Err(Error)
+            }
+            None => return Ok(None)
+        };
+        if res < 0. {
+            error!(
+                "Error in config file {}: \
+                 '{}' expected to be a positive number.",
+                self.path.display(), key
+            );
+            Err(Error)
+        }
+        else {
+            Ok(Some(res))
+        }
+    }
+
+    /// Takes a fraction from the config file.
+    ///
+    /// The value must be a number greater than 0 and at most 1. If the
+    /// value is present but not such a number, prints an error message
+    /// and returns an error. If it isn’t present, returns `Ok(None)`.
+    fn take_fraction(&mut self, key: &str) -> Result<Option<f64>, Error> {
+        match self.take_f64(key)? {
+            Some(value) if !is_fraction(value) => {
+                error!(
+                    "Error in config file {}: \
+                     '{}' expected to be greater than 0 and at most 1.",
+                    self.path.display(), key
+                );
+                Err(Error)
+            }
+            value => Ok(value)
+        }
+    }
+
+    /// Takes a small unsigned integer value from the config file.
+    ///
+    /// While the result is returned as an `usize`, it musn’t be in the
+    /// range of a `u16`.
+    ///
+    /// The value is taken from the given `key`. Returns `Ok(None)` if there
+    /// is no such key. Returns an error if the key exists but the value
+    /// isn’t an integer or if it is out of bounds.
+    fn take_small_usize(&mut self, key: &str) -> Result<Option<usize>, Error> {
+        match self.content.remove(key) {
+            Some(value) => {
+                if let toml::Value::Integer(res) = value {
+                    if res < 0 {
+                        error!(
+                            "Error in config file {}: \
+                            '{}' expected to be a positive integer.",
+                            self.path.display(), key
+                        );
+                        Err(Error)
+                    }
                     else if res > ::std::u16::MAX.into() {
                         error!(
                             "Error in config file {}: \
@@ -2040,9 +3601,33 @@ impl ConfigFile {
         &mut self,
         key: &str
     ) -> Result<Option<HashMap<String, String>>, Error> {
+        let pairs = match self.take_string_pairs(key)? {
+            Some(pairs) => pairs,
+            None => return Ok(None)
+        };
+        let mut res = HashMap::new();
+        for (left, right) in pairs {
+            if res.insert(left, right).is_some() {
+                error!(
+                    "Error in config file {}: \
+                    'duplicate item in '{}'.",
+                    self.path.display(),
+                    key
+                );
+                return Err(Error);
+            }
+        }
+        Ok(Some(res))
+    }
+
+    /// Takes an ordered list of string pairs from the config file.
+    fn take_string_pairs(
+        &mut self,
+        key: &str
+    ) -> Result<Option<Vec<(String, String)>>, Error> {
         match self.content.remove(key) {
             Some(::toml::Value::Array(vec)) => {
-                let mut res = HashMap::new();
+                let mut res = Vec::new();
                 for value in vec.into_iter() {
                     let mut pair = match value {
                         ::toml::Value::Array(pair) => pair.into_iter(),
@@ -2089,15 +3674,7 @@ impl ConfigFile {
                         );
                         return Err(Error);
                     }
-                    if res.insert(left, right).is_some() {
-                        error!(
-                            "Error in config file {}: \
-                            'duplicate item in '{}'.",
-                            self.path.display(),
-                            key
-                        );
-                        return Err(Error);
-                    }
+                    res.push((left, right));
                 }
                 Ok(Some(res))
             }
@@ -2113,6 +3690,196 @@ impl ConfigFile {
         }
     }
 
+    /// Takes the per-repository RRDP timeouts from the config file.
+    ///
+    /// The value is an array of tables, each with a `notify-uri` and the
+    /// optional timeouts `timeout` and `deadline` in seconds.
+    fn take_rrdp_timeouts(
+        &mut self,
+        key: &str
+    ) -> Result<HashMap<uri::Https, RrdpTimeouts>, Error> {
+        let vec = match self.content.remove(key) {
+            Some(::toml::Value::Array(vec)) => vec,
+            Some(_) => {
+                error!(
+                    "Error in config file {}: \
+                     '{}' expected to be an array of tables.",
+                    self.path.display(), key
+                );
+                return Err(Error)
+            }
+            None => return Ok(HashMap::new())
+        };
+        let mut res = HashMap::new();
+        for value in vec {
+            let table = match value {
+                ::toml::Value::Table(table) => table,
+                _ => {
+                    error!(
+                        "Error in config file {}: \
+                         '{}' expected to be an array of tables.",
+                        self.path.display(), key
+                    );
+                    return Err(Error)
+                }
+            };
+            let mut item = ConfigFile {
+                content: table,
+                path: self.path.clone(),
+                dir: self.dir.clone(),
+            };
+            let notify_uri = match item.take_string("notify-uri")? {
+                Some(value) => match uri::Https::from_str(&value) {
+                    Ok(uri) => uri,
+                    Err(_) => {
+                        error!(
+                            "Error in config file {}: \
+                             invalid notify-uri '{}' in '{}'.",
+                            self.path.display(), value, key
+                        );
+                        return Err(Error)
+                    }
+                }
+                None => {
+                    error!(
+                        "Error in config file {}: \
+                         missing notify-uri in '{}'.",
+                        self.path.display(), key
+                    );
+                    return Err(Error)
+                }
+            };
+            let timeouts = RrdpTimeouts {
+                timeout: item.take_u64("timeout")?.map(Duration::from_secs),
+                deadline: item.take_u64("deadline")?.map(Duration::from_secs),
+            };
+            item.check_exhausted()?;
+            if res.insert(notify_uri, timeouts).is_some() {
+                error!(
+                    "Error in config file {}: \
+                    'duplicate item in '{}'.",
+                    self.path.display(),
+                    key
+                );
+                return Err(Error);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Takes the pinned sessions of RRDP repositories from the config file.
+    ///
+    /// The value has to be an array of string pairs, each with the
+    /// notification URI and the session.
+    fn take_rrdp_session_pins(
+        &mut self,
+        key: &str
+    ) -> Result<HashMap<uri::Https, Uuid>, Error> {
+        let pairs = match self.take_string_pairs(key)? {
+            Some(pairs) => pairs,
+            None => return Ok(HashMap::new())
+        };
+        let mut res = HashMap::new();
+        for (uri, session) in pairs {
+            let uri = match uri::Https::from_str(&uri) {
+                Ok(uri) => uri,
+                Err(_) => {
+                    error!(
+                        "Error in config file {}: \
+                         invalid notify URI '{}' in '{}'.",
+                        self.path.display(), uri, key
+                    );
+                    return Err(Error)
+                }
+            };
+            let session = match Uuid::from_str(&session) {
+                Ok(session) => session,
+                Err(_) => {
+                    error!(
+                        "Error in config file {}: \
+                         invalid session '{}' in '{}'.",
+                        self.path.display(), session, key
+                    );
+                    return Err(Error)
+                }
+            };
+            if res.insert(uri, session).is_some() {
+                error!(
+                    "Error in config file {}: \
+                    'duplicate item in '{}'.",
+                    self.path.display(),
+                    key
+                );
+                return Err(Error);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Takes the client certificates for RRDP hosts from the config file.
+    ///
+    /// The value has to be an array of tables each with a `host` and an
+    /// `identity` path. Host names are converted to lower case.
+    fn take_rrdp_client_certs(
+        &mut self,
+        key: &str
+    ) -> Result<HashMap<String, PathBuf>, Error> {
+        let vec = match self.content.remove(key) {
+            Some(::toml::Value::Array(vec)) => vec,
+            Some(_) => {
+                error!(
+                    "Error in config file {}: \
+                     '{}' expected to be an array of tables.",
+                    self.path.display(), key
+                );
+                return Err(Error)
+            }
+            None => return Ok(HashMap::new())
+        };
+        let mut res = HashMap::new();
+        for value in vec {
+            let table = match value {
+                ::toml::Value::Table(table) => table,
+                _ => {
+                    error!(
+                        "Error in config file {}: \
+                         '{}' expected to be an array of tables.",
+                        self.path.display(), key
+                    );
+                    return Err(Error)
+                }
+            };
+            let mut item = ConfigFile {
+                content: table,
+                path: self.path.clone(),
+                dir: self.dir.clone(),
+            };
+            let host = match item.take_string("host")? {
+                Some(host) => host.to_ascii_lowercase(),
+                None => {
+                    error!(
+                        "Error in config file {}: \
+                         missing host in '{}'.",
+                        self.path.display(), key
+                    );
+                    return Err(Error)
+                }
+            };
+            let identity = item.take_mandatory_path("identity")?;
+            item.check_exhausted()?;
+            if res.insert(host, identity).is_some() {
+                error!(
+                    "Error in config file {}: \
+                    'duplicate item in '{}'.",
+                    self.path.display(),
+                    key
+                );
+                return Err(Error);
+            }
+        }
+        Ok(res)
+    }
+
     /// Checks whether the config file is now empty.
     ///
     /// If it isn’t, logs a complaint and returns an error.
@@ -2171,6 +3938,43 @@ where T: FromStr, T::Err: fmt::Display {
     }
 }
 
+/// Returns whether a value is a fraction greater than 0 and at most 1.
+///
+/// This is false for NaN.
+fn is_fraction(value: f64) -> bool {
+    value > 0. && value <= 1.
+}
+
+/// Converts a number of seconds into a timeout with zero meaning none.
+fn nonzero_secs(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    }
+    else {
+        Some(Duration::from_secs(secs))
+    }
+}
+
+/// Converts a count into a limit with zero meaning none.
+fn nonzero_count(count: usize) -> Option<usize> {
+    if count == 0 {
+        None
+    }
+    else {
+        Some(count)
+    }
+}
+
+/// Converts a size into a limit with zero meaning none.
+fn nonzero_size(size: u64) -> Option<u64> {
+    if size == 0 {
+        None
+    }
+    else {
+        Some(size)
+    }
+}
+
 /// Converts the syslog facility name to the facility type.
 #[cfg(unix)]
 fn facility_to_string(facility: Facility) -> String {
@@ -2312,6 +4116,295 @@ mod test {
         );
     }
 
+    #[test]
+    #[cfg(unix)] // ... because of drive letters in absolute paths on Windows.
+    fn rrdp_repository_timeouts() {
+        let config = ConfigFile::parse(
+            "repository-dir = \"/repodir\"\n\
+             tal-dir = \"taldir\"\n\
+             [[rrdp-repository-timeouts]]\n\
+             notify-uri = \"https://slow.example.net/notification.xml\"\n\
+             timeout = 300\n\
+             [[rrdp-repository-timeouts]]\n\
+             notify-uri = \"https://example.net/notification.xml\"\n\
+             deadline = 600\n",
+            &Path::new("/test/routinator.conf")
+        ).unwrap();
+        let config = Config::from_config_file(config).unwrap();
+        let timeouts = &config.rrdp_repository_timeouts;
+        assert_eq!(timeouts.len(), 2);
+        assert_eq!(
+            timeouts.get(&uri::Https::from_str(
+                "https://slow.example.net/notification.xml"
+            ).unwrap()),
+            Some(&RrdpTimeouts {
+                timeout: Some(Duration::from_secs(300)),
+                deadline: None,
+            })
+        );
+        assert_eq!(
+            timeouts.get(&uri::Https::from_str(
+                "https://example.net/notification.xml"
+            ).unwrap()),
+            Some(&RrdpTimeouts {
+                timeout: None,
+                deadline: Some(Duration::from_secs(600)),
+            })
+        );
+
+        assert!(Config::from_config_file(ConfigFile::parse(
+            "repository-dir = \"/repodir\"\n\
+             tal-dir = \"taldir\"\n\
+             [[rrdp-repository-timeouts]]\n\
+             timeout = 300\n",
+            &Path::new("/test/routinator.conf")
+        ).unwrap()).is_err());
+    }
+
+    #[test]
+    fn rrdp_deadline_warning() {
+        let parse = |value: &str| {
+            Config::from_config_file(ConfigFile::parse(
+                &format!(
+                    "repository-dir = \"/repodir\"\n\
+                     tal-dir = \"taldir\"\n\
+                     rrdp-deadline-warning = {}\n",
+                    value
+                ),
+                &Path::new("/test/routinator.conf")
+            ).unwrap())
+        };
+        assert_eq!(parse("0.5").unwrap().rrdp_deadline_warning, 0.5);
+        assert_eq!(parse("1").unwrap().rrdp_deadline_warning, 1.);
+        for value in &["0", "-0.5", "1.5", "nan", "inf"] {
+            assert!(parse(value).is_err());
+        }
+
+        let args = |value: &str| {
+            get_default_config().apply_arg_matches(
+                &Config::config_args(App::new("routinator"))
+                    .get_matches_from_safe(vec![
+                        "routinator", "--rrdp-deadline-warning", value
+                    ]).unwrap(),
+                Path::new("/test")
+            )
+        };
+        assert!(args("0.9").is_ok());
+        for value in &["0", "-1", "2", "NaN", "inf"] {
+            assert!(args(value).is_err());
+        }
+    }
+
+    #[test]
+    fn rrdp_dedup_and_archive() {
+        let config = |content: &str| {
+            Config::from_config_file(ConfigFile::parse(
+                &format!(
+                    "repository-dir = \"/repodir\"\n\
+                     tal-dir = \"taldir\"\n{}",
+                    content
+                ),
+                &Path::new("/test/routinator.conf")
+            ).unwrap())
+        };
+        assert!(config("rrdp-dedup = true\n").is_ok());
+        assert!(config("rrdp-archive = true\n").is_ok());
+        assert!(
+            config("rrdp-dedup = true\nrrdp-archive = true\n").is_err()
+        );
+
+        // Also if only one of them is given on the command line.
+        let mut config = config("rrdp-dedup = true\n").unwrap();
+        assert!(config.apply_arg_matches(
+            &Config::config_args(App::new("routinator"))
+                .get_matches_from_safe(vec![
+                    "routinator", "--rrdp-archive"
+                ]).unwrap(),
+            Path::new("/test")
+        ).is_err());
+    }
+
+    #[test]
+    fn rrdp_session_pins() {
+        let config = ConfigFile::parse(
+            "repository-dir = \"/repodir\"\n\
+             tal-dir = \"taldir\"\n\
+             rrdp-session-pins = [\n\
+               [\"https://example.net/notification.xml\",\n\
+                \"9df4b597-af9e-4dca-bdda-719cce2c4e28\"],\n\
+               [\"https://other.example.net/notification.xml\",\n\
+                \"8a1b7f2c-2f23-4c2e-a3ae-3b1b1a4e0d6c\"],\n\
+             ]\n",
+            &Path::new("/test/routinator.conf")
+        ).unwrap();
+        let mut config = Config::from_config_file(config).unwrap();
+        let uri = uri::Https::from_str(
+            "https://example.net/notification.xml"
+        ).unwrap();
+        assert_eq!(config.rrdp_session_pins.len(), 2);
+        assert_eq!(
+            config.rrdp_session_pins.get(&uri),
+            Some(&Uuid::from_str(
+                "9df4b597-af9e-4dca-bdda-719cce2c4e28"
+            ).unwrap())
+        );
+
+        // A pin can be cleared on the command line.
+        config.apply_arg_matches(
+            &Config::config_args(App::new("routinator"))
+                .get_matches_from_safe(vec![
+                    "routinator", "--rrdp-unpin-session", uri.as_str()
+                ]).unwrap(),
+            Path::new("/test")
+        ).unwrap();
+        assert_eq!(config.rrdp_session_pins.len(), 1);
+        assert!(config.rrdp_session_pins.get(&uri).is_none());
+
+        assert!(Config::from_config_file(ConfigFile::parse(
+            "repository-dir = \"/repodir\"\n\
+             tal-dir = \"taldir\"\n\
+             rrdp-session-pins = [\n\
+               [\"https://example.net/notification.xml\", \"nope\"],\n\
+             ]\n",
+            &Path::new("/test/routinator.conf")
+        ).unwrap()).is_err());
+    }
+
+    #[test]
+    fn rrdp_dir_names() {
+        let config = |content: &str| {
+            Config::from_config_file(ConfigFile::parse(
+                &format!(
+                    "repository-dir = \"/repodir\"\n\
+                     tal-dir = \"taldir\"\n{}",
+                    content
+                ),
+                &Path::new("/test/routinator.conf")
+            ).unwrap())
+        };
+        let mut names = config(
+            "rrdp-state-file = \"rrdp-state\"\n\
+             rrdp-notification-file = \"notify.xml\"\n"
+        ).unwrap().rrdp_dir_names;
+        assert_eq!(names.state, "rrdp-state");
+        assert_eq!(names.notification, "notify.xml");
+        names.state = "state.txt".into();
+        names.notification = "notification.xml".into();
+        assert_eq!(names, ServerDirNames::default());
+        assert!(config("rrdp-hashes-file = \"data\"\n").is_err());
+        assert!(config("rrdp-manifest-file = \"sub/m.txt\"\n").is_err());
+
+        // The command line can create collisions, too.
+        let config = process_basic_args(&[
+            "routinator", "--rrdp-data-dir", "objects"
+        ]);
+        assert_eq!(config.rrdp_dir_names.data, "objects");
+        let mut config = get_default_config();
+        assert!(config.apply_arg_matches(
+            &Config::config_args(App::new("routinator"))
+                .get_matches_from_safe(vec![
+                    "routinator", "--rrdp-state-file", "hashes.txt"
+                ]).unwrap(),
+            Path::new("/test")
+        ).is_err());
+    }
+
+    #[test]
+    fn rrdp_timeout_args() {
+        let config = process_basic_args(&[
+            "routinator", "--rrdp-timeout", "30",
+            "--rrdp-connect-timeout", "5"
+        ]);
+        assert_eq!(
+            config.rrdp_timeout, Some(Some(Duration::from_secs(30)))
+        );
+        assert_eq!(
+            config.rrdp_connect_timeout, Some(Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn rrdp_address_family() {
+        let config = ConfigFile::parse(
+            "repository-dir = \"/repodir\"\n\
+             tal-dir = \"taldir\"\n\
+             rrdp-address-family = \"ipv4\"\n",
+            &Path::new("/test/routinator.conf")
+        ).unwrap();
+        let mut config = Config::from_config_file(config).unwrap();
+        assert_eq!(config.rrdp_address_family, AddressFamily::Ipv4);
+        assert_eq!(
+            config.rrdp_address_family.unspecified_addr(),
+            Some(IpAddr::from(Ipv4Addr::UNSPECIFIED))
+        );
+        let family = config.rrdp_address_family;
+        assert!(family.contains("192.0.2.1".parse().unwrap()));
+        assert!(!family.contains("2001:db8::1".parse().unwrap()));
+
+        config.apply_arg_matches(
+            &Config::config_args(App::new("routinator"))
+                .get_matches_from_safe(vec![
+                    "routinator", "--rrdp-address-family", "any"
+                ]).unwrap(),
+            Path::new("/test")
+        ).unwrap();
+        assert_eq!(config.rrdp_address_family, AddressFamily::Any);
+        assert_eq!(config.rrdp_address_family.unspecified_addr(), None);
+
+        assert!(Config::from_config_file(ConfigFile::parse(
+            "repository-dir = \"/repodir\"\n\
+             tal-dir = \"taldir\"\n\
+             rrdp-address-family = \"ipx\"\n",
+            &Path::new("/test/routinator.conf")
+        ).unwrap()).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)] // ... because of drive letters in absolute paths on Windows.
+    fn rrdp_client_certs() {
+        let config = ConfigFile::parse(
+            "repository-dir = \"/repodir\"\n\
+             tal-dir = \"taldir\"\n\
+             [[rrdp-client-certs]]\n\
+             host = \"RRDP.Example.net\"\n\
+             identity = \"client.pem\"\n",
+            &Path::new("/test/routinator.conf")
+        ).unwrap();
+        let config = Config::from_config_file(config).unwrap();
+        assert_eq!(config.rrdp_client_certs.len(), 1);
+        assert_eq!(
+            config.rrdp_client_certs.get("rrdp.example.net"),
+            Some(&PathBuf::from("/test/client.pem"))
+        );
+
+        assert!(Config::from_config_file(ConfigFile::parse(
+            "repository-dir = \"/repodir\"\n\
+             tal-dir = \"taldir\"\n\
+             [[rrdp-client-certs]]\n\
+             host = \"rrdp.example.net\"\n",
+            &Path::new("/test/routinator.conf")
+        ).unwrap()).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)] // ... because of drive letters in absolute paths on Windows.
+    fn rrdp_host_map() {
+        let config = ConfigFile::parse(
+            "repository-dir = \"/repodir\"\n\
+             tal-dir = \"taldir\"\n\
+             rrdp-host-map = [\
+                 [\"RRDP.Example.net\", \"mirror.internal\"]\
+             ]\n",
+            &Path::new("/test/routinator.conf")
+        ).unwrap();
+        let config = Config::from_config_file(config).unwrap();
+        assert_eq!(config.rrdp_host_map.len(), 1);
+        assert_eq!(
+            config.rrdp_host_map.get("rrdp.example.net").map(String::as_str),
+            Some("mirror.internal")
+        );
+    }
+
     #[test]
     #[cfg(unix)] // ... because of drive letters in absolute paths on Windows.
     fn minimal_config_file() {
@@ -370,6 +370,165 @@ fn metrics_active(
         }
     }
 
+    // rrdp_consecutive_failures
+    writeln!(res, "
+        \n\
+        # HELP routinator_rrdp_consecutive_failures number of consecutive \
+            runs the RRDP server has been broken\n\
+        # TYPE routinator_rrdp_consecutive_failures gauge"
+    ).unwrap();
+    for metrics in metrics.rrdp() {
+        writeln!(
+            res,
+            "routinator_rrdp_consecutive_failures{{uri=\"{}\"}} {}",
+            metrics.notify_uri, metrics.consecutive_failures
+        ).unwrap();
+    }
+
+    // rrdp_deadline_warning
+    writeln!(res, "
+        \n\
+        # HELP routinator_rrdp_deadline_warning RRDP servers whose update \
+            got close to its deadline\n\
+        # TYPE routinator_rrdp_deadline_warning gauge"
+    ).unwrap();
+    for metrics in metrics.rrdp() {
+        if let Some(step) = metrics.deadline_warning {
+            writeln!(
+                res,
+                "routinator_rrdp_deadline_warning{{uri=\"{}\",step=\"{}\"}} 1",
+                metrics.notify_uri, step
+            ).unwrap();
+        }
+    }
+
+    // rrdp_update_kind
+    writeln!(res, "
+        \n\
+        # HELP routinator_rrdp_update_kind how the RRDP server was updated\n\
+        # TYPE routinator_rrdp_update_kind gauge"
+    ).unwrap();
+    for metrics in metrics.rrdp() {
+        if let Some(kind) = metrics.update_kind {
+            writeln!(
+                res,
+                "routinator_rrdp_update_kind{{uri=\"{}\",kind=\"{}\"}} 1",
+                metrics.notify_uri, kind.as_str()
+            ).unwrap();
+        }
+    }
+
+    // rrdp_bytes_downloaded
+    writeln!(res, "
+        \n\
+        # HELP routinator_rrdp_bytes_downloaded bytes downloaded during \
+            the last update of the RRDP server\n\
+        # TYPE routinator_rrdp_bytes_downloaded gauge"
+    ).unwrap();
+    for metrics in metrics.rrdp() {
+        writeln!(
+            res,
+            "routinator_rrdp_bytes_downloaded{{uri=\"{}\"}} {}",
+            metrics.notify_uri, metrics.bytes_downloaded
+        ).unwrap();
+    }
+
+    // rrdp_total_bytes_downloaded
+    writeln!(res,
+        "\n\
+        # HELP routinator_rrdp_total_bytes_downloaded bytes downloaded for \
+            all RRDP servers during the last validation run\n\
+        # TYPE routinator_rrdp_total_bytes_downloaded gauge\n\
+        routinator_rrdp_total_bytes_downloaded {}",
+        metrics.rrdp_bytes_downloaded(),
+    ).unwrap();
+
+    // rrdp_objects
+    writeln!(res, "
+        \n\
+        # HELP routinator_rrdp_objects number of objects in the local copy \
+            of the RRDP server\n\
+        # TYPE routinator_rrdp_objects gauge"
+    ).unwrap();
+    for metrics in metrics.rrdp() {
+        if let Some(count) = metrics.object_count {
+            writeln!(
+                res,
+                "routinator_rrdp_objects{{uri=\"{}\"}} {}",
+                metrics.notify_uri, count
+            ).unwrap();
+        }
+    }
+
+    // rrdp_deltas_applied
+    writeln!(res, "
+        \n\
+        # HELP routinator_rrdp_deltas_applied number of deltas applied \
+            during the last update of the RRDP server\n\
+        # TYPE routinator_rrdp_deltas_applied gauge"
+    ).unwrap();
+    for metrics in metrics.rrdp() {
+        if let Some(count) = metrics.deltas_applied {
+            writeln!(
+                res,
+                "routinator_rrdp_deltas_applied{{uri=\"{}\"}} {}",
+                metrics.notify_uri, count
+            ).unwrap();
+        }
+    }
+
+    // rrdp_delta_serial_span
+    writeln!(res, "
+        \n\
+        # HELP routinator_rrdp_delta_serial_span difference between the \
+            serial numbers before and after applying deltas during the last \
+            update of the RRDP server\n\
+        # TYPE routinator_rrdp_delta_serial_span gauge"
+    ).unwrap();
+    for metrics in metrics.rrdp() {
+        if let Some((from, to)) = metrics.delta_serials {
+            writeln!(
+                res,
+                "routinator_rrdp_delta_serial_span{{uri=\"{}\"}} {}",
+                metrics.notify_uri, to.wrapping_sub(from)
+            ).unwrap();
+        }
+    }
+
+    // rrdp_lock_wait
+    writeln!(res, "
+        \n\
+        # HELP routinator_rrdp_lock_wait time in seconds the last update of \
+            the RRDP server waited for its update lock\n\
+        # TYPE routinator_rrdp_lock_wait gauge"
+    ).unwrap();
+    for metrics in metrics.rrdp() {
+        if let Some(wait) = metrics.lock_wait {
+            writeln!(
+                res,
+                "routinator_rrdp_lock_wait{{uri=\"{}\"}} {:.3}",
+                metrics.notify_uri, wait.as_secs_f64()
+            ).unwrap();
+        }
+    }
+
+    // rrdp_work_time
+    writeln!(res, "
+        \n\
+        # HELP routinator_rrdp_work_time time in seconds the last update of \
+            the RRDP server spent fetching and applying data\n\
+        # TYPE routinator_rrdp_work_time gauge"
+    ).unwrap();
+    for metrics in metrics.rrdp() {
+        if let Some(time) = metrics.work_time {
+            writeln!(
+                res,
+                "routinator_rrdp_work_time{{uri=\"{}\"}} {:.3}",
+                metrics.notify_uri, time.as_secs_f64()
+            ).unwrap();
+        }
+    }
+
     // rtr_connections
     writeln!(res, "
         \n\
@@ -26,6 +26,9 @@ pub struct Metrics {
     /// RRDP metrics.
     rrdp: Vec<RrdpServerMetrics>,
 
+    /// Number of bytes downloaded via RRDP for all servers.
+    rrdp_bytes_downloaded: u64,
+
     /// Number of stale objects.
     stale_count: AtomicU64,
 
@@ -40,6 +43,7 @@ impl Metrics {
             tals: Vec::new(),
             rsync: Vec::new(),
             rrdp: Vec::new(),
+            rrdp_bytes_downloaded: 0,
             stale_count: AtomicU64::new(0),
             local_vrps: 0,
         }
@@ -71,6 +75,10 @@ impl Metrics {
         self.rrdp = rrdp
     }
 
+    pub fn set_rrdp_bytes_downloaded(&mut self, bytes: u64) {
+        self.rrdp_bytes_downloaded = bytes
+    }
+
     pub fn time(&self) -> DateTime<Utc> {
         self.time
     }
@@ -95,6 +103,10 @@ impl Metrics {
         &self.rrdp
     }
 
+    pub fn rrdp_bytes_downloaded(&self) -> u64 {
+        self.rrdp_bytes_downloaded
+    }
+
     pub fn rsync_complete(&self) -> bool {
         for metrics in &self.rsync {
             match metrics.status {
@@ -189,6 +201,36 @@ pub struct RrdpServerMetrics {
     pub notify_status: Option<reqwest::StatusCode>,
     pub serial: Option<u64>,
     pub duration: Result<Duration, SystemTimeError>,
+
+    /// The number of consecutive runs the server has been broken.
+    pub consecutive_failures: u32,
+
+    /// The update step during which the deadline warning was issued.
+    pub deadline_warning: Option<&'static str>,
+
+    /// How the server was updated.
+    pub update_kind: Option<RrdpUpdateKind>,
+
+    /// The number of bytes downloaded during the update.
+    pub bytes_downloaded: u64,
+
+    /// The number of objects in the local copy after the update.
+    pub object_count: Option<usize>,
+
+    /// The number of deltas applied if the server was updated via deltas.
+    pub deltas_applied: Option<usize>,
+
+    /// The serial numbers before and after applying the deltas.
+    pub delta_serials: Option<(u64, u64)>,
+
+    /// How long the update waited for the update lock.
+    pub lock_wait: Option<Duration>,
+
+    /// How long the actual work of the update took.
+    ///
+    /// This covers fetching and applying the updates as well as checking
+    /// the local copy if that failed.
+    pub work_time: Option<Duration>,
 }
 
 impl RrdpServerMetrics {
@@ -197,7 +239,47 @@ impl RrdpServerMetrics {
             notify_uri,
             notify_status: None,
             serial: None,
-            duration: Ok(Duration::from_secs(0))
+            duration: Ok(Duration::from_secs(0)),
+            consecutive_failures: 0,
+            deadline_warning: None,
+            update_kind: None,
+            bytes_downloaded: 0,
+            object_count: None,
+            deltas_applied: None,
+            delta_serials: None,
+            lock_wait: None,
+            work_time: None,
+        }
+    }
+}
+
+
+//------------ RrdpUpdateKind ------------------------------------------------
+
+/// The way an RRDP server was updated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RrdpUpdateKind {
+    /// The server was updated via deltas.
+    Delta,
+
+    /// The server was updated via the snapshot.
+    Snapshot,
+
+    /// The local copy was current already.
+    NotNeeded,
+
+    /// The update failed.
+    Failed,
+}
+
+impl RrdpUpdateKind {
+    /// Returns the name of the update kind used in metrics.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RrdpUpdateKind::Delta => "delta",
+            RrdpUpdateKind::Snapshot => "snapshot",
+            RrdpUpdateKind::NotNeeded => "not-needed",
+            RrdpUpdateKind::Failed => "failed",
         }
     }
 }
@@ -30,6 +30,7 @@ use crate::origins::{AddressOrigins, AddressPrefix, OriginsHistory};
 use crate::output;
 use crate::output::OutputFormat;
 use crate::repository::Repository;
+use crate::rrdp;
 use crate::rtr::{rtr_listener};
 use crate::slurm::LocalExceptions;
 use crate::validity::RouteValidity;
@@ -63,6 +64,7 @@ pub enum Operation {
     #[cfg(feature = "rta")]
     ValidateDocument(ValidateDocument),
     Update(Update),
+    Rrdp(Rrdp),
     PrintConfig(PrintConfig),
     Man(Man),
 }
@@ -86,6 +88,7 @@ impl Operation {
         let app = ValidateDocument::config_args(app);
 
         let app = Update::config_args(app);
+        let app = Rrdp::config_args(app);
         let app = PrintConfig::config_args(app);
         Man::config_args(app)
     }
@@ -120,6 +123,9 @@ impl Operation {
             ("update", Some(matches)) => {
                 Operation::Update(Update::from_arg_matches(matches)?)
             }
+            ("rrdp", Some(matches)) => {
+                Operation::Rrdp(Rrdp::from_arg_matches(matches)?)
+            }
             ("config", Some(matches)) => {
                 Operation::PrintConfig(
                     PrintConfig::from_arg_matches(matches, cur_dir, config)?
@@ -160,6 +166,7 @@ impl Operation {
             #[cfg(feature = "rta")]
             Operation::ValidateDocument(cmd) => cmd.run(config),
             Operation::Update(cmd) => cmd.run(config),
+            Operation::Rrdp(cmd) => cmd.run(config),
             Operation::PrintConfig(cmd) => cmd.run(config),
             Operation::Man(cmd) => cmd.run(config),
         }
@@ -386,10 +393,14 @@ impl Server {
         let history = OriginsHistory::new(&config);
         let (mut notify, rtr) = rtr_listener(history.clone(), &config)?;
         let http = http_listener(&history, &config)?;
+        let debug = repo.rrdp_debug_listener(&config)?;
 
         if self.detach {
             Self::daemonize(&mut config)?;
         }
+        if let Some(debug) = debug {
+            debug.spawn();
+        }
 
         let mut runtime = config.runtime()?;
         let mut rtr = runtime.spawn(rtr);
@@ -998,6 +1009,224 @@ impl Update {
 }
 
 
+//------------ Rrdp ----------------------------------------------------------
+
+/// Inspect the local RRDP cache.
+pub enum Rrdp {
+    /// Print the digest of an RRDP server’s data directory.
+    Digest {
+        /// The data directory.
+        path: PathBuf,
+    },
+
+    /// Report what updating all servers in the cache would do.
+    Check,
+
+    /// Report the health of all servers in the cache.
+    Doctor,
+
+    /// Run an update cycle against a local mock repository.
+    Selftest,
+
+    /// Verify the local copies of all servers without network access.
+    Verify,
+
+    /// Export statistics of all servers in the cache.
+    Stats {
+        /// The output format.
+        format: rrdp::StatsFormat,
+    },
+}
+
+impl Rrdp {
+    /// Adds the command configuration to a clap app.
+    pub fn config_args<'a: 'b, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        app.subcommand(SubCommand::with_name("rrdp")
+            .about("Inspects the local RRDP cache")
+            .subcommand(SubCommand::with_name("check")
+                .about("Reports what updating cached RRDP servers would do")
+            )
+            .subcommand(SubCommand::with_name("digest")
+                .about("Prints the digest of an RRDP data directory")
+                .arg(Arg::with_name("data-dir")
+                    .value_name("DIR")
+                    .help("The data directory of an RRDP server")
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("doctor")
+                .about("Reports the health of all cached RRDP servers")
+            )
+            .subcommand(SubCommand::with_name("selftest")
+                .about("Tests RRDP updates against a local mock repository")
+            )
+            .subcommand(SubCommand::with_name("verify")
+                .about("Verifies the local copies of cached RRDP servers")
+            )
+            .subcommand(SubCommand::with_name("stats")
+                .about("Exports statistics of all cached RRDP servers")
+                .arg(Arg::with_name("format")
+                    .short("f")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .possible_values(rrdp::StatsFormat::VALUES)
+                    .default_value("csv")
+                    .help("Sets the output format")
+                )
+            )
+        )
+    }
+
+    /// Creates a command from clap matches.
+    pub fn from_arg_matches(matches: &ArgMatches) -> Result<Self, Error> {
+        match matches.subcommand() {
+            ("check", Some(_)) => Ok(Rrdp::Check),
+            ("digest", Some(matches)) => {
+                Ok(Rrdp::Digest {
+                    // Required argument, so unwrap is fine.
+                    path: matches.value_of("data-dir").unwrap().into()
+                })
+            }
+            ("doctor", Some(_)) => Ok(Rrdp::Doctor),
+            ("selftest", Some(_)) => Ok(Rrdp::Selftest),
+            ("verify", Some(_)) => Ok(Rrdp::Verify),
+            ("stats", Some(matches)) => {
+                Ok(Rrdp::Stats {
+                    // Argument has a default, so unwrap is fine.
+                    format: matches.value_of("format").unwrap().parse()?
+                })
+            }
+            _ => {
+                error!(
+                    "Error: an rrdp command is required.\n\
+                     \nAvailable commands are:\
+                     \n   check     Reports what updates would do\
+                     \n   digest    Prints the digest of a data directory\
+                     \n   doctor    Reports the health of cached servers\
+                     \n   selftest  Tests updates against a mock repository\
+                     \n   stats     Exports statistics of cached servers\
+                     \n   verify    Verifies the local copies offline\
+                     \n\
+                     \nSee routinator rrdp -h for a usage summary."
+                );
+                Err(Error)
+            }
+        }
+    }
+
+    fn run(self, config: Config) -> Result<(), ExitError> {
+        match self {
+            Rrdp::Check => {
+                let mut cache = match rrdp::Cache::new(&config, true)? {
+                    Some(cache) => cache,
+                    None => {
+                        error!("RRDP is disabled.");
+                        return Err(ExitError::Generic)
+                    }
+                };
+                cache.ignite()?;
+                for (path, uri, report) in cache.check()? {
+                    println!("{} ({}): {}", uri, path.display(), report);
+                }
+                Ok(())
+            }
+            Rrdp::Digest { path } => {
+                println!(
+                    "{}",
+                    rrdp::server::data_digest(
+                        &path, config.rrdp_local_digest,
+                        config.rrdp_deep_digest
+                    )?
+                );
+                Ok(())
+            }
+            Rrdp::Doctor => {
+                let mut cache = match rrdp::Cache::open(&config, true)? {
+                    Some(cache) => cache,
+                    None => {
+                        error!("RRDP is disabled.");
+                        return Err(ExitError::Generic)
+                    }
+                };
+                cache.ignite()?;
+                for (path, uri, diagnosis) in cache.doctor()? {
+                    match uri {
+                        Some(uri) => {
+                            println!(
+                                "{} ({}): {}", uri, path.display(), diagnosis
+                            );
+                        }
+                        None => {
+                            println!("{}: {}", path.display(), diagnosis);
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Rrdp::Selftest => {
+                let tmp = match tempfile::tempdir() {
+                    Ok(tmp) => tmp,
+                    Err(err) => {
+                        error!(
+                            "Failed to create temporary directory: {}", err
+                        );
+                        return Err(ExitError::Generic)
+                    }
+                };
+                rrdp::selftest(tmp.path())?;
+                println!("RRDP self test succeeded.");
+                Ok(())
+            }
+            Rrdp::Verify => {
+                let cache = match rrdp::Cache::new(&config, false)? {
+                    Some(cache) => cache,
+                    None => {
+                        error!("RRDP is disabled.");
+                        return Err(ExitError::Generic)
+                    }
+                };
+                let mut consistent = true;
+                for (path, uri, report) in cache.verify_local()? {
+                    consistent = consistent && report.consistent;
+                    match uri {
+                        Some(uri) => {
+                            println!(
+                                "{} ({}): {}", uri, path.display(), report
+                            );
+                        }
+                        None => {
+                            println!("{}: {}", path.display(), report);
+                        }
+                    }
+                }
+                if consistent {
+                    Ok(())
+                }
+                else {
+                    Err(ExitError::Generic)
+                }
+            }
+            Rrdp::Stats { format } => {
+                let cache = match rrdp::Cache::new(&config, false)? {
+                    Some(cache) => cache,
+                    None => {
+                        error!("RRDP is disabled.");
+                        return Err(ExitError::Generic)
+                    }
+                };
+                let stats = cache.stats()?;
+                let stdout = io::stdout();
+                if let Err(err) = format.write(&stats, &mut stdout.lock()) {
+                    error!("Failed to write statistics: {}", err);
+                    return Err(ExitError::Generic)
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+
 //------------ Config --------------------------------------------------------
 
 
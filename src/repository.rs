@@ -128,6 +128,16 @@ impl Repository {
         })
     }
 
+    /// Binds the RRDP debug socket if RRDP is used and a socket configured.
+    pub fn rrdp_debug_listener(
+        &self, config: &Config
+    ) -> Result<Option<rrdp::DebugListener>, Error> {
+        match self.rrdp {
+            Some(ref rrdp) => rrdp.debug_listener(config),
+            None => Ok(None)
+        }
+    }
+
     /// Reloads the TAL files based on the config object.
     pub fn reload_tals(&mut self, config: &Config) -> Result<(), Error> {
         self.tals = Self::load_tals(config)?;
@@ -238,6 +248,7 @@ impl Repository {
         self.ignite()?;
         let run = Run::new(self, processor)?;
         run.process()?;
+        run.done();
         Ok(run.into_metrics())
     }
 
@@ -288,9 +299,17 @@ impl<'a, P> Run<'a, P> {
         })
     }
 
+    /// Finishes the run once all processing is done.
+    pub fn done(&self) {
+        if let Some(ref rrdp) = self.rrdp {
+            rrdp.done()
+        }
+    }
+
     pub fn into_metrics(self) -> Metrics {
         let mut res = self.metrics;
         if let Some(rrdp) = self.rrdp {
+            res.set_rrdp_bytes_downloaded(rrdp.take_total_downloaded());
             res.set_rrdp(rrdp.into_metrics());
         }
         if let Some(rsync) = self.rsync {
@@ -793,6 +812,9 @@ impl<'a, P: ProcessRun> Run<'a, P> {
     }
 
     /// Loads the content of a file from the given URI.
+    ///
+    /// If the file can’t be loaded from the RRDP server for any reason
+    /// other than it being absent, falls back to rsync.
     fn load_file(
         &self,
         rrdp_server: Option<rrdp::ServerId>,
@@ -800,8 +822,10 @@ impl<'a, P: ProcessRun> Run<'a, P> {
     ) -> Option<Bytes> {
         if let Some(id) = rrdp_server {
             if let Some(rrdp) = self.rrdp.as_ref() {
-                if let Ok(res) = rrdp.load_file(id, uri) {
-                    return res
+                match rrdp.load_file(id, uri) {
+                    rrdp::LoadResult::Found(bytes) => return Some(bytes),
+                    rrdp::LoadResult::NotFound => return None,
+                    _ => { }
                 }
             }
         }
@@ -0,0 +1,978 @@
+//! Keeping the objects of a local copy in a single archive.
+//!
+//! This is a private module for organizational purposes.
+//!
+//! With many small objects, keeping one file per object strains file
+//! systems. The `ArchiveStore` instead keeps each data directory as an
+//! archive: a directory holding only an index file and a single objects
+//! file to which the content of all objects is appended. The index lists
+//! the offset, length, and path of each object, with the path relative to
+//! the data directory and its components separated by slashes. Reading
+//! through the store gives the same directory tree with the same file
+//! sizes as before, so the digest of the local copy doesn’t change.
+//!
+//! A plain directory becomes an archive when it is moved into place via
+//! `rename`, which is how an update via the snapshot installs its data
+//! directory. Updates via deltas append published objects to the objects
+//! file and rewrite the index in `commit`. Until then, changes are only
+//! kept in memory. If the index isn’t written because the process dies,
+//! the archive doesn’t match the state file and the server is updated via
+//! the snapshot the next time.
+//!
+//! Since objects are never overwritten in place, the objects file keeps
+//! growing. Once less than half of it is in use, `commit` copies the
+//! objects still in use into a new objects file. The index names the
+//! objects file it refers to, so writing the new index switches files
+//! atomically. Objects files not named by the index are removed then.
+//!
+//! Paths not inside an archive are passed on to the file system.
+
+use std::{cmp, fs, io};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use rand::random;
+use super::store::{CacheStore, FsStore, StoreEntry};
+use super::utils::{lock_recovered, move_path, sync_dir};
+
+
+//------------ Configuration Constants ---------------------------------------
+
+/// The file name of the index of an archive.
+const INDEX_NAME: &str = "#index";
+
+/// The file name the index is written to before replacing the index.
+const NEW_INDEX_NAME: &str = "#index.new";
+
+/// The start of the file names of objects files.
+const OBJECTS_PREFIX: &str = "#objects.";
+
+/// The size of an objects file below which it is never compacted.
+const COMPACT_MIN: u64 = 1024 * 1024;
+
+
+//------------ ArchiveStore --------------------------------------------------
+
+/// A store keeping data directories as archives.
+#[derive(Debug)]
+pub struct ArchiveStore {
+    /// The directory archives are kept under.
+    ///
+    /// Only directories below it are checked for being archives.
+    base: PathBuf,
+
+    /// Whether to flush archives to storage when committing changes.
+    fsync: bool,
+
+    /// The archives used so far.
+    ///
+    /// The keys are the paths of the archive directories.
+    archives: Mutex<HashMap<PathBuf, Arc<Mutex<Archive>>>>,
+}
+
+impl ArchiveStore {
+    /// Creates a new store for archives under `base`.
+    pub fn new(base: PathBuf, fsync: bool) -> Self {
+        ArchiveStore {
+            base,
+            fsync,
+            archives: Default::default(),
+        }
+    }
+
+    /// Returns the archive `path` is in and the path relative to it.
+    ///
+    /// The relative path is empty if `path` is the archive directory.
+    fn find(
+        &self, path: &Path
+    ) -> Result<Option<(Arc<Mutex<Archive>>, String)>, io::Error> {
+        if !path.starts_with(&self.base) {
+            return Ok(None)
+        }
+        let mut archives = lock_recovered(&self.archives);
+        let roots: Vec<_> = path.ancestors().take_while(|root| {
+            *root != self.base
+        }).collect();
+
+        // Try an archive we know about first so we don’t have to check
+        // all the ancestors for an index file.
+        let known = roots.iter().find(|root| {
+            archives.contains_key(**root)
+        }).cloned();
+        for root in known.into_iter().chain(roots.iter().cloned()) {
+            if let Some(archive) = Self::get(&mut archives, root)? {
+                return Ok(Some((archive, relative_path(root, path)?)))
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the archive at `root` if there is one.
+    ///
+    /// A known archive is only used if its index file hasn’t changed.
+    /// Otherwise the index is loaded again.
+    fn get(
+        archives: &mut HashMap<PathBuf, Arc<Mutex<Archive>>>, root: &Path
+    ) -> Result<Option<Arc<Mutex<Archive>>>, io::Error> {
+        let stamp = match IndexStamp::load(root) {
+            Some(stamp) => stamp,
+            None => {
+                archives.remove(root);
+                return Ok(None)
+            }
+        };
+        if let Some(archive) = archives.get(root) {
+            if lock_recovered(archive).stamp == Some(stamp) {
+                return Ok(Some(archive.clone()))
+            }
+        }
+        let archive = Arc::new(Mutex::new(Archive::load(root)?));
+        archives.insert(root.into(), archive.clone());
+        Ok(Some(archive))
+    }
+
+    /// Forgets about all archives at or below `path`.
+    fn forget(&self, path: &Path) {
+        lock_recovered(&self.archives).retain(|root, _| {
+            !root.starts_with(path)
+        })
+    }
+}
+
+impl CacheStore for ArchiveStore {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, io::Error> {
+        match self.find(path)? {
+            Some((archive, rel)) => lock_recovered(&archive).read(&rel, None),
+            None => fs::read(path)
+        }
+    }
+
+    fn read_prefix(
+        &self, path: &Path, len: usize
+    ) -> Result<Vec<u8>, io::Error> {
+        match self.find(path)? {
+            Some((archive, rel)) => {
+                lock_recovered(&archive).read(&rel, Some(len))
+            }
+            None => FsStore.read_prefix(path, len)
+        }
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> Result<(), io::Error> {
+        match self.find(path)? {
+            Some((archive, rel)) => lock_recovered(&archive).insert(rel, data),
+            None => fs::write(path, data)
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<StoreEntry>, io::Error> {
+        match self.find(path)? {
+            Some((archive, rel)) => lock_recovered(&archive).read_dir(&rel),
+            None => FsStore.read_dir(path)
+        }
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        match self.find(path) {
+            Ok(Some((archive, rel))) => lock_recovered(&archive).is_dir(&rel),
+            Ok(None) => path.is_dir(),
+            Err(_) => false
+        }
+    }
+
+    fn is_local(&self, path: &Path) -> bool {
+        matches!(self.find(path), Ok(None))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), io::Error> {
+        match self.find(from)? {
+            Some((_, ref rel)) if rel.is_empty() => {
+                self.forget(from);
+                self.forget(to);
+                move_path(from, to)
+            }
+            Some((archive, rel)) => {
+                let data = lock_recovered(&archive).read(&rel, None)?;
+                self.write(to, &data)?;
+                lock_recovered(&archive).remove(&rel)
+            }
+            None if fs::symlink_metadata(from)?.is_dir() => {
+                Archive::pack(from, self.fsync)?;
+                self.forget(to);
+                move_path(from, to)
+            }
+            None => {
+                match self.find(to)? {
+                    Some((archive, rel)) => {
+                        let data = fs::read(from)?;
+                        lock_recovered(&archive).insert(rel, &data)?;
+                        fs::remove_file(from)
+                    }
+                    None => move_path(from, to)
+                }
+            }
+        }
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), io::Error> {
+        match self.find(path)? {
+            Some((archive, rel)) => lock_recovered(&archive).remove(&rel),
+            None => fs::remove_file(path)
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<(), io::Error> {
+        match self.find(path)? {
+            Some((archive, rel)) if !rel.is_empty() => {
+                lock_recovered(&archive).remove_dir(&rel)
+            }
+            _ => {
+                self.forget(path);
+                fs::remove_dir_all(path)
+            }
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), io::Error> {
+        match self.find(path)? {
+            // Directories in an archive exist as long as they have files.
+            Some(_) => Ok(()),
+            None => fs::create_dir_all(path)
+        }
+    }
+
+    fn sync(&self, path: &Path) -> Result<(), io::Error> {
+        match self.find(path)? {
+            Some((archive, _)) => lock_recovered(&archive).commit(true),
+            None => FsStore.sync(path)
+        }
+    }
+
+    fn commit(&self, path: &Path) -> Result<(), io::Error> {
+        match self.find(path)? {
+            Some((archive, _)) => {
+                lock_recovered(&archive).commit(self.fsync)
+            }
+            None => Ok(())
+        }
+    }
+}
+
+
+//------------ Archive -------------------------------------------------------
+
+/// A single archive.
+#[derive(Debug)]
+struct Archive {
+    /// The directory of the archive.
+    root: PathBuf,
+
+    /// The file name of the current objects file.
+    objects: String,
+
+    /// The length of the current objects file.
+    objects_len: u64,
+
+    /// The objects by their path relative to the archive directory.
+    index: BTreeMap<String, IndexEntry>,
+
+    /// Whether the index has changed since it was last written.
+    dirty: bool,
+
+    /// Whether the archive has been flushed since it was last changed.
+    synced: bool,
+
+    /// The stamp of the index file when it was last read or written.
+    stamp: Option<IndexStamp>,
+}
+
+impl Archive {
+    /// Loads the archive in the directory `root`.
+    fn load(root: &Path) -> Result<Self, io::Error> {
+        let path = root.join(INDEX_NAME);
+        let stamp = IndexStamp::load(root);
+        let mut lines = io::BufReader::new(fs::File::open(&path)?).lines();
+        let objects = match lines.next() {
+            Some(line) => {
+                let line = line?;
+                if !line.starts_with("objects ") {
+                    return Err(invalid_index(&path))
+                }
+                line["objects ".len()..].to_string()
+            }
+            None => return Err(invalid_index(&path))
+        };
+        if !objects.starts_with(OBJECTS_PREFIX) || objects.contains('/') {
+            return Err(invalid_index(&path))
+        }
+        let mut index = BTreeMap::new();
+        for line in lines {
+            let line = line?;
+            let mut parts = line.splitn(3, ' ');
+            let offset = parts.next().and_then(|s| s.parse().ok());
+            let len = parts.next().and_then(|s| s.parse().ok());
+            let rel = parts.next();
+            match (offset, len, rel) {
+                (Some(offset), Some(len), Some(rel)) if !rel.is_empty() => {
+                    index.insert(rel.into(), IndexEntry { offset, len });
+                }
+                _ => return Err(invalid_index(&path))
+            }
+        }
+        let objects_len = fs::metadata(root.join(&objects))?.len();
+        Ok(Archive {
+            root: root.into(),
+            objects,
+            objects_len,
+            index,
+            dirty: false,
+            synced: true,
+            stamp,
+        })
+    }
+
+    /// Turns the plain directory `dir` into an archive.
+    ///
+    /// All files are copied into the objects file and then removed.
+    fn pack(dir: &Path, fsync: bool) -> Result<(), io::Error> {
+        let mut files = Vec::new();
+        collect_files(dir, String::new(), &mut files)?;
+        files.sort();
+        let objects = objects_name();
+        let mut target = io::BufWriter::new(
+            fs::File::create(dir.join(&objects))?
+        );
+        let mut index = BTreeMap::new();
+        let mut offset = 0;
+        for (rel, path) in files {
+            let len = io::copy(&mut fs::File::open(path)?, &mut target)?;
+            index.insert(rel, IndexEntry { offset, len });
+            offset += len;
+        }
+        let target = target.into_inner()?;
+        if fsync {
+            target.sync_all()?;
+        }
+        let mut archive = Archive {
+            root: dir.into(),
+            objects,
+            objects_len: offset,
+            index,
+            dirty: true,
+            synced: false,
+            stamp: None,
+        };
+        archive.write_index(fsync)?;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if archive.is_own_file(&entry.file_name().to_string_lossy()) {
+                continue
+            }
+            if entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            }
+            else {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        if fsync {
+            sync_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the content of an object or the first `limit` bytes of it.
+    fn read(
+        &self, rel: &str, limit: Option<usize>
+    ) -> Result<Vec<u8>, io::Error> {
+        let entry = match self.index.get(rel) {
+            Some(entry) => *entry,
+            None => return Err(io::ErrorKind::NotFound.into())
+        };
+        let len = match limit {
+            Some(limit) => cmp::min(limit as u64, entry.len),
+            None => entry.len
+        };
+        let mut file = fs::File::open(self.root.join(&self.objects))?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let mut res = Vec::with_capacity(len as usize);
+        file.take(len).read_to_end(&mut res)?;
+        if (res.len() as u64) < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof, "truncated RRDP archive"
+            ))
+        }
+        Ok(res)
+    }
+
+    /// Adds an object, replacing an existing object at the same path.
+    fn insert(&mut self, rel: String, data: &[u8]) -> Result<(), io::Error> {
+        if rel.is_empty() || rel.contains('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid path for RRDP archive"
+            ))
+        }
+        let mut file = fs::OpenOptions::new().append(true).open(
+            self.root.join(&self.objects)
+        )?;
+        // A failed earlier write may have left data behind.
+        let offset = file.metadata()?.len();
+        file.write_all(data)?;
+        let len = data.len() as u64;
+        self.index.insert(rel, IndexEntry { offset, len });
+        self.objects_len = offset + len;
+        self.changed();
+        Ok(())
+    }
+
+    /// Removes an object.
+    fn remove(&mut self, rel: &str) -> Result<(), io::Error> {
+        if self.index.remove(rel).is_none() {
+            return Err(io::ErrorKind::NotFound.into())
+        }
+        self.changed();
+        Ok(())
+    }
+
+    /// Removes all objects in a directory.
+    fn remove_dir(&mut self, rel: &str) -> Result<(), io::Error> {
+        let prefix = format!("{}/", rel);
+        let paths: Vec<_> = self.index.range(prefix.clone()..).take_while(
+            |(path, _)| path.starts_with(&prefix)
+        ).map(|(path, _)| path.clone()).collect();
+        if paths.is_empty() {
+            return Err(io::ErrorKind::NotFound.into())
+        }
+        for path in paths {
+            self.index.remove(&path);
+        }
+        self.changed();
+        Ok(())
+    }
+
+    /// Returns whether there is a directory at the given path.
+    fn is_dir(&self, rel: &str) -> bool {
+        if rel.is_empty() {
+            return true
+        }
+        let prefix = format!("{}/", rel);
+        self.index.range(prefix.clone()..).next().map(|(path, _)| {
+            path.starts_with(&prefix)
+        }).unwrap_or(false)
+    }
+
+    /// Returns the entries of the directory at the given path.
+    fn read_dir(&self, rel: &str) -> Result<Vec<StoreEntry>, io::Error> {
+        let prefix = if rel.is_empty() {
+            String::new()
+        }
+        else {
+            format!("{}/", rel)
+        };
+        let mut res: Vec<StoreEntry> = Vec::new();
+        for (path, entry) in self.index.range(prefix.clone()..) {
+            if !path.starts_with(&prefix) {
+                break
+            }
+            let rest = &path[prefix.len()..];
+            match rest.find('/') {
+                Some(pos) => {
+                    // All paths in a directory are next to each other, so
+                    // we only need to check the last entry for duplicates.
+                    let name = &rest[..pos];
+                    let known = res.last().map(|last| {
+                        last.size.is_none() && last.name == name
+                    }).unwrap_or(false);
+                    if !known {
+                        res.push(StoreEntry { name: name.into(), size: None })
+                    }
+                }
+                None => {
+                    res.push(StoreEntry {
+                        name: rest.into(), size: Some(entry.len)
+                    })
+                }
+            }
+        }
+        if res.is_empty() && !rel.is_empty() {
+            return Err(io::ErrorKind::NotFound.into())
+        }
+        Ok(res)
+    }
+
+    /// Writes all changes to the archive.
+    ///
+    /// If `fsync` is `true`, the archive is flushed to storage, too.
+    fn commit(&mut self, fsync: bool) -> Result<(), io::Error> {
+        if self.dirty {
+            self.compact(fsync)?;
+            if fsync {
+                fs::File::open(self.root.join(&self.objects))?.sync_all()?;
+            }
+            self.write_index(fsync)?;
+            self.remove_stale_objects();
+            self.synced = fsync;
+        }
+        else if fsync && !self.synced {
+            fs::File::open(self.root.join(&self.objects))?.sync_all()?;
+            fs::File::open(self.root.join(INDEX_NAME))?.sync_all()?;
+            sync_dir(&self.root)?;
+            self.synced = true;
+        }
+        Ok(())
+    }
+
+    /// Copies the objects into a new objects file if enough are unused.
+    fn compact(&mut self, fsync: bool) -> Result<(), io::Error> {
+        let used: u64 = self.index.values().map(|entry| entry.len).sum();
+        if self.objects_len < COMPACT_MIN || self.objects_len <= 2 * used {
+            return Ok(())
+        }
+        let objects = objects_name();
+        let mut source = fs::File::open(self.root.join(&self.objects))?;
+        let mut target = io::BufWriter::new(
+            fs::File::create(self.root.join(&objects))?
+        );
+        let mut index = BTreeMap::new();
+        let mut offset = 0;
+        for (path, entry) in &self.index {
+            source.seek(SeekFrom::Start(entry.offset))?;
+            let len = io::copy(
+                &mut (&mut source).take(entry.len), &mut target
+            )?;
+            if len < entry.len {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof, "truncated RRDP archive"
+                ))
+            }
+            index.insert(path.clone(), IndexEntry { offset, len });
+            offset += len;
+        }
+        let target = target.into_inner()?;
+        if fsync {
+            target.sync_all()?;
+        }
+        self.objects = objects;
+        self.objects_len = offset;
+        self.index = index;
+        Ok(())
+    }
+
+    /// Writes the index file.
+    fn write_index(&mut self, fsync: bool) -> Result<(), io::Error> {
+        let tmp = self.root.join(NEW_INDEX_NAME);
+        let mut file = io::BufWriter::new(fs::File::create(&tmp)?);
+        writeln!(file, "objects {}", self.objects)?;
+        for (path, entry) in &self.index {
+            writeln!(file, "{} {} {}", entry.offset, entry.len, path)?;
+        }
+        let file = file.into_inner()?;
+        if fsync {
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, self.root.join(INDEX_NAME))?;
+        if fsync {
+            sync_dir(&self.root)?;
+        }
+        self.stamp = IndexStamp::load(&self.root);
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Removes all objects files other than the current one.
+    fn remove_stale_objects(&self) {
+        let dir = match fs::read_dir(&self.root) {
+            Ok(dir) => dir,
+            Err(_) => return
+        };
+        for entry in dir.filter_map(Result::ok) {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(OBJECTS_PREFIX) && name != self.objects {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    /// Returns whether a file name is that of a file of the archive itself.
+    fn is_own_file(&self, name: &str) -> bool {
+        name == INDEX_NAME || name == NEW_INDEX_NAME || name == self.objects
+    }
+
+    /// Marks the archive as changed.
+    fn changed(&mut self) {
+        self.dirty = true;
+        self.synced = false;
+    }
+}
+
+
+//------------ IndexEntry ----------------------------------------------------
+
+/// The location of an object in the objects file.
+#[derive(Clone, Copy, Debug)]
+struct IndexEntry {
+    /// The offset of the object in the objects file.
+    offset: u64,
+
+    /// The length of the object.
+    len: u64,
+}
+
+
+//------------ IndexStamp ----------------------------------------------------
+
+/// Identifies a version of an index file.
+///
+/// This is used to notice when an index file has been replaced or
+/// removed behind our back.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct IndexStamp {
+    /// The length of the index file.
+    len: u64,
+
+    /// The modification time of the index file if available.
+    modified: Option<SystemTime>,
+}
+
+impl IndexStamp {
+    /// Returns the stamp of the index file of the archive at `root`.
+    ///
+    /// Returns `None` if there is no index file.
+    fn load(root: &Path) -> Option<Self> {
+        let meta = fs::metadata(root.join(INDEX_NAME)).ok()?;
+        if !meta.is_file() {
+            return None
+        }
+        Some(IndexStamp { len: meta.len(), modified: meta.modified().ok() })
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+/// Returns a new file name for an objects file.
+fn objects_name() -> String {
+    format!("{}{}", OBJECTS_PREFIX, random::<u32>())
+}
+
+/// Returns `path` relative to `root` with slashes as separators.
+fn relative_path(root: &Path, path: &Path) -> Result<String, io::Error> {
+    let rel = path.strip_prefix(root).map_err(|_| {
+        io::Error::from(io::ErrorKind::InvalidInput)
+    })?;
+    let mut res = String::new();
+    for component in rel.components() {
+        let name = match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None
+        };
+        let name = match name {
+            Some(name) => name,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "invalid path for RRDP archive"
+                ))
+            }
+        };
+        if !res.is_empty() {
+            res.push('/');
+        }
+        res.push_str(name);
+    }
+    Ok(res)
+}
+
+/// Adds all files under `dir` to `files`.
+///
+/// Each file is added with its path relative to the top-level directory
+/// with `prefix` being the relative path of `dir`.
+fn collect_files(
+    dir: &Path, prefix: String, files: &mut Vec<(String, PathBuf)>
+) -> Result<(), io::Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid file name for RRDP archive"
+                ))
+            }
+        };
+        let rel = if prefix.is_empty() {
+            name
+        }
+        else {
+            format!("{}/{}", prefix, name)
+        };
+        if entry.file_type()?.is_dir() {
+            collect_files(&entry.path(), rel, files)?;
+        }
+        else {
+            files.push((rel, entry.path()));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the error for an invalid index file.
+fn invalid_index(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid RRDP archive index {}", path.display())
+    )
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+    use crate::metrics::RrdpUpdateKind;
+    use crate::rrdp::mock::{MockClient, https_uri, rsync_uri, write_objects};
+    use crate::rrdp::server::{LoadResult, Server, ServerOptions};
+    use crate::rrdp::store::SharedStore;
+
+    fn entries(store: &dyn CacheStore, path: &Path) -> Vec<StoreEntry> {
+        let mut res = store.read_dir(path).unwrap();
+        res.sort_by(|left, right| left.name.cmp(&right.name));
+        res
+    }
+
+    fn setup() -> (tempfile::TempDir, ArchiveStore, PathBuf, PathBuf) {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = ArchiveStore::new(tmp.path().into(), false);
+        let plain = tmp.path().join("tmp");
+        write_objects(&plain, "example.net", &[
+            ("a.cer", b"certificate"), ("a.roa", b"roa"),
+            ("sub/b.mft", b"manifest"),
+        ]);
+        let data = tmp.path().join("server").join("data");
+        fs::create_dir_all(data.parent().unwrap()).unwrap();
+        (tmp, store, plain, data)
+    }
+
+    #[test]
+    fn pack_and_read() {
+        let (_tmp, store, plain, data) = setup();
+        let expected = entries(&FsStore, &plain.join("example.net/repo"));
+        store.rename(&plain, &data).unwrap();
+        assert!(!plain.exists());
+
+        // Only the archive’s own files are left on disk.
+        let mut files: Vec<_> = fs::read_dir(&data).unwrap().map(|entry| {
+            entry.unwrap().file_name().into_string().unwrap()
+        }).collect();
+        files.sort();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0], INDEX_NAME);
+        assert!(files[1].starts_with(OBJECTS_PREFIX));
+
+        // But the store sees the same tree as before.
+        let repo = data.join("example.net/repo");
+        assert_eq!(entries(&store, &repo), expected);
+        assert_eq!(
+            entries(&store, &data),
+            vec![StoreEntry { name: "example.net".into(), size: None }]
+        );
+        assert!(store.is_dir(&data));
+        assert!(store.is_dir(&repo.join("sub")));
+        assert!(!store.is_dir(&repo.join("a.cer")));
+        assert!(!store.is_dir(&repo.join("none")));
+        assert!(!store.is_local(&repo.join("a.cer")));
+        assert_eq!(store.read(&repo.join("a.cer")).unwrap(), b"certificate");
+        assert_eq!(
+            store.read_prefix(&repo.join("sub/b.mft"), 3).unwrap(), b"man"
+        );
+        assert_eq!(
+            store.read(&repo.join("none.cer")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            store.read_dir(&repo.join("none")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        // Moving the archive moves all of it.
+        let old = data.with_file_name("data.old");
+        store.rename(&data, &old).unwrap();
+        assert!(!store.is_dir(&data));
+        assert_eq!(
+            store.read(&old.join("example.net/repo/a.cer")).unwrap(),
+            b"certificate"
+        );
+        store.remove_dir_all(&old).unwrap();
+        assert!(!old.exists());
+    }
+
+    #[test]
+    fn apply_and_commit() {
+        let (tmp, store, plain, data) = setup();
+        store.rename(&plain, &data).unwrap();
+        let repo = data.join("example.net/repo");
+
+        // Objects are moved in like from the temporary directory.
+        let source = tmp.path().join("source");
+        fs::write(&source, b"new certificate").unwrap();
+        store.create_dir_all(&repo.join("new")).unwrap();
+        store.rename(&source, &repo.join("new/c.cer")).unwrap();
+        assert!(!source.exists());
+        store.remove_file(&repo.join("a.roa")).unwrap();
+        store.remove_dir_all(&repo.join("sub")).unwrap();
+        assert_eq!(
+            store.remove_file(&repo.join("a.roa")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            store.read(&repo.join("new/c.cer")).unwrap(), b"new certificate"
+        );
+
+        // Without a commit, a new store doesn’t see the changes.
+        let fresh = ArchiveStore::new(tmp.path().into(), false);
+        assert_eq!(fresh.read(&repo.join("a.roa")).unwrap(), b"roa");
+        assert!(fresh.read(&repo.join("new/c.cer")).is_err());
+
+        store.commit(&data).unwrap();
+        let fresh = ArchiveStore::new(tmp.path().into(), false);
+        assert_eq!(
+            entries(&fresh, &repo),
+            vec![
+                StoreEntry { name: "a.cer".into(), size: Some(11) },
+                StoreEntry { name: "new".into(), size: None },
+            ]
+        );
+        assert_eq!(
+            fresh.read(&repo.join("new/c.cer")).unwrap(), b"new certificate"
+        );
+
+        // A store notices when an archive changes behind its back.
+        fresh.write(&repo.join("a.cer"), b"other").unwrap();
+        fresh.commit(&data).unwrap();
+        assert_eq!(store.read(&repo.join("a.cer")).unwrap(), b"other");
+    }
+
+    #[test]
+    fn compact() {
+        let (_tmp, store, plain, data) = setup();
+        store.rename(&plain, &data).unwrap();
+        let path = data.join("example.net/repo/a.cer");
+        let objects_len = || {
+            fs::read_dir(&data).unwrap().filter_map(|entry| {
+                let entry = entry.unwrap();
+                if entry.file_name().to_string_lossy().starts_with(
+                    OBJECTS_PREFIX
+                ) {
+                    Some(entry.metadata().unwrap().len())
+                }
+                else {
+                    None
+                }
+            }).collect::<Vec<_>>()
+        };
+
+        // Replacing a large object leaves the old copies behind ...
+        let large = vec![0x30u8; COMPACT_MIN as usize / 2];
+        store.write(&path, &large).unwrap();
+        store.write(&path, &large).unwrap();
+        store.write(&path, &large).unwrap();
+        let len = objects_len();
+        assert_eq!(len.len(), 1);
+        assert!(len[0] > COMPACT_MIN);
+
+        // ... until they are removed during commit.
+        store.commit(&data).unwrap();
+        let len = objects_len();
+        assert_eq!(len.len(), 1);
+        assert_eq!(len[0], COMPACT_MIN / 2 + 3 + 8);
+        assert_eq!(store.read(&path).unwrap(), large);
+        let fresh = ArchiveStore::new(data.parent().unwrap().into(), false);
+        assert_eq!(
+            fresh.read(&data.join("example.net/repo/sub/b.mft")).unwrap(),
+            b"manifest"
+        );
+    }
+
+    #[test]
+    fn plain_paths() {
+        let (tmp, store, plain, _) = setup();
+        let path = plain.join("example.net/repo/a.cer");
+        assert!(store.is_local(&path));
+        assert_eq!(store.read(&path).unwrap(), b"certificate");
+        store.write(&path, b"changed").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"changed");
+        let moved = tmp.path().join("moved.cer");
+        store.rename(&path, &moved).unwrap();
+        assert_eq!(fs::read(&moved).unwrap(), b"changed");
+        store.remove_file(&moved).unwrap();
+        assert!(!moved.exists());
+    }
+
+    #[test]
+    fn archive_store() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let options = Arc::new(ServerOptions {
+            store: SharedStore::new(
+                ArchiveStore::new(cache_dir.clone(), false)
+            ),
+            .. Default::default()
+        });
+        let session = Uuid::new_v4();
+        client.publish(
+            session, 1,
+            &[("a.cer", b"first".as_ref()), ("b.roa", b"roa".as_ref())], &[]
+        );
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, options.clone()
+        );
+        server.update(&client);
+        assert!(!server.is_broken());
+
+        // The data directory only holds the archive.
+        let data_files = || {
+            fs::read_dir(server.server_dir().join("data")).unwrap().count()
+        };
+        assert_eq!(data_files(), 2);
+        assert_eq!(
+            server.load_file(&rsync_uri("b.roa")).found().unwrap().as_ref(),
+            b"roa"
+        );
+
+        // There is no file metadata for objects in the archive.
+        assert!(matches!(
+            server.load_file_with_meta(&rsync_uri("b.roa")),
+            LoadResult::IoError
+        ));
+
+        // Deltas are applied to the archive.
+        let hash = client.publish_delta(
+            session, 2, "a.cer", b"first", b"second"
+        );
+        client.publish(
+            session, 2,
+            &[("a.cer", b"second".as_ref()), ("b.roa", b"roa".as_ref())],
+            &[(2, hash)]
+        );
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            options
+        );
+        server.update(&client);
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Delta)
+        );
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"second"
+        );
+        assert_eq!(data_files(), 2);
+    }
+}
@@ -0,0 +1,169 @@
+//! Not retrying broken RRDP servers too often.
+//!
+//! This is a private module for organizational purposes.
+//!
+//! When the local copy of a server is found to be unusable, it is removed
+//! and the next update starts from scratch. For a server that is down for
+//! good, this means a failing snapshot download in every run. To avoid
+//! this, the time before which the server shouldn’t be tried again is
+//! recorded in a file in the backoff directory. Since this directory lives
+//! outside the RRDP cache directory, it survives both the removal of the
+//! local copy and restarting the process.
+//!
+//! The files are named after the SHA-256 hash of the notification URI.
+//! They contain the time in seconds since the Unix epoch on the first line
+//! followed by the notification URI on the second for the benefit of
+//! human readers.
+
+use std::{fs, io};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::warn;
+use ring::digest;
+use rpki::uri;
+use rpki::rrdp::DigestHex;
+use crate::config::Config;
+
+
+//------------ BrokenBackoff -------------------------------------------------
+
+/// The times before which broken servers shouldn’t be updated again.
+///
+/// By default, there is no backoff at all.
+#[derive(Clone, Debug, Default)]
+pub struct BrokenBackoff {
+    /// The directory of the backoff files or `None` if disabled.
+    dir: Option<PathBuf>,
+
+    /// The time to wait before trying a broken server again.
+    delay: Duration,
+}
+
+impl BrokenBackoff {
+    /// Creates a backoff keeping its files in `dir`.
+    ///
+    /// A `delay` of zero disables the backoff.
+    pub fn new(dir: PathBuf, delay: Duration) -> Self {
+        BrokenBackoff {
+            dir: if delay == Duration::from_secs(0) { None }
+                 else { Some(dir) },
+            delay,
+        }
+    }
+
+    /// Creates the backoff from the configuration.
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            config.cache_dir.join("rrdp-backoff"), config.rrdp_broken_backoff
+        )
+    }
+
+    /// Returns the time before which a server shouldn’t be updated.
+    ///
+    /// Returns `None` if there is no such time.
+    pub fn retry_after(&self, notify_uri: &uri::Https) -> Option<SystemTime> {
+        let content = fs::read_to_string(self.path(notify_uri)?).ok()?;
+        let secs = u64::from_str(content.lines().next()?).ok()?;
+        Some(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Records that a server was found broken at `now`.
+    pub fn mark(&self, notify_uri: &uri::Https, now: SystemTime) {
+        let path = match self.path(notify_uri) {
+            Some(path) => path,
+            None => return
+        };
+        let until = (now + self.delay).duration_since(
+            UNIX_EPOCH
+        ).unwrap_or_default().as_secs();
+        let res = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(
+            |_| fs::write(&path, format!("{}\n{}\n", until, notify_uri))
+        );
+        if let Err(err) = res {
+            warn!(
+                "RRDP {}: failed to write backoff file {}: {}",
+                notify_uri, path.display(), err
+            );
+        }
+    }
+
+    /// Forgets about a server having been broken.
+    pub fn clear(&self, notify_uri: &uri::Https) {
+        let path = match self.path(notify_uri) {
+            Some(path) => path,
+            None => return
+        };
+        if let Err(err) = fs::remove_file(&path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                warn!(
+                    "RRDP {}: failed to remove backoff file {}: {}",
+                    notify_uri, path.display(), err
+                );
+            }
+        }
+    }
+
+    /// Returns the path of the backoff file for a server.
+    fn path(&self, notify_uri: &uri::Https) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| {
+            dir.join(DigestHex::from(
+                digest::digest(&digest::SHA256, notify_uri.as_str().as_bytes())
+            ).to_string())
+        })
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn notify_uri() -> uri::Https {
+        uri::Https::from_str("https://example.net/notification.xml").unwrap()
+    }
+
+    #[test]
+    fn mark_and_clear() {
+        let tmp = tempfile::tempdir().unwrap();
+        let backoff = BrokenBackoff::new(
+            tmp.path().join("backoff"), Duration::from_secs(3600)
+        );
+        assert_eq!(backoff.retry_after(&notify_uri()), None);
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        backoff.mark(&notify_uri(), now);
+        assert_eq!(
+            backoff.retry_after(&notify_uri()),
+            Some(now + Duration::from_secs(3600))
+        );
+
+        // The time survives in another instance.
+        let backoff = BrokenBackoff::new(
+            tmp.path().join("backoff"), Duration::from_secs(60)
+        );
+        assert_eq!(
+            backoff.retry_after(&notify_uri()),
+            Some(now + Duration::from_secs(3600))
+        );
+        backoff.clear(&notify_uri());
+        assert_eq!(backoff.retry_after(&notify_uri()), None);
+        backoff.clear(&notify_uri());
+    }
+
+    #[test]
+    fn disabled() {
+        let tmp = tempfile::tempdir().unwrap();
+        let backoff = BrokenBackoff::new(
+            tmp.path().join("backoff"), Duration::from_secs(0)
+        );
+        backoff.mark(&notify_uri(), SystemTime::now());
+        assert_eq!(backoff.retry_after(&notify_uri()), None);
+        assert!(!tmp.path().join("backoff").exists());
+        assert_eq!(
+            BrokenBackoff::default().retry_after(&notify_uri()), None
+        );
+    }
+}
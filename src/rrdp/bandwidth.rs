@@ -0,0 +1,165 @@
+//! Accounting for and limiting the bandwidth used by RRDP downloads.
+//!
+//! This is a private module for organizational purposes.
+//!
+//! All bytes read from response bodies are recorded with a single
+//! `Bandwidth` shared by all downloads of the HTTP client. It keeps the
+//! total number of bytes downloaded and, if a rate is configured, throttles
+//! the readers via a token bucket. The bucket holds at most one second
+//! worth of bytes. A reader taking more bytes than are in the bucket leaves
+//! it in debt and sleeps until the debt is paid off. Since all readers share
+//! the bucket, later readers also wait for the debts of earlier ones, which
+//! keeps the combined rate at the limit.
+
+use std::thread;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+
+//------------ Bandwidth -----------------------------------------------------
+
+/// The bandwidth used by all downloads.
+#[derive(Debug)]
+pub struct Bandwidth {
+    /// The number of bytes downloaded since the last call to `take_total`.
+    total: AtomicU64,
+
+    /// The token bucket if the rate is limited.
+    bucket: Option<Mutex<TokenBucket>>,
+}
+
+impl Bandwidth {
+    /// Creates a new value limited to `rate` bytes per second.
+    ///
+    /// If `rate` is `None` or zero, the bandwidth isn’t limited.
+    pub fn new(rate: Option<u64>) -> Self {
+        Bandwidth {
+            total: AtomicU64::new(0),
+            bucket: rate.and_then(|rate| {
+                if rate == 0 {
+                    None
+                }
+                else {
+                    Some(Mutex::new(TokenBucket::new(rate, Instant::now())))
+                }
+            }),
+        }
+    }
+
+    /// Records that `bytes` have been read.
+    ///
+    /// If the rate is limited, this sleeps as long as necessary to stay
+    /// within the limit.
+    pub fn record(&self, bytes: u64) {
+        self.total.fetch_add(bytes, Ordering::Relaxed);
+        let wait = match self.bucket {
+            Some(ref bucket) => {
+                bucket.lock().unwrap().take(bytes, Instant::now())
+            }
+            None => return
+        };
+        if let Some(wait) = wait {
+            thread::sleep(wait)
+        }
+    }
+
+    /// Returns and resets the number of bytes downloaded.
+    pub fn take_total(&self) -> u64 {
+        self.total.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl Default for Bandwidth {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+
+//------------ TokenBucket ---------------------------------------------------
+
+/// A token bucket with one token per byte.
+#[derive(Debug)]
+struct TokenBucket {
+    /// The number of tokens added per second.
+    ///
+    /// This is also the capacity of the bucket.
+    rate: u64,
+
+    /// The number of tokens currently in the bucket.
+    ///
+    /// This becomes negative if more tokens were taken than available.
+    tokens: f64,
+
+    /// The time tokens were last added.
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a new, full bucket.
+    fn new(rate: u64, now: Instant) -> Self {
+        TokenBucket { rate, tokens: rate as f64, last: now }
+    }
+
+    /// Takes `bytes` tokens out of the bucket at `now`.
+    ///
+    /// Returns how long to wait until the bucket is out of debt again or
+    /// `None` if there is no debt.
+    fn take(&mut self, bytes: u64, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.last);
+        self.last = now;
+        let rate = self.rate as f64;
+        self.tokens = (
+            self.tokens + elapsed.as_secs_f64() * rate
+        ).min(rate);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0. {
+            None
+        }
+        else {
+            Some(Duration::from_secs_f64(-self.tokens / rate))
+        }
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+
+        // The bucket starts out full.
+        assert_eq!(bucket.take(1000, start), None);
+
+        // Now it is in debt.
+        assert_eq!(bucket.take(500, start), Some(Duration::from_millis(500)));
+
+        // Later readers wait for the debt, too.
+        assert_eq!(
+            bucket.take(250, start + Duration::from_millis(250)),
+            Some(Duration::from_millis(500))
+        );
+
+        // The bucket never holds more than one second worth.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take(1000, later), None);
+        assert_eq!(bucket.take(500, later), Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn total() {
+        let bandwidth = Bandwidth::new(Some(0));
+        assert!(bandwidth.bucket.is_none());
+        bandwidth.record(10);
+        bandwidth.record(5);
+        assert_eq!(bandwidth.take_total(), 15);
+        assert_eq!(bandwidth.take_total(), 0);
+    }
+}
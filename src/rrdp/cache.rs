@@ -6,7 +6,7 @@ use std::{fs, io};
 use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use bytes::Bytes;
 use log::{error, info, warn};
 use rpki::uri;
@@ -15,8 +15,15 @@ use crate::config::Config;
 use crate::metrics::RrdpServerMetrics;
 use crate::operation::Error;
 use crate::utils::UriExt;
-use super::http::HttpClient;
-use super::server::{Server, ServerState};
+use super::debug::DebugListener;
+use super::http::{HttpClient, ReqwestClient};
+use super::server::{
+    CheckReport, Diagnosis, LoadResult, Server, ServerOptions, ServerState,
+    VerifyReport,
+};
+use super::sessions::SessionTracker;
+use super::stats::ServerStats;
+use super::throttle::LoadThrottle;
 
 
 ///----------- Configuration Constants ---------------------------------------
@@ -39,14 +46,33 @@ pub struct Cache {
     /// A HTTP client.
     ///
     /// If this is `None`, we don’t actually do updates.
-    http: Option<HttpClient>,
+    http: Option<ReqwestClient>,
 
     /// Whether to filter dubious authorities in notify URIs.
     filter_dubious: bool,
+
+    /// The options for the individual servers.
+    options: Arc<ServerOptions>,
+
+    /// Pauses server updates while the system is too busy.
+    throttle: LoadThrottle,
+
+    /// Backs off from servers that change their session too often.
+    sessions: SessionTracker,
+
+    /// The number of consecutive runs each server has been broken.
+    failures: Arc<FailureCounts>,
 }
 
 impl Cache {
+    /// Creates the directories used by the cache.
+    ///
+    /// A read-only cache is never written to, so nothing is created and the
+    /// directories have to exist already.
     pub fn init(config: &Config) -> Result<(), Error> {
+        if config.rrdp_read_only {
+            return Ok(())
+        }
         let rrdp_dir = Self::cache_dir(config);
         if let Err(err) = fs::create_dir_all(&rrdp_dir) {
             error!(
@@ -63,28 +89,54 @@ impl Cache {
             );
             return Err(Error);
         }
-        HttpClient::init(config)?;
+        ReqwestClient::init(config)?;
         Ok(())
     }
 
     pub fn new(config: &Config, update: bool) -> Result<Option<Self>, Error> {
+        if !config.disable_rrdp {
+            Self::init(config)?;
+        }
+        Self::open(config, update)
+    }
+
+    /// Opens an existing cache without creating any directories.
+    ///
+    /// This is for commands that only inspect the cache. If the cache
+    /// directory doesn’t exist, they will fail when they access it.
+    pub fn open(
+        config: &Config, update: bool
+    ) -> Result<Option<Self>, Error> {
         if config.disable_rrdp {
             Ok(None)
         }
         else {
-            Self::init(config)?;
+            let options = ServerOptions::from_config(config);
+            let http = if update {
+                let mut http = ReqwestClient::new(config)?;
+                http.set_clock(options.clock.clone());
+                Some(http)
+            }
+            else {
+                None
+            };
             Ok(Some(Cache {
                 cache_dir: Self::cache_dir(config),
                 ta_dir: Self::ta_dir(config),
-                http: if update { Some(HttpClient::new(config)?) }
-                      else { None },
-                filter_dubious: !config.allow_dubious_hosts
+                http,
+                filter_dubious: !config.allow_dubious_hosts,
+                throttle: LoadThrottle::new(config.rrdp_max_load),
+                sessions: {
+                    SessionTracker::from_config(config, options.clock.clone())
+                },
+                options: Arc::new(options),
+                failures: Default::default(),
             }))
         }
     }
 
     pub fn ignite(&mut self) -> Result<(), Error> {
-        self.http.as_mut().map_or(Ok(()), HttpClient::ignite)
+        self.http.as_mut().map_or(Ok(()), ReqwestClient::ignite)
     }
 
     fn cache_dir(config: &Config) -> PathBuf {
@@ -98,6 +150,158 @@ impl Cache {
     pub fn start(&self) -> Result<Run, Error> {
         Run::new(self)
     }
+
+    /// Binds the RRDP debug socket if one is configured.
+    pub fn debug_listener(
+        &self, config: &Config
+    ) -> Result<Option<DebugListener>, Error> {
+        match config.rrdp_debug_listen {
+            Some(addr) => {
+                DebugListener::bind(
+                    addr, self.cache_dir.clone(), self.filter_dubious,
+                    self.failures.clone(), self.options.clone()
+                ).map(Some)
+            }
+            None => Ok(None)
+        }
+    }
+
+    /// Diagnoses the health of all servers in the cache.
+    ///
+    /// Returns the path, the notification URI if known, and the diagnosis
+    /// for each server directory. If the cache was created without
+    /// updates, only the local copies are verified. Nothing is changed.
+    pub fn doctor(
+        &self
+    ) -> Result<Vec<(PathBuf, Option<uri::Https>, Diagnosis)>, Error> {
+        let mut res = Vec::new();
+        for (path, state) in self.server_dirs()? {
+            let state = match state {
+                Some(state) => state,
+                None => {
+                    res.push((path, None, Diagnosis::Broken));
+                    continue
+                }
+            };
+            let server = Server::existing(
+                state.notify_uri.clone(), path.clone(), self.options.clone()
+            );
+            let diagnosis = match self.http {
+                Some(ref http) => server.diagnose(http),
+                None => match server.verify() {
+                    Ok(state) => {
+                        Diagnosis::Unreachable { serial: state.serial }
+                    }
+                    Err(_) => Diagnosis::Broken
+                }
+            };
+            res.push((path, Some(state.notify_uri), diagnosis));
+        }
+        Ok(res)
+    }
+
+    /// Verifies the local copies of all servers in the cache.
+    ///
+    /// Returns the path, the notification URI if known, and the report for
+    /// each server directory. This never accesses the network and doesn’t
+    /// change anything.
+    pub fn verify_local(
+        &self
+    ) -> Result<Vec<(PathBuf, Option<uri::Https>, VerifyReport)>, Error> {
+        let mut res = Vec::new();
+        for (path, state) in self.server_dirs()? {
+            let state = match state {
+                Some(state) => state,
+                None => {
+                    res.push((path, None, VerifyReport::default()));
+                    continue
+                }
+            };
+            let server = Server::existing(
+                state.notify_uri.clone(), path.clone(), self.options.clone()
+            );
+            let report = server.verify_local();
+            res.push((path, Some(state.notify_uri), report));
+        }
+        Ok(res)
+    }
+
+    /// Checks what updating all servers in the cache would do.
+    ///
+    /// Returns the path, the notification URI, and the check report for
+    /// each server directory with a readable state. Server directories
+    /// themselves are not changed. The cache must have been created with
+    /// updates enabled.
+    pub fn check(
+        &self
+    ) -> Result<Vec<(PathBuf, uri::Https, CheckReport)>, Error> {
+        let http = match self.http {
+            Some(ref http) => http,
+            None => {
+                error!("Fatal: RRDP updates are disabled.");
+                return Err(Error)
+            }
+        };
+        let mut res = Vec::new();
+        for (path, state) in self.server_dirs()? {
+            let state = match state {
+                Some(state) => state,
+                None => continue
+            };
+            let server = Server::existing(
+                state.notify_uri.clone(), path.clone(), self.options.clone()
+            );
+            res.push((path, state.notify_uri, server.check(http)));
+        }
+        Ok(res)
+    }
+
+    /// Returns all server directories in the cache sorted by path.
+    ///
+    /// Also returns the state of each directory if it could be loaded.
+    fn server_dirs(
+        &self
+    ) -> Result<Vec<(PathBuf, Option<ServerState>)>, Error> {
+        let dir = match self.cache_dir.read_dir() {
+            Ok(dir) => dir,
+            Err(err) => {
+                error!(
+                    "Fatal: Cannot open RRDP cache dir '{}': {}",
+                    self.cache_dir.display(), err
+                );
+                return Err(Error)
+            }
+        };
+        let mut res = Vec::new();
+        for entry in dir {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    error!(
+                        "Fatal: Cannot iterate over RRDP cache dir '{}': {}",
+                        self.cache_dir.display(), err
+                    );
+                    return Err(Error)
+                }
+            };
+            if !entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                continue
+            }
+            let path = entry.path();
+            let state = ServerState::load(
+                &*self.options.store,
+                &self.options.dir_names.state_path(&path)
+            ).ok();
+            res.push((path, state));
+        }
+        res.sort_by(|left, right| left.0.cmp(&right.0));
+        Ok(res)
+    }
+
+    /// Returns the statistics of all servers in the cache.
+    pub fn stats(&self) -> Result<Vec<ServerStats>, Error> {
+        ServerStats::collect(&self.cache_dir, &self.options)
+    }
 }
 
 
@@ -145,7 +349,10 @@ impl<'a> Run<'a> {
                 continue
             }
             let path = entry.path();
-            match ServerState::load(&path.join("state.txt")) {
+            match ServerState::load(
+                &*cache.options.store,
+                &cache.options.dir_names.state_path(&path)
+            ) {
                 Ok(state) => {
                     info!(
                         "RRDP: Known server {} at {}",
@@ -153,7 +360,9 @@ impl<'a> Run<'a> {
                         path.display()
                     );
                     let _ = servers.insert(
-                        Server::existing(state.notify_uri, path)
+                        Server::existing(
+                            state.notify_uri, path, cache.options.clone()
+                        )
                     );
                 }
                 Err(_) => {
@@ -170,7 +379,15 @@ impl<'a> Run<'a> {
         })
     }
 
+    /// Loads a trust anchor certificate.
+    ///
+    /// The certificate is fetched and stored for later runs. If fetching
+    /// fails, the stored copy is used. A read-only cache only uses the
+    /// stored copy.
     pub fn load_ta(&self, uri: &uri::Https, info: &TalInfo) -> Option<Bytes> {
+        if self.cache.options.read_only {
+            return self.read_ta(uri, info)
+        }
         match self.get_ta(uri) {
             Ok(Some(bytes)) => {
                 self.store_ta(&bytes, uri, info);
@@ -202,30 +419,46 @@ impl<'a> Run<'a> {
     /// create or update the server and then return its ID.
     ///
     /// Returns `None` if creating failed or if the server is unknown and
-    /// updating is disabled
+    /// updating is disabled or the cache is read-only.
     #[allow(clippy::question_mark)] // Explicit if: more understandable code
     pub fn load_server(&self, notify_uri: &uri::Https) -> Option<ServerId> {
         let res = self.servers.read().unwrap().find(notify_uri);
         let (id, server) = match res {
             Some(some) => some,
             None => {
-                if self.cache.http.is_none() {
+                if self.cache.http.is_none() || self.cache.options.read_only {
                     return None
                 }
                 let server = if
                     self.cache.filter_dubious
                     && notify_uri.has_dubious_authority()
                 {
-                    Server::create_broken(notify_uri.clone())
+                    Server::create_broken(
+                        notify_uri.clone(), self.cache.options.clone()
+                    )
                 }
                 else {
-                    Server::create(notify_uri.clone(), &self.cache.cache_dir)
+                    Server::create(
+                        notify_uri.clone(), &self.cache.cache_dir,
+                        self.cache.options.clone()
+                    )
                 };
                 self.servers.write().unwrap().insert(server)
             }
         };
         if let Some(ref http) = self.cache.http {
-            server.update(http);
+            if !server.is_current() {
+                if self.cache.sessions.should_skip(notify_uri)
+                    && server.session().is_some()
+                {
+                    server.keep_current();
+                }
+                else {
+                    self.cache.throttle.wait();
+                    server.update(http);
+                    self.cache.sessions.update(notify_uri, server.session());
+                }
+            }
         }
         if server.is_broken() {
             None
@@ -239,16 +472,35 @@ impl<'a> Run<'a> {
         &self,
         server_id: ServerId,
         uri: &uri::Rsync
-    ) -> Result<Option<Bytes>, Error> {
+    ) -> LoadResult {
         self.servers.read().unwrap().get(server_id).load_file(uri)
     }
 
     pub fn cleanup(&self) {
+        if self.cache.options.read_only {
+            return
+        }
         self.servers.write().unwrap().cleanup(&self.cache.cache_dir);
+        self.cache.options.dedup.cleanup();
+    }
+
+    /// Returns and resets the number of bytes downloaded for all servers.
+    pub fn take_total_downloaded(&self) -> u64 {
+        self.cache.http.as_ref().map_or(0, |http| {
+            http.take_total_downloaded()
+        })
+    }
+
+    /// Finishes the run.
+    ///
+    /// This records which servers were broken in this run, so it needs to
+    /// be called once all updates are done.
+    pub fn done(&self) {
+        self.servers.read().unwrap().record_failures(&self.cache.failures)
     }
 
     pub fn into_metrics(self) -> Vec<RrdpServerMetrics> {
-        self.servers.into_inner().unwrap().into_metrics()
+        self.servers.into_inner().unwrap().into_metrics(&self.cache.failures)
     }
 }
 
@@ -457,8 +709,70 @@ impl ServerSet {
         self.servers.iter().any(|server| server.server_dir() == path)
     }
 
-    pub fn into_metrics(self) -> Vec<RrdpServerMetrics> {
-        self.servers.into_iter().filter_map(|server| server.metrics()).collect()
+    /// Records in `failures` which updated servers were broken in this run.
+    pub fn record_failures(&self, failures: &FailureCounts) {
+        for server in &self.servers {
+            if server.metrics().is_some() {
+                failures.update(server.notify_uri(), server.is_broken());
+            }
+        }
+    }
+
+    /// Converts the set into the metrics of all updated servers.
+    ///
+    /// The number of consecutive failures is taken from `failures` which
+    /// should have been updated via `record_failures` before.
+    pub fn into_metrics(
+        self,
+        failures: &FailureCounts
+    ) -> Vec<RrdpServerMetrics> {
+        self.servers.into_iter().filter_map(|server| {
+            let mut metrics = server.metrics()?;
+            metrics.consecutive_failures = failures.get(server.notify_uri());
+            Some(metrics)
+        }).collect()
+    }
+}
+
+
+//------------ FailureCounts -------------------------------------------------
+
+/// The number of consecutive runs RRDP servers have been broken.
+///
+/// Because servers are loaded afresh for every validation run, a server
+/// that was broken during one run is tried again in the next. This type
+/// remembers for how many runs in a row this has failed.
+#[derive(Debug, Default)]
+pub struct FailureCounts {
+    counts: Mutex<HashMap<uri::Https, u32>>,
+}
+
+impl FailureCounts {
+    /// Updates the count for a server after a run.
+    ///
+    /// Returns the new number of consecutive failures.
+    pub fn update(&self, notify_uri: &uri::Https, broken: bool) -> u32 {
+        let mut counts = self.counts.lock().unwrap();
+        if !broken {
+            if counts.remove(notify_uri).is_some() {
+                info!("RRDP {}: server recovered.", notify_uri);
+            }
+            return 0
+        }
+        let count = counts.entry(notify_uri.clone()).or_insert(0);
+        *count = count.saturating_add(1);
+        if *count > 1 {
+            warn!(
+                "RRDP {}: server broken for {} consecutive runs.",
+                notify_uri, *count
+            );
+        }
+        *count
+    }
+
+    /// Returns the number of consecutive failures of a server.
+    pub fn get(&self, notify_uri: &uri::Https) -> u32 {
+        self.counts.lock().unwrap().get(notify_uri).copied().unwrap_or(0)
     }
 }
 
@@ -469,3 +783,82 @@ impl ServerSet {
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct ServerId(usize);
 
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+    use uuid::Uuid;
+    use crate::rrdp::mock::{MockClient, https_uri};
+
+    #[test]
+    fn failure_counts() {
+        let failures = FailureCounts::default();
+        let uri = uri::Https::from_str(
+            "https://example.net/notification.xml"
+        ).unwrap();
+        let other = uri::Https::from_str(
+            "https://example.org/notification.xml"
+        ).unwrap();
+
+        assert_eq!(failures.update(&uri, true), 1);
+        assert_eq!(failures.update(&other, true), 1);
+        assert_eq!(failures.update(&uri, true), 2);
+
+        // The next run succeeds.
+        assert_eq!(failures.update(&uri, false), 0);
+        assert_eq!(failures.update(&uri, true), 1);
+        assert_eq!(failures.update(&other, true), 2);
+    }
+
+    #[test]
+    fn failure_then_recovery() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let failures = FailureCounts::default();
+        let run = |client: &MockClient| {
+            let mut servers = ServerSet::new();
+            let (_, server) = servers.insert(Server::create(
+                https_uri("notification.xml"), &cache_dir, Default::default()
+            ));
+            server.update(client);
+            servers.record_failures(&failures);
+            servers.into_metrics(&failures)[0].consecutive_failures
+        };
+
+        // Nothing is published, so the server is broken twice in a row.
+        assert_eq!(run(&client), 1);
+        assert_eq!(run(&client), 2);
+
+        // Once it serves a snapshot, the count is reset.
+        client.publish(Uuid::new_v4(), 1, &[("a.cer", b"cert".as_ref())], &[]);
+        assert_eq!(run(&client), 0);
+        assert_eq!(failures.get(&https_uri("notification.xml")), 0);
+    }
+
+    #[test]
+    fn open_doesnt_create() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.cache_dir = tmp.path().join("repository");
+        let cache = Cache::open(&config, false).unwrap().unwrap();
+        assert!(!config.cache_dir.exists());
+        assert!(cache.doctor().is_err());
+    }
+
+    #[test]
+    fn read_only_init() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.cache_dir = tmp.path().join("repository");
+        config.rrdp_read_only = true;
+        Cache::init(&config).unwrap();
+        assert!(!config.cache_dir.exists());
+
+        config.rrdp_read_only = false;
+        Cache::init(&config).unwrap();
+        assert!(Cache::cache_dir(&config).is_dir());
+        assert!(Cache::ta_dir(&config).is_dir());
+    }
+}
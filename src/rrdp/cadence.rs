@@ -0,0 +1,182 @@
+//! Observing how often RRDP servers publish new data.
+//!
+//! This is a private module for organizational purposes.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use rand::random;
+use rpki::uri;
+use uuid::Uuid;
+
+
+//------------ Configuration Constants ---------------------------------------
+
+/// The weight of a new observation in the average interval.
+const WEIGHT: f64 = 0.25;
+
+/// The maximum share by which a suggested delay is moved either way.
+const JITTER: f64 = 0.25;
+
+
+//------------ CadenceTracker ------------------------------------------------
+
+/// Tracks the interval between serial changes of servers across runs.
+///
+/// The interval is kept as an exponentially weighted moving average of the
+/// observed intervals, so it follows changes in a server’s cadence without
+/// being thrown off by a single outlier. Since serials are only observed
+/// when a server is updated, the interval can’t be more precise than the
+/// time between updates.
+#[derive(Debug, Default)]
+pub struct CadenceTracker {
+    /// The cadence of each server.
+    servers: Mutex<HashMap<uri::Https, Cadence>>,
+}
+
+/// The observed cadence of a single server.
+#[derive(Clone, Copy, Debug)]
+struct Cadence {
+    /// The session of the last observed serial.
+    session: Uuid,
+
+    /// The last observed serial.
+    serial: u64,
+
+    /// The time the serial was first observed.
+    changed: Instant,
+
+    /// The average interval between serials once known.
+    interval: Option<Duration>,
+}
+
+impl CadenceTracker {
+    /// Records the session and serial of a server after an update.
+    ///
+    /// A new session starts over since its serials can’t be compared to
+    /// those of the old one.
+    pub fn update(
+        &self,
+        notify_uri: &uri::Https,
+        session: Uuid,
+        serial: u64,
+        now: Instant,
+    ) {
+        let mut servers = self.servers.lock().unwrap();
+        let cadence = servers.entry(notify_uri.clone()).or_insert(Cadence {
+            session, serial, changed: now, interval: None
+        });
+        if cadence.session != session || serial < cadence.serial {
+            *cadence = Cadence {
+                session, serial, changed: now, interval: None
+            };
+            return
+        }
+        if serial == cadence.serial {
+            return
+        }
+
+        // Several serials may have been published since we last looked, so
+        // the time is spread evenly over all of them.
+        let steps = serial - cadence.serial;
+        let sample = now.duration_since(cadence.changed).div_f64(
+            steps as f64
+        );
+        cadence.interval = Some(match cadence.interval {
+            Some(interval) => {
+                interval.mul_f64(1. - WEIGHT) + sample.mul_f64(WEIGHT)
+            }
+            None => sample
+        });
+        cadence.serial = serial;
+        cadence.changed = now;
+    }
+
+    /// Returns the average interval between serials of a server if known.
+    pub fn interval(&self, notify_uri: &uri::Https) -> Option<Duration> {
+        self.servers.lock().unwrap().get(notify_uri).and_then(|cadence| {
+            cadence.interval
+        })
+    }
+
+    /// Returns a suggested delay before updating a server again.
+    ///
+    /// The delay is based on the average interval between serials of the
+    /// server or on `fallback` if that isn’t known yet. It is moved by up
+    /// to a quarter either way at random so that servers with the same
+    /// cadence aren’t all updated at the same time.
+    pub fn next_update_after(
+        &self, notify_uri: &uri::Https, fallback: Duration
+    ) -> Duration {
+        jittered(
+            self.interval(notify_uri).unwrap_or(fallback),
+            random::<f64>()
+        )
+    }
+}
+
+/// Moves a delay by up to `JITTER` either way.
+///
+/// The value of `sample` has to be between 0 and 1 and picks where in the
+/// range the delay ends up.
+fn jittered(delay: Duration, sample: f64) -> Duration {
+    delay.mul_f64(1. + JITTER * (2. * sample - 1.))
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    fn notify_uri() -> uri::Https {
+        uri::Https::from_str("https://example.net/notification.xml").unwrap()
+    }
+
+    #[test]
+    fn average_interval() {
+        let tracker = CadenceTracker::default();
+        let session = Uuid::new_v4();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // A single serial doesn’t give an interval.
+        tracker.update(&notify_uri(), session, 10, at(0));
+        assert_eq!(tracker.interval(&notify_uri()), None);
+
+        // An unchanged serial doesn’t count.
+        tracker.update(&notify_uri(), session, 10, at(300));
+        assert_eq!(tracker.interval(&notify_uri()), None);
+        tracker.update(&notify_uri(), session, 11, at(600));
+        assert_eq!(
+            tracker.interval(&notify_uri()), Some(Duration::from_secs(600))
+        );
+
+        // Two serials in 600 seconds, i.e., 300 seconds each.
+        tracker.update(&notify_uri(), session, 13, at(1200));
+        assert_eq!(
+            tracker.interval(&notify_uri()), Some(Duration::from_secs(525))
+        );
+
+        // A new session starts over.
+        tracker.update(&notify_uri(), Uuid::new_v4(), 1, at(1800));
+        assert_eq!(tracker.interval(&notify_uri()), None);
+    }
+
+    #[test]
+    fn jitter() {
+        let delay = Duration::from_secs(600);
+        assert_eq!(jittered(delay, 0.), Duration::from_secs(450));
+        assert_eq!(jittered(delay, 0.5), delay);
+        assert_eq!(jittered(delay, 1.), Duration::from_secs(750));
+
+        let tracker = CadenceTracker::default();
+        for _ in 0..100 {
+            let next = tracker.next_update_after(&notify_uri(), delay);
+            assert!(next >= Duration::from_secs(450));
+            assert!(next <= Duration::from_secs(750));
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Access to the current time.
+//!
+//! This is a private module for organizational purposes.
+//!
+//! All time-dependent behaviour of the RRDP cache should get the current
+//! time through a `Clock` so that it can be tested with a `MockClock`
+//! without having to actually wait.
+
+use std::{fmt, thread};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+#[cfg(test)] use std::sync::Mutex;
+
+
+//------------ Clock ---------------------------------------------------------
+
+/// A source for the current time.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current monotonic time.
+    fn now(&self) -> Instant;
+
+    /// Returns the current wall-clock time.
+    fn system_now(&self) -> SystemTime;
+
+    /// Waits for the given duration to pass.
+    fn sleep(&self, duration: Duration);
+}
+
+
+//------------ SystemClock ---------------------------------------------------
+
+/// The clock of the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+
+//------------ SharedClock ---------------------------------------------------
+
+/// A clock that can be shared between all users.
+///
+/// This is the system clock by default.
+#[derive(Clone, Debug)]
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    /// Creates a shared clock from the given clock.
+    pub fn new<C: Clock + 'static>(clock: C) -> Self {
+        SharedClock(Arc::new(clock))
+    }
+
+    /// Returns the current monotonic time.
+    pub fn now(&self) -> Instant {
+        self.0.now()
+    }
+
+    /// Returns the current wall-clock time.
+    pub fn system_now(&self) -> SystemTime {
+        self.0.system_now()
+    }
+
+    /// Waits for the given duration to pass.
+    pub fn sleep(&self, duration: Duration) {
+        self.0.sleep(duration)
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self::new(SystemClock)
+    }
+}
+
+
+//------------ MockClock -----------------------------------------------------
+
+/// A clock that only moves when told to.
+///
+/// Clones of the clock share their time, so a clone can be handed to the
+/// code under test and the original be used to advance the time.
+#[cfg(test)]
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    /// The monotonic time the clock started at.
+    start: Instant,
+
+    /// The wall-clock time the clock started at.
+    system_start: SystemTime,
+
+    /// How far the clock has been advanced.
+    offset: Arc<Mutex<Duration>>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    /// Creates a new clock starting at the current time.
+    pub fn new() -> Self {
+        MockClock {
+            start: Instant::now(),
+            system_start: SystemTime::now(),
+            offset: Default::default(),
+        }
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        *self.offset.lock().unwrap() += duration
+    }
+
+    /// Returns a shared clock using this clock.
+    pub fn shared(&self) -> SharedClock {
+        SharedClock::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.offset.lock().unwrap()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.system_start + *self.offset.lock().unwrap()
+    }
+
+    /// Advances the clock rather than waiting.
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mock_clock() {
+        let mock = MockClock::new();
+        let clock = mock.shared();
+        let start = clock.now();
+        let system_start = clock.system_now();
+        assert_eq!(clock.now(), start);
+
+        mock.advance(Duration::from_secs(3600));
+        assert_eq!(clock.now() - start, Duration::from_secs(3600));
+        assert_eq!(
+            clock.system_now().duration_since(system_start).unwrap(),
+            Duration::from_secs(3600)
+        );
+
+        clock.sleep(Duration::from_secs(60));
+        assert_eq!(clock.now() - start, Duration::from_secs(3660));
+    }
+}
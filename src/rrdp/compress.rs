@@ -0,0 +1,179 @@
+//! Storing object files in compressed form.
+//!
+//! This is a private module for organizational purposes.
+//!
+//! A compressed object file starts with the four bytes of `MAGIC`, followed
+//! by the size of the object as an eight byte unsigned integer in network
+//! byte order and then the object compressed with zstd. Any other file
+//! contains the object as is. Since RPKI objects start with a DER encoded
+//! sequence, i.e., a byte 0x30, they are never mistaken for compressed
+//! files. Objects that happen to start with `MAGIC` anyway are always
+//! stored compressed so that reading a file is never ambiguous.
+
+use std::{fs, io};
+use std::io::{Read, Write};
+use std::path::Path;
+use zstd::stream::{Encoder, decode_all};
+use super::store::{CacheStore, FsStore};
+
+
+/// The bytes a compressed object file starts with.
+const MAGIC: &[u8; 4] = b"RRZ\x01";
+
+/// The length of the header of a compressed object file.
+const HEADER_LEN: usize = 12;
+
+/// The zstd compression level.
+///
+/// Zero selects the default level of the library.
+const LEVEL: i32 = 0;
+
+
+/// Writes an object to the given writer.
+///
+/// If `compress` is `true`, the object is written in compressed form.
+pub fn write_object<W: Write>(
+    mut target: W, data: &[u8], compress: bool
+) -> Result<(), io::Error> {
+    if !compress && !data.starts_with(MAGIC) {
+        target.write_all(data)?;
+        return target.flush()
+    }
+    target.write_all(MAGIC)?;
+    target.write_all(&(data.len() as u64).to_be_bytes())?;
+    let mut encoder = Encoder::new(target, LEVEL)?;
+    encoder.write_all(data)?;
+    encoder.finish()?.flush()
+}
+
+/// Writes an object to a new file at the given path.
+pub fn create_object(
+    path: &Path, data: &[u8], compress: bool
+) -> Result<(), io::Error> {
+    write_object(io::BufWriter::new(fs::File::create(path)?), data, compress)
+}
+
+/// Reads the object from the file at the given path.
+///
+/// Compressed objects are decompressed.
+pub fn read_object(path: &Path) -> Result<Vec<u8>, io::Error> {
+    read_stored_object(&FsStore, path)
+}
+
+/// Reads the object from the file at the given path in a store.
+///
+/// Compressed objects are decompressed.
+pub fn read_stored_object(
+    store: &dyn CacheStore, path: &Path
+) -> Result<Vec<u8>, io::Error> {
+    decode_object(store.read(path)?)
+}
+
+/// Reads the object and the metadata of the file at the given path.
+///
+/// The metadata is taken from the opened file and describes the file as
+/// stored. For a compressed object, its length is not the object size.
+pub fn read_object_with_meta(
+    path: &Path
+) -> Result<(Vec<u8>, fs::Metadata), io::Error> {
+    let mut file = fs::File::open(path)?;
+    let meta = file.metadata()?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok((decode_object(data)?, meta))
+}
+
+/// Decodes the content of an object file.
+fn decode_object(data: Vec<u8>) -> Result<Vec<u8>, io::Error> {
+    let size = match header_size(&data) {
+        Some(size) => size,
+        None => return Ok(data)
+    };
+    let res = decode_all(&data[HEADER_LEN..])?;
+    if res.len() as u64 != size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData, "compressed object size mismatch"
+        ))
+    }
+    Ok(res)
+}
+
+/// Returns the size of the object in the file at the given path.
+///
+/// The `len` is the length of the file. Only if it may hold a compressed
+/// object is the file opened to read its header.
+pub fn object_size(path: &Path, len: u64) -> Result<u64, io::Error> {
+    stored_object_size(&FsStore, path, len)
+}
+
+/// Returns the size of the object in the file at the given path in a store.
+///
+/// This is the same as `object_size` but reads the header via the store.
+pub fn stored_object_size(
+    store: &dyn CacheStore, path: &Path, len: u64
+) -> Result<u64, io::Error> {
+    if len < HEADER_LEN as u64 {
+        return Ok(len)
+    }
+    let header = store.read_prefix(path, HEADER_LEN)?;
+    Ok(header_size(&header).unwrap_or(len))
+}
+
+/// Returns the object size from the header of a compressed object file.
+fn header_size(data: &[u8]) -> Option<u64> {
+    if data.len() < HEADER_LEN || !data.starts_with(MAGIC) {
+        return None
+    }
+    let mut size = [0u8; 8];
+    size.copy_from_slice(&data[MAGIC.len()..HEADER_LEN]);
+    Some(u64::from_be_bytes(size))
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(data: &[u8], compress: bool) -> u64 {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("object");
+        create_object(&path, data, compress).unwrap();
+        assert_eq!(read_object(&path).unwrap(), data);
+        let (read, meta) = read_object_with_meta(&path).unwrap();
+        assert_eq!(read, data);
+        let len = fs::metadata(&path).unwrap().len();
+        assert_eq!(meta.len(), len);
+        assert_eq!(object_size(&path, len).unwrap(), data.len() as u64);
+        len
+    }
+
+    #[test]
+    fn plain_and_compressed() {
+        let data = [0x30u8; 1000];
+        assert_eq!(round_trip(&data, false), 1000);
+        assert!(round_trip(&data, true) < 1000);
+        round_trip(b"", true);
+        assert_eq!(round_trip(b"roa", false), 3);
+    }
+
+    #[test]
+    fn magic_is_escaped() {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&1234u64.to_be_bytes());
+        data.extend_from_slice(b"not zstd");
+        assert!(round_trip(&data, false) != data.len() as u64);
+    }
+
+    #[test]
+    fn corrupt_size() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("object");
+        let mut data = Vec::new();
+        write_object(&mut data, b"certificate", true).unwrap();
+        data[HEADER_LEN - 1] += 1;
+        fs::write(&path, &data).unwrap();
+        assert!(read_object(&path).is_err());
+    }
+}
@@ -0,0 +1,348 @@
+//! A socket for inspecting the RRDP cache while running.
+//!
+//! This is a private module for organizational purposes.
+//!
+//! The debug socket accepts TCP connections and reads a single request
+//! line from each of them. The line contains the notification URI of an
+//! RRDP server, optionally followed by the word `data` separated by white
+//! space. The response lists all objects currently cached for the server,
+//! one line containing the rsync URI and size in bytes of the object
+//! separated by a space per object. If `data` was requested, each line is
+//! followed by exactly as many bytes of object content as given by the
+//! size. If the server cannot be inspected, the response is a single line
+//! starting with `error:`. Either way, the connection is closed once the
+//! response has been written.
+//!
+//! Each connection is served on a thread of its own so that a slow client
+//! doesn’t hold up the others. Only `MAX_CONNECTIONS` connections are
+//! served at the same time. Any further connection is refused with an
+//! error line.
+
+use std::{fs, io, thread};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use log::{error, info};
+use rpki::uri;
+use crate::operation::Error;
+use crate::utils::UriExt;
+use super::cache::FailureCounts;
+use super::server::{Server, ServerOptions, ServerState};
+
+
+///----------- Configuration Constants ---------------------------------------
+
+/// The maximum length of a request line.
+const MAX_REQUEST_LEN: u64 = 4096;
+
+/// How long to wait for the request line.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum number of connections served at the same time.
+const MAX_CONNECTIONS: usize = 8;
+
+
+//------------ DebugListener -------------------------------------------------
+
+/// A bound but not yet running RRDP debug socket.
+#[derive(Debug)]
+pub struct DebugListener {
+    /// The listening socket.
+    listener: TcpListener,
+
+    /// The base directory of the RRDP server cache.
+    cache_dir: PathBuf,
+
+    /// Whether to refuse servers with dubious authorities.
+    filter_dubious: bool,
+
+    /// The number of consecutive runs each server has been broken.
+    failures: Arc<FailureCounts>,
+
+    /// The options for the servers of the cache.
+    options: Arc<ServerOptions>,
+}
+
+impl DebugListener {
+    /// Binds the debug socket to the given address.
+    pub fn bind(
+        addr: SocketAddr,
+        cache_dir: PathBuf,
+        filter_dubious: bool,
+        failures: Arc<FailureCounts>,
+        options: Arc<ServerOptions>,
+    ) -> Result<Self, Error> {
+        let listener = match TcpListener::bind(addr) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Fatal: can’t bind to {}: {}", addr, err);
+                return Err(Error)
+            }
+        };
+        Ok(DebugListener {
+            listener, cache_dir, filter_dubious, failures, options
+        })
+    }
+
+    /// Returns the address the socket is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.listener.local_addr().map_err(|err| {
+            error!("Failed to get address of RRDP debug socket: {}", err);
+            Error
+        })
+    }
+
+    /// Starts serving requests on a thread of its own.
+    pub fn spawn(self) {
+        thread::spawn(move || self.run());
+    }
+
+    /// Serves requests until accepting connections fails.
+    ///
+    /// Each connection is handed to a thread of its own.
+    fn run(self) {
+        let listener = Arc::new(self);
+        let active = Arc::new(AtomicUsize::new(0));
+        for stream in listener.listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("RRDP debug socket failed: {}", err);
+                    return
+                }
+            };
+            let slot = match ConnectionSlot::acquire(active.clone()) {
+                Some(slot) => slot,
+                None => {
+                    let _ = writeln!(stream, "error: too many connections");
+                    continue
+                }
+            };
+            let listener = listener.clone();
+            thread::spawn(move || {
+                if let Err(err) = listener.serve(stream) {
+                    info!("RRDP debug connection failed: {}", err);
+                }
+                drop(slot)
+            });
+        }
+    }
+
+    /// Serves a single connection.
+    fn serve(&self, mut stream: TcpStream) -> Result<(), io::Error> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut line = String::new();
+        BufReader::new(
+            (&mut stream).take(MAX_REQUEST_LEN)
+        ).read_line(&mut line)?;
+        let mut words = line.split_whitespace();
+        let (uri, data) = match (words.next(), words.next(), words.next()) {
+            (Some(uri), None, None) => (uri, false),
+            (Some(uri), Some("data"), None) => (uri, true),
+            _ => {
+                return writeln!(stream, "error: invalid request")
+            }
+        };
+        let notify_uri = match uri::Https::from_str(uri) {
+            Ok(uri) => uri,
+            Err(_) => {
+                return writeln!(stream, "error: invalid notification URI")
+            }
+        };
+        if self.filter_dubious && notify_uri.has_dubious_authority() {
+            return writeln!(stream, "error: skipped repository")
+        }
+        if self.failures.get(&notify_uri) > 0 {
+            return writeln!(stream, "error: broken repository")
+        }
+        let path = match self.find_server(&notify_uri)? {
+            Some(path) => path,
+            None => {
+                return writeln!(stream, "error: unknown repository")
+            }
+        };
+        let server = Server::existing(notify_uri, path, self.options.clone());
+        let mut target = io::BufWriter::new(stream);
+        if server.stream_objects(data, &mut target).is_err() {
+            // We may have written some of the objects already, but the
+            // client will still recognize the error line.
+            writeln!(target, "\nerror: failed to read repository")?;
+        }
+        target.flush()
+    }
+
+    /// Finds the directory of the server with the given notification URI.
+    fn find_server(
+        &self, notify_uri: &uri::Https
+    ) -> Result<Option<PathBuf>, io::Error> {
+        for entry in fs::read_dir(&self.cache_dir)? {
+            let path = entry?.path();
+            let state = ServerState::load(
+                &*self.options.store, &self.options.dir_names.state_path(&path)
+            );
+            if let Ok(state) = state {
+                if state.notify_uri == *notify_uri {
+                    return Ok(Some(path))
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+
+//------------ ConnectionSlot ------------------------------------------------
+
+/// One of the `MAX_CONNECTIONS` connections served at the same time.
+///
+/// The slot is released again when the value is dropped, so it is released
+/// no matter how serving the connection ends.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    /// Takes a slot if there still is one.
+    fn acquire(active: Arc<AtomicUsize>) -> Option<Self> {
+        if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            active.fetch_sub(1, Ordering::SeqCst);
+            None
+        }
+        else {
+            Some(ConnectionSlot(active))
+        }
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Shutdown;
+    use crate::rrdp::mock::{SEED_OBJECTS, seed_server};
+
+    fn notify_uri() -> uri::Https {
+        uri::Https::from_str("https://example.net/notification.xml").unwrap()
+    }
+
+    /// Creates a cache directory with a single server.
+    fn seed_cache(cache_dir: &std::path::Path) {
+        seed_server(
+            &cache_dir.join("example.net"), "example.net", 12, SEED_OBJECTS
+        );
+    }
+
+    fn request(addr: SocketAddr, request: &str) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut res = Vec::new();
+        stream.read_to_end(&mut res).unwrap();
+        res
+    }
+
+    #[test]
+    fn stream_objects() {
+        let tmp = tempfile::tempdir().unwrap();
+        seed_cache(tmp.path());
+        let failures = Arc::new(FailureCounts::default());
+        let listener = DebugListener::bind(
+            SocketAddr::from(([127, 0, 0, 1], 0)), tmp.path().into(),
+            true, failures.clone(), Default::default()
+        ).unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.spawn();
+
+        assert_eq!(
+            String::from_utf8(
+                request(addr, "https://example.net/notification.xml\n")
+            ).unwrap(),
+            "rsync://example.net/repo/a.cer 11\n\
+             rsync://example.net/repo/sub/b.roa 3\n"
+        );
+        assert_eq!(
+            request(addr, "https://example.net/notification.xml data\n"),
+            b"rsync://example.net/repo/a.cer 11\ncertificate\
+              rsync://example.net/repo/sub/b.roa 3\nroa".as_ref()
+        );
+        assert_eq!(
+            request(addr, "https://example.org/notification.xml\n"),
+            b"error: unknown repository\n".as_ref()
+        );
+
+        // Once the server was broken in a run, it isn’t available anymore.
+        failures.update(&notify_uri(), true);
+        assert_eq!(
+            request(addr, "https://example.net/notification.xml\n"),
+            b"error: broken repository\n".as_ref()
+        );
+    }
+
+    #[test]
+    fn concurrent_connections() {
+        let tmp = tempfile::tempdir().unwrap();
+        seed_cache(tmp.path());
+        let spawn = || {
+            let listener = DebugListener::bind(
+                SocketAddr::from(([127, 0, 0, 1], 0)), tmp.path().into(),
+                true, Default::default(), Default::default()
+            ).unwrap();
+            let addr = listener.local_addr().unwrap();
+            listener.spawn();
+            addr
+        };
+
+        // A client that doesn’t send its request doesn’t hold up others.
+        let addr = spawn();
+        let _idle = TcpStream::connect(addr).unwrap();
+        assert_eq!(
+            request(addr, "https://example.org/notification.xml\n"),
+            b"error: unknown repository\n".as_ref()
+        );
+
+        // Once all connections are busy, further ones are refused.
+        let addr = spawn();
+        let mut busy = Vec::new();
+        while busy.len() < MAX_CONNECTIONS {
+            busy.push(TcpStream::connect(addr).unwrap());
+        }
+        // The request isn’t read, so we don’t send one lest the server
+        // reset the connection.
+        let mut res = Vec::new();
+        TcpStream::connect(addr).unwrap().read_to_end(&mut res).unwrap();
+        assert_eq!(res, b"error: too many connections\n");
+    }
+
+    #[test]
+    fn dir_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        seed_cache(tmp.path());
+        let base = tmp.path().join("example.net");
+        fs::rename(base.join("state.txt"), base.join("current.txt")).unwrap();
+        let mut options = ServerOptions::default();
+        options.dir_names.state = "current.txt".into();
+        let listener = DebugListener::bind(
+            SocketAddr::from(([127, 0, 0, 1], 0)), tmp.path().into(),
+            true, Default::default(), Arc::new(options)
+        ).unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.spawn();
+        assert_eq!(
+            String::from_utf8(
+                request(addr, "https://example.net/notification.xml\n")
+            ).unwrap(),
+            "rsync://example.net/repo/a.cer 11\n\
+             rsync://example.net/repo/sub/b.roa 3\n"
+        );
+    }
+}
@@ -0,0 +1,275 @@
+//! Sharing identical objects between RRDP servers.
+//!
+//! This is a private module for organizational purposes.
+//!
+//! If enabled, the object files of all servers become hard links into a
+//! common object directory where each distinct file content is kept only
+//! once, named after the SHA-256 hash of the file as stored. Since a hard
+//! link is an ordinary file, reading objects and calculating the digest of
+//! a data directory work the same as before. Sharing files is safe because
+//! files in the data directories are never changed in place but always
+//! replaced by renaming a new file over them.
+//!
+//! Once no data directory links to a file anymore, the object directory
+//! holds the only link to it and the file is removed during cleanup.
+//! Finding out about this requires the link count of a file, so sharing is
+//! only available on Unix systems.
+
+use std::{fs, io};
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use ring::digest;
+use rpki::rrdp::DigestHex;
+use crate::config::Config;
+use super::utils::random_path;
+
+
+//------------ ObjectDedup ---------------------------------------------------
+
+/// The common directory for sharing object files.
+///
+/// By default, files aren’t shared.
+#[derive(Clone, Debug, Default)]
+pub struct ObjectDedup {
+    /// The object directory or `None` if disabled.
+    dir: Option<PathBuf>,
+}
+
+impl ObjectDedup {
+    /// Creates a value sharing files via the object directory `dir`.
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        ObjectDedup { dir }
+    }
+
+    /// Creates the value from the configuration.
+    pub fn from_config(config: &Config) -> Self {
+        if !config.rrdp_dedup {
+            return Self::default()
+        }
+        if config.rrdp_archive {
+            warn!(
+                "Objects kept in RRDP archives can’t be shared. \
+                 Ignoring rrdp-dedup."
+            );
+            return Self::default()
+        }
+        if cfg!(not(unix)) {
+            warn!(
+                "Sharing identical RRDP objects is only supported on Unix \
+                 systems. Ignoring rrdp-dedup."
+            );
+            return Self::default()
+        }
+        Self::new(Some(config.cache_dir.join("rrdp-objects")))
+    }
+
+    /// Replaces the given files with links into the object directory.
+    ///
+    /// Failing to do so for a file isn’t fatal since the file stays as it
+    /// was. It is logged and the next file is tried.
+    pub fn link_files<'a>(&self, paths: impl Iterator<Item = &'a Path>) {
+        let dir = match self.dir {
+            Some(ref dir) => dir,
+            None => return
+        };
+        if let Err(err) = fs::create_dir_all(dir.join("tmp")) {
+            info!(
+                "Failed to create RRDP object directory {}: {}",
+                dir.display(), err
+            );
+            return
+        }
+        for path in paths {
+            if let Err(err) = Self::link(dir, path) {
+                info!(
+                    "Failed to share RRDP object {}: {}",
+                    path.display(), err
+                );
+            }
+        }
+    }
+
+    /// Replaces all files under `path` with links into the object directory.
+    pub fn link_tree(&self, path: &Path) {
+        if self.dir.is_none() {
+            return
+        }
+        let mut files = Vec::new();
+        let mut dirs = vec![path.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    info!(
+                        "Failed to read directory {}: {}", dir.display(), err
+                    );
+                    continue
+                }
+            };
+            for entry in entries.filter_map(Result::ok) {
+                match entry.file_type() {
+                    Ok(file_type) if file_type.is_dir() => {
+                        dirs.push(entry.path())
+                    }
+                    Ok(file_type) if file_type.is_file() => {
+                        files.push(entry.path())
+                    }
+                    _ => { }
+                }
+            }
+        }
+        self.link_files(files.iter().map(PathBuf::as_path))
+    }
+
+    /// Replaces a single file with a link into the object directory.
+    fn link(dir: &Path, path: &Path) -> Result<(), io::Error> {
+        let data = fs::read(path)?;
+        let target = dir.join(DigestHex::from(
+            digest::digest(&digest::SHA256, &data)
+        ).to_string());
+
+        // If there is no such file yet, the file itself becomes it.
+        match fs::hard_link(path, &target) {
+            Ok(()) => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => { }
+            Err(err) => return Err(err)
+        }
+        if Self::is_same_file(path, &target)? {
+            return Ok(())
+        }
+
+        // Link to the shared file next to it and then move the link over
+        // the file so the file never goes missing.
+        let tmp = random_path(&dir.join("tmp"));
+        fs::hard_link(&target, &tmp)?;
+        fs::rename(&tmp, path).map_err(|err| {
+            let _ = fs::remove_file(&tmp);
+            err
+        })
+    }
+
+    /// Returns whether two paths refer to the same file.
+    #[cfg(unix)]
+    fn is_same_file(left: &Path, right: &Path) -> Result<bool, io::Error> {
+        use std::os::unix::fs::MetadataExt;
+
+        let left = fs::metadata(left)?;
+        let right = fs::metadata(right)?;
+        Ok(left.dev() == right.dev() && left.ino() == right.ino())
+    }
+
+    #[cfg(not(unix))]
+    fn is_same_file(_left: &Path, _right: &Path) -> Result<bool, io::Error> {
+        Ok(false)
+    }
+
+    /// Removes all files not used by any data directory anymore.
+    ///
+    /// This must not run while servers are being updated.
+    pub fn cleanup(&self) {
+        let dir = match self.dir {
+            Some(ref dir) => dir,
+            None => return
+        };
+        let _ = fs::remove_dir_all(dir.join("tmp"));
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                if err.kind() != io::ErrorKind::NotFound {
+                    info!(
+                        "Failed to read RRDP object directory {}: {}",
+                        dir.display(), err
+                    );
+                }
+                return
+            }
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let unused = entry.metadata().map(|meta| {
+                meta.is_file() && Self::link_count(&meta) <= 1
+            }).unwrap_or(false);
+            if unused {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    /// Returns the number of links to a file.
+    #[cfg(unix)]
+    fn link_count(meta: &fs::Metadata) -> u64 {
+        use std::os::unix::fs::MetadataExt;
+
+        meta.nlink()
+    }
+
+    /// Returns the number of links to a file.
+    ///
+    /// Without link counts, files are assumed to be in use.
+    #[cfg(not(unix))]
+    fn link_count(_meta: &fs::Metadata) -> u64 {
+        2
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn link_and_cleanup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dedup = ObjectDedup::new(Some(tmp.path().join("objects")));
+        let one = tmp.path().join("one");
+        let two = tmp.path().join("two").join("sub");
+        fs::create_dir_all(&one).unwrap();
+        fs::create_dir_all(&two).unwrap();
+        fs::write(one.join("a.cer"), b"certificate").unwrap();
+        fs::write(one.join("b.roa"), b"roa").unwrap();
+        fs::write(two.join("c.cer"), b"certificate").unwrap();
+
+        dedup.link_tree(&one);
+        dedup.link_files(Some(two.join("c.cer")).iter().map(AsRef::as_ref));
+        let objects = || {
+            fs::read_dir(tmp.path().join("objects")).unwrap().filter(
+                |entry| entry.as_ref().unwrap().file_type().unwrap().is_file()
+            ).count()
+        };
+        assert_eq!(objects(), 2);
+        assert!(
+            ObjectDedup::is_same_file(&one.join("a.cer"), &two.join("c.cer"))
+            .unwrap()
+        );
+        assert_eq!(fs::read(two.join("c.cer")).unwrap(), b"certificate");
+
+        // Linking again changes nothing.
+        dedup.link_tree(tmp.path().join("two").as_ref());
+        assert_eq!(objects(), 2);
+
+        // Replacing a file doesn’t affect the other one.
+        fs::remove_file(one.join("a.cer")).unwrap();
+        fs::write(one.join("a.cer"), b"new certificate").unwrap();
+        assert_eq!(fs::read(two.join("c.cer")).unwrap(), b"certificate");
+
+        // Only files still in use survive cleanup.
+        fs::remove_file(one.join("b.roa")).unwrap();
+        dedup.cleanup();
+        assert_eq!(objects(), 1);
+        fs::remove_file(two.join("c.cer")).unwrap();
+        dedup.cleanup();
+        assert_eq!(objects(), 0);
+    }
+
+    #[test]
+    fn disabled() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("a.cer"), b"certificate").unwrap();
+        let dedup = ObjectDedup::default();
+        dedup.link_tree(tmp.path());
+        dedup.cleanup();
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,260 @@
+//! A local cache of downloaded RRDP delta files.
+//!
+//! This is a private module for organizational purposes.
+
+use std::{fs, io};
+use std::path::PathBuf;
+use log::{debug, info};
+use ring::constant_time::verify_slices_are_equal;
+use rpki::rrdp::UriAndHash;
+use tempfile::NamedTempFile;
+use uuid::Uuid;
+use super::http::DigestRead;
+
+
+//------------ DeltaCache ----------------------------------------------------
+
+/// A directory keeping recently downloaded delta files.
+///
+/// Each delta is stored in a file whose name is made up of the session,
+/// serial number, and hash of the delta as given in the notification file.
+/// A cached delta is only ever used if its content still matches the hash.
+/// Only the given number of most recently stored deltas are kept.
+#[derive(Clone, Debug)]
+pub struct DeltaCache {
+    /// The directory containing the delta files.
+    dir: PathBuf,
+
+    /// The maximum number of delta files to keep.
+    keep: usize,
+}
+
+impl DeltaCache {
+    /// Creates a new delta cache in the given directory.
+    ///
+    /// The directory has to exist already.
+    pub fn new(dir: PathBuf, keep: usize) -> Self {
+        DeltaCache { dir, keep }
+    }
+
+    /// Returns the path of the file for a delta.
+    fn path(&self, session: Uuid, delta: &(u64, UriAndHash)) -> PathBuf {
+        self.dir.join(
+            format!("{}-{}-{}.xml", session, delta.0, delta.1.hash())
+        )
+    }
+
+    /// Returns whether there is a cached copy of a delta.
+    ///
+    /// The content of the copy isn’t checked.
+    pub fn contains(&self, session: Uuid, delta: &(u64, UriAndHash)) -> bool {
+        self.path(session, delta).is_file()
+    }
+
+    /// Opens the cached copy of a delta.
+    ///
+    /// Returns `None` if there is no copy or if its content doesn’t match
+    /// the hash, in which case the copy is removed.
+    pub fn open(
+        &self, session: Uuid, delta: &(u64, UriAndHash)
+    ) -> Option<fs::File> {
+        let path = self.path(session, delta);
+        let digest = DigestRead::sha256(fs::File::open(&path).ok()?);
+        let ok = match digest.read_all() {
+            Ok(digest) => {
+                verify_slices_are_equal(
+                    digest.as_ref(), delta.1.hash().as_ref()
+                ).is_ok()
+            }
+            Err(_) => false
+        };
+        if !ok {
+            info!("Removing corrupt cached delta {}.", path.display());
+            let _ = fs::remove_file(&path);
+            return None
+        }
+        fs::File::open(&path).ok()
+    }
+
+    /// Creates a temporary file for storing a delta while it is downloaded.
+    pub fn create(&self) -> Option<NamedTempFile> {
+        match NamedTempFile::new_in(&self.dir) {
+            Ok(file) => Some(file),
+            Err(err) => {
+                info!(
+                    "Failed to create file in delta cache {}: {}",
+                    self.dir.display(), err
+                );
+                None
+            }
+        }
+    }
+
+    /// Stores a completely downloaded delta in the cache.
+    ///
+    /// Removes the oldest deltas if there are too many afterwards.
+    pub fn store(
+        &self, file: NamedTempFile, session: Uuid, delta: &(u64, UriAndHash)
+    ) {
+        let path = self.path(session, delta);
+        if let Err(err) = file.persist(&path) {
+            info!(
+                "Failed to store delta in cache as {}: {}",
+                path.display(), err
+            );
+            return
+        }
+        if let Err(err) = self.prune() {
+            info!(
+                "Failed to clean up delta cache {}: {}",
+                self.dir.display(), err
+            );
+        }
+    }
+
+    /// Removes all but the `keep` most recently stored deltas.
+    ///
+    /// Deltas may be stored and pruned by several updates at the same
+    /// time, so a file that is already gone counts as removed.
+    fn prune(&self) -> Result<(), io::Error> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().map(|ext| ext == "xml") != Some(true) {
+                continue
+            }
+            match entry.metadata().and_then(|meta| meta.modified()) {
+                Ok(modified) => files.push((modified, path)),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => { }
+                Err(err) => return Err(err)
+            }
+        }
+        if files.len() <= self.keep {
+            return Ok(())
+        }
+        files.sort();
+        let remove = files.len() - self.keep;
+        for (_, path) in files.into_iter().take(remove) {
+            debug!("Removing cached delta {}.", path.display());
+            match fs::remove_file(&path) {
+                Ok(()) => { }
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => { }
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(())
+    }
+}
+
+
+//------------ Tee -----------------------------------------------------------
+
+/// A reader that writes all data read to a file as well.
+///
+/// If writing fails, the file is dropped and reading continues.
+pub struct Tee<R> {
+    reader: R,
+    file: Option<NamedTempFile>,
+}
+
+impl<R> Tee<R> {
+    pub fn new(reader: R, file: Option<NamedTempFile>) -> Self {
+        Tee { reader, file }
+    }
+
+    /// Returns the file if all data could be written to it.
+    pub fn into_file(self) -> Option<NamedTempFile> {
+        self.file
+    }
+}
+
+impl<R: io::Read> io::Read for Tee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let res = self.reader.read(buf)?;
+        if let Some(ref mut file) = self.file {
+            if let Err(err) = io::Write::write_all(file, &buf[..res]) {
+                info!("Failed to write to delta cache: {}", err);
+                self.file = None
+            }
+        }
+        Ok(res)
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::str::FromStr;
+    use filetime::{FileTime, set_file_mtime};
+    use ring::digest;
+    use rpki::uri;
+    use rpki::rrdp::DigestHex;
+
+    fn delta(serial: u64, data: &[u8]) -> (u64, UriAndHash) {
+        (
+            serial,
+            UriAndHash::new(
+                uri::Https::from_str(
+                    &format!("https://example.net/delta-{}.xml", serial)
+                ).unwrap(),
+                DigestHex::from(digest::digest(&digest::SHA256, data))
+            )
+        )
+    }
+
+    fn store(cache: &DeltaCache, delta: &(u64, UriAndHash), data: &[u8]) {
+        let mut tee = Tee::new(data, cache.create());
+        let mut read = Vec::new();
+        tee.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        cache.store(tee.into_file().unwrap(), Uuid::nil(), delta);
+    }
+
+    #[test]
+    fn store_and_open() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = DeltaCache::new(tmp.path().into(), 2);
+        let first = delta(1, b"first");
+        store(&cache, &first, b"first");
+
+        let mut data = Vec::new();
+        cache.open(Uuid::nil(), &first).unwrap()
+            .read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"first");
+
+        // Different session, serial or hash don’t match.
+        assert!(cache.open(Uuid::new_v4(), &first).is_none());
+        assert!(cache.open(Uuid::nil(), &delta(2, b"first")).is_none());
+        assert!(cache.open(Uuid::nil(), &delta(1, b"other")).is_none());
+
+        // Corrupt content is removed.
+        let path = cache.path(Uuid::nil(), &first);
+        fs::File::create(&path).unwrap().write_all(b"changed").unwrap();
+        assert!(cache.open(Uuid::nil(), &first).is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn prune() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = DeltaCache::new(tmp.path().into(), 2);
+        for serial in 1..5 {
+            let data = format!("delta {}", serial);
+            let delta = delta(serial, data.as_bytes());
+            store(&cache, &delta, data.as_bytes());
+            // Order the modification times without waiting.
+            set_file_mtime(
+                cache.path(Uuid::nil(), &delta),
+                FileTime::from_unix_time(serial as i64 * 3600, 0)
+            ).unwrap();
+        }
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 2);
+        assert!(cache.open(Uuid::nil(), &delta(3, b"delta 3")).is_some());
+        assert!(cache.open(Uuid::nil(), &delta(4, b"delta 4")).is_some());
+    }
+}
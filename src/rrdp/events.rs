@@ -0,0 +1,198 @@
+//! Structured events during RRDP server updates.
+//!
+//! This is a private module for organizational purposes.
+//!
+//! In addition to the human-readable log messages, updates can report what
+//! they are doing via events. Each event has a kind, the notification URI
+//! of the server, and a number of numeric details. If enabled, events are
+//! logged at info level as single line JSON objects. They can also be
+//! handed to a listener.
+
+use std::fmt;
+use std::sync::Arc;
+use log::info;
+use rpki::uri;
+
+
+//------------ EventKind -----------------------------------------------------
+
+/// The kind of an event.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EventKind {
+    /// An update of the server has started.
+    UpdateStart,
+
+    /// The server has been updated via deltas.
+    ///
+    /// The details are the new serial and the number of deltas applied.
+    DeltaSuccess,
+
+    /// An update via the snapshot has started.
+    ///
+    /// The detail is the serial of the snapshot.
+    SnapshotStart,
+
+    /// The local copy of the server has been found unusable.
+    ///
+    /// The detail is whether the local copy is removed.
+    Broken,
+
+    /// The deltas can’t be used because some are missing.
+    ///
+    /// The details are the serial of the local copy and of the server.
+    SerialGap,
+}
+
+impl EventKind {
+    /// Returns the name of the event kind.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::UpdateStart => "update_start",
+            EventKind::DeltaSuccess => "delta_success",
+            EventKind::SnapshotStart => "snapshot_start",
+            EventKind::Broken => "broken",
+            EventKind::SerialGap => "serial_gap",
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+
+//------------ Event ---------------------------------------------------------
+
+/// An event during the update of a server.
+///
+/// Displaying the event produces a JSON object.
+#[derive(Clone, Copy, Debug)]
+pub struct Event<'a> {
+    /// The notification URI of the server.
+    pub notify_uri: &'a uri::Https,
+
+    /// The kind of the event.
+    pub kind: EventKind,
+
+    /// The names and values of the details.
+    pub details: &'a [(&'static str, u64)],
+}
+
+impl<'a> fmt::Display for Event<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "{{\"event\":\"{}\",\"notifyUri\":\"{}\"",
+            self.kind, self.notify_uri
+        )?;
+        for (name, value) in self.details {
+            write!(f, ",\"{}\":{}", name, value)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+
+//------------ EventLog ------------------------------------------------------
+
+/// A listener for events.
+pub type EventListener = Arc<dyn Fn(&Event) + Send + Sync>;
+
+/// Where events go.
+///
+/// By default, events are dropped.
+#[derive(Clone, Default)]
+pub struct EventLog {
+    /// Whether to log events.
+    log: bool,
+
+    /// The listener to hand events to.
+    listener: Option<EventListener>,
+}
+
+impl EventLog {
+    /// Creates a new event log that logs events if `log` is `true`.
+    pub fn new(log: bool) -> Self {
+        EventLog { log, listener: None }
+    }
+
+    /// Hands all events to `listener`, too.
+    pub fn set_listener(&mut self, listener: EventListener) {
+        self.listener = Some(listener)
+    }
+
+    /// Reports an event.
+    pub fn emit(
+        &self,
+        notify_uri: &uri::Https,
+        kind: EventKind,
+        details: &[(&'static str, u64)],
+    ) {
+        if !self.log && self.listener.is_none() {
+            return
+        }
+        let event = Event { notify_uri, kind, details };
+        if self.log {
+            info!("RRDP event: {}", event);
+        }
+        if let Some(ref listener) = self.listener {
+            listener(&event)
+        }
+    }
+}
+
+impl fmt::Debug for EventLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EventLog")
+            .field("log", &self.log)
+            .field("listener", &self.listener.is_some())
+            .finish()
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+
+    #[test]
+    fn display() {
+        let notify_uri = uri::Https::from_str(
+            "https://example.net/notify.xml"
+        ).unwrap();
+        let record = Event {
+            notify_uri: &notify_uri,
+            kind: EventKind::SerialGap,
+            details: &[("serial", 12), ("serverSerial", 15)],
+        }.to_string();
+        let json: serde_json::Value = serde_json::from_str(&record).unwrap();
+        assert_eq!(json["event"], "serial_gap");
+        assert_eq!(json["notifyUri"], "https://example.net/notify.xml");
+        assert_eq!(json["serial"], 12);
+        assert_eq!(json["serverSerial"], 15);
+    }
+
+    #[test]
+    fn listener() {
+        let notify_uri = uri::Https::from_str(
+            "https://example.net/notify.xml"
+        ).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut log = EventLog::default();
+        log.emit(&notify_uri, EventKind::UpdateStart, &[]);
+        let target = events.clone();
+        log.set_listener(Arc::new(move |event: &Event| {
+            target.lock().unwrap().push((event.kind, event.details.to_vec()))
+        }));
+        log.emit(&notify_uri, EventKind::Broken, &[("removed", 1)]);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![(EventKind::Broken, vec![("removed", 1)])]
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! The hashes of the objects published by an RRDP server.
+//!
+//! This is a private module for organizational purposes.
+
+use std::io;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::str::FromStr;
+use log::info;
+use ring::digest;
+use ring::constant_time::verify_slices_are_equal;
+use rpki::uri;
+use rpki::rrdp::DigestHex;
+use crate::operation::Error;
+use super::store::CacheStore;
+
+
+//------------ ObjectHashes --------------------------------------------------
+
+/// The SHA-256 hashes of all objects of an RRDP server.
+///
+/// The hashes are recorded while applying snapshots and deltas and are kept
+/// in a file next to the server’s state file. The file contains one line
+/// per object with the object’s rsync URI and the hex-encoded hash
+/// separated by white space.
+#[derive(Clone, Debug, Default)]
+pub struct ObjectHashes {
+    hashes: HashMap<uri::Rsync, DigestHex>,
+}
+
+impl ObjectHashes {
+    /// Creates a new, empty set of hashes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the hashes from the file at the given path in `store`.
+    ///
+    /// Returns `Ok(None)` if there is no such file.
+    pub fn load(
+        store: &dyn CacheStore, path: &Path
+    ) -> Result<Option<Self>, Error> {
+        match Self::_load(store, path) {
+            Ok(res) => Ok(Some(res)),
+            Err(err) => {
+                if err.kind() == io::ErrorKind::NotFound {
+                    Ok(None)
+                }
+                else {
+                    info!(
+                        "Failed to read object hashes file '{}': {}",
+                        path.display(), err
+                    );
+                    Err(Error)
+                }
+            }
+        }
+    }
+
+    fn _load(store: &dyn CacheStore, path: &Path) -> Result<Self, io::Error> {
+        let data = store.read(path)?;
+        let mut res = Self::new();
+        for line in data.as_slice().lines() {
+            let line = line?;
+            let mut line = line.split_whitespace();
+            let (uri, hash) = match (line.next(), line.next(), line.next()) {
+                (Some(uri), Some(hash), None) => (uri, hash),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData, "invalid data"
+                    ))
+                }
+            };
+            let uri = uri::Rsync::from_str(uri).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "bad URI")
+            })?;
+            let hash = DigestHex::from_str(hash).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "bad hash")
+            })?;
+            res.hashes.insert(uri, hash);
+        }
+        Ok(res)
+    }
+
+    /// Saves the hashes to the file at the given path in `store`.
+    pub fn save(
+        &self, store: &dyn CacheStore, path: &Path
+    ) -> Result<(), Error> {
+        self._save(store, path).map_err(|err| {
+            info!(
+                "Failed to write object hashes file '{}': {}",
+                path.display(), err
+            );
+            Error
+        })
+    }
+
+    fn _save(
+        &self, store: &dyn CacheStore, path: &Path
+    ) -> Result<(), io::Error> {
+        let mut items: Vec<_> = self.hashes.iter().collect();
+        items.sort_by_cached_key(|item| item.0.to_string());
+        let mut data = Vec::new();
+        for (uri, hash) in items {
+            writeln!(data, "{} {}", uri, hash)?;
+        }
+        store.write(path, &data)
+    }
+
+    /// Returns the number of objects.
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Returns whether there are no objects at all.
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Returns an iterator over the URIs of all objects.
+    pub fn uris(&self) -> impl Iterator<Item = &uri::Rsync> {
+        self.hashes.keys()
+    }
+
+    /// Returns the hash for the object with the given URI.
+    pub fn get(&self, uri: &uri::Rsync) -> Option<&DigestHex> {
+        self.hashes.get(uri)
+    }
+
+    /// Adds the hash of the given object data.
+    pub fn insert_data(&mut self, uri: uri::Rsync, data: &[u8]) {
+        self.insert(uri, digest::digest(&digest::SHA256, data).into())
+    }
+
+    /// Adds or replaces the hash for an object.
+    pub fn insert(&mut self, uri: uri::Rsync, hash: DigestHex) {
+        self.hashes.insert(uri, hash);
+    }
+
+    /// Removes the hash for an object.
+    pub fn remove(&mut self, uri: &uri::Rsync) {
+        self.hashes.remove(uri);
+    }
+
+    /// Checks that the data for an object matches its hash.
+    ///
+    /// Returns `false` if there is no hash for the object at all.
+    pub fn verify(&self, uri: &uri::Rsync, data: &[u8]) -> bool {
+        match self.hashes.get(uri) {
+            Some(hash) => {
+                verify_slices_are_equal(
+                    digest::digest(&digest::SHA256, data).as_ref(),
+                    hash.as_ref()
+                ).is_ok()
+            }
+            None => false
+        }
+    }
+}
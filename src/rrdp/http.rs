@@ -2,13 +2,24 @@
 //!
 //! This is an internal module for organizational purposes.
 
-use std::{error, fmt, fs, io};
-use std::io::Write;
+use std::{cmp, error, fmt, fs, io};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
-use log::{error, info};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use log::{debug, error, info, warn};
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
 use reqwest::{Certificate, Proxy, StatusCode};
+use reqwest::header::{
+    ACCEPT_ENCODING, CONTENT_ENCODING, ETAG, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE, HeaderMap, HeaderName,
+    HeaderValue,
+};
 use reqwest::blocking::{Client, ClientBuilder, Response};
+#[cfg(feature = "rustls-tls")] use reqwest::Identity;
 use ring::digest;
 use ring::constant_time::verify_slices_are_equal;
 use rpki::uri;
@@ -16,10 +27,17 @@ use rpki::rrdp::{
     DigestHex, NotificationFile, ProcessDelta, ProcessSnapshot, UriAndHash
 };
 use rpki::xml::decode as xml;
-use tempfile::TempDir;
+use tempfile::{NamedTempFile, TempDir};
 use uuid::Uuid;
-use crate::config::Config;
+use crate::config::{AddressFamily, Config};
 use crate::operation::Error;
+use super::bandwidth::Bandwidth;
+use super::clock::SharedClock;
+use super::compress;
+use super::deltas::{DeltaCache, Tee};
+use super::hashes::ObjectHashes;
+use super::partial::{content_range_start, range_validator, PartialSnapshot};
+use super::store::{CacheStore, SharedStore};
 use super::utils::create_unique_file;
 
 
@@ -30,16 +48,269 @@ use super::utils::create_unique_file;
 /// This is mentioned in the man page. If you change it, also change it there.
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
 
+/// The default timeout for fetching a notification file.
+///
+/// This is mentioned in the man page. If you change it, also change it there.
+pub const DEFAULT_NOTIFICATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The size we assume for a response that doesn’t state its length.
+///
+/// This is only used for accounting against the memory budget.
+const UNKNOWN_SIZE_ESTIMATE: u64 = 1024 * 1024;
+
+/// The maximum number of URIs to try for a single delta.
+///
+/// This includes the URI given in the notification file.
+const MAX_DELTA_CANDIDATES: usize = 4;
+
+/// The default number of deltas to download concurrently.
+///
+/// This is mentioned in the man page. If you change it, also change it there.
+pub const DEFAULT_DELTA_WORKERS: usize = 4;
+
+/// The default number of times to retry a request after a transient error.
+///
+/// This is mentioned in the man page. If you change it, also change it there.
+pub const DEFAULT_RETRIES: usize = 2;
+
+/// The default delay before retrying a request for the first time.
+///
+/// This is mentioned in the man page. If you change it, also change it there.
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The default time after which idle connections are closed.
+///
+/// This is mentioned in the man page. If you change it, also change it there.
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// The default number of idle connections to keep per host.
+///
+/// This is mentioned in the man page. If you change it, also change it there.
+pub const DEFAULT_POOL_MAX_IDLE: usize = 4;
+
+/// The largest power of two the retry delay is multiplied with.
+const MAX_RETRY_BACKOFF: u32 = 10;
+
+/// The number of snapshot objects between two progress reports.
+const SNAPSHOT_PROGRESS_INTERVAL: usize = 10_000;
+
 
 //------------ HttpClient ----------------------------------------------------
 
+/// The operations needed for updating an RRDP server.
+///
+/// This is implemented by `ReqwestClient` which fetches everything via
+/// HTTPS. Other implementations can provide the files from elsewhere, e.g.,
+/// from memory for testing. All implementations need to check the hashes
+/// of snapshots and deltas given in the notification file.
+pub trait HttpClient: Sync {
+    /// Returns the directory for temporary files.
+    ///
+    /// This needs to be on the same file system as the RRDP cache so that
+    /// content can be moved into place.
+    fn tmp_dir(&self) -> &Path;
+
+    /// Acquires a permit for updating a server, waiting if necessary.
+    ///
+    /// The permit needs to be held for the duration of the update.
+    fn acquire_update(&self) -> UpdatePermit;
+
+    /// Fetches and parses the notification file if it has changed.
+    ///
+    /// The validators from a previous response are sent along with the
+    /// request. Returns `Ok(None)` if the server reports that the file
+    /// hasn’t changed since then. Otherwise returns the notification file
+    /// together with its validators. The status code of the response is
+    /// stored in `status` if there was one.
+    fn conditional_notification_file(
+        &self,
+        uri: &uri::Https,
+        validators: Option<&CacheValidators>,
+        status: &mut Option<StatusCode>,
+    ) -> Result<Option<NotificationResponse>, RequestError>;
+
+    /// Fetches and parses the notification file.
+    ///
+    /// Returns the parsed notification file and its raw content.
+    fn notification_file(
+        &self,
+        uri: &uri::Https,
+        status: &mut Option<StatusCode>,
+    ) -> Result<(NotificationFile, Vec<u8>), RequestError> {
+        match self.conditional_notification_file(uri, None, status)? {
+            Some(response) => Ok((response.notify, response.raw)),
+            // Without validators, the server can’t claim nothing changed.
+            None => Err(RequestError::Failed)
+        }
+    }
+
+    /// Fetches the snapshot and writes its objects.
+    ///
+    /// The path of each object is determined via `path_op`. The objects
+    /// are written compressed if `compress` is `true`. Each object is handed
+    /// to `check` before it is written and the snapshot fails as soon as an
+    /// object is rejected. If `progress` is given, it is called every so
+    /// many objects and once more after the last object has been written.
+    /// Returns the hashes of all the objects written.
+    fn snapshot(
+        &self,
+        server_uri: &uri::Https,
+        notify: &NotificationFile,
+        path_op: &dyn Fn(&uri::Rsync) -> Result<PathBuf, Error>,
+        compress: bool,
+        check: &ObjectCheck,
+        progress: Option<&mut dyn FnMut(SnapshotProgress)>,
+    ) -> Result<ObjectHashes, Error>;
+
+    /// Downloads deltas ahead of processing them.
+    ///
+    /// The result has an element for each delta in the order given which
+    /// is `None` if the delta wasn’t downloaded. By default, nothing is
+    /// downloaded ahead.
+    fn prefetch_deltas(
+        &self,
+        _server_uri: &uri::Https,
+        _notify: &NotificationFile,
+        deltas: &[(u64, UriAndHash)],
+    ) -> Vec<Option<NamedTempFile>> {
+        deltas.iter().map(|_| None).collect()
+    }
+
+    /// Processes a delta, fetching it if necessary.
+    ///
+    /// If `prefetched` contains the delta’s content downloaded earlier, it
+    /// may be used instead of fetching it. The changes are added to
+    /// `targets`. If an error is returned, they have been rolled back
+    /// already.
+    fn delta(
+        &self,
+        server_uri: &uri::Https,
+        notify: &NotificationFile,
+        delta: &(u64, UriAndHash),
+        prefetched: Option<NamedTempFile>,
+        targets: &mut DeltaTargets,
+        path_op: &dyn Fn(&uri::Rsync) -> Result<PathBuf, Error>,
+    ) -> Result<(), Error>;
+
+    /// Returns and resets the number of bytes downloaded for a server.
+    ///
+    /// By default, nothing is ever downloaded.
+    fn take_downloaded(&self, _server_uri: &uri::Https) -> u64 {
+        0
+    }
+
+    /// Sets the time by which the current update of a server has to end.
+    ///
+    /// Requests on behalf of the server are not retried if waiting for the
+    /// retry would run past this time. Passing `None` removes the deadline.
+    /// By default, deadlines are ignored.
+    fn set_deadline(
+        &self, _server_uri: &uri::Https, _deadline: Option<Instant>
+    ) {
+    }
+
+    /// Returns and resets the number of bytes downloaded for all servers.
+    ///
+    /// By default, nothing is ever downloaded.
+    fn take_total_downloaded(&self) -> u64 {
+        0
+    }
+}
+
+
+//------------ ReqwestClient -------------------------------------------------
+
+/// The HTTP client fetching RRDP files via reqwest.
+
 #[derive(Debug)]
-pub struct HttpClient {
+pub struct ReqwestClient {
     client: Result<Client, Option<ClientBuilder>>,
     tmp_dir: PathBuf,
+
+    /// The clients presenting a client certificate to specific hosts.
+    host_clients: Vec<HostClient>,
+
+    /// The budget for the bytes of concurrent snapshot and delta downloads.
+    memory: MemoryBudget,
+
+    /// The limit for the number of servers updating at the same time.
+    updates: UpdateLimit,
+
+    /// Mirrors for deltas as pairs of URI prefix and mirror prefix.
+    mirrors: Vec<(String, String)>,
+
+    /// Request timeouts for specific servers.
+    ///
+    /// The keys are notification URIs.
+    timeouts: HashMap<uri::Https, Duration>,
+
+    /// Request timeouts for the different kinds of requests.
+    ///
+    /// These apply to all servers without a timeout of their own.
+    kind_timeouts: RequestTimeouts,
+
+    /// Redirect all requests for a URI prefix to a different prefix.
+    ///
+    /// This is only used by the self test to send requests meant for HTTPS
+    /// URIs to a local plain HTTP server.
+    redirect: Option<(String, String)>,
+
+    /// Hosts to send requests to instead of the host of the URI.
+    ///
+    /// The keys are host names in lower case. Only the request goes to the
+    /// other host, the URIs themselves are still checked as before.
+    host_map: HashMap<String, String>,
+
+    /// The cache of recently downloaded deltas if enabled.
+    delta_cache: Option<DeltaCache>,
+
+    /// The number of deltas of a server to download concurrently.
+    delta_workers: usize,
+
+    /// The number of bytes downloaded on behalf of each server.
+    ///
+    /// The keys are notification URIs.
+    downloaded: Mutex<HashMap<uri::Https, Arc<AtomicU64>>>,
+
+    /// The bandwidth used by all downloads and its limit.
+    bandwidth: Arc<Bandwidth>,
+
+    /// The maximum size of a single snapshot or delta after decoding.
+    max_file_size: Option<u64>,
+
+    /// The maximum bytes downloaded on behalf of a server in one update.
+    max_download_size: Option<u64>,
+
+    /// The maximum size of a notification file as transferred.
+    max_notification_size: Option<u64>,
+
+    /// The maximum number of deltas listed in a notification file.
+    max_notification_deltas: Option<usize>,
+
+    /// Additional hosts snapshots and deltas may be fetched from.
+    ///
+    /// Normally, they have to be on the same host as the notification file.
+    allowed_hosts: Vec<String>,
+
+    /// The policy for retrying requests after transient errors.
+    retry: RetryPolicy,
+
+    /// The deadlines of the current updates of servers.
+    ///
+    /// The keys are notification URIs.
+    deadlines: Mutex<HashMap<uri::Https, Instant>>,
+
+    /// The clock to take the time from and wait with before retrying.
+    clock: SharedClock,
+
+    /// Whether to keep snapshot downloads for resuming them.
+    resume_snapshots: bool,
+
+    /// The address family connections are restricted to.
+    address_family: AddressFamily,
 }
 
-impl HttpClient {
+impl ReqwestClient {
     pub fn init(config: &Config) -> Result<(), Error> {
         let tmp_dir = config.cache_dir.join("tmp");
         if let Err(err) = fs::create_dir_all(&tmp_dir) {
@@ -49,12 +320,105 @@ impl HttpClient {
             );
             return Err(Error);
         }
+        if config.rrdp_delta_cache.is_some() {
+            let delta_dir = Self::delta_dir(config);
+            if let Err(err) = fs::create_dir_all(&delta_dir) {
+                error!(
+                    "Failed to create RRDP delta cache directory {}: {}.",
+                    delta_dir.display(), err
+                );
+                return Err(Error);
+            }
+        }
         Ok(())
     }
 
+    fn delta_dir(config: &Config) -> PathBuf {
+        config.cache_dir.join("rrdp-deltas")
+    }
+
     pub fn new(config: &Config) -> Result<Self, Error> {
+        let mut host_clients = Vec::new();
+        for (host, path) in &config.rrdp_client_certs {
+            host_clients.push(HostClient {
+                host: host.clone(),
+                identity: path.clone(),
+                client: Err(Some(
+                    Self::add_identity(Self::builder(config)?, path)?
+                )),
+            });
+        }
+        Ok(ReqwestClient {
+            client: Err(Some(Self::builder(config)?)),
+            tmp_dir: config.cache_dir.join("tmp"),
+            host_clients,
+            memory: MemoryBudget::new(config.rrdp_memory_budget),
+            updates: UpdateLimit::new(config.rrdp_max_updates),
+            mirrors: config.rrdp_mirrors.clone(),
+            timeouts: config.rrdp_repository_timeouts.iter().filter_map(
+                |(uri, timeouts)| {
+                    timeouts.timeout.map(|timeout| (uri.clone(), timeout))
+                }
+            ).collect(),
+            kind_timeouts: RequestTimeouts::from_config(config),
+            redirect: None,
+            host_map: Self::host_map(config)?,
+            delta_cache: config.rrdp_delta_cache.map(|keep| {
+                DeltaCache::new(Self::delta_dir(config), keep)
+            }),
+            delta_workers: config.rrdp_delta_workers,
+            downloaded: Default::default(),
+            bandwidth: Arc::new(Bandwidth::new(config.rrdp_rate_limit)),
+            max_file_size: config.rrdp_max_file_size,
+            max_download_size: config.rrdp_max_download_size,
+            max_notification_size: config.rrdp_max_notification_size,
+            max_notification_deltas: config.rrdp_max_notification_deltas,
+            allowed_hosts: config.rrdp_allowed_hosts.clone(),
+            retry: RetryPolicy::from_config(config),
+            deadlines: Default::default(),
+            clock: Default::default(),
+            resume_snapshots: config.rrdp_resume_snapshots,
+            address_family: config.rrdp_address_family,
+        })
+    }
+
+    /// Returns the host map from the configuration.
+    ///
+    /// Since sending requests elsewhere should never come as a surprise,
+    /// each mapping is logged as a warning.
+    fn host_map(config: &Config) -> Result<HashMap<String, String>, Error> {
+        let mut res = HashMap::new();
+        for (host, target) in &config.rrdp_host_map {
+            let valid = !target.is_empty() && target.chars().all(|ch| {
+                ch.is_ascii_alphanumeric() || ch == '-' || ch == '.'
+            });
+            if !valid {
+                error!(
+                    "Invalid target host '{}' for RRDP host {}.", target, host
+                );
+                return Err(Error)
+            }
+            warn!(
+                "RRDP requests for host {} are sent to host {} instead.",
+                host, target
+            );
+            res.insert(host.to_ascii_lowercase(), target.clone());
+        }
+        Ok(res)
+    }
+
+    /// Creates a client builder with the settings from the configuration.
+    fn builder(config: &Config) -> Result<ClientBuilder, Error> {
         let mut builder = Client::builder();
         builder = builder.user_agent(&config.rrdp_user_agent);
+        if !config.rrdp_headers.is_empty() {
+            let mut headers = HeaderMap::new();
+            for header in &config.rrdp_headers {
+                let (name, value) = Self::parse_header(header)?;
+                headers.append(name, value);
+            }
+            builder = builder.default_headers(headers);
+        }
         match config.rrdp_timeout {
             Some(Some(timeout)) => {
                 builder = builder.timeout(timeout);
@@ -67,9 +431,31 @@ impl HttpClient {
         if let Some(timeout) = config.rrdp_connect_timeout {
             builder = builder.connect_timeout(timeout);
         }
-        if let Some(addr) = config.rrdp_local_addr {
-            builder = builder.local_address(addr)
+        match config.rrdp_local_addr {
+            Some(addr) => {
+                if !config.rrdp_address_family.contains(addr) {
+                    error!(
+                        "RRDP local address {} conflicts with address \
+                         family {}.",
+                        addr, config.rrdp_address_family
+                    );
+                    return Err(Error)
+                }
+                builder = builder.local_address(addr)
+            }
+            None => {
+                // Binding to the unspecified address of a family makes
+                // connections to the other family fail.
+                if let Some(addr) = config.rrdp_address_family
+                    .unspecified_addr()
+                {
+                    builder = builder.local_address(addr)
+                }
+            }
         }
+        builder = builder
+            .pool_idle_timeout(config.rrdp_pool_idle_timeout)
+            .pool_max_idle_per_host(config.rrdp_pool_max_idle);
         for path in &config.rrdp_root_certs {
             builder = builder.add_root_certificate(
                 Self::load_cert(path)?
@@ -87,14 +473,109 @@ impl HttpClient {
             };
             builder = builder.proxy(proxy);
         }
-        Ok(HttpClient {
-            client: Err(Some(builder)),
-            tmp_dir: config.cache_dir.join("tmp"),
+        Ok(builder)
+    }
+
+    /// Creates a client that redirects all requests for `prefix`.
+    ///
+    /// The part of request URIs that starts with `prefix` is replaced with
+    /// `origin` which may use plain HTTP. The client ignores all proxies
+    /// and is ignited already. Requests are not retried.
+    pub fn with_redirect(
+        tmp_dir: PathBuf,
+        prefix: String,
+        origin: String,
+    ) -> Result<Self, Error> {
+        let client = match Client::builder()
+            .no_proxy().timeout(DEFAULT_TIMEOUT).build()
+        {
+            Ok(client) => client,
+            Err(err) => {
+                error!("Failed to initialize HTTP client: {}.", err);
+                return Err(Error)
+            }
+        };
+        Ok(ReqwestClient {
+            client: Ok(client),
+            tmp_dir,
+            host_clients: Vec::new(),
+            memory: MemoryBudget::new(None),
+            updates: UpdateLimit::new(None),
+            mirrors: Vec::new(),
+            timeouts: HashMap::new(),
+            kind_timeouts: Default::default(),
+            redirect: Some((prefix, origin)),
+            host_map: HashMap::new(),
+            delta_cache: None,
+            delta_workers: DEFAULT_DELTA_WORKERS,
+            downloaded: Default::default(),
+            bandwidth: Default::default(),
+            max_file_size: None,
+            max_download_size: None,
+            max_notification_size: None,
+            max_notification_deltas: None,
+            allowed_hosts: Vec::new(),
+            retry: RetryPolicy::default(),
+            deadlines: Default::default(),
+            clock: Default::default(),
+            resume_snapshots: false,
+            address_family: AddressFamily::Any,
         })
     }
 
+    /// Sets the size limits for snapshots and deltas.
+    ///
+    /// The `file` limit applies to each snapshot or delta after its content
+    /// encoding has been removed. The `download` limit applies to all bytes
+    /// transferred on behalf of a server until the next call to
+    /// `take_downloaded`.
+    pub fn set_size_limits(
+        &mut self, file: Option<u64>, download: Option<u64>
+    ) {
+        self.max_file_size = file;
+        self.max_download_size = download;
+    }
+
+    /// Sets the limits for notification files.
+    ///
+    /// The `size` limit applies to the notification file as transferred.
+    /// The `deltas` limit applies to the number of deltas it lists.
+    pub fn set_notification_limits(
+        &mut self, size: Option<u64>, deltas: Option<usize>
+    ) {
+        self.max_notification_size = size;
+        self.max_notification_deltas = deltas;
+    }
+
+    /// Sets the policy for retrying requests after transient errors.
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry
+    }
+
+    /// Sets the clock used for deadlines and for waiting before retrying.
+    ///
+    /// This should be the same clock the servers use for their deadlines.
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.clock = clock
+    }
+
+    /// Sets whether snapshot downloads are kept for resuming them.
+    pub fn set_resume_snapshots(&mut self, resume: bool) {
+        self.resume_snapshots = resume
+    }
+
     pub fn ignite(&mut self) -> Result<(), Error> {
-        let builder = match self.client.as_mut() {
+        Self::ignite_client(&mut self.client)?;
+        for host in &mut self.host_clients {
+            Self::ignite_client(&mut host.client)?;
+        }
+        Ok(())
+    }
+
+    fn ignite_client(
+        target: &mut Result<Client, Option<ClientBuilder>>
+    ) -> Result<(), Error> {
+        let builder = match target.as_mut() {
             Ok(_) => return Ok(()),
             Err(builder) => match builder.take() {
                 Some(builder) => builder,
@@ -111,12 +592,54 @@ impl HttpClient {
                 return Err(Error)
             }
         };
-        self.client = Ok(client);
+        *target = Ok(client);
         Ok(())
     }
 
-    fn client(&self) -> &Client {
-        self.client.as_ref().unwrap()
+    /// Returns the client to use for requests to `uri`.
+    ///
+    /// If a client certificate is configured for the host of `uri`, the
+    /// client presenting it is used. Otherwise it is the general client.
+    fn client(&self, uri: &uri::Https) -> &Client {
+        match self.host_client(uri) {
+            Some(host) => host.client.as_ref().unwrap(),
+            None => self.client.as_ref().unwrap()
+        }
+    }
+
+    /// Returns the client with a client certificate for `uri` if any.
+    fn host_client(&self, uri: &uri::Https) -> Option<&HostClient> {
+        let host = authority_host(uri.authority());
+        self.host_clients.iter().find(|item| {
+            item.host.eq_ignore_ascii_case(host)
+        })
+    }
+
+    /// Parses an additional request header given as `name: value`.
+    fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), Error> {
+        let pos = match header.find(':') {
+            Some(pos) => pos,
+            None => {
+                error!(
+                    "Invalid rrdp-header '{}': expected 'name: value'.",
+                    header
+                );
+                return Err(Error)
+            }
+        };
+        let name = HeaderName::from_str(header[..pos].trim());
+        let value = HeaderValue::from_str(header[pos + 1..].trim());
+        match (name, value) {
+            (Ok(name), Ok(value)) => Ok((name, value)),
+            (Err(err), _) => {
+                error!("Invalid rrdp-header '{}': {}.", header, err);
+                Err(Error)
+            }
+            (_, Err(err)) => {
+                error!("Invalid rrdp-header '{}': {}.", header, err);
+                Err(Error)
+            }
+        }
     }
 
     fn load_cert(path: &Path) -> Result<Certificate, Error> {
@@ -147,110 +670,1347 @@ impl HttpClient {
         })
     }
  
-    pub fn tmp_dir(&self) -> &Path {
-        &self.tmp_dir
+    /// Adds the client certificate from the file at `path` to a builder.
+    ///
+    /// The file has to contain the certificate chain and the private key
+    /// in PEM encoding.
+    #[cfg(feature = "rustls-tls")]
+    fn add_identity(
+        builder: ClientBuilder, path: &Path
+    ) -> Result<ClientBuilder, Error> {
+        let data = fs::read(path).map_err(|err| {
+            error!(
+                "Cannot read rrdp-client-certs file '{}': {}'",
+                path.display(), err
+            );
+            Error
+        })?;
+        let identity = Identity::from_pem(&data).map_err(|err| {
+            error!(
+                "Cannot decode rrdp-client-certs file '{}': {}'",
+                path.display(), err
+            );
+            Error
+        })?;
+        Ok(builder.identity(identity))
     }
 
-    pub fn notification_file(
-        &self,
+    #[cfg(not(feature = "rustls-tls"))]
+    fn add_identity(
+        _builder: ClientBuilder, path: &Path
+    ) -> Result<ClientBuilder, Error> {
+        error!(
+            "Cannot use rrdp-client-certs file '{}': client certificates \
+             require the rustls-tls feature.",
+            path.display()
+        );
+        Err(Error)
+    }
+
+    /// Parses the content of a notification file.
+    ///
+    /// Because a missing snapshot element or a serial number too large for
+    /// us are only reported as a generic XML error by the parser, we check
+    /// for them explicitly if parsing fails so we can give a more helpful
+    /// error.
+    pub fn parse_notification(
+        data: &[u8]
+    ) -> Result<NotificationFile, NotificationError> {
+        match NotificationFile::parse(data) {
+            // The deltas are left in the server’s order. They are sorted
+            // when calculating the deltas to follow so misbehaving
+            // servers can be reported there.
+            Ok(res) => Ok(res),
+            Err(err) => {
+                if has_serial_overflow(data) {
+                    Err(NotificationError::SerialOverflow)
+                }
+                else if has_element(data, b"snapshot") {
+                    Err(NotificationError::Xml(err))
+                }
+                else {
+                    Err(NotificationError::MissingSnapshot)
+                }
+            }
+        }
+    }
+
+    /// Checks that a notification file doesn’t list too many deltas.
+    ///
+    /// This only counts the delta elements in the raw data so that it can
+    /// be done before parsing. If there are more than `limit`, returns the
+    /// number of deltas.
+    pub fn check_delta_count(
+        data: &[u8], limit: Option<usize>
+    ) -> Result<(), usize> {
+        let limit = match limit {
+            Some(limit) => limit,
+            None => return Ok(())
+        };
+        let count = count_elements(data, b"delta");
+        if count > limit {
+            Err(count)
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    /// Checks that a notification file only refers to acceptable hosts.
+    ///
+    /// The snapshot and all deltas have to be on the same authority as the
+    /// notification file at `uri` or on one of the `allowed` hosts. If
+    /// that isn’t the case, returns the first offending URI.
+    pub fn check_locations<'a>(
         uri: &uri::Https,
-        status: &mut Option<StatusCode>,
-    ) -> Result<NotificationFile, Error> {
-        let response = match self.response(uri) {
-            Ok(response) => {
-                *status = Some(response.status());
-                response
+        notify: &'a NotificationFile,
+        allowed: &[String],
+    ) -> Result<(), &'a uri::Https> {
+        let acceptable = |target: &uri::Https| {
+            let authority = target.authority();
+            authority.eq_ignore_ascii_case(uri.authority())
+            || allowed.iter().any(|host| {
+                authority.eq_ignore_ascii_case(host)
+            })
+        };
+        let snapshot = notify.snapshot.uri();
+        if !acceptable(snapshot) {
+            return Err(snapshot)
+        }
+        for delta in &notify.deltas {
+            if !acceptable(delta.1.uri()) {
+                return Err(delta.1.uri())
             }
-            Err(_) => {
-                *status = None;
-                return Err(Error);
+        }
+        Ok(())
+    }
+
+    /// Downloads the snapshot into a partial snapshot file.
+    ///
+    /// If an earlier download of the same snapshot was interrupted, asks
+    /// the server for the missing rest only via a range request. If the
+    /// server answers with the full snapshot instead, e.g., because it
+    /// doesn’t support range requests or the snapshot has changed, the
+    /// download starts over. Because ranges refer to the body as
+    /// transferred, the snapshot is requested without content encoding.
+    ///
+    /// If the download fails, the partial file is kept for the next
+    /// attempt. Returns the complete file opened for reading.
+    fn download_snapshot(
+        &self,
+        server_uri: &uri::Https,
+        notify: &NotificationFile,
+        partial: &PartialSnapshot,
+        counter: Arc<AtomicU64>,
+    ) -> Result<fs::File, Error> {
+        let uri = notify.snapshot.uri();
+        let resume = partial.resume_point(notify);
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+        if let Some((len, ref validator)) = resume {
+            if let Ok(value) = HeaderValue::from_str(
+                &format!("bytes={}-", len)
+            ) {
+                headers.insert(RANGE, value);
+            }
+            if let Ok(value) = HeaderValue::from_str(validator) {
+                if !validator.is_empty() {
+                    headers.insert(IF_RANGE, value);
+                }
+            }
+        }
+        let response = match self.conditional_response(
+            server_uri, uri, RequestKind::Snapshot, Some(headers)
+        ) {
+            Ok(response) => response,
+            Err(err) => {
+                // A failed range request may be due to the partial file
+                // itself, e.g., if it is complete already. Only a timeout
+                // is worth resuming after.
+                if resume.is_some() && err != RequestError::Timeout {
+                    partial.remove();
+                }
+                return Err(err.into())
             }
         };
-        if !response.status().is_success() {
+        let _permit = self.memory.acquire(Self::expected_size(&response));
+        let offset = if response.status() == StatusCode::PARTIAL_CONTENT {
+            let start = content_range_start(response.headers());
+            match resume {
+                Some((len, _)) if start == Some(len) => len,
+                _ => {
+                    info!("{}: unexpected partial content.", uri);
+                    partial.remove();
+                    return Err(Error)
+                }
+            }
+        }
+        else {
+            if resume.is_some() {
+                debug!("{}: cannot resume download. Starting over.", uri);
+            }
+            0
+        };
+        let file = if offset > 0 {
+            debug!("{}: resuming download after {} bytes.", uri, offset);
+            partial.append()
+        }
+        else {
+            // An encoded body can’t be resumed as the decoded data is
+            // stored.
+            let validator = if response.headers().contains_key(
+                CONTENT_ENCODING
+            ) {
+                None
+            }
+            else {
+                Some(range_validator(response.headers()).unwrap_or_default())
+            };
+            partial.start(notify, validator.as_ref().map(String::as_str))
+        };
+        let mut file = file.map_err(|err| {
             info!(
-                "RRDP {}: Getting notification file failed with status {}",
-                uri, response.status()
+                "Failed to write partial snapshot {}: {}",
+                partial.path().display(), err
             );
-            return Err(Error);
+            Error
+        })?;
+        let mut body = DecodedBody::from_response(
+            uri, response, counter, self.bandwidth.clone(),
+            self.max_download_size
+        )?;
+        if let Err(err) = io::copy(&mut body, &mut file) {
+            info!("{}: {}", uri, err);
+            return Err(Error)
         }
-        match NotificationFile::parse(io::BufReader::new(response)) {
-            Ok(mut res) => {
-                res.deltas.sort_by_key(|delta| delta.0);
-                Ok(res)
+        partial.open().map_err(|err| {
+            info!(
+                "Failed to open partial snapshot {}: {}",
+                partial.path().display(), err
+            );
+            Error
+        })
+    }
+
+    /// Downloads a single delta into a temporary file.
+    fn prefetch_delta(
+        &self,
+        server_uri: &uri::Https,
+        notify: &NotificationFile,
+        delta: &(u64, UriAndHash),
+    ) -> Option<NamedTempFile> {
+        if let Some(ref cache) = self.delta_cache {
+            if cache.contains(notify.session_id, delta) {
+                return None
             }
+        }
+        let uri = delta.1.uri();
+        let response = self.server_response(
+            server_uri, uri, RequestKind::Delta
+        ).ok()?;
+        let _permit = self.memory.acquire(Self::expected_size(&response));
+        let body = DecodedBody::from_response(
+            uri, response, self.download_counter(server_uri),
+            self.bandwidth.clone(), self.max_download_size
+        ).ok()?;
+        let mut body = LimitRead::new(body, uri, self.max_file_size);
+        let mut file = match NamedTempFile::new_in(&self.tmp_dir) {
+            Ok(file) => file,
             Err(err) => {
-                error!("{}: {}", uri, err);
-                Err(Error)
+                info!(
+                    "Failed to create temporary file in {}: {}",
+                    self.tmp_dir.display(), err
+                );
+                return None
             }
+        };
+        if let Err(err) = io::copy(&mut body, &mut file) {
+            info!("{}: {}", uri, err);
+            return None
         }
+        Some(file)
     }
 
-    pub fn snapshot<F: Fn(&uri::Rsync) -> PathBuf>(
+    /// Processes a delta downloaded earlier.
+    ///
+    /// Adds the file to the delta cache if that is enabled and processing
+    /// succeeds.
+    fn prefetched_delta<F: Fn(&uri::Rsync) -> Result<PathBuf, Error>>(
         &self,
+        file: NamedTempFile,
+        server_uri: &uri::Https,
         notify: &NotificationFile,
+        delta: &(u64, UriAndHash),
+        targets: &mut DeltaTargets,
         path_op: F
     ) -> Result<(), Error> {
-        let mut processor = SnapshotProcessor { notify, path_op };
-        let mut reader = io::BufReader::new(DigestRead::sha256(
-                self.response(notify.snapshot.uri())?
-        ));
-        if let Err(err) = processor.process(&mut reader) {
-            error!("{}: {}", notify.snapshot.uri(), err);
-            return Err(Error)
-        }
-        let digest = reader.into_inner().into_digest();
-        if verify_slices_are_equal(
-            digest.as_ref(),
-            notify.snapshot.hash().as_ref()
-        ).is_err() {
-            info!("{}: hash value mismatch.", notify.snapshot.uri());
-            return Err(Error)
+        let reader = file.reopen().map_err(|err| {
+            info!(
+                "Failed to open downloaded delta {}: {}",
+                file.path().display(), err
+            );
+            Error
+        })?;
+        self.process_delta(
+            delta.1.uri(), reader, server_uri, notify, delta, targets,
+            path_op
+        )?;
+        if let Some(ref cache) = self.delta_cache {
+            cache.store(file, notify.session_id, delta);
         }
         Ok(())
     }
 
-    pub fn delta<F: Fn(&uri::Rsync) -> PathBuf>(
+    /// Fetches and processes a delta from a specific URI.
+    fn delta_from<F: Fn(&uri::Rsync) -> Result<PathBuf, Error>>(
         &self,
+        uri: &uri::Https,
         server_uri: &uri::Https,
         notify: &NotificationFile,
         delta: &(u64, UriAndHash),
         targets: &mut DeltaTargets,
         path_op: F
     ) -> Result<(), Error> {
-        let mut processor = DeltaProcessor {
-            server_uri, notify, delta, path_op, targets
-        };
-        let mut reader = io::BufReader::new(DigestRead::sha256(
-            self.response(delta.1.uri())?
-        ));
-        if let Err(err) = processor.process(&mut reader) {
-            if let ProcessError::Xml(err) = err {
-                info!("Bad content in {}: {}", delta.1.uri(), err);
+        let response = self.server_response(
+            server_uri, uri, RequestKind::Delta
+        )?;
+        let _permit = self.memory.acquire(Self::expected_size(&response));
+        let body = DecodedBody::from_response(
+            uri, response, self.download_counter(server_uri),
+            self.bandwidth.clone(), self.max_download_size
+        )?;
+        let cache = match self.delta_cache {
+            Some(ref cache) => cache,
+            None => {
+                return self.process_delta(
+                    uri, body, server_uri, notify, delta, targets,
+                    path_op
+                )
             }
-            return Err(Error)
-        }
-        let digest = reader.into_inner().into_digest();
-        if verify_slices_are_equal(
-            digest.as_ref(),
-            delta.1.hash().as_ref()
-        ).is_err() {
-            error!("{}: hash value mismatch.", delta.1.uri());
-            return Err(Error)
+        };
+        let mut tee = Tee::new(body, cache.create());
+        self.process_delta(
+            uri, &mut tee, server_uri, notify, delta, targets, path_op
+        )?;
+        if let Some(file) = tee.into_file() {
+            cache.store(file, notify.session_id, delta);
         }
         Ok(())
     }
 
-    pub fn response(
+    /// Processes a delta read from the given reader.
+    ///
+    /// The content is checked against the hash given in the notification
+    /// file. Because this can only happen once all of it has been read, the
+    /// changes are added to `targets` first and the caller has to roll them
+    /// back if an error is returned. The URI is only used for error
+    /// reporting. Reading fails if the delta exceeds the maximum file size.
+    fn process_delta<R, F>(
         &self,
-        uri: &uri::Https
+        uri: &uri::Https,
+        reader: R,
+        server_uri: &uri::Https,
+        notify: &NotificationFile,
+        delta: &(u64, UriAndHash),
+        targets: &mut DeltaTargets,
+        path_op: F
+    ) -> Result<(), Error>
+    where R: io::Read, F: Fn(&uri::Rsync) -> Result<PathBuf, Error> {
+        process_delta(
+            uri, reader, self.max_file_size,
+            DeltaProcessor::new(server_uri, notify, delta, targets, path_op)
+        )
+    }
+
+    /// Returns the URIs to try for a delta.
+    ///
+    /// The first URI is always the one from the notification file. It is
+    /// followed by the URIs of all mirrors whose prefix matches, up to a
+    /// total of `MAX_DELTA_CANDIDATES`.
+    fn delta_uris(
+        mirrors: &[(String, String)],
+        uri: &uri::Https
+    ) -> Vec<uri::Https> {
+        let mut res = vec![uri.clone()];
+        for (prefix, mirror) in mirrors {
+            if res.len() >= MAX_DELTA_CANDIDATES {
+                break
+            }
+            if !uri.as_str().starts_with(prefix.as_str()) {
+                continue
+            }
+            let candidate = format!(
+                "{}{}", mirror, &uri.as_str()[prefix.len()..]
+            );
+            match uri::Https::from_str(&candidate) {
+                Ok(candidate) => res.push(candidate),
+                Err(_) => {
+                    info!("Ignoring invalid RRDP mirror URI {}.", candidate);
+                }
+            }
+        }
+        res
+    }
+
+    pub fn response(
+        &self,
+        uri: &uri::Https
     ) -> Result<Response, Error> {
-        self.client().get(uri.as_str()).send().and_then(|res| {
+        let request = self.client(uri).get(&*self.request_uri(uri));
+        request.send().and_then(|res| {
             res.error_for_status()
         }).map_err(|err| {
+            self.report_rejected_identity(uri, &err);
             info!("{}: {}", uri, err);
             Error
         })
     }
+
+    /// Returns the response for a request on behalf of an RRDP server.
+    ///
+    /// This applies the request timeout configured for the server or, if
+    /// there is none, for the kind of request.
+    fn server_response(
+        &self,
+        server_uri: &uri::Https,
+        uri: &uri::Https,
+        kind: RequestKind,
+    ) -> Result<Response, RequestError> {
+        self.conditional_response(server_uri, uri, kind, None)
+    }
+
+    /// Sends a request on behalf of a server with additional headers.
+    ///
+    /// If the request fails for a transient reason, it is retried as
+    /// described by the client’s retry policy. Transient reasons are
+    /// failing to connect, timeouts, and responses with status 429 or any
+    /// 5xx status. Any other response, including a 404, and any other
+    /// error, such as a failed TLS handshake, is final. Errors while
+    /// reading the body of a response happen later and are not retried
+    /// here. A request is not retried if waiting for the retry would run
+    /// past the deadline of the server’s update.
+    fn conditional_response(
+        &self,
+        server_uri: &uri::Https,
+        uri: &uri::Https,
+        kind: RequestKind,
+        headers: Option<HeaderMap>,
+    ) -> Result<Response, RequestError> {
+        let timeout = self.request_timeout(server_uri, kind);
+        let mut retry = 0;
+        let res = loop {
+            let mut request = self.client(uri).get(
+                &*self.request_uri(uri)
+            );
+            if let Some(timeout) = timeout {
+                request = request.timeout(timeout);
+            }
+            if kind != RequestKind::Notification {
+                request = request.header(
+                    ACCEPT_ENCODING, DecodedBody::ACCEPT
+                );
+            }
+            if let Some(ref headers) = headers {
+                request = request.headers(headers.clone());
+            }
+            let res = request.send();
+            if retry >= self.retry.retries {
+                break res
+            }
+            let reason = match res {
+                Ok(ref res) if is_transient_status(res.status()) => {
+                    format!("status {}", res.status())
+                }
+                Err(ref err) if is_transient_error(err) => err.to_string(),
+                _ => break res
+            };
+            let delay = self.retry.delay(retry);
+            if self.past_deadline(server_uri, delay) {
+                info!(
+                    "{}: {} request failed with {}. Not retrying since \
+                     that would exceed the update deadline.",
+                    uri, kind, reason
+                );
+                break res
+            }
+            info!(
+                "{}: {} request failed with {}. Retrying in {} ms.",
+                uri, kind, reason, delay.as_millis()
+            );
+            self.clock.sleep(delay);
+            retry += 1;
+        };
+        res.and_then(|res| {
+            res.error_for_status()
+        }).map_err(|err| {
+            if !err.is_timeout() {
+                self.report_rejected_identity(uri, &err);
+                self.report_address_family(uri, &err);
+                info!("{}: {}", uri, err);
+                return RequestError::Failed
+            }
+            match timeout {
+                Some(timeout) => {
+                    info!(
+                        "{}: {} request timed out after {} seconds.",
+                        uri, kind, timeout.as_secs()
+                    );
+                }
+                None => info!("{}: {} request timed out.", uri, kind)
+            }
+            RequestError::Timeout
+        })
+    }
+
+    /// Reports a request error that may be due to our client certificate.
+    ///
+    /// If a client certificate is configured for the host of `uri` and
+    /// the request failed without a response or with a 401 or 403 status,
+    /// the server may well have rejected the certificate. Since that is
+    /// likely a configuration problem, it is logged as a warning.
+    fn report_rejected_identity(
+        &self, uri: &uri::Https, err: &reqwest::Error
+    ) {
+        let host = match self.host_client(uri) {
+            Some(host) => host,
+            None => return
+        };
+        let rejected = match err.status() {
+            Some(status) => {
+                status == StatusCode::UNAUTHORIZED
+                || status == StatusCode::FORBIDDEN
+            }
+            None => !err.is_builder() && !err.is_redirect()
+        };
+        if rejected {
+            warn!(
+                "{}: request failed while presenting the client certificate \
+                 from '{}'. The server may have rejected the certificate: {}",
+                uri, host.identity.display(), err
+            );
+        }
+    }
+
+    /// Reports a request error that may be due to the address family.
+    ///
+    /// If connections are restricted to one address family and the
+    /// request failed without a response, the server may well not be
+    /// reachable via that family. Since the remedy is changing the
+    /// configuration, this is logged as a warning.
+    fn report_address_family(&self, uri: &uri::Https, err: &reqwest::Error) {
+        if self.address_family == AddressFamily::Any {
+            return
+        }
+        if err.status().is_none() && !err.is_builder() && !err.is_redirect() {
+            warn!(
+                "{}: request failed while restricted to {}. The server may \
+                 not be reachable via this address family, see the \
+                 rrdp-address-family option: {}",
+                uri, self.address_family, err
+            );
+        }
+    }
+
+    /// Returns the URI to actually send a request for `uri` to.
+    ///
+    /// If the host of `uri` is in the host map, it is replaced by the
+    /// mapped host while the port and path are kept.
+    fn request_uri<'a>(&self, uri: &'a uri::Https) -> Cow<'a, str> {
+        if let Some((ref prefix, ref origin)) = self.redirect {
+            if uri.as_str().starts_with(prefix.as_str()) {
+                return Cow::Owned(
+                    format!("{}{}", origin, &uri.as_str()[prefix.len()..])
+                )
+            }
+        }
+        if !self.host_map.is_empty() {
+            let host = authority_host(uri.authority());
+            if let Some(target) = self.host_map.get(
+                &host.to_ascii_lowercase()
+            ) {
+                let rest = &uri.as_str()["https://".len() + host.len()..];
+                debug!("{}: sending request to host {}.", uri, target);
+                return Cow::Owned(format!("https://{}{}", target, rest))
+            }
+        }
+        Cow::Borrowed(uri.as_str())
+    }
+
+    /// Returns the timeout for a request on behalf of a server.
+    ///
+    /// A timeout configured for the server overrides the one for the kind
+    /// of request. If neither is set, the timeout of the client applies.
+    fn request_timeout(
+        &self, server_uri: &uri::Https, kind: RequestKind
+    ) -> Option<Duration> {
+        self.timeouts.get(server_uri).cloned().or_else(|| {
+            self.kind_timeouts.get(kind)
+        })
+    }
+
+    /// Returns whether waiting for `delay` runs past a server’s deadline.
+    fn past_deadline(&self, server_uri: &uri::Https, delay: Duration) -> bool {
+        match self.deadlines.lock().unwrap().get(server_uri) {
+            Some(deadline) => self.clock.now() + delay >= *deadline,
+            None => false
+        }
+    }
+
+    /// Returns the counter of bytes downloaded on behalf of a server.
+    fn download_counter(&self, server_uri: &uri::Https) -> Arc<AtomicU64> {
+        self.downloaded.lock().unwrap().entry(
+            server_uri.clone()
+        ).or_default().clone()
+    }
+
+    /// Returns the size of a response for memory accounting.
+    fn expected_size(response: &Response) -> u64 {
+        response.content_length().unwrap_or(UNKNOWN_SIZE_ESTIMATE)
+    }
+}
+
+impl HttpClient for ReqwestClient {
+    fn tmp_dir(&self) -> &Path {
+        &self.tmp_dir
+    }
+
+    fn acquire_update(&self) -> UpdatePermit {
+        self.updates.acquire()
+    }
+
+    /// Fetches and parses the notification file if it has changed.
+    ///
+    /// The notification file is rejected if it refers to snapshots or
+    /// deltas on hosts that aren’t allowed. It is also rejected without
+    /// parsing it if it is larger than the configured size or lists more
+    /// deltas than configured.
+    fn conditional_notification_file(
+        &self,
+        uri: &uri::Https,
+        validators: Option<&CacheValidators>,
+        status: &mut Option<StatusCode>,
+    ) -> Result<Option<NotificationResponse>, RequestError> {
+        let headers = validators.map(CacheValidators::request_headers);
+        let mut response = match self.conditional_response(
+            uri, uri, RequestKind::Notification, headers
+        ) {
+            Ok(response) => {
+                *status = Some(response.status());
+                response
+            }
+            Err(err) => {
+                *status = None;
+                return Err(err);
+            }
+        };
+        if response.status() == StatusCode::NOT_MODIFIED
+            && validators.is_some()
+        {
+            debug!("RRDP {}: Notification file not modified.", uri);
+            return Ok(None)
+        }
+        if !response.status().is_success() {
+            info!(
+                "RRDP {}: Getting notification file failed with status {}",
+                uri, response.status()
+            );
+            return Err(RequestError::Failed);
+        }
+        if let Some(limit) = self.max_notification_size {
+            if response.content_length().map_or(false, |len| len > limit) {
+                warn!(
+                    "RRDP {}: notification file exceeds maximum size of {} \
+                     bytes. Keeping current data.",
+                    uri, limit
+                );
+                return Err(RequestError::Failed)
+            }
+        }
+        let mut data = LimitWrite::new(self.max_notification_size);
+        let res = response.copy_to(&mut data);
+        let exceeded = data.exceeded();
+        let data = data.into_inner();
+        self.download_counter(uri).fetch_add(
+            data.len() as u64, Ordering::Relaxed
+        );
+        self.bandwidth.record(data.len() as u64);
+        if exceeded {
+            warn!(
+                "RRDP {}: notification file exceeds maximum size of {} \
+                 bytes. Keeping current data.",
+                uri, self.max_notification_size.unwrap_or(0)
+            );
+            return Err(RequestError::Failed)
+        }
+        if let Err(err) = res {
+            if err.is_timeout() {
+                info!("RRDP {}: Reading notification file timed out.", uri);
+                return Err(RequestError::Timeout)
+            }
+            info!(
+                "RRDP {}: Failed to read notification file: {}", uri, err
+            );
+            return Err(RequestError::Failed)
+        }
+        if let Err(count) = Self::check_delta_count(
+            &data, self.max_notification_deltas
+        ) {
+            warn!(
+                "RRDP {}: notification file lists {} deltas, more than the \
+                 maximum of {}. Keeping current data.",
+                uri, count, self.max_notification_deltas.unwrap_or(0)
+            );
+            return Err(RequestError::Failed)
+        }
+        let validators = CacheValidators::from_headers(response.headers());
+        match Self::parse_notification(&data) {
+            Ok(notify) => {
+                if let Err(target) = Self::check_locations(
+                    uri, &notify, &self.allowed_hosts
+                ) {
+                    warn!(
+                        "RRDP {}: notification file refers to {} on a \
+                         different host. Keeping current data.",
+                        uri, target
+                    );
+                    return Err(RequestError::Failed)
+                }
+                Ok(Some(NotificationResponse {
+                    notify, raw: data, validators
+                }))
+            }
+            Err(NotificationError::MissingSnapshot) => {
+                warn!(
+                    "RRDP {}: No snapshot in notification file. \
+                     Keeping current data.",
+                    uri
+                );
+                Err(RequestError::Failed)
+            }
+            Err(NotificationError::SerialOverflow) => {
+                error!(
+                    "RRDP {}: Serial number in notification file exceeds \
+                     the supported maximum of {}. Keeping current data.",
+                    uri, u64::max_value()
+                );
+                Err(RequestError::Failed)
+            }
+            Err(NotificationError::Xml(err)) => {
+                error!("{}: {}", uri, err);
+                Err(RequestError::Failed)
+            }
+        }
+    }
+
+    /// Fetches the snapshot and writes its objects.
+    ///
+    /// The progress callback is never called while a lock is held. If
+    /// resuming snapshots is enabled, the snapshot is downloaded into a
+    /// file first. See `download_snapshot` for details.
+    fn snapshot(
+        &self,
+        server_uri: &uri::Https,
+        notify: &NotificationFile,
+        path_op: &dyn Fn(&uri::Rsync) -> Result<PathBuf, Error>,
+        compress: bool,
+        check: &ObjectCheck,
+        progress: Option<&mut dyn FnMut(SnapshotProgress)>,
+    ) -> Result<ObjectHashes, Error> {
+        let counter = self.download_counter(server_uri);
+        let mut processor = SnapshotProcessor {
+            session: notify.session_id, serial: notify.serial,
+            path_op, compress, check: check.clone(),
+            hashes: ObjectHashes::new(),
+            progress: progress.map(|op| {
+                (op, counter.clone(), counter.load(Ordering::Relaxed))
+            }),
+        };
+        if self.resume_snapshots {
+            let partial = PartialSnapshot::new(&self.tmp_dir, server_uri);
+            let file = self.download_snapshot(
+                server_uri, notify, &partial, counter
+            )?;
+            let res = process_snapshot(
+                file, notify, self.max_file_size, &mut processor
+            );
+            partial.remove();
+            res?;
+            return Ok(processor.hashes)
+        }
+        let response = self.server_response(
+            server_uri, notify.snapshot.uri(), RequestKind::Snapshot
+        )?;
+        let _permit = self.memory.acquire(Self::expected_size(&response));
+        let body = DecodedBody::from_response(
+            notify.snapshot.uri(), response, counter,
+            self.bandwidth.clone(), self.max_download_size
+        )?;
+        process_snapshot(body, notify, self.max_file_size, &mut processor)?;
+        Ok(processor.hashes)
+    }
+
+    /// Downloads deltas ahead of processing them.
+    ///
+    /// Fetches the deltas from their original URIs using up to the
+    /// configured number of concurrent workers and stores each of them in
+    /// a temporary file. The result has an element for each delta in the
+    /// order given which is `None` if the delta wasn’t downloaded, either
+    /// because downloading failed or because it is in the delta cache.
+    /// Prefetching is skipped entirely for a single delta or a single
+    /// worker.
+    fn prefetch_deltas(
+        &self,
+        server_uri: &uri::Https,
+        notify: &NotificationFile,
+        deltas: &[(u64, UriAndHash)],
+    ) -> Vec<Option<NamedTempFile>> {
+        let workers = cmp::min(self.delta_workers, deltas.len());
+        if workers < 2 {
+            return deltas.iter().map(|_| None).collect()
+        }
+        let next = AtomicUsize::new(0);
+        let files = Mutex::new(
+            deltas.iter().map(|_| None).collect::<Vec<_>>()
+        );
+        let res = crossbeam_utils::thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|_| {
+                    loop {
+                        let idx = next.fetch_add(1, Ordering::Relaxed);
+                        let delta = match deltas.get(idx) {
+                            Some(delta) => delta,
+                            None => break
+                        };
+                        let file = self.prefetch_delta(
+                            server_uri, notify, delta
+                        );
+                        files.lock().unwrap()[idx] = file;
+                    }
+                });
+            }
+        });
+        if res.is_err() {
+            // A worker panicked. Whatever it was doing is missing from the
+            // result and will just be fetched again later.
+            error!("RRDP {}: delta download worker failed.", server_uri);
+        }
+        files.into_inner().unwrap()
+    }
+
+    /// Processes a delta, fetching it if necessary.
+    ///
+    /// If `prefetched` contains the delta’s content downloaded earlier, it
+    /// is tried first. Next, if the delta cache has a copy of the delta,
+    /// uses that. If fetching the delta from the URI given in the
+    /// notification file fails, tries the configured mirrors in order. The
+    /// content is always checked against the hash given in the
+    /// notification file.
+    fn delta(
+        &self,
+        server_uri: &uri::Https,
+        notify: &NotificationFile,
+        delta: &(u64, UriAndHash),
+        prefetched: Option<NamedTempFile>,
+        targets: &mut DeltaTargets,
+        path_op: &dyn Fn(&uri::Rsync) -> Result<PathBuf, Error>,
+    ) -> Result<(), Error> {
+        if let Some(file) = prefetched {
+            let mark = targets.mark();
+            if self.prefetched_delta(
+                file, server_uri, notify, delta, targets, &path_op
+            ).is_ok() {
+                return Ok(())
+            }
+            targets.rollback(mark);
+        }
+        if let Some(mut file) = self.delta_cache.as_ref().and_then(|cache| {
+            cache.open(notify.session_id, delta)
+        }) {
+            let mark = targets.mark();
+            if self.process_delta(
+                delta.1.uri(), &mut file, server_uri, notify, delta, targets,
+                &path_op
+            ).is_ok() {
+                debug!("{}: using cached copy.", delta.1.uri());
+                return Ok(())
+            }
+            targets.rollback(mark);
+        }
+        let candidates = Self::delta_uris(&self.mirrors, delta.1.uri());
+        for (idx, uri) in candidates.iter().enumerate() {
+            let mark = targets.mark();
+            if self.delta_from(
+                uri, server_uri, notify, delta, targets, &path_op
+            ).is_ok() {
+                return Ok(())
+            }
+            targets.rollback(mark);
+            if let Some(next) = candidates.get(idx + 1) {
+                info!("{}: trying mirror {}.", uri, next);
+            }
+        }
+        Err(Error)
+    }
+
+    /// Returns and resets the number of bytes downloaded for a server.
+    ///
+    /// This is the size of the response bodies as transferred, i.e.,
+    /// before decompression.
+    fn take_downloaded(&self, server_uri: &uri::Https) -> u64 {
+        self.downloaded.lock().unwrap().remove(server_uri).map(|counter| {
+            counter.load(Ordering::Relaxed)
+        }).unwrap_or(0)
+    }
+
+    fn take_total_downloaded(&self) -> u64 {
+        self.bandwidth.take_total()
+    }
+
+    fn set_deadline(
+        &self, server_uri: &uri::Https, deadline: Option<Instant>
+    ) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        match deadline {
+            Some(deadline) => {
+                deadlines.insert(server_uri.clone(), deadline);
+            }
+            None => {
+                deadlines.remove(server_uri);
+            }
+        }
+    }
+}
+
+
+//------------ HostClient ----------------------------------------------------
+
+/// An HTTP client presenting a client certificate to a specific host.
+#[derive(Debug)]
+struct HostClient {
+    /// The host name in lower case.
+    host: String,
+
+    /// The path of the file the certificate was loaded from.
+    identity: PathBuf,
+
+    /// The client or, before ignition, its builder.
+    client: Result<Client, Option<ClientBuilder>>,
+}
+
+/// Returns the host part of a URI authority.
+///
+/// This strips the port if there is one. IPv6 addresses keep their
+/// brackets.
+fn authority_host(authority: &str) -> &str {
+    if authority.starts_with('[') {
+        match authority.find(']') {
+            Some(pos) => &authority[..=pos],
+            None => authority
+        }
+    }
+    else {
+        match authority.find(':') {
+            Some(pos) => &authority[..pos],
+            None => authority
+        }
+    }
+}
+
+
+//------------ NotificationResponse ------------------------------------------
+
+/// A notification file fetched from a server.
+#[derive(Clone, Debug)]
+pub struct NotificationResponse {
+    /// The parsed notification file.
+    pub notify: NotificationFile,
+
+    /// The raw content of the notification file.
+    pub raw: Vec<u8>,
+
+    /// The validators for fetching the file again.
+    pub validators: CacheValidators,
+}
+
+
+//------------ CacheValidators -----------------------------------------------
+
+/// The HTTP validators of a previously fetched notification file.
+///
+/// These are sent along with the next request so the server can answer
+/// with a 304 Not Modified if the file hasn’t changed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CacheValidators {
+    /// The value of the `ETag` header.
+    pub etag: Option<String>,
+
+    /// The value of the `Last-Modified` header.
+    pub last_modified: Option<String>,
+}
+
+impl CacheValidators {
+    /// Takes the validators from the headers of a response.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let value = |name| {
+            headers.get(name).and_then(|value: &HeaderValue| {
+                value.to_str().ok()
+            }).map(String::from)
+        };
+        CacheValidators {
+            etag: value(ETAG),
+            last_modified: value(LAST_MODIFIED),
+        }
+    }
+
+    /// Returns whether there are no validators at all.
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// Returns the headers for a conditional request.
+    ///
+    /// Values that can’t be used as header values are skipped.
+    fn request_headers(&self) -> HeaderMap {
+        let mut res = HeaderMap::new();
+        if let Some(ref etag) = self.etag {
+            if let Ok(value) = HeaderValue::from_str(etag) {
+                res.insert(IF_NONE_MATCH, value);
+            }
+        }
+        if let Some(ref last_modified) = self.last_modified {
+            if let Ok(value) = HeaderValue::from_str(last_modified) {
+                res.insert(IF_MODIFIED_SINCE, value);
+            }
+        }
+        res
+    }
+}
+
+
+//------------ RequestKind ---------------------------------------------------
+
+/// The kind of a request made on behalf of an RRDP server.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RequestKind {
+    /// Fetching the notification file.
+    Notification,
+
+    /// Fetching the snapshot.
+    Snapshot,
+
+    /// Fetching a delta.
+    Delta,
+}
+
+impl fmt::Display for RequestKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            RequestKind::Notification => "notification",
+            RequestKind::Snapshot => "snapshot",
+            RequestKind::Delta => "delta",
+        })
+    }
+}
+
+
+//------------ RetryPolicy ---------------------------------------------------
+
+/// The policy for retrying requests after transient errors.
+///
+/// A request is retried up to `retries` times. The first retry happens
+/// after `delay` and the delay doubles for each further retry.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// The number of times to retry a request.
+    pub retries: usize,
+
+    /// The delay before the first retry.
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Creates the policy from the configuration.
+    pub fn from_config(config: &Config) -> Self {
+        RetryPolicy {
+            retries: config.rrdp_retries,
+            delay: config.rrdp_retry_delay,
+        }
+    }
+
+    /// Returns the delay before the given retry.
+    ///
+    /// Retries are counted from zero. The growth of the delay is capped so
+    /// a large number of retries doesn’t wait forever.
+    pub fn delay(&self, retry: usize) -> Duration {
+        let factor = 1u32 << cmp::min(retry, MAX_RETRY_BACKOFF as usize);
+        self.delay.checked_mul(factor).unwrap_or(self.delay)
+    }
+}
+
+/// Returns whether a response status indicates a transient error.
+fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Returns whether a request error without a response is transient.
+///
+/// This is the case for timeouts and for failing to connect. The latter
+/// is recognized by the I/O error at the bottom of the error, so that
+/// failures such as a rejected certificate are not retried.
+fn is_transient_error(err: &reqwest::Error) -> bool {
+    if err.is_timeout() {
+        return true
+    }
+    let mut source = error::Error::source(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return is_transient_io_error(err.kind())
+        }
+        source = error::Error::source(err);
+    }
+    false
+}
+
+/// Returns whether an I/O error kind indicates a connection failure.
+fn is_transient_io_error(kind: io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::AddrNotAvailable
+        | io::ErrorKind::TimedOut
+    )
+}
+
+
+//------------ RequestTimeouts -----------------------------------------------
+
+/// The timeouts for the different kinds of requests.
+///
+/// A value of `None` leaves the timeout of the client in place.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestTimeouts {
+    /// The timeout for fetching notification files.
+    pub notification: Option<Duration>,
+
+    /// The timeout for fetching snapshots.
+    pub snapshot: Option<Duration>,
+
+    /// The timeout for fetching deltas.
+    pub delta: Option<Duration>,
+}
+
+impl RequestTimeouts {
+    /// Creates the timeouts from the configuration.
+    pub fn from_config(config: &Config) -> Self {
+        RequestTimeouts {
+            notification: config.rrdp_notification_timeout,
+            snapshot: config.rrdp_snapshot_timeout,
+            delta: config.rrdp_delta_timeout,
+        }
+    }
+
+    /// Returns the timeout for the given kind of request.
+    pub fn get(&self, kind: RequestKind) -> Option<Duration> {
+        match kind {
+            RequestKind::Notification => self.notification,
+            RequestKind::Snapshot => self.snapshot,
+            RequestKind::Delta => self.delta,
+        }
+    }
+}
+
+
+//------------ MemoryBudget --------------------------------------------------
+
+/// An approximate limit for the bytes of concurrent downloads.
+///
+/// Before a download starts processing its response, it acquires a permit
+/// for the expected size of the response. If all the permits currently out
+/// plus the new one would exceed the limit, acquiring blocks until enough
+/// permits have been dropped. So that we never stall forever, a download is
+/// always allowed to proceed if nothing else is in flight, even if it
+/// alone exceeds the limit.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    /// The limit in bytes or `None` for no limit at all.
+    limit: Option<u64>,
+
+    /// The number of bytes currently handed out.
+    used: Mutex<u64>,
+
+    /// A condition variable signalled whenever a permit is dropped.
+    released: Condvar,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<u64>) -> Self {
+        MemoryBudget {
+            limit,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Acquires a permit for `size` bytes, waiting if necessary.
+    pub fn acquire(&self, size: u64) -> MemoryPermit {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return MemoryPermit { budget: self, size: 0 }
+        };
+        let mut used = self.used.lock().unwrap();
+        while *used > 0 && used.saturating_add(size) > limit {
+            used = self.released.wait(used).unwrap();
+        }
+        *used += size;
+        MemoryPermit { budget: self, size }
+    }
+
+    fn release(&self, size: u64) {
+        if size == 0 {
+            return
+        }
+        let mut used = self.used.lock().unwrap();
+        *used -= size;
+        self.released.notify_all();
+    }
+}
+
+
+//------------ MemoryPermit --------------------------------------------------
+
+/// A permit to use some of the memory budget.
+///
+/// The bytes are given back to the budget when the permit is dropped.
+#[derive(Debug)]
+pub struct MemoryPermit<'a> {
+    budget: &'a MemoryBudget,
+    size: u64,
+}
+
+impl<'a> Drop for MemoryPermit<'a> {
+    fn drop(&mut self) {
+        self.budget.release(self.size)
+    }
+}
+
+
+//------------ UpdateLimit ---------------------------------------------------
+
+/// A limit for the number of servers updating at the same time.
+///
+/// Before a server starts updating, it acquires a permit. If the limit of
+/// permits is out already, acquiring blocks until a permit is dropped.
+#[derive(Debug)]
+pub struct UpdateLimit {
+    /// The maximum number of permits or `None` for no limit at all.
+    limit: Option<usize>,
+
+    /// The number of permits currently handed out.
+    running: Mutex<usize>,
+
+    /// A condition variable signalled whenever a permit is dropped.
+    released: Condvar,
+}
+
+impl UpdateLimit {
+    pub fn new(limit: Option<usize>) -> Self {
+        UpdateLimit {
+            limit,
+            running: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Acquires a permit, waiting if necessary.
+    pub fn acquire(&self) -> UpdatePermit {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return UpdatePermit { limit: None }
+        };
+        let mut running = self.running.lock().unwrap();
+        while *running >= limit {
+            running = self.released.wait(running).unwrap();
+        }
+        *running += 1;
+        UpdatePermit { limit: Some(self) }
+    }
+
+    fn release(&self) {
+        let mut running = self.running.lock().unwrap();
+        *running -= 1;
+        self.released.notify_one();
+    }
+}
+
+
+//------------ UpdatePermit --------------------------------------------------
+
+/// A permit to update a server.
+///
+/// The permit is given back to the limit when it is dropped.
+#[derive(Debug)]
+pub struct UpdatePermit<'a> {
+    limit: Option<&'a UpdateLimit>,
+}
+
+impl<'a> Drop for UpdatePermit<'a> {
+    fn drop(&mut self) {
+        if let Some(limit) = self.limit {
+            limit.release()
+        }
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Returns whether XML data seems to contain an element of the given name.
+///
+/// This is only a quick check on the raw data and doesn’t parse anything.
+fn has_element(data: &[u8], name: &[u8]) -> bool {
+    data.windows(name.len() + 1).any(|window| {
+        window[0] == b'<' && &window[1..] == name
+    })
+}
+
+/// Returns how many elements of the given name XML data seems to contain.
+///
+/// This is only a quick check on the raw data and doesn’t parse anything.
+fn count_elements(data: &[u8], name: &[u8]) -> usize {
+    data.windows(name.len() + 2).filter(|window| {
+        window[0] == b'<' && &window[1..=name.len()] == name && {
+            let next = window[name.len() + 1];
+            next.is_ascii_whitespace() || next == b'/' || next == b'>'
+        }
+    }).count()
+}
+
+/// Returns whether XML data seems to contain a serial attribute too large.
+///
+/// This is only a quick check on the raw data and doesn’t parse anything.
+/// It looks for all decimal values of attributes named `serial` and
+/// checks whether any of them doesn’t fit into a `u64`.
+fn has_serial_overflow(data: &[u8]) -> bool {
+    const ATTR: &[u8] = b"serial=\"";
+    let mut data = data;
+    while let Some(pos) = data.windows(ATTR.len()).position(|window| {
+        window == ATTR
+    }) {
+        data = &data[pos + ATTR.len()..];
+        let len = data.iter().take_while(|ch| ch.is_ascii_digit()).count();
+        if len > 0 && data.get(len) == Some(&b'"') {
+            // The slice is all ASCII digits, so from_utf8 can’t fail.
+            let value = std::str::from_utf8(&data[..len]).unwrap_or("");
+            if u64::from_str(value).is_err() {
+                return true
+            }
+        }
+        data = &data[len..];
+    }
+    false
 }
 
 
@@ -291,15 +2051,412 @@ impl<R: io::Read> io::Read for DigestRead<R> {
 }
 
 
+//------------ DecodedBody ---------------------------------------------------
+
+/// The body of a response with its content encoding removed.
+///
+/// Decompression happens while reading, so the compressed body is never
+/// kept in memory as a whole. A corrupt or truncated compressed body
+/// results in a read error.
+pub enum DecodedBody<R> {
+    /// The body wasn’t encoded.
+    Identity(R),
+
+    /// The body was encoded with gzip.
+    ///
+    /// A gzip body may consist of several members that are concatenated.
+    Gzip(MultiGzDecoder<R>),
+
+    /// The body was encoded with deflate, i.e., as a zlib stream.
+    Deflate(ZlibDecoder<R>),
+}
+
+impl DecodedBody<CountRead<Response>> {
+    /// The value of the Accept-Encoding header for requests.
+    pub const ACCEPT: &'static str = "gzip, deflate";
+
+    /// Creates the decoded body of a response.
+    ///
+    /// The bytes of the body as transferred are added to `counter` and
+    /// recorded with `bandwidth`. If `limit` is given, reading fails once
+    /// the counter exceeds it. Logs
+    /// and returns an error if the content encoding isn’t supported. The
+    /// URI is only used for error reporting.
+    pub fn from_response(
+        uri: &uri::Https,
+        response: Response,
+        counter: Arc<AtomicU64>,
+        bandwidth: Arc<Bandwidth>,
+        limit: Option<u64>,
+    ) -> Result<Self, RequestError> {
+        let encoding = match response.headers().get(CONTENT_ENCODING) {
+            Some(value) => match value.to_str() {
+                Ok(value) => Some(value.trim().to_ascii_lowercase()),
+                Err(_) => {
+                    info!("{}: invalid Content-Encoding header.", uri);
+                    return Err(RequestError::Failed)
+                }
+            }
+            None => None
+        };
+        Self::new(
+            CountRead::new(response, counter, bandwidth, uri, limit),
+            encoding.as_ref().map(String::as_str)
+        ).map_err(
+            |_| {
+                info!(
+                    "{}: unsupported content encoding '{}'.",
+                    uri, encoding.unwrap_or_default()
+                );
+                RequestError::Failed
+            }
+        )
+    }
+}
+
+impl<R: io::Read> DecodedBody<R> {
+    /// Creates a decoded body for the given content encoding.
+    ///
+    /// Returns an error if the encoding isn’t supported.
+    pub fn new(reader: R, encoding: Option<&str>) -> Result<Self, Error> {
+        match encoding {
+            None | Some("identity") => Ok(DecodedBody::Identity(reader)),
+            Some("gzip") | Some("x-gzip") => {
+                Ok(DecodedBody::Gzip(MultiGzDecoder::new(reader)))
+            }
+            Some("deflate") => {
+                Ok(DecodedBody::Deflate(ZlibDecoder::new(reader)))
+            }
+            Some(_) => Err(Error)
+        }
+    }
+}
+
+impl<R: io::Read> io::Read for DecodedBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        match *self {
+            DecodedBody::Identity(ref mut reader) => reader.read(buf),
+            DecodedBody::Gzip(ref mut reader) => reader.read(buf),
+            DecodedBody::Deflate(ref mut reader) => reader.read(buf),
+        }
+    }
+}
+
+
+//------------ CountRead -----------------------------------------------------
+
+/// A reader that counts the bytes read.
+///
+/// The counter may be shared with other readers. If a limit is given,
+/// reading fails once the counter exceeds it. All bytes are also recorded
+/// with the bandwidth which may throttle reading.
+pub struct CountRead<R> {
+    reader: R,
+    counter: Arc<AtomicU64>,
+    bandwidth: Arc<Bandwidth>,
+
+    /// The limit and the URI to report when it is exceeded.
+    limit: Option<(u64, uri::Https)>,
+}
+
+impl<R> CountRead<R> {
+    pub fn new(
+        reader: R,
+        counter: Arc<AtomicU64>,
+        bandwidth: Arc<Bandwidth>,
+        uri: &uri::Https,
+        limit: Option<u64>,
+    ) -> Self {
+        CountRead {
+            reader, counter, bandwidth,
+            limit: limit.map(|limit| (limit, uri.clone())),
+        }
+    }
+}
+
+impl<R: io::Read> io::Read for CountRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let res = self.reader.read(buf)?;
+        let total = self.counter.fetch_add(
+            res as u64, Ordering::Relaxed
+        ) + res as u64;
+        self.bandwidth.record(res as u64);
+        if let Some((limit, ref uri)) = self.limit {
+            if total > limit {
+                warn!(
+                    "{}: downloads for the repository exceed the \
+                     maximum of {} bytes.",
+                    uri, limit
+                );
+                return Err(size_exceeded())
+            }
+        }
+        Ok(res)
+    }
+}
+
+
+//------------ LimitRead -----------------------------------------------------
+
+/// A reader that fails once more than a given number of bytes was read.
+///
+/// Without a limit, it just passes everything through. The URI is only used
+/// for error reporting.
+pub struct LimitRead<'a, R> {
+    reader: R,
+    uri: &'a uri::Https,
+    limit: Option<u64>,
+    read: u64,
+}
+
+impl<'a, R> LimitRead<'a, R> {
+    pub fn new(reader: R, uri: &'a uri::Https, limit: Option<u64>) -> Self {
+        LimitRead { reader, uri, limit, read: 0 }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<'a, R: io::Read> io::Read for LimitRead<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return self.reader.read(buf)
+        };
+        if self.read > limit {
+            return Err(size_exceeded())
+        }
+        let res = self.reader.read(buf)?;
+        self.read += res as u64;
+        if self.read > limit {
+            warn!("{}: exceeds maximum size of {} bytes.", self.uri, limit);
+            return Err(size_exceeded())
+        }
+        Ok(res)
+    }
+}
+
+/// Returns the error for a reader that exceeded its limit.
+fn size_exceeded() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "size limit exceeded")
+}
+
+
+//------------ LimitWrite ----------------------------------------------------
+
+/// A writer collecting data in memory that fails beyond a given size.
+///
+/// Without a limit, it collects everything.
+struct LimitWrite {
+    data: Vec<u8>,
+    limit: Option<u64>,
+    exceeded: bool,
+}
+
+impl LimitWrite {
+    fn new(limit: Option<u64>) -> Self {
+        LimitWrite { data: Vec::new(), limit, exceeded: false }
+    }
+
+    /// Returns whether writing failed because of the limit.
+    fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+
+    fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl io::Write for LimitWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        if let Some(limit) = self.limit {
+            if (self.data.len() + buf.len()) as u64 > limit {
+                self.exceeded = true;
+                return Err(size_exceeded())
+            }
+        }
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+
+//------------ snapshot_from_file --------------------------------------------
+
+/// Reads a snapshot from a local file and writes its objects.
+///
+/// The session and serial number of the snapshot have to be `session` and
+/// `serial`. Since there is no notification file, there is no hash to check
+/// the file against. The objects are written compressed if `compress` is
+/// `true`. Returns the hashes of all the objects written.
+pub fn snapshot_from_file<F: Fn(&uri::Rsync) -> Result<PathBuf, Error>>(
+    path: &Path,
+    session: Uuid,
+    serial: u64,
+    path_op: F,
+    compress: bool,
+) -> Result<ObjectHashes, Error> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            error!(
+                "Failed to open snapshot file '{}': {}",
+                path.display(), err
+            );
+            return Err(Error)
+        }
+    };
+    let mut processor = SnapshotProcessor::new(
+        session, serial, path_op, compress
+    );
+    if let Err(err) = processor.process(&mut io::BufReader::new(file)) {
+        error!("{}: {}", path.display(), err);
+        return Err(Error)
+    }
+    Ok(processor.into_hashes())
+}
+
+
+//------------ process_snapshot and process_delta ---------------------------
+
+/// Processes a snapshot read from the given reader.
+///
+/// The content is checked against the hash given in the notification
+/// file. Reading fails if the snapshot exceeds `max_file_size`.
+pub fn process_snapshot<R, F>(
+    reader: R,
+    notify: &NotificationFile,
+    max_file_size: Option<u64>,
+    processor: &mut SnapshotProcessor<F>,
+) -> Result<(), Error>
+where R: io::Read, F: Fn(&uri::Rsync) -> Result<PathBuf, Error> {
+    let mut reader = io::BufReader::new(LimitRead::new(
+        DigestRead::sha256(reader), notify.snapshot.uri(), max_file_size
+    ));
+    if let Err(err) = processor.process(&mut reader) {
+        error!("{}: {}", notify.snapshot.uri(), err);
+        return Err(Error)
+    }
+    processor.report_progress();
+    let digest = reader.into_inner().into_inner().into_digest();
+    if verify_slices_are_equal(
+        digest.as_ref(),
+        notify.snapshot.hash().as_ref()
+    ).is_err() {
+        info!("{}: hash value mismatch.", notify.snapshot.uri());
+        return Err(Error)
+    }
+    Ok(())
+}
+
+/// Processes a delta read from the given reader.
+///
+/// The content is checked against the hash given in the notification
+/// file. Because this can only happen once all of it has been read, the
+/// changes are added to the processor’s targets first and the caller has
+/// to roll them back if an error is returned. The URI is only used for
+/// error reporting. Reading fails if the delta exceeds `max_file_size`.
+pub fn process_delta<R, F>(
+    uri: &uri::Https,
+    reader: R,
+    max_file_size: Option<u64>,
+    mut processor: DeltaProcessor<F>,
+) -> Result<(), Error>
+where R: io::Read, F: Fn(&uri::Rsync) -> Result<PathBuf, Error> {
+    let mut reader = io::BufReader::new(LimitRead::new(
+        DigestRead::sha256(reader), uri, max_file_size
+    ));
+    if let Err(err) = processor.process(&mut reader) {
+        if let ProcessError::Xml(err) = err {
+            info!("Bad content in {}: {}", uri, err);
+        }
+        return Err(Error)
+    }
+    let digest = reader.into_inner().into_inner().into_digest();
+    if verify_slices_are_equal(
+        digest.as_ref(),
+        processor.delta.1.hash().as_ref()
+    ).is_err() {
+        error!("{}: hash value mismatch.", uri);
+        return Err(Error)
+    }
+    Ok(())
+}
+
+
 //------------ SnapshotProcessor ---------------------------------------------
 
 pub struct SnapshotProcessor<'a, F> {
-    notify: &'a NotificationFile,
+    /// The session the snapshot has to have.
+    session: Uuid,
+
+    /// The serial number the snapshot has to have.
+    serial: u64,
+
     path_op: F,
+
+    /// Whether to write objects compressed.
+    compress: bool,
+
+    /// The check each object has to pass before it is written.
+    check: ObjectCheck,
+
+    hashes: ObjectHashes,
+
+    /// The progress callback, download counter, and its start value.
+    progress: Option<(
+        &'a mut dyn FnMut(SnapshotProgress), Arc<AtomicU64>, u64
+    )>,
+}
+
+impl<'a, F> SnapshotProcessor<'a, F> {
+    /// Creates a processor for the snapshot with session and serial.
+    ///
+    /// The objects are written to the paths returned by `path_op`,
+    /// compressed if `compress` is `true`. All objects are accepted unless
+    /// a check is set via `set_check`.
+    pub fn new(
+        session: Uuid, serial: u64, path_op: F, compress: bool
+    ) -> Self {
+        SnapshotProcessor {
+            session, serial, path_op, compress,
+            check: ObjectCheck::default(),
+            hashes: ObjectHashes::new(),
+            progress: None,
+        }
+    }
+
+    /// Sets the check each object has to pass before it is written.
+    pub fn set_check(&mut self, check: ObjectCheck) {
+        self.check = check
+    }
+
+    /// Returns the hashes of all objects written.
+    pub fn into_hashes(self) -> ObjectHashes {
+        self.hashes
+    }
+
+    /// Calls the progress callback if there is one.
+    fn report_progress(&mut self) {
+        let objects = self.hashes.len();
+        if let Some((ref mut op, ref counter, start)) = self.progress {
+            op(SnapshotProgress {
+                bytes: counter.load(Ordering::Relaxed).saturating_sub(start),
+                objects,
+            })
+        }
+    }
 }
 
 impl<'a, F> ProcessSnapshot for SnapshotProcessor<'a, F>
-where F: Fn(&uri::Rsync) -> PathBuf {
+where F: Fn(&uri::Rsync) -> Result<PathBuf, Error> {
     type Err = SnapshotError;
 
     fn meta(
@@ -307,15 +2464,15 @@ where F: Fn(&uri::Rsync) -> PathBuf {
         session_id: Uuid,
         serial: u64,
     ) -> Result<(), Self::Err> {
-        if session_id != self.notify.session_id {
+        if session_id != self.session {
             return Err(SnapshotError::SessionMismatch {
-                expected: self.notify.session_id,
+                expected: self.session,
                 received: session_id
             })
         }
-        if serial != self.notify.serial {
+        if serial != self.serial {
             return Err(SnapshotError::SerialMismatch {
-                expected: self.notify.serial,
+                expected: self.serial,
                 received: serial
             })
         }
@@ -327,7 +2484,13 @@ where F: Fn(&uri::Rsync) -> PathBuf {
         uri: uri::Rsync,
         data: Vec<u8>,
     ) -> Result<(), Self::Err> {
-        let path = (self.path_op)(&uri);
+        let path = match (self.path_op)(&uri) {
+            Ok(path) => path,
+            Err(_) => return Err(SnapshotError::IllegalPath(uri))
+        };
+        if !self.check.accepts(&uri, &data) {
+            return Err(SnapshotError::Rejected(uri))
+        }
 
         if let Err(err) = fs::create_dir_all(path.parent().unwrap()) {
             return Err(SnapshotError::Io(
@@ -336,27 +2499,77 @@ where F: Fn(&uri::Rsync) -> PathBuf {
             ))
         }
 
-        let mut file = match fs::File::create(&path) {
-            Ok(file) => file,
-            Err(err) => {
-                return Err(SnapshotError::Io(
-                    path.to_string_lossy().into(),
-                    err
-                ))
-            }
-        };
-
-        if let Err(err) = file.write_all(data.as_ref()) {
+        if let Err(err) = compress::create_object(
+            &path, data.as_ref(), self.compress
+        ) {
             return Err(SnapshotError::Io(
                 path.to_string_lossy().into(),
                 err
             ))
         }
+        self.hashes.insert_data(uri, &data);
+        if self.hashes.len() % SNAPSHOT_PROGRESS_INTERVAL == 0 {
+            self.report_progress()
+        }
         Ok(())
     }
 }
 
 
+//------------ SnapshotProgress ----------------------------------------------
+
+/// The progress of downloading a snapshot.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SnapshotProgress {
+    /// The number of bytes downloaded so far.
+    ///
+    /// This is the size of the response body as transferred, i.e., before
+    /// decompression.
+    pub bytes: u64,
+
+    /// The number of objects written so far.
+    pub objects: usize,
+}
+
+
+//------------ ObjectCheck ---------------------------------------------------
+
+/// A check of objects read from a snapshot before they are written.
+///
+/// The check is given the URI and content of an object and returns whether
+/// the object is acceptable. This allows rejecting a snapshot with
+/// obviously malformed objects early rather than validating them after
+/// the snapshot has been applied. By default, all objects are accepted.
+#[derive(Clone, Default)]
+pub struct ObjectCheck(
+    Option<Arc<dyn Fn(&uri::Rsync, &[u8]) -> bool + Send + Sync>>
+);
+
+impl ObjectCheck {
+    /// Creates a check from the given closure.
+    pub fn new<F>(op: F) -> Self
+    where F: Fn(&uri::Rsync, &[u8]) -> bool + Send + Sync + 'static {
+        ObjectCheck(Some(Arc::new(op)))
+    }
+
+    /// Returns whether the object passes the check.
+    pub fn accepts(&self, uri: &uri::Rsync, data: &[u8]) -> bool {
+        match self.0 {
+            Some(ref op) => op(uri, data),
+            None => true
+        }
+    }
+}
+
+impl fmt::Debug for ObjectCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ObjectCheck").field(
+            &if self.0.is_some() { "custom" } else { "accept all" }
+        ).finish()
+    }
+}
+
+
 //------------ DeltaProcessor ------------------------------------------------
 
 pub struct DeltaProcessor<'a, F> {
@@ -368,6 +2581,25 @@ pub struct DeltaProcessor<'a, F> {
 }
 
 impl<'a, F> DeltaProcessor<'a, F> {
+    /// Creates a processor for a delta of the server at `server_uri`.
+    ///
+    /// The changes are added to `targets` using the paths returned by
+    /// `path_op`.
+    pub fn new(
+        server_uri: &'a uri::Https,
+        notify: &'a NotificationFile,
+        delta: &'a (u64, UriAndHash),
+        targets: &'a mut DeltaTargets,
+        path_op: F,
+    ) -> Self {
+        DeltaProcessor { server_uri, notify, delta, path_op, targets }
+    }
+
+    /// Checks that the local object at `path` has the given hash.
+    ///
+    /// This is done for objects replaced as well as withdrawn by a delta.
+    /// A mismatch means that the local copy has drifted from the server’s
+    /// view and the delta can’t be applied.
     fn check_hash(
         &self,
         uri: &uri::Rsync,
@@ -384,18 +2616,10 @@ impl<'a, F> DeltaProcessor<'a, F> {
                 return Err(ProcessError::Error)
             }
         };
-        let file = match fs::File::open(path) {
-            Ok(file) => file,
-            Err(err) => {
-                info!(
-                    "Failed to open file '{}': {}",
-                    path.display(), err
-                );
-                return Err(ProcessError::Error)
-            }
-        };
-        let digest = match DigestRead::sha256(file).read_all() {
-            Ok(digest) => digest,
+        let digest = match compress::read_stored_object(
+            &*self.targets.store, path
+        ) {
+            Ok(data) => digest::digest(&digest::SHA256, &data),
             Err(err) => {
                 info!(
                     "Failed to read file '{}': {}",
@@ -414,7 +2638,7 @@ impl<'a, F> DeltaProcessor<'a, F> {
 }
 
 impl<'a, F> ProcessDelta for DeltaProcessor<'a, F>
-where F: Fn(&uri::Rsync) -> PathBuf {
+where F: Fn(&uri::Rsync) -> Result<PathBuf, Error> {
     type Err = ProcessError;
 
     fn meta(
@@ -447,11 +2671,11 @@ where F: Fn(&uri::Rsync) -> PathBuf {
         hash: Option<DigestHex>,
         data: Vec<u8>
     ) -> Result<(), Self::Err> {
-        let target = (self.path_op)(&uri);
+        let target = (self.path_op)(&uri).map_err(|_| ProcessError::Error)?;
         if let Some(hash) = hash {
             self.check_hash(&uri, &target, hash)?;
         }
-        self.targets.publish(target, data)
+        self.targets.publish(uri, target, data)
     }
 
     fn withdraw(
@@ -459,9 +2683,9 @@ where F: Fn(&uri::Rsync) -> PathBuf {
         uri: uri::Rsync,
         hash: DigestHex
     ) -> Result<(), Self::Err> {
-        let target = (self.path_op)(&uri);
+        let target = (self.path_op)(&uri).map_err(|_| ProcessError::Error)?;
         self.check_hash(&uri, &target, hash)?;
-        self.targets.withdraw(target);
+        self.targets.withdraw(uri, target);
         Ok(())
     }
 }
@@ -472,20 +2696,40 @@ where F: Fn(&uri::Rsync) -> PathBuf {
 pub struct DeltaTargets {
     tmp_dir: TempDir,
     targets: Vec<DeltaEntry>,
+
+    /// Whether to write objects compressed.
+    compress: bool,
+
+    /// The store the targets are in.
+    store: SharedStore,
+
+    /// The changes to the object hashes.
+    ///
+    /// Published objects have their new hash, withdrawn objects `None`.
+    hashes: Vec<(uri::Rsync, Option<DigestHex>)>,
 }
 
 enum DeltaEntry {
     Publish {
+        uri: uri::Rsync,
         source: PathBuf,
         target: PathBuf,
+        size: u64,
     },
     Withdraw {
+        uri: uri::Rsync,
         target: PathBuf
     }
 }
 
 impl DeltaTargets {
-    pub fn new(cache_dir: &Path) -> Result<Self, Error> {
+    /// Creates new targets using a temporary directory under `cache_dir`.
+    ///
+    /// Published objects are written compressed if `compress` is `true`.
+    /// Existing objects are read and changed via `store`.
+    pub fn new(
+        cache_dir: &Path, compress: bool, store: SharedStore
+    ) -> Result<Self, Error> {
         Ok(DeltaTargets {
             tmp_dir: match TempDir::new_in(cache_dir) {
                 Ok(tmp_dir) => tmp_dir,
@@ -497,17 +2741,91 @@ impl DeltaTargets {
                     return Err(Error)
                 }
             },
-            targets: Vec::new()
+            targets: Vec::new(),
+            compress,
+            store,
+            hashes: Vec::new(),
         })
     }
 
-    pub fn apply(self) -> Result<(), Error> {
+    /// Returns a mark for the current set of targets.
+    ///
+    /// The mark can be used to later roll back all changes made after it
+    /// was taken.
+    pub fn mark(&self) -> DeltaMark {
+        DeltaMark {
+            targets: self.targets.len(),
+            hashes: self.hashes.len(),
+        }
+    }
+
+    /// Drops all changes added since the mark was taken.
+    pub fn rollback(&mut self, mark: DeltaMark) {
+        for entry in self.targets.drain(mark.targets..) {
+            if let DeltaEntry::Publish { source, .. } = entry {
+                let _ = fs::remove_file(source);
+            }
+        }
+        self.hashes.truncate(mark.hashes);
+    }
+
+    /// Applies the changes of the deltas to a set of object hashes.
+    pub fn update_hashes(&self, hashes: &mut ObjectHashes) {
+        for (uri, hash) in &self.hashes {
+            match *hash {
+                Some(ref hash) => hashes.insert(uri.clone(), hash.clone()),
+                None => hashes.remove(uri),
+            }
+        }
+    }
+
+    /// Applies the changes to the server directory.
+    ///
+    /// Adjusts `object_count` for all objects added and removed and adds
+    /// the URI and kind of each change to `uris` in the order they were
+    /// applied. If it can’t be told whether an object was added or removed,
+    /// the count becomes unknown. Objects republished with identical
+    /// content aren’t changes and are left out.
+    ///
+    /// Returns the target paths of all files written together with their
+    /// size and of all files removed with a size of `None`.
+    pub fn apply(
+        self,
+        object_count: &mut Option<usize>,
+        uris: &mut Vec<(uri::Rsync, ChangeKind)>,
+    ) -> Result<Vec<(PathBuf, Option<u64>)>, Error> {
+        let store = self.store;
+        let mut changes = Vec::with_capacity(self.targets.len());
         for entry in self.targets {
             match entry {
-                DeltaEntry::Publish { source, target } => {
-                    let _ = fs::remove_file(&target); // Just to make sure.
-                    let _ = target.parent().map(fs::create_dir_all);
-                    if let Err(err) = fs::rename(&source, &target) {
+                DeltaEntry::Publish { uri, source, target, size } => {
+                    // An object republished unchanged keeps its file so
+                    // that its modification time stays the same.
+                    if Self::is_unchanged(&*store, &source, &target) {
+                        continue
+                    }
+
+                    // Removing fails with not found if this is a new
+                    // object. If it fails otherwise, we can’t tell.
+                    let kind = match store.remove_file(&target) {
+                        Ok(()) => ChangeKind::Modified,
+                        Err(ref err)
+                            if err.kind() == io::ErrorKind::NotFound =>
+                        {
+                            *object_count = object_count.map(|count| {
+                                count + 1
+                            });
+                            ChangeKind::Added
+                        }
+                        Err(_) => {
+                            *object_count = None;
+                            ChangeKind::Modified
+                        }
+                    };
+                    let _ = target.parent().map(|parent| {
+                        store.create_dir_all(parent)
+                    });
+                    if let Err(err) = store.rename(&source, &target) {
                         info!(
                             "Failed to move delta source '{}' to \
                             target '{}': {}",
@@ -517,51 +2835,95 @@ impl DeltaTargets {
                         );
                         return Err(Error);
                     }
+                    changes.push((target, Some(size)));
+                    uris.push((uri, kind));
                 }
-                DeltaEntry::Withdraw { target } => {
-                    if let Err(err) = fs::remove_file(&target) {
+                DeltaEntry::Withdraw { uri, target } => {
+                    if let Err(err) = store.remove_file(&target) {
                         info!(
                             "Failed to delete file '{}': {}",
                             target.display(), err
                         );
                         return Err(Error);
                     }
+                    *object_count = object_count.and_then(|count| {
+                        count.checked_sub(1)
+                    });
+                    changes.push((target, None));
+                    uris.push((uri, ChangeKind::Withdrawn));
                 }
             }
         }
-        Ok(())
+        Ok(changes)
     }
 
-    fn publish(
+    /// Returns whether a published object is identical to its target.
+    ///
+    /// The source is always a plain file while the target is in the store.
+    /// If the target is local, the sizes are compared first so that the
+    /// content only needs reading if they are the same.
+    fn is_unchanged(
+        store: &dyn CacheStore, source: &Path, target: &Path
+    ) -> bool {
+        if store.is_local(target) {
+            match (fs::metadata(target), fs::metadata(source)) {
+                (Ok(target), Ok(source)) => {
+                    if target.len() != source.len() {
+                        return false
+                    }
+                }
+                _ => return false
+            }
+        }
+        match (store.read(target), fs::read(source)) {
+            (Ok(target), Ok(source)) => target == source,
+            _ => false
+        }
+    }
+
+    /// Adds an object to be published at the target path.
+    pub fn publish(
         &mut self,
+        uri: uri::Rsync,
         target: PathBuf,
         data: Vec<u8>
     ) -> Result<(), ProcessError> {
-        let (mut file, source) = create_unique_file(self.tmp_dir.path())?;
-        if let Err(err) = file.write_all(data.as_ref()) {
+        let (file, source) = create_unique_file(self.tmp_dir.path())?;
+        if let Err(err) = compress::write_object(
+            io::BufWriter::new(file), data.as_ref(), self.compress
+        ) {
             info!(
                 "Failed to temporary file '{}': {}",
                 source.display(), err
             );
             return Err(ProcessError::Error)
         }
-        self.targets.push(DeltaEntry::Publish { source, target });
+        self.targets.push(DeltaEntry::Publish {
+            uri: uri.clone(), source, target, size: data.len() as u64
+        });
+        self.hashes.push((
+            uri, Some(digest::digest(&digest::SHA256, &data).into())
+        ));
         Ok(())
     }
 
-    fn withdraw(&mut self, target: PathBuf) {
-        self.targets.push(DeltaEntry::Withdraw { target })
+    /// Adds an object to be withdrawn from the target path.
+    pub fn withdraw(&mut self, uri: uri::Rsync, target: PathBuf) {
+        self.targets.push(DeltaEntry::Withdraw {
+            uri: uri.clone(), target
+        });
+        self.hashes.push((uri, None));
     }
 
     fn target_path<'s>(&'s self, target_path: &'s Path) -> Option<&'s Path> {
         for entry in &self.targets {
             match *entry {
-                DeltaEntry::Publish { ref source, ref target } => {
+                DeltaEntry::Publish { ref source, ref target, .. } => {
                     if target == target_path {
                         return Some(source)
                     }
                 }
-                DeltaEntry::Withdraw { ref target } => {
+                DeltaEntry::Withdraw { ref target, .. } => {
                     if target == target_path {
                         return None
                     }
@@ -573,8 +2935,53 @@ impl DeltaTargets {
 }
     
 
+//------------ ChangeKind ----------------------------------------------------
+
+/// The kind of change an update made to an object.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChangeKind {
+    /// The object was newly published.
+    Added,
+
+    /// The object replaced an earlier version.
+    Modified,
+
+    /// The object was withdrawn.
+    Withdrawn,
+}
+
+
+//------------ DeltaMark -----------------------------------------------------
+
+/// A position in a set of delta targets to roll back to.
+#[derive(Clone, Copy, Debug)]
+pub struct DeltaMark {
+    targets: usize,
+    hashes: usize,
+}
+
+
 //============ Errors ========================================================
 
+/// A request on behalf of an RRDP server has failed.
+///
+/// The reason has been logged already.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RequestError {
+    /// The request did not finish within its timeout.
+    Timeout,
+
+    /// The request failed for some other reason.
+    Failed,
+}
+
+impl From<RequestError> for Error {
+    fn from(_: RequestError) -> Error {
+        Error
+    }
+}
+
+
 #[derive(Debug)]
 pub enum SnapshotError {
     Xml(xml::Error),
@@ -586,6 +2993,8 @@ pub enum SnapshotError {
         expected: u64,
         received: u64,
     },
+    IllegalPath(uri::Rsync),
+    Rejected(uri::Rsync),
     Io(String, io::Error),
 }
 
@@ -615,6 +3024,12 @@ impl fmt::Display for SnapshotError {
                      expected, received
                 )
             }
+            SnapshotError::IllegalPath(ref uri) => {
+                write!(f, "illegal path in URI {}", uri)
+            }
+            SnapshotError::Rejected(ref uri) => {
+                write!(f, "object {} rejected", uri)
+            }
             SnapshotError::Io(ref s, ref err) => {
                 write!(f, "{}: {}", s, err)
             }
@@ -625,6 +3040,30 @@ impl fmt::Display for SnapshotError {
 impl error::Error for SnapshotError { }
 
 
+#[derive(Debug)]
+pub enum NotificationError {
+    Xml(xml::Error),
+    MissingSnapshot,
+    SerialOverflow,
+}
+
+impl fmt::Display for NotificationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NotificationError::Xml(ref err) => err.fmt(f),
+            NotificationError::MissingSnapshot => {
+                f.write_str("no snapshot in notification")
+            }
+            NotificationError::SerialOverflow => {
+                f.write_str("serial number in notification too large")
+            }
+        }
+    }
+}
+
+impl error::Error for NotificationError { }
+
+
 #[derive(Debug)]
 pub enum ProcessError {
     Xml(xml::Error),
@@ -648,9 +3087,40 @@ impl From<Error> for ProcessError {
 
 #[cfg(test)]
 mod test {
+    use std::io::Write;
+    use std::thread::sleep;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use ring::digest;
+    use crate::metrics::RrdpUpdateKind;
+    use crate::rrdp::clock::MockClock;
+    use crate::rrdp::selftest::{HTTPS_BASE, MockRepository, rsync_uri};
+    use crate::rrdp::server::{Server, ServerOptions};
     use super::*;
 
+    /// Creates the directories, repository, and client for a test.
+    ///
+    /// Returns the temporary directory, the RRDP cache directory within
+    /// it, an empty repository, and a client fetching from it.
+    fn setup() -> (TempDir, PathBuf, MockRepository, ReqwestClient) {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache_dir = tmp.path().join("rrdp");
+        let tmp_dir = tmp.path().join("tmp");
+        fs::create_dir_all(&cache_dir).unwrap();
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let repo = MockRepository::start().unwrap();
+        let http = ReqwestClient::with_redirect(
+            tmp_dir, HTTPS_BASE.into(), repo.server.origin()
+        ).unwrap();
+        (tmp, cache_dir, repo, http)
+    }
+
+    /// Returns the URI of the mock repository’s notification file.
+    fn notify_uri() -> uri::Https {
+        uri::Https::from_str(
+            &format!("{}notification.xml", HTTPS_BASE)
+        ).unwrap()
+    }
+
     #[test]
     fn digest_read_read_all() {
         let test = b"sdafkljfasdkjlfashjklfasdklhjfasdklhjfasd";
@@ -659,5 +3129,962 @@ mod test {
             digest::digest(&digest::SHA256, test).as_ref()
         );
     }
-}
 
+    #[test]
+    fn cache_validators() {
+        let mut headers = HeaderMap::new();
+        assert!(CacheValidators::from_headers(&headers).is_empty());
+        assert!(CacheValidators::default().request_headers().is_empty());
+
+        headers.insert(ETAG, HeaderValue::from_static("\"abc\""));
+        headers.insert(
+            LAST_MODIFIED,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+        let validators = CacheValidators::from_headers(&headers);
+        assert_eq!(validators.etag.as_ref().unwrap(), "\"abc\"");
+        let request = validators.request_headers();
+        assert_eq!(request[IF_NONE_MATCH], "\"abc\"");
+        assert_eq!(
+            request[IF_MODIFIED_SINCE], "Wed, 21 Oct 2015 07:28:00 GMT"
+        );
+
+        // Values not allowed in headers are skipped.
+        let validators = CacheValidators {
+            etag: Some("\"a\nb\"".into()),
+            last_modified: None,
+        };
+        assert!(validators.request_headers().is_empty());
+    }
+
+    const NOTIFICATION_START: &[u8] =
+        b"<notification xmlns=\"http://www.ripe.net/rpki/rrdp\" \
+          version=\"1\" \
+          session_id=\"9df4b597-af9e-4dca-bdda-719cce2c4e28\" \
+          serial=\"2\">\n";
+
+    const NOTIFICATION_SNAPSHOT: &[u8] =
+        b"  <snapshot uri=\"https://example.net/snapshot.xml\" \
+          hash=\"2a51c2b6e1fa1d6c4e7d4d0bb2e9e0a6\
+          3d6e9b2d0e73f6b1d29cd563d1fe1b61\"/>\n";
+
+    const NOTIFICATION_DELTA: &[u8] =
+        b"  <delta serial=\"2\" uri=\"https://example.net/delta-2.xml\" \
+          hash=\"e3b0c44298fc1c149afbf4c8996fb924\
+          27ae41e4649b934ca495991b7852b855\"/>\n";
+
+    const NOTIFICATION_END: &[u8] = b"</notification>\n";
+
+    fn notification(parts: &[&[u8]]) -> Vec<u8> {
+        let mut res = NOTIFICATION_START.to_vec();
+        for part in parts {
+            res.extend_from_slice(part);
+        }
+        res.extend_from_slice(NOTIFICATION_END);
+        res
+    }
+
+    #[test]
+    fn parse_notification() {
+        let notify = ReqwestClient::parse_notification(
+            &notification(&[NOTIFICATION_SNAPSHOT, NOTIFICATION_DELTA])
+        ).unwrap();
+        assert_eq!(notify.serial, 2);
+        assert_eq!(notify.deltas.len(), 1);
+    }
+
+    #[test]
+    fn check_locations() {
+        let uri = uri::Https::from_str(
+            "https://example.net/notification.xml"
+        ).unwrap();
+        let notify = ReqwestClient::parse_notification(
+            &notification(&[NOTIFICATION_SNAPSHOT, NOTIFICATION_DELTA])
+        ).unwrap();
+        assert!(ReqwestClient::check_locations(&uri, &notify, &[]).is_ok());
+
+        // A delta on a different host is refused unless allowed.
+        let notify = ReqwestClient::parse_notification(
+            &notification(&[
+                NOTIFICATION_SNAPSHOT,
+                b"  <delta serial=\"2\" \
+                  uri=\"https://elsewhere.example/delta-2.xml\" \
+                  hash=\"e3b0c44298fc1c149afbf4c8996fb924\
+                  27ae41e4649b934ca495991b7852b855\"/>\n"
+            ])
+        ).unwrap();
+        assert_eq!(
+            ReqwestClient::check_locations(&uri, &notify, &[]).unwrap_err()
+                .as_str(),
+            "https://elsewhere.example/delta-2.xml"
+        );
+        assert!(ReqwestClient::check_locations(
+            &uri, &notify, &["Elsewhere.Example".into()]
+        ).is_ok());
+
+        // So is a snapshot.
+        let other = uri::Https::from_str(
+            "https://other.example/notification.xml"
+        ).unwrap();
+        let notify = ReqwestClient::parse_notification(
+            &notification(&[NOTIFICATION_SNAPSHOT])
+        ).unwrap();
+        assert_eq!(
+            ReqwestClient::check_locations(&other, &notify, &[]).unwrap_err()
+                .as_str(),
+            "https://example.net/snapshot.xml"
+        );
+    }
+
+    #[test]
+    fn check_delta_count() {
+        let data = notification(&[
+            NOTIFICATION_SNAPSHOT, NOTIFICATION_DELTA, NOTIFICATION_DELTA,
+            NOTIFICATION_DELTA
+        ]);
+        assert_eq!(ReqwestClient::check_delta_count(&data, None), Ok(()));
+        assert_eq!(ReqwestClient::check_delta_count(&data, Some(3)), Ok(()));
+        assert_eq!(
+            ReqwestClient::check_delta_count(&data, Some(2)), Err(3)
+        );
+        assert_eq!(count_elements(b"<deltas><delta/></deltas>", b"delta"), 1);
+    }
+
+    #[test]
+    fn limit_write() {
+        let mut data = LimitWrite::new(Some(4));
+        assert!(data.write_all(b"abc").is_ok());
+        assert!(data.write_all(b"de").is_err());
+        assert!(data.exceeded());
+        assert_eq!(data.into_inner(), b"abc");
+
+        let mut data = LimitWrite::new(None);
+        assert!(data.write_all(b"abcde").is_ok());
+        assert!(!data.exceeded());
+    }
+
+    #[test]
+    fn parse_notification_without_snapshot() {
+        match ReqwestClient::parse_notification(
+            &notification(&[NOTIFICATION_DELTA])
+        ) {
+            Err(NotificationError::MissingSnapshot) => { }
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("notification without snapshot accepted"),
+        }
+    }
+
+    #[test]
+    fn parse_notification_serial_overflow() {
+        // One more than u64::max_value().
+        let data = b"<notification xmlns=\"http://www.ripe.net/rpki/rrdp\" \
+          version=\"1\" \
+          session_id=\"9df4b597-af9e-4dca-bdda-719cce2c4e28\" \
+          serial=\"18446744073709551616\">\n";
+        let mut data = data.to_vec();
+        data.extend_from_slice(NOTIFICATION_SNAPSHOT);
+        data.extend_from_slice(NOTIFICATION_END);
+        match ReqwestClient::parse_notification(&data) {
+            Err(NotificationError::SerialOverflow) => { }
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("overflowing serial accepted"),
+        }
+
+        // The largest serial is fine.
+        let data = String::from_utf8(data).unwrap().replace(
+            "18446744073709551616", "18446744073709551615"
+        );
+        assert_eq!(
+            ReqwestClient::parse_notification(data.as_bytes()).unwrap().serial,
+            u64::max_value()
+        );
+        assert!(!has_serial_overflow(&notification(&[NOTIFICATION_DELTA])));
+    }
+
+    #[test]
+    fn memory_budget_serializes() {
+        let budget = MemoryBudget::new(Some(100));
+        let current = AtomicUsize::new(0);
+        let max = Mutex::new(0);
+        crossbeam_utils::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|_| {
+                    let _permit = budget.acquire(60);
+                    let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                    {
+                        let mut max = max.lock().unwrap();
+                        if now > *max {
+                            *max = now
+                        }
+                    }
+                    sleep(Duration::from_millis(20));
+                    current.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        }).unwrap();
+        assert_eq!(*max.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn memory_budget_unlimited() {
+        let budget = MemoryBudget::new(None);
+        let _first = budget.acquire(u64::max_value());
+        let _second = budget.acquire(u64::max_value());
+    }
+
+    #[test]
+    fn update_limit() {
+        let limit = UpdateLimit::new(Some(2));
+        let current = AtomicUsize::new(0);
+        let max = Mutex::new(0);
+        crossbeam_utils::thread::scope(|scope| {
+            for _ in 0..6 {
+                scope.spawn(|_| {
+                    let _permit = limit.acquire();
+                    let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                    {
+                        let mut max = max.lock().unwrap();
+                        if now > *max {
+                            *max = now
+                        }
+                    }
+                    sleep(Duration::from_millis(20));
+                    current.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        }).unwrap();
+        assert_eq!(*max.lock().unwrap(), 2);
+
+        let unlimited = UpdateLimit::new(None);
+        let _permits: Vec<_> = (0..10).map(|_| unlimited.acquire()).collect();
+    }
+
+    #[test]
+    fn delta_uris() {
+        let uri = uri::Https::from_str(
+            "https://rrdp.example.net/1/delta.xml"
+        ).unwrap();
+        let mirrors = vec![
+            (
+                "https://rrdp.example.net/".into(),
+                "https://mirror.example.org/rrdp/".into()
+            ),
+            (
+                "https://other.example.net/".into(),
+                "https://mirror.example.org/other/".into()
+            ),
+            (
+                "https://rrdp.example.net/1/".into(),
+                "https://backup.example.com/".into()
+            ),
+        ];
+        assert_eq!(
+            ReqwestClient::delta_uris(&mirrors, &uri).iter().map(|uri| {
+                uri.as_str()
+            }).collect::<Vec<_>>(),
+            vec![
+                "https://rrdp.example.net/1/delta.xml",
+                "https://mirror.example.org/rrdp/1/delta.xml",
+                "https://backup.example.com/delta.xml",
+            ]
+        );
+        assert_eq!(ReqwestClient::delta_uris(&[], &uri).len(), 1);
+    }
+
+    #[test]
+    fn delta_targets_rollback() {
+        let tmp = tempfile::tempdir().unwrap();
+        let rsync = |path: &str| {
+            uri::Rsync::from_str(
+                &format!("rsync://example.net/repo/{}", path)
+            ).unwrap()
+        };
+        let mut targets = DeltaTargets::new(
+            tmp.path(), false, Default::default()
+        ).unwrap();
+        targets.publish(
+            rsync("a.cer"), tmp.path().join("a.cer"), b"a".to_vec()
+        ).unwrap();
+        let mark = targets.mark();
+
+        // A failed attempt from the first URI.
+        targets.publish(
+            rsync("b.cer"), tmp.path().join("b.cer"), b"b".to_vec()
+        ).unwrap();
+        targets.withdraw(rsync("c.cer"), tmp.path().join("c.cer"));
+        targets.rollback(mark);
+
+        // The successful attempt from a mirror.
+        targets.publish(
+            rsync("b.cer"), tmp.path().join("b.cer"), b"mirror".to_vec()
+        ).unwrap();
+
+        let mut hashes = ObjectHashes::new();
+        targets.update_hashes(&mut hashes);
+        assert!(hashes.verify(&rsync("a.cer"), b"a"));
+        assert!(hashes.verify(&rsync("b.cer"), b"mirror"));
+        assert!(!hashes.verify(&rsync("b.cer"), b"b"));
+        let mut uris = Vec::new();
+        targets.apply(&mut 0, &mut uris).unwrap();
+        assert_eq!(fs::read(tmp.path().join("b.cer")).unwrap(), b"mirror");
+        assert!(!tmp.path().join("c.cer").exists());
+        assert_eq!(
+            uris,
+            vec![
+                (rsync("a.cer"), ChangeKind::Added),
+                (rsync("b.cer"), ChangeKind::Added),
+            ]
+        );
+    }
+
+    #[test]
+    fn cached_delta() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache_dir = tmp.path().join("deltas");
+        fs::create_dir(&cache_dir).unwrap();
+        let delta_data = b"<delta xmlns=\"http://www.ripe.net/rpki/rrdp\" \
+          version=\"1\" \
+          session_id=\"9df4b597-af9e-4dca-bdda-719cce2c4e28\" \
+          serial=\"2\">\n\
+          <publish uri=\"rsync://example.net/repo/a.cer\">\
+          Y2VydGlmaWNhdGU=</publish>\n\
+          </delta>\n";
+        let hash = DigestHex::from(
+            digest::digest(&digest::SHA256, delta_data)
+        );
+        let notify = ReqwestClient::parse_notification(&notification(&[
+            NOTIFICATION_SNAPSHOT,
+            format!(
+                "  <delta serial=\"2\" \
+                 uri=\"https://example.net/delta-2.xml\" hash=\"{}\"/>\n",
+                hash
+            ).as_bytes()
+        ])).unwrap();
+        let delta = notify.deltas[0].clone();
+
+        // Put the delta into the cache.
+        let cache = DeltaCache::new(cache_dir.clone(), 10);
+        let mut tee = Tee::new(delta_data.as_ref(), cache.create());
+        io::copy(&mut tee, &mut io::sink()).unwrap();
+        cache.store(tee.into_file().unwrap(), notify.session_id, &delta);
+
+        // The client can’t actually do requests, so processing the delta
+        // only succeeds if the cached copy is used.
+        let client = ReqwestClient {
+            client: Err(None),
+            tmp_dir: PathBuf::new(),
+            host_clients: Vec::new(),
+            memory: MemoryBudget::new(None),
+            updates: UpdateLimit::new(None),
+            mirrors: Vec::new(),
+            timeouts: HashMap::new(),
+            kind_timeouts: Default::default(),
+            redirect: None,
+            host_map: HashMap::new(),
+            delta_cache: Some(cache),
+            delta_workers: 1,
+            downloaded: Default::default(),
+            bandwidth: Default::default(),
+            max_file_size: None,
+            max_download_size: None,
+            max_notification_size: None,
+            max_notification_deltas: None,
+            allowed_hosts: Vec::new(),
+            retry: Default::default(),
+            deadlines: Default::default(),
+            clock: Default::default(),
+            resume_snapshots: false,
+            address_family: AddressFamily::Any,
+        };
+        let server_uri = uri::Https::from_str(
+            "https://example.net/notification.xml"
+        ).unwrap();
+        let mut targets = DeltaTargets::new(
+            tmp.path(), false, Default::default()
+        ).unwrap();
+        client.delta(
+            &server_uri, &notify, &delta, None, &mut targets,
+            &|_| Ok(tmp.path().join("a.cer"))
+        ).unwrap();
+        let mut object_count = Some(0);
+        targets.apply(&mut object_count, &mut Vec::new()).unwrap();
+        assert_eq!(object_count, Some(1));
+        assert_eq!(
+            fs::read(tmp.path().join("a.cer")).unwrap(), b"certificate"
+        );
+
+        // Without a cache, a prefetched copy is used just the same.
+        let client = ReqwestClient { delta_cache: None, .. client };
+        let mut file = NamedTempFile::new_in(tmp.path()).unwrap();
+        file.write_all(delta_data).unwrap();
+        let mut targets = DeltaTargets::new(
+            tmp.path(), false, Default::default()
+        ).unwrap();
+        client.delta(
+            &server_uri, &notify, &delta, Some(file), &mut targets,
+            &|_| Ok(tmp.path().join("b.cer"))
+        ).unwrap();
+        targets.apply(&mut 0, &mut Vec::new()).unwrap();
+        assert_eq!(
+            fs::read(tmp.path().join("b.cer")).unwrap(), b"certificate"
+        );
+
+        // A single delta is never prefetched.
+        assert!(
+            client.prefetch_deltas(
+                &server_uri, &notify, &notify.deltas
+            )[0].is_none()
+        );
+    }
+
+    #[test]
+    fn decoded_body() {
+        use std::io::Read;
+        use flate2::Compression;
+        use flate2::write::{GzEncoder, ZlibEncoder};
+
+        fn decode(
+            data: &[u8], encoding: Option<&str>
+        ) -> Result<Vec<u8>, io::Error> {
+            let mut res = Vec::new();
+            DecodedBody::new(data, encoding).unwrap().read_to_end(&mut res)?;
+            Ok(res)
+        }
+
+        let data = b"<snapshot>some objects</snapshot>".repeat(100);
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&data).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let mut deflate = ZlibEncoder::new(
+            Vec::new(), Compression::default()
+        );
+        deflate.write_all(&data).unwrap();
+        let deflate = deflate.finish().unwrap();
+
+        assert_eq!(decode(&data, None).unwrap(), data);
+        assert_eq!(decode(&data, Some("identity")).unwrap(), data);
+        assert_eq!(decode(&gzip, Some("gzip")).unwrap(), data);
+        assert_eq!(decode(&deflate, Some("deflate")).unwrap(), data);
+        assert!(DecodedBody::new(data.as_slice(), Some("br")).is_err());
+
+        // All members of a multi-member gzip body are decoded.
+        let mut multi = gzip.clone();
+        multi.extend_from_slice(&gzip);
+        assert_eq!(decode(&multi, Some("gzip")).unwrap(), data.repeat(2));
+
+        // Truncated or garbled gzip is an error.
+        assert!(decode(&gzip[..gzip.len() - 4], Some("gzip")).is_err());
+        assert!(decode(&data, Some("gzip")).is_err());
+    }
+
+    #[test]
+    fn host_clients() {
+        assert_eq!(authority_host("example.net"), "example.net");
+        assert_eq!(authority_host("example.net:8443"), "example.net");
+        assert_eq!(authority_host("[2001:db8::1]:443"), "[2001:db8::1]");
+        assert_eq!(authority_host("[2001:db8::1]"), "[2001:db8::1]");
+
+        let mut client = ReqwestClient::with_redirect(
+            PathBuf::new(), String::new(), String::new()
+        ).unwrap();
+        client.host_clients.push(HostClient {
+            host: "rrdp.example.net".into(),
+            identity: "client.pem".into(),
+            client: Err(None),
+        });
+        let host = |uri: &str| {
+            client.host_client(
+                &uri::Https::from_str(uri).unwrap()
+            ).map(|host| host.identity.clone())
+        };
+        assert_eq!(
+            host("https://RRDP.example.net:8443/notification.xml"),
+            Some("client.pem".into())
+        );
+        assert_eq!(host("https://example.net/notification.xml"), None);
+        assert_eq!(
+            host("https://rrdp.example.net.example.org/notification.xml"),
+            None
+        );
+    }
+
+    #[test]
+    fn host_map() {
+        let mut client = ReqwestClient::with_redirect(
+            PathBuf::new(), "https://local.example.net/".into(),
+            "http://127.0.0.1:8080/".into()
+        ).unwrap();
+        client.host_map.insert(
+            "rrdp.example.net".into(), "mirror.internal".into()
+        );
+        let request = |uri: &str| {
+            client.request_uri(
+                &uri::Https::from_str(uri).unwrap()
+            ).into_owned()
+        };
+        assert_eq!(
+            request("https://RRDP.example.net/rrdp/notification.xml"),
+            "https://mirror.internal/rrdp/notification.xml"
+        );
+        assert_eq!(
+            request("https://rrdp.example.net:8443/rrdp/snapshot.xml"),
+            "https://mirror.internal:8443/rrdp/snapshot.xml"
+        );
+        assert_eq!(
+            request("https://rrdp.example.net.example.org/notification.xml"),
+            "https://rrdp.example.net.example.org/notification.xml"
+        );
+        assert_eq!(
+            request("https://local.example.net/notification.xml"),
+            "http://127.0.0.1:8080/notification.xml"
+        );
+    }
+
+    #[test]
+    fn parse_header() {
+        let (name, value) = ReqwestClient::parse_header(
+            "X-Relying-Party: rp.example.net"
+        ).unwrap();
+        assert_eq!(name, "x-relying-party");
+        assert_eq!(value, "rp.example.net");
+        let (name, value) = ReqwestClient::parse_header("X-Empty:").unwrap();
+        assert_eq!(name, "x-empty");
+        assert_eq!(value, "");
+        assert!(ReqwestClient::parse_header("X-Relying-Party").is_err());
+        assert!(ReqwestClient::parse_header(": value").is_err());
+        assert!(ReqwestClient::parse_header("X Space: value").is_err());
+        assert!(ReqwestClient::parse_header("X-Line: a\nb").is_err());
+    }
+
+    #[test]
+    fn retry_policy() {
+        let policy = RetryPolicy {
+            retries: 3, delay: Duration::from_secs(1)
+        };
+        assert_eq!(policy.delay(0), Duration::from_secs(1));
+        assert_eq!(policy.delay(1), Duration::from_secs(2));
+        assert_eq!(policy.delay(2), Duration::from_secs(4));
+        assert_eq!(policy.delay(100), Duration::from_secs(1024));
+
+        assert!(is_transient_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_transient_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_transient_status(StatusCode::BAD_GATEWAY));
+        assert!(!is_transient_status(StatusCode::NOT_FOUND));
+        assert!(!is_transient_status(StatusCode::FORBIDDEN));
+        assert!(!is_transient_status(StatusCode::OK));
+
+        assert!(is_transient_io_error(io::ErrorKind::ConnectionRefused));
+        assert!(is_transient_io_error(io::ErrorKind::TimedOut));
+        assert!(!is_transient_io_error(io::ErrorKind::InvalidData));
+        assert!(!is_transient_io_error(io::ErrorKind::Other));
+    }
+
+    #[test]
+    fn request_timeout() {
+        let slow = uri::Https::from_str(
+            "https://slow.example.net/notification.xml"
+        ).unwrap();
+        let other = uri::Https::from_str(
+            "https://example.net/notification.xml"
+        ).unwrap();
+        let mut timeouts = HashMap::new();
+        timeouts.insert(slow.clone(), Duration::from_secs(300));
+        let client = ReqwestClient {
+            client: Err(None),
+            tmp_dir: PathBuf::new(),
+            host_clients: Vec::new(),
+            memory: MemoryBudget::new(None),
+            updates: UpdateLimit::new(None),
+            mirrors: Vec::new(),
+            timeouts,
+            kind_timeouts: RequestTimeouts {
+                notification: Some(Duration::from_secs(10)),
+                snapshot: None,
+                delta: Some(Duration::from_secs(60)),
+            },
+            redirect: None,
+            host_map: HashMap::new(),
+            delta_cache: None,
+            delta_workers: 1,
+            downloaded: Default::default(),
+            bandwidth: Default::default(),
+            max_file_size: None,
+            max_download_size: None,
+            max_notification_size: None,
+            max_notification_deltas: None,
+            allowed_hosts: Vec::new(),
+            retry: Default::default(),
+            deadlines: Default::default(),
+            clock: Default::default(),
+            resume_snapshots: false,
+            address_family: AddressFamily::Any,
+        };
+
+        // The server’s own timeout applies to all kinds of requests.
+        for &kind in &[
+            RequestKind::Notification, RequestKind::Snapshot,
+            RequestKind::Delta
+        ] {
+            assert_eq!(
+                client.request_timeout(&slow, kind),
+                Some(Duration::from_secs(300))
+            );
+        }
+
+        // Other servers get the timeout for the kind if there is one.
+        assert_eq!(
+            client.request_timeout(&other, RequestKind::Notification),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            client.request_timeout(&other, RequestKind::Snapshot), None
+        );
+        assert_eq!(
+            client.request_timeout(&other, RequestKind::Delta),
+            Some(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn snapshot_progress() {
+        let (tmp, _, mut repo, http) = setup();
+        let notify_uri = notify_uri();
+        repo.publish(&[
+            ("a.cer", Some(b"first certificate".as_ref())),
+            ("b.roa", Some(b"some roa".as_ref())),
+        ], false);
+        let (notify, _) = http.notification_file(
+            &notify_uri, &mut None
+        ).unwrap();
+
+        let mut reports = Vec::new();
+        let hashes = http.snapshot(
+            &notify_uri, &notify, &|uri| Ok(tmp.path().join(uri.path())),
+            false, &Default::default(),
+            Some(&mut |progress| reports.push(progress))
+        ).unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].objects, 2);
+        assert!(reports[0].bytes > 0);
+    }
+
+    #[test]
+    fn size_limits() {
+        static LARGE: [u8; 4096] = [0x30; 4096];
+
+        let (tmp, cache_dir, mut repo, mut http) = setup();
+        let tmp_dir = tmp.path().join("tmp");
+        let notify_uri = notify_uri();
+        repo.publish(&[("a.cer", Some(b"first certificate".as_ref()))], false);
+        let server = Server::create(
+            notify_uri.clone(), &cache_dir, Default::default()
+        );
+        server.update(&http);
+        repo.check("snapshot", &server).unwrap();
+
+        // Failed updates keep the old content usable.
+        let check_failed = |server: &Server| {
+            assert_eq!(
+                server.metrics().unwrap().update_kind,
+                Some(RrdpUpdateKind::Failed)
+            );
+            assert!(!server.is_broken());
+            assert!(server.verify().is_ok());
+            assert_eq!(
+                server.load_file(
+                    &rsync_uri("a.cer").unwrap()
+                ).found().unwrap().as_ref(),
+                b"first certificate"
+            );
+            assert_eq!(fs::read_dir(&tmp_dir).unwrap().count(), 0);
+        };
+
+        // A delta too large.
+        repo.publish(&[("b.roa", Some(&LARGE[..]))], true);
+        http.set_size_limits(Some(1024), None);
+        let server = Server::existing(
+            notify_uri.clone(), server.server_dir().into(), Default::default()
+        );
+        server.update(&http);
+        check_failed(&server);
+        assert!(
+            server.load_file(&rsync_uri("b.roa").unwrap()).is_not_found()
+        );
+
+        // Too much downloaded for the repository.
+        http.set_size_limits(None, Some(100));
+        let server = Server::existing(
+            notify_uri.clone(), server.server_dir().into(), Default::default()
+        );
+        server.update(&http);
+        check_failed(&server);
+
+        // Without limits, the delta is applied.
+        http.set_size_limits(None, None);
+        let server = Server::existing(
+            notify_uri.clone(), server.server_dir().into(), Default::default()
+        );
+        server.update(&http);
+        repo.check("delta", &server).unwrap();
+
+        // A snapshot too large.
+        repo.publish(&[("c.roa", Some(&LARGE[..]))], false);
+        http.set_size_limits(Some(1024), None);
+        let server = Server::existing(
+            notify_uri, server.server_dir().into(), Default::default()
+        );
+        server.update(&http);
+        check_failed(&server);
+        assert!(
+            server.load_file(&rsync_uri("c.roa").unwrap()).is_not_found()
+        );
+    }
+
+    #[test]
+    fn notification_limits() {
+        let (_tmp, cache_dir, mut repo, mut http) = setup();
+        let notify_uri = notify_uri();
+        repo.publish(&[("a.cer", Some(b"first certificate".as_ref()))], false);
+        let server = Server::create(
+            notify_uri.clone(), &cache_dir, Default::default()
+        );
+        server.update(&http);
+        repo.check("snapshot", &server).unwrap();
+
+        // Failed updates keep the old content usable.
+        let check_failed = |server: &Server| {
+            assert_eq!(
+                server.metrics().unwrap().update_kind,
+                Some(RrdpUpdateKind::Failed)
+            );
+            assert!(!server.is_broken());
+            assert!(server.verify().is_ok());
+            assert_eq!(
+                server.load_file(
+                    &rsync_uri("a.cer").unwrap()
+                ).found().unwrap().as_ref(),
+                b"first certificate"
+            );
+        };
+
+        // A notification file listing too many deltas.
+        repo.publish(&[("b.roa", Some(b"first roa".as_ref()))], true);
+        repo.publish(&[("c.roa", Some(b"second roa".as_ref()))], true);
+        repo.publish(&[("d.roa", Some(b"third roa".as_ref()))], true);
+        http.set_notification_limits(None, Some(2));
+        let server = Server::existing(
+            notify_uri.clone(), server.server_dir().into(), Default::default()
+        );
+        server.update(&http);
+        check_failed(&server);
+
+        // A notification file too large.
+        http.set_notification_limits(Some(100), None);
+        let server = Server::existing(
+            notify_uri.clone(), server.server_dir().into(), Default::default()
+        );
+        server.update(&http);
+        check_failed(&server);
+
+        // Within the limits, the deltas are applied.
+        http.set_notification_limits(Some(64 * 1024), Some(3));
+        let server = Server::existing(
+            notify_uri, server.server_dir().into(), Default::default()
+        );
+        server.update(&http);
+        repo.check("delta", &server).unwrap();
+    }
+
+    #[test]
+    fn retry_transient_errors() {
+        let (_tmp, cache_dir, mut repo, mut http) = setup();
+        http.set_retry_policy(RetryPolicy {
+            retries: 2, delay: Duration::from_millis(1)
+        });
+        let notify_uri = notify_uri();
+        repo.publish(&[("a.cer", Some(b"first certificate".as_ref()))], false);
+        repo.server.fail("notification.xml", 2);
+        let server = Server::create(
+            notify_uri.clone(), &cache_dir, Default::default()
+        );
+        server.update(&http);
+        repo.check("snapshot", &server).unwrap();
+
+        // Two failures are retried, so the delta is used.
+        repo.publish(&[("a.cer", Some(b"second certificate".as_ref()))], true);
+        repo.server.fail(&format!("delta-{}.xml", repo.serial), 2);
+        let server = Server::existing(
+            notify_uri.clone(), server.server_dir().into(), Default::default()
+        );
+        server.update(&http);
+        repo.check("delta", &server).unwrap();
+        repo.check_metrics("delta", &server, RrdpUpdateKind::Delta).unwrap();
+
+        // Three failures are too many and there is no snapshot to fall
+        // back to.
+        repo.publish(&[("a.cer", Some(b"third certificate".as_ref()))], true);
+        repo.server.fail(&format!("delta-{}.xml", repo.serial), 3);
+        let server = Server::existing(
+            notify_uri, server.server_dir().into(), Default::default()
+        );
+        server.update(&http);
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Failed)
+        );
+    }
+
+    #[test]
+    fn mirror_fallback() {
+        let (_tmp, cache_dir, mut repo, mut http) = setup();
+        http.mirrors = vec![
+            (HTTPS_BASE.into(), format!("{}mirror/", HTTPS_BASE))
+        ];
+        let notify_uri = notify_uri();
+        repo.publish(&[("a.cer", Some(b"first certificate".as_ref()))], false);
+        let server = Server::create(
+            notify_uri.clone(), &cache_dir, Default::default()
+        );
+        server.update(&http);
+        repo.check("snapshot", &server).unwrap();
+
+        // The delta is gone from the primary but the mirror has it.
+        repo.publish(&[("a.cer", Some(b"second certificate".as_ref()))], true);
+        let delta = format!("delta-{}.xml", repo.serial);
+        let data = repo.server.withdraw(&delta).unwrap();
+        repo.server.serve(&format!("mirror/{}", delta), data);
+        let server = Server::existing(
+            notify_uri.clone(), server.server_dir().into(), Default::default()
+        );
+        server.update(&http);
+        repo.check("mirror", &server).unwrap();
+        repo.check_metrics("mirror", &server, RrdpUpdateKind::Delta).unwrap();
+
+        // A mirror serving content that doesn’t match the hash from the
+        // notification file is rejected.
+        repo.publish(&[("a.cer", Some(b"third certificate".as_ref()))], true);
+        let delta = format!("delta-{}.xml", repo.serial);
+        let data = String::from_utf8(
+            repo.server.withdraw(&delta).unwrap()
+        ).unwrap();
+        let forged = data.replace(
+            &base64::encode(b"third certificate"),
+            &base64::encode(b"forged certificate")
+        );
+        assert_ne!(data, forged);
+        repo.server.serve(&format!("mirror/{}", delta), forged.into_bytes());
+        let server = Server::existing(
+            notify_uri, server.server_dir().into(), Default::default()
+        );
+        server.update(&http);
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Failed)
+        );
+        assert_eq!(
+            server.load_file(
+                &rsync_uri("a.cer").unwrap()
+            ).found().unwrap().as_ref(),
+            b"second certificate"
+        );
+    }
+
+    #[test]
+    fn retry_within_deadline() {
+        let (_tmp, cache_dir, mut repo, mut http) = setup();
+        let clock = MockClock::new();
+        http.set_retry_policy(RetryPolicy {
+            retries: 2, delay: Duration::from_secs(3600)
+        });
+        http.set_clock(clock.shared());
+        let notify_uri = notify_uri();
+        repo.publish(&[("a.cer", Some(b"first certificate".as_ref()))], false);
+
+        // Waiting an hour for the retry would exceed the deadline, so the
+        // update fails without waiting.
+        let mut options = ServerOptions::default();
+        options.clock = clock.shared();
+        options.deadlines.insert(
+            notify_uri.clone(), Duration::from_secs(600)
+        );
+        repo.server.fail("notification.xml", 1);
+        let start = clock.shared().now();
+        let server = Server::create(
+            notify_uri.clone(), &cache_dir, Arc::new(options)
+        );
+        server.update(&http);
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Failed)
+        );
+        assert!(clock.shared().now() - start < Duration::from_secs(600));
+
+        // Without a deadline, the client waits via the clock and retries.
+        let mut options = ServerOptions::default();
+        options.clock = clock.shared();
+        repo.server.fail("notification.xml", 1);
+        let server = Server::create(
+            notify_uri, &cache_dir, Arc::new(options)
+        );
+        server.update(&http);
+        repo.check("snapshot", &server).unwrap();
+        assert!(clock.shared().now() - start >= Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn resume_snapshot() {
+        static LARGE: [u8; 4096] = [0x30; 4096];
+
+        let (tmp, cache_dir, mut repo, mut http) = setup();
+        let tmp_dir = tmp.path().join("tmp");
+        http.set_resume_snapshots(true);
+        let notify_uri = notify_uri();
+        repo.publish(&[("a.cer", Some(&LARGE[..]))], false);
+        let snapshot = format!("snapshot-{}.xml", repo.serial);
+
+        // An interrupted download is kept.
+        repo.server.truncate(&snapshot, 1000);
+        let server = Server::create(
+            notify_uri.clone(), &cache_dir, Default::default()
+        );
+        server.update(&http);
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Failed)
+        );
+        let sizes: Vec<_> = fs::read_dir(&tmp_dir).unwrap().map(|entry| {
+            entry.unwrap().metadata().unwrap().len()
+        }).collect();
+        assert_eq!(sizes.len(), 2);
+        assert!(sizes.contains(&1000));
+
+        // The next update only fetches the rest.
+        let server = Server::create(
+            notify_uri.clone(), &cache_dir, Default::default()
+        );
+        server.update(&http);
+        repo.check("resumed", &server).unwrap();
+        assert_eq!(
+            *repo.server.ranges.lock().unwrap(), vec![(snapshot, 1000)]
+        );
+        assert_eq!(fs::read_dir(&tmp_dir).unwrap().count(), 0);
+
+        // A download for an older serial starts over.
+        repo.publish(&[("b.roa", Some(b"roa".as_ref()))], false);
+        let snapshot = format!("snapshot-{}.xml", repo.serial);
+        repo.server.truncate(&snapshot, 1000);
+        let server = Server::existing(
+            notify_uri.clone(), server.server_dir().into(), Default::default()
+        );
+        server.update(&http);
+        repo.publish(&[("c.roa", Some(b"roa".as_ref()))], false);
+        let server = Server::existing(
+            notify_uri, server.server_dir().into(), Default::default()
+        );
+        server.update(&http);
+        repo.check("restarted", &server).unwrap();
+        assert_eq!(repo.server.ranges.lock().unwrap().len(), 1);
+        assert_eq!(fs::read_dir(&tmp_dir).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,172 @@
+//! Digests for the local copy of RRDP servers.
+//!
+//! This is a private module for organizational purposes.
+//!
+//! The digest of a server’s data directory only serves to detect changes
+//! made behind our back, so next to SHA-256 it can be calculated with the
+//! non-cryptographic 64 bit FNV-1a hash which is a lot cheaper for large
+//! repositories. The types here hide which of the two is used.
+
+use std::fmt;
+use std::fmt::Write;
+use std::str::FromStr;
+use ring::digest;
+use rpki::rrdp::DigestHex;
+use crate::config::DigestAlgorithm;
+
+
+//------------ Context -------------------------------------------------------
+
+/// A context for calculating a digest step by step.
+#[derive(Clone)]
+pub enum Context {
+    /// A SHA-256 context.
+    Sha256(digest::Context),
+
+    /// The FNV-1a hash calculated so far.
+    Fnv1a(u64),
+}
+
+impl Context {
+    /// The offset basis of 64 bit FNV-1a.
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+    /// The prime of 64 bit FNV-1a.
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    /// Creates a new context for the given algorithm.
+    pub fn new(algorithm: DigestAlgorithm) -> Self {
+        match algorithm {
+            DigestAlgorithm::Sha256 => {
+                Context::Sha256(digest::Context::new(&digest::SHA256))
+            }
+            DigestAlgorithm::Fnv1a => Context::Fnv1a(Self::FNV_OFFSET),
+        }
+    }
+
+    /// Adds data to the digest.
+    pub fn update(&mut self, data: &[u8]) {
+        match *self {
+            Context::Sha256(ref mut context) => context.update(data),
+            Context::Fnv1a(ref mut hash) => {
+                for &ch in data {
+                    *hash ^= u64::from(ch);
+                    *hash = hash.wrapping_mul(Self::FNV_PRIME);
+                }
+            }
+        }
+    }
+
+    /// Finishes the digest.
+    pub fn finish(self) -> Digest {
+        match self {
+            Context::Sha256(context) => Digest::Sha256(context.finish()),
+            Context::Fnv1a(hash) => Digest::Fnv1a(hash.to_be_bytes()),
+        }
+    }
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Context::Sha256(_) => f.write_str("Context::Sha256"),
+            Context::Fnv1a(hash) => {
+                f.debug_tuple("Context::Fnv1a").field(&hash).finish()
+            }
+        }
+    }
+}
+
+
+//------------ Digest --------------------------------------------------------
+
+/// The final value of a digest.
+#[derive(Clone, Copy, Debug)]
+pub enum Digest {
+    /// A SHA-256 digest.
+    Sha256(digest::Digest),
+
+    /// A FNV-1a hash in network byte order.
+    Fnv1a([u8; 8]),
+}
+
+impl Digest {
+    /// Calculates the digest of `data` using the given algorithm.
+    pub fn of(algorithm: DigestAlgorithm, data: &[u8]) -> Self {
+        let mut context = Context::new(algorithm);
+        context.update(data);
+        context.finish()
+    }
+}
+
+impl AsRef<[u8]> for Digest {
+    fn as_ref(&self) -> &[u8] {
+        match *self {
+            Digest::Sha256(ref digest) => digest.as_ref(),
+            Digest::Fnv1a(ref hash) => hash.as_ref(),
+        }
+    }
+}
+
+impl From<Digest> for DigestHex {
+    fn from(digest: Digest) -> Self {
+        match digest {
+            Digest::Sha256(digest) => DigestHex::from(digest),
+            Digest::Fnv1a(hash) => {
+                let mut hex = String::with_capacity(16);
+                for ch in hash.iter() {
+                    write!(hex, "{:02x}", ch).expect("writing to string");
+                }
+                DigestHex::from_str(&hex).expect("invalid hex digest")
+            }
+        }
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fnv1a() {
+        // Test vectors from the FNV reference.
+        assert_eq!(
+            Digest::of(DigestAlgorithm::Fnv1a, b"").as_ref(),
+            &0xcbf2_9ce4_8422_2325u64.to_be_bytes()
+        );
+        assert_eq!(
+            Digest::of(DigestAlgorithm::Fnv1a, b"a").as_ref(),
+            &0xaf63_dc4c_8601_ec8cu64.to_be_bytes()
+        );
+        assert_eq!(
+            Digest::of(DigestAlgorithm::Fnv1a, b"foobar").as_ref(),
+            &0x8594_4171_f739_67e8u64.to_be_bytes()
+        );
+
+        // Adding in steps gives the same hash.
+        let mut context = Context::new(DigestAlgorithm::Fnv1a);
+        context.update(b"foo");
+        context.update(b"bar");
+        assert_eq!(
+            context.finish().as_ref(),
+            Digest::of(DigestAlgorithm::Fnv1a, b"foobar").as_ref()
+        );
+        assert_eq!(
+            DigestHex::from(
+                Digest::of(DigestAlgorithm::Fnv1a, b"foobar")
+            ).to_string(),
+            "85944171f73967e8"
+        );
+    }
+
+    #[test]
+    fn sha256() {
+        assert_eq!(
+            Digest::of(DigestAlgorithm::Sha256, b"foobar").as_ref(),
+            digest::digest(&digest::SHA256, b"foobar").as_ref()
+        );
+    }
+}
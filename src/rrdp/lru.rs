@@ -0,0 +1,204 @@
+//! Keeping recently loaded objects in memory.
+//!
+//! This is a private module for organizational purposes.
+//!
+//! A server can keep the objects loaded most recently in a cache bounded by
+//! the total size of the objects. If adding an object exceeds the limit,
+//! the objects used least recently are dropped until it fits. Objects
+//! larger than the limit are never kept. Since the cache doesn’t know when
+//! the local copy changes, it needs to be cleared whenever that happens.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use bytes::Bytes;
+use rpki::uri;
+
+
+//------------ LoadCache -----------------------------------------------------
+
+/// A bounded cache of recently loaded objects.
+///
+/// By default, nothing is cached.
+#[derive(Debug, Default)]
+pub struct LoadCache {
+    /// The cached objects or `None` if disabled.
+    objects: Option<Mutex<LruObjects>>,
+}
+
+impl LoadCache {
+    /// Creates a cache for objects of at most `limit` bytes in total.
+    ///
+    /// If `limit` is `None` or zero, nothing is cached.
+    pub fn new(limit: Option<u64>) -> Self {
+        LoadCache {
+            objects: limit.and_then(|limit| {
+                if limit == 0 {
+                    None
+                }
+                else {
+                    Some(Mutex::new(LruObjects::new(limit)))
+                }
+            }),
+        }
+    }
+
+    /// Returns the cached object for the given URI.
+    pub fn get(&self, uri: &uri::Rsync) -> Option<Bytes> {
+        self.objects.as_ref()?.lock().unwrap().get(uri)
+    }
+
+    /// Adds an object to the cache.
+    pub fn insert(&self, uri: &uri::Rsync, data: &Bytes) {
+        if let Some(ref objects) = self.objects {
+            objects.lock().unwrap().insert(uri, data)
+        }
+    }
+
+    /// Drops all cached objects.
+    pub fn clear(&self) {
+        if let Some(ref objects) = self.objects {
+            objects.lock().unwrap().clear()
+        }
+    }
+}
+
+
+//------------ LruObjects ----------------------------------------------------
+
+/// The objects of a cache in the order of their last use.
+#[derive(Debug)]
+struct LruObjects {
+    /// The maximum total size of the objects.
+    limit: u64,
+
+    /// The current total size of the objects.
+    size: u64,
+
+    /// The tick to assign to the next use of an object.
+    next_tick: u64,
+
+    /// The objects and the tick of their last use.
+    objects: HashMap<uri::Rsync, (Bytes, u64)>,
+
+    /// The URIs of the objects by the tick of their last use.
+    order: BTreeMap<u64, uri::Rsync>,
+}
+
+impl LruObjects {
+    /// Creates a new, empty value.
+    fn new(limit: u64) -> Self {
+        LruObjects {
+            limit,
+            size: 0,
+            next_tick: 0,
+            objects: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Returns an object and marks it as used.
+    fn get(&mut self, uri: &uri::Rsync) -> Option<Bytes> {
+        let tick = self.next_tick;
+        let (data, last) = self.objects.get_mut(uri)?;
+        let data = data.clone();
+        self.order.remove(&*last);
+        *last = tick;
+        self.order.insert(tick, uri.clone());
+        self.next_tick += 1;
+        Some(data)
+    }
+
+    /// Adds an object, dropping the least recently used ones if necessary.
+    fn insert(&mut self, uri: &uri::Rsync, data: &Bytes) {
+        let len = data.len() as u64;
+        self.remove(uri);
+        if len > self.limit {
+            return
+        }
+        while self.size + len > self.limit {
+            let oldest = match self.order.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break
+            };
+            self.remove(&oldest);
+        }
+        self.objects.insert(uri.clone(), (data.clone(), self.next_tick));
+        self.order.insert(self.next_tick, uri.clone());
+        self.next_tick += 1;
+        self.size += len;
+    }
+
+    /// Removes an object if present.
+    fn remove(&mut self, uri: &uri::Rsync) {
+        if let Some((data, tick)) = self.objects.remove(uri) {
+            self.order.remove(&tick);
+            self.size -= data.len() as u64;
+        }
+    }
+
+    /// Removes all objects.
+    fn clear(&mut self) {
+        self.objects.clear();
+        self.order.clear();
+        self.size = 0;
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    fn rsync_uri(path: &str) -> uri::Rsync {
+        uri::Rsync::from_str(
+            &format!("rsync://example.com/repo/{}", path)
+        ).unwrap()
+    }
+
+    fn insert(cache: &LoadCache, path: &str, data: &'static [u8]) {
+        cache.insert(&rsync_uri(path), &Bytes::from_static(data))
+    }
+
+    fn get(cache: &LoadCache, path: &str) -> Option<Bytes> {
+        cache.get(&rsync_uri(path))
+    }
+
+    #[test]
+    fn least_recently_used() {
+        let cache = LoadCache::new(Some(10));
+        insert(&cache, "a.cer", b"aaaa");
+        insert(&cache, "b.crl", b"bbbb");
+        assert_eq!(get(&cache, "a.cer").unwrap().as_ref(), b"aaaa");
+
+        // Adding c.mft drops b.crl which was used least recently.
+        insert(&cache, "c.mft", b"cccc");
+        assert_eq!(get(&cache, "b.crl"), None);
+        assert_eq!(get(&cache, "a.cer").unwrap().as_ref(), b"aaaa");
+        assert_eq!(get(&cache, "c.mft").unwrap().as_ref(), b"cccc");
+
+        // Replacing an object accounts for the new size.
+        insert(&cache, "a.cer", b"aaaaaa");
+        assert_eq!(get(&cache, "a.cer").unwrap().as_ref(), b"aaaaaa");
+        assert_eq!(get(&cache, "c.mft").unwrap().as_ref(), b"cccc");
+
+        // Objects larger than the limit are never kept.
+        insert(&cache, "d.roa", b"ddddddddddd");
+        assert_eq!(get(&cache, "d.roa"), None);
+        assert_eq!(get(&cache, "a.cer").unwrap().as_ref(), b"aaaaaa");
+
+        cache.clear();
+        assert_eq!(get(&cache, "a.cer"), None);
+        assert_eq!(get(&cache, "c.mft"), None);
+    }
+
+    #[test]
+    fn disabled() {
+        for cache in &[LoadCache::default(), LoadCache::new(Some(0))] {
+            insert(cache, "a.cer", b"aaaa");
+            assert_eq!(get(cache, "a.cer"), None);
+        }
+    }
+}
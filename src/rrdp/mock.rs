@@ -0,0 +1,463 @@
+//! An in-memory HTTP client and fixtures for testing.
+//!
+//! This is a private module only available in tests.
+
+use std::{fs, io, mem};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use reqwest::StatusCode;
+use ring::digest;
+use rpki::uri;
+use rpki::rrdp::{DigestHex, NotificationFile, UriAndHash};
+use tempfile::{NamedTempFile, TempDir};
+use uuid::Uuid;
+use crate::operation::Error;
+use super::hashes::ObjectHashes;
+use super::http::{
+    CacheValidators, DeltaProcessor, DeltaTargets, HttpClient,
+    NotificationResponse, ObjectCheck, ReqwestClient, RequestError,
+    SnapshotProcessor, SnapshotProgress, UpdateLimit, UpdatePermit,
+    process_delta, process_snapshot,
+};
+use super::server::{ServerDirNames, ServerState, data_digest};
+use super::store::{CacheStore, FsStore, StoreEntry};
+
+
+//------------ MockClient ----------------------------------------------------
+
+/// An HTTP client serving files from memory.
+///
+/// Files are published under their URI via `serve`. Requests for any other
+/// URI fail as if the server had answered with a 404. Hashes are checked
+/// just like the real client does, so forged content can be served to test
+/// how servers deal with it. If enabled via `enable_etags`, notification
+/// files come with an ETag and conditional requests with a matching one
+/// are answered with a 304.
+#[derive(Debug)]
+pub struct MockClient {
+    /// The directory for temporary files.
+    tmp_dir: PathBuf,
+
+    /// The published files.
+    files: Mutex<HashMap<uri::Https, Vec<u8>>>,
+
+    /// The URIs of all requests in order.
+    requests: Mutex<Vec<uri::Https>>,
+
+    /// The limit of concurrent updates which there is none of.
+    updates: UpdateLimit,
+
+    /// Whether notification files are served with an ETag.
+    etags: bool,
+}
+
+impl MockClient {
+    /// Creates a client without any files.
+    pub fn new(tmp_dir: PathBuf) -> Self {
+        MockClient {
+            tmp_dir,
+            files: Default::default(),
+            requests: Default::default(),
+            updates: UpdateLimit::new(None),
+            etags: false,
+        }
+    }
+
+    /// Creates a client with a new temporary directory.
+    ///
+    /// Returns the directory, which is removed when dropped, the RRDP
+    /// cache directory within it, and the client.
+    pub fn setup() -> (TempDir, PathBuf, Self) {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache_dir = tmp.path().join("rrdp");
+        let tmp_dir = tmp.path().join("tmp");
+        fs::create_dir_all(&cache_dir).unwrap();
+        fs::create_dir_all(&tmp_dir).unwrap();
+        (tmp, cache_dir, MockClient::new(tmp_dir))
+    }
+
+    /// Serves notification files with an ETag from now on.
+    pub fn enable_etags(&mut self) {
+        self.etags = true
+    }
+
+    /// Publishes a file, replacing the one currently published.
+    pub fn serve(&self, uri: &uri::Https, data: Vec<u8>) {
+        self.files.lock().unwrap().insert(uri.clone(), data);
+    }
+
+    /// Publishes a snapshot with the given objects and a notification.
+    ///
+    /// The notification also lists the given deltas which have to be
+    /// published separately.
+    pub fn publish(
+        &self,
+        session: Uuid,
+        serial: u64,
+        objects: &[(&str, &[u8])],
+        deltas: &[(u64, String)],
+    ) {
+        let mut xml = header("snapshot", session, serial);
+        for (path, data) in objects {
+            xml.push_str(&format!(
+                "  <publish uri=\"{}\">{}</publish>\n",
+                rsync_uri(path), base64::encode(data)
+            ));
+        }
+        xml.push_str("</snapshot>\n");
+        let snapshot_uri = https_uri(&format!("snapshot-{}.xml", serial));
+        let snapshot_hash = sha256(xml.as_bytes());
+        self.serve(&snapshot_uri, xml.into_bytes());
+
+        let mut xml = header("notification", session, serial);
+        xml.push_str(&format!(
+            "  <snapshot uri=\"{}\" hash=\"{}\"/>\n",
+            snapshot_uri, snapshot_hash
+        ));
+        for (serial, hash) in deltas {
+            xml.push_str(&format!(
+                "  <delta serial=\"{}\" uri=\"{}\" hash=\"{}\"/>\n",
+                serial, https_uri(&format!("delta-{}.xml", serial)), hash
+            ));
+        }
+        xml.push_str("</notification>\n");
+        self.serve(&https_uri("notification.xml"), xml.into_bytes());
+    }
+
+    /// Publishes a delta replacing one object and returns its hash.
+    pub fn publish_delta(
+        &self,
+        session: Uuid,
+        serial: u64,
+        path: &str,
+        old: &[u8],
+        new: &[u8],
+    ) -> String {
+        self.publish_changes(session, serial, &[(path, Some(old), Some(new))])
+    }
+
+    /// Publishes a delta with the given changes and returns its hash.
+    ///
+    /// Each change is the path of an object, its old content or `None` if
+    /// it is added, and its new content or `None` if it is withdrawn.
+    pub fn publish_changes(
+        &self,
+        session: Uuid,
+        serial: u64,
+        changes: &[(&str, Option<&[u8]>, Option<&[u8]>)],
+    ) -> String {
+        let mut xml = header("delta", session, serial);
+        for &(path, old, new) in changes {
+            match (old, new) {
+                (Some(old), Some(new)) => {
+                    xml.push_str(&format!(
+                        "  <publish uri=\"{}\" hash=\"{}\">{}</publish>\n",
+                        rsync_uri(path), sha256(old),
+                        base64::encode(new)
+                    ))
+                }
+                (None, Some(new)) => {
+                    xml.push_str(&format!(
+                        "  <publish uri=\"{}\">{}</publish>\n",
+                        rsync_uri(path), base64::encode(new)
+                    ))
+                }
+                (Some(old), None) => {
+                    xml.push_str(&format!(
+                        "  <withdraw uri=\"{}\" hash=\"{}\"/>\n",
+                        rsync_uri(path), sha256(old)
+                    ))
+                }
+                (None, None) => { }
+            }
+        }
+        xml.push_str("</delta>\n");
+        let hash = sha256(xml.as_bytes());
+        self.serve(
+            &https_uri(&format!("delta-{}.xml", serial)), xml.into_bytes()
+        );
+        hash
+    }
+
+    /// Returns the currently published content of a file.
+    pub fn published(&self, uri: &uri::Https) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().get(uri).cloned()
+    }
+
+    /// Returns and clears the URIs of all requests so far.
+    pub fn take_requests(&self) -> Vec<uri::Https> {
+        self.requests.lock().unwrap().drain(..).collect()
+    }
+
+    /// Returns the content of a file, recording the request.
+    fn get(&self, uri: &uri::Https) -> Option<Vec<u8>> {
+        self.requests.lock().unwrap().push(uri.clone());
+        self.files.lock().unwrap().get(uri).cloned()
+    }
+}
+
+impl HttpClient for MockClient {
+    fn tmp_dir(&self) -> &Path {
+        &self.tmp_dir
+    }
+
+    fn acquire_update(&self) -> UpdatePermit {
+        self.updates.acquire()
+    }
+
+    fn conditional_notification_file(
+        &self,
+        uri: &uri::Https,
+        validators: Option<&CacheValidators>,
+        status: &mut Option<StatusCode>,
+    ) -> Result<Option<NotificationResponse>, RequestError> {
+        let data = match self.get(uri) {
+            Some(data) => data,
+            None => {
+                *status = Some(StatusCode::NOT_FOUND);
+                return Err(RequestError::Failed)
+            }
+        };
+        let etag = if self.etags {
+            Some(format!("\"{}\"", sha256(&data)))
+        }
+        else {
+            None
+        };
+        if etag.is_some()
+            && validators.and_then(|v| v.etag.as_ref()) == etag.as_ref()
+        {
+            *status = Some(StatusCode::NOT_MODIFIED);
+            return Ok(None)
+        }
+        *status = Some(StatusCode::OK);
+        match ReqwestClient::parse_notification(&data) {
+            Ok(notify) => Ok(Some(NotificationResponse {
+                notify, raw: data,
+                validators: CacheValidators {
+                    etag, last_modified: None
+                }
+            })),
+            Err(_) => Err(RequestError::Failed)
+        }
+    }
+
+    fn snapshot(
+        &self,
+        _server_uri: &uri::Https,
+        notify: &NotificationFile,
+        path_op: &dyn Fn(&uri::Rsync) -> Result<PathBuf, Error>,
+        compress: bool,
+        check: &ObjectCheck,
+        progress: Option<&mut dyn FnMut(SnapshotProgress)>,
+    ) -> Result<ObjectHashes, Error> {
+        let data = self.get(notify.snapshot.uri()).ok_or(Error)?;
+        let mut processor = SnapshotProcessor::new(
+            notify.session_id, notify.serial, path_op, compress
+        );
+        processor.set_check(check.clone());
+        process_snapshot(data.as_slice(), notify, None, &mut processor)?;
+        let hashes = processor.into_hashes();
+        if let Some(progress) = progress {
+            progress(SnapshotProgress {
+                bytes: data.len() as u64, objects: hashes.len()
+            })
+        }
+        Ok(hashes)
+    }
+
+    fn delta(
+        &self,
+        server_uri: &uri::Https,
+        notify: &NotificationFile,
+        delta: &(u64, UriAndHash),
+        _prefetched: Option<NamedTempFile>,
+        targets: &mut DeltaTargets,
+        path_op: &dyn Fn(&uri::Rsync) -> Result<PathBuf, Error>,
+    ) -> Result<(), Error> {
+        let data = self.get(delta.1.uri()).ok_or(Error)?;
+        let mark = targets.mark();
+        let res = process_delta(
+            delta.1.uri(), data.as_slice(), None,
+            DeltaProcessor::new(server_uri, notify, delta, targets, path_op)
+        );
+        if res.is_err() {
+            targets.rollback(mark);
+        }
+        res
+    }
+}
+
+
+//------------ Fixtures ------------------------------------------------------
+
+/// The objects of the server created by `seed_server` in most tests.
+pub const SEED_OBJECTS: &[(&str, &[u8])] = &[
+    ("a.cer", b"certificate"), ("sub/b.roa", b"roa"),
+];
+
+/// Returns an HTTPS URI for a path on the mock server.
+pub fn https_uri(path: &str) -> uri::Https {
+    uri::Https::from_str(
+        &format!("https://example.net/{}", path)
+    ).unwrap()
+}
+
+/// Returns an rsync URI for a path in the mock repository.
+pub fn rsync_uri(path: &str) -> uri::Rsync {
+    uri::Rsync::from_str(
+        &format!("rsync://example.net/repo/{}", path)
+    ).unwrap()
+}
+
+/// Returns the hex-encoded SHA-256 hash of some data.
+pub fn sha256(data: &[u8]) -> String {
+    DigestHex::from(digest::digest(&digest::SHA256, data)).to_string()
+}
+
+/// Returns the opening tag of an RRDP document.
+pub fn header(element: &str, session: Uuid, serial: u64) -> String {
+    format!(
+        "<{} xmlns=\"http://www.ripe.net/rpki/rrdp\" version=\"1\" \
+         session_id=\"{}\" serial=\"{}\">\n",
+        element, session, serial
+    )
+}
+
+/// Writes objects into a data directory and returns their hashes.
+///
+/// The objects are given by their path within the module
+/// `rsync://<host>/repo/`.
+pub fn write_objects(
+    data: &Path, host: &str, objects: &[(&str, &[u8])]
+) -> ObjectHashes {
+    let mut hashes = ObjectHashes::new();
+    for (path, content) in objects {
+        let file = data.join(host).join("repo").join(path);
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, content).unwrap();
+        hashes.insert_data(
+            uri::Rsync::from_str(
+                &format!("rsync://{}/repo/{}", host, path)
+            ).unwrap(),
+            content
+        );
+    }
+    hashes
+}
+
+/// Creates a server directory at `base` holding the given objects.
+///
+/// The server’s notification URI is `https://<host>/notification.xml`. Its
+/// state has the nil session and the given serial and matches the objects
+/// as do the object hashes. The directory uses the default names.
+pub fn seed_server(
+    base: &Path, host: &str, serial: u64, objects: &[(&str, &[u8])]
+) {
+    let names = ServerDirNames::default();
+    let data = names.data_path(base);
+    write_objects(&data, host, objects).save(
+        &FsStore, &names.hashes_path(base)
+    ).unwrap();
+    ServerState {
+        notify_uri: uri::Https::from_str(
+            &format!("https://{}/notification.xml", host)
+        ).unwrap(),
+        session: Uuid::nil(),
+        serial,
+        hash: data_digest(&data, Default::default(), false).unwrap(),
+        object_count: Some(objects.len()),
+        algorithm: Default::default(),
+        deep_digest: false,
+        compressed: false,
+        digest_version: Default::default(),
+        validators: Default::default(),
+        past_sessions: Vec::new(),
+    }.save(&FsStore, &names.state_path(base)).unwrap();
+}
+
+
+//------------ RecordingStore ------------------------------------------------
+
+/// A file system store recording which files are read and listed.
+///
+/// This allows tests to check how often the local copy is walked or an
+/// object read.
+#[derive(Clone, Debug, Default)]
+pub struct RecordingStore {
+    /// The paths of the files read.
+    reads: Arc<Mutex<Vec<PathBuf>>>,
+
+    /// The paths of the directories listed.
+    listed: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl RecordingStore {
+    /// Returns the paths of the files read since the last call.
+    pub fn take_reads(&self) -> Vec<PathBuf> {
+        mem::take(&mut *self.reads.lock().unwrap())
+    }
+
+    /// Returns the paths of the directories listed since the last call.
+    pub fn take_listed(&self) -> Vec<PathBuf> {
+        mem::take(&mut *self.listed.lock().unwrap())
+    }
+}
+
+impl CacheStore for RecordingStore {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, io::Error> {
+        self.reads.lock().unwrap().push(path.into());
+        FsStore.read(path)
+    }
+
+    fn read_prefix(
+        &self, path: &Path, len: usize
+    ) -> Result<Vec<u8>, io::Error> {
+        self.reads.lock().unwrap().push(path.into());
+        FsStore.read_prefix(path, len)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> Result<(), io::Error> {
+        FsStore.write(path, data)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<StoreEntry>, io::Error> {
+        self.listed.lock().unwrap().push(path.into());
+        FsStore.read_dir(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        FsStore.is_dir(path)
+    }
+
+    fn is_local(&self, path: &Path) -> bool {
+        FsStore.is_local(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), io::Error> {
+        FsStore.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), io::Error> {
+        FsStore.remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<(), io::Error> {
+        FsStore.remove_dir_all(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), io::Error> {
+        FsStore.create_dir_all(path)
+    }
+
+    fn sync(&self, path: &Path) -> Result<(), io::Error> {
+        FsStore.sync(path)
+    }
+
+    fn commit(&self, path: &Path) -> Result<(), io::Error> {
+        FsStore.commit(path)
+    }
+}
+
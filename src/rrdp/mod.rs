@@ -1,9 +1,36 @@
 /// Local repository copy synchronized with RRDP.
 
 pub use self::cache::{Cache, Run, ServerId};
+pub use self::debug::DebugListener;
+pub use self::selftest::selftest;
+pub use self::server::LoadResult;
+pub use self::stats::{ServerStats, StatsFormat};
+pub use self::store::{CacheStore, FsStore, SharedStore, StoreEntry};
 
+mod archive;
+mod backoff;
+mod bandwidth;
 mod cache;
+mod cadence;
+mod clock;
+mod compress;
+mod debug;
+mod dedup;
+mod deltas;
+mod events;
+mod hashes;
+mod localdigest;
 pub mod http;
+mod lru;
+#[cfg(test)]
+mod mock;
+mod partial;
 pub mod server;
+mod selftest;
+mod sessions;
+mod stats;
+mod store;
+mod throttle;
+mod timeline;
 mod utils;
 
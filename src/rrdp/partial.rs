@@ -0,0 +1,247 @@
+//! Partially downloaded RRDP snapshots.
+//!
+//! This is a private module for organizational purposes.
+
+use std::{fs, io};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use reqwest::header::{CONTENT_RANGE, ETAG, LAST_MODIFIED, HeaderMap};
+use ring::digest;
+use rpki::uri;
+use rpki::rrdp::{DigestHex, NotificationFile};
+
+
+//------------ PartialSnapshot -----------------------------------------------
+
+/// A snapshot download kept in the temporary directory.
+///
+/// The body of the snapshot response is written to a data file as it
+/// arrives. A marker file next to it records which snapshot is being
+/// downloaded and the validator of the response. Both files are named
+/// after the notification URI, so there is at most one partial snapshot
+/// per server and starting a download for a new snapshot replaces that of
+/// an older one.
+///
+/// The marker file contains one line each for the session, the serial
+/// number, the hash, and the URI of the snapshot, followed by a line with
+/// the validator which may be empty. If there is no marker file, the data
+/// file can’t be resumed.
+#[derive(Clone, Debug)]
+pub struct PartialSnapshot {
+    /// The path of the data file.
+    data_path: PathBuf,
+
+    /// The path of the marker file.
+    marker_path: PathBuf,
+}
+
+impl PartialSnapshot {
+    /// Returns the partial snapshot of a server in the given directory.
+    pub fn new(tmp_dir: &Path, server_uri: &uri::Https) -> Self {
+        let name = DigestHex::from(
+            digest::digest(&digest::SHA256, server_uri.as_str().as_bytes())
+        );
+        PartialSnapshot {
+            data_path: tmp_dir.join(format!("snapshot-{}.partial", name)),
+            marker_path: tmp_dir.join(format!("snapshot-{}.marker", name)),
+        }
+    }
+
+    /// Returns where to resume downloading the snapshot of `notify`.
+    ///
+    /// Returns the number of bytes already downloaded and the validator for
+    /// making the range request conditional. Returns `None` if there is
+    /// nothing to resume, including if the partial download is for a
+    /// different snapshot.
+    pub fn resume_point(
+        &self, notify: &NotificationFile
+    ) -> Option<(u64, String)> {
+        let marker = fs::read_to_string(&self.marker_path).ok()?;
+        let mut lines = marker.lines();
+        for expected in &Self::marker_lines(notify) {
+            if lines.next() != Some(expected.as_str()) {
+                return None
+            }
+        }
+        let validator = lines.next().unwrap_or("").into();
+        let len = fs::metadata(&self.data_path).ok()?.len();
+        if len == 0 {
+            return None
+        }
+        Some((len, validator))
+    }
+
+    /// Starts downloading the snapshot of `notify` from the beginning.
+    ///
+    /// If `validator` is `None`, the download can’t be resumed and no
+    /// marker is written. Returns the truncated data file.
+    pub fn start(
+        &self, notify: &NotificationFile, validator: Option<&str>
+    ) -> Result<fs::File, io::Error> {
+        let _ = fs::remove_file(&self.marker_path);
+        let file = fs::File::create(&self.data_path)?;
+        if let Some(validator) = validator {
+            let mut marker = String::new();
+            for line in &Self::marker_lines(notify) {
+                marker.push_str(line);
+                marker.push('\n');
+            }
+            marker.push_str(validator);
+            marker.push('\n');
+            fs::write(&self.marker_path, marker)?;
+        }
+        Ok(file)
+    }
+
+    /// Opens the data file for appending to it.
+    pub fn append(&self) -> Result<fs::File, io::Error> {
+        fs::OpenOptions::new().append(true).open(&self.data_path)
+    }
+
+    /// Opens the data file for reading.
+    pub fn open(&self) -> Result<fs::File, io::Error> {
+        fs::File::open(&self.data_path)
+    }
+
+    /// Returns the path of the data file.
+    pub fn path(&self) -> &Path {
+        &self.data_path
+    }
+
+    /// Removes the partial snapshot.
+    pub fn remove(&self) {
+        let _ = fs::remove_file(&self.marker_path);
+        let _ = fs::remove_file(&self.data_path);
+    }
+
+    /// Returns the lines identifying the snapshot of `notify`.
+    fn marker_lines(notify: &NotificationFile) -> [String; 4] {
+        [
+            notify.session_id.to_string(),
+            notify.serial.to_string(),
+            notify.snapshot.hash().to_string(),
+            notify.snapshot.uri().to_string(),
+        ]
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Returns the validator for making a range request conditional.
+///
+/// This is the entity tag unless it is a weak one, since those can’t be
+/// used for range requests, and the last modified date otherwise.
+pub fn range_validator(headers: &HeaderMap) -> Option<String> {
+    if let Some(etag) = headers.get(ETAG).and_then(|value| {
+        value.to_str().ok()
+    }) {
+        if !etag.starts_with("W/") {
+            return Some(etag.into())
+        }
+    }
+    headers.get(LAST_MODIFIED).and_then(|value| {
+        value.to_str().ok()
+    }).map(Into::into)
+}
+
+/// Returns the first byte of the range in a partial response.
+pub fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?.trim();
+    if !value.starts_with("bytes ") {
+        return None
+    }
+    let range = &value["bytes ".len()..];
+    u64::from_str(&range[..range.find('-')?]).ok()
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use reqwest::header::HeaderValue;
+
+    fn notification(serial: u64) -> NotificationFile {
+        NotificationFile::parse(format!(
+            "<notification xmlns=\"http://www.ripe.net/rpki/rrdp\" \
+             version=\"1\" \
+             session_id=\"9df4b597-af9e-4dca-bdda-719cce2c4e28\" \
+             serial=\"{}\">\n\
+             <snapshot uri=\"https://example.net/snapshot.xml\" \
+             hash=\"2a51c2b6e1fa1d6c4e7d4d0bb2e9e0a6\
+             3d6e9b2d0e73f6b1d29cd563d1fe1b61\"/>\n\
+             </notification>\n",
+            serial
+        ).as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn resume_point() {
+        let tmp = tempfile::tempdir().unwrap();
+        let partial = PartialSnapshot::new(
+            tmp.path(),
+            &uri::Https::from_str("https://example.net/notify.xml").unwrap()
+        );
+        let notify = notification(2);
+        assert_eq!(partial.resume_point(&notify), None);
+
+        // Nothing downloaded yet.
+        let mut file = partial.start(&notify, Some("\"abc\"")).unwrap();
+        assert_eq!(partial.resume_point(&notify), None);
+        file.write_all(b"<snapshot").unwrap();
+        drop(file);
+        assert_eq!(
+            partial.resume_point(&notify), Some((9, "\"abc\"".into()))
+        );
+        partial.append().unwrap().write_all(b" ").unwrap();
+        assert_eq!(
+            partial.resume_point(&notify), Some((10, "\"abc\"".into()))
+        );
+
+        // A new serial doesn’t resume.
+        assert_eq!(partial.resume_point(&notification(3)), None);
+
+        // Without a validator, there still is a marker.
+        partial.start(&notify, Some("")).unwrap().write_all(b"<").unwrap();
+        assert_eq!(partial.resume_point(&notify), Some((1, "".into())));
+
+        // Without a marker, there isn’t.
+        partial.start(&notify, None).unwrap().write_all(b"<").unwrap();
+        assert_eq!(partial.resume_point(&notify), None);
+
+        partial.remove();
+        assert!(!partial.path().exists());
+    }
+
+    #[test]
+    fn headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(range_validator(&headers), None);
+        assert_eq!(content_range_start(&headers), None);
+        headers.insert(
+            LAST_MODIFIED,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+        headers.insert(ETAG, HeaderValue::from_static("W/\"abc\""));
+        assert_eq!(
+            range_validator(&headers).unwrap(),
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        );
+        headers.insert(ETAG, HeaderValue::from_static("\"abc\""));
+        assert_eq!(range_validator(&headers).unwrap(), "\"abc\"");
+
+        headers.insert(
+            CONTENT_RANGE, HeaderValue::from_static("bytes 100-199/200")
+        );
+        assert_eq!(content_range_start(&headers), Some(100));
+        headers.insert(
+            CONTENT_RANGE, HeaderValue::from_static("bytes 7-9/*")
+        );
+        assert_eq!(content_range_start(&headers), Some(7));
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static("items 1-2"));
+        assert_eq!(content_range_start(&headers), None);
+    }
+}
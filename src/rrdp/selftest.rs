@@ -0,0 +1,529 @@
+//! An end-to-end test of the RRDP update cycle.
+//!
+//! This is a private module for organizational purposes.
+//!
+//! The self test starts a minimal HTTP server on the loopback interface
+//! that publishes a mock RRDP repository. It then creates an RRDP server in
+//! a temporary cache, updates it from the snapshot, publishes two deltas,
+//! updates it again, and checks the content of the cache after each step.
+//!
+//! The mock repository is also used by the tests of the HTTP client.
+
+use std::{fs, io, thread};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use log::{error, info};
+use ring::digest;
+use rpki::uri;
+use rpki::rrdp::DigestHex;
+use uuid::Uuid;
+use crate::metrics::RrdpUpdateKind;
+use crate::operation::Error;
+use super::http::ReqwestClient;
+use super::server::{LoadResult, Server};
+
+
+///----------- Configuration Constants ---------------------------------------
+
+/// The URI prefix of the mock repository’s HTTPS URIs.
+pub const HTTPS_BASE: &str = "https://rrdp.selftest.invalid/";
+
+/// The URI prefix of the mock repository’s rsync URIs.
+const RSYNC_BASE: &str = "rsync://rrdp.selftest.invalid/repo/";
+
+
+//------------ selftest ------------------------------------------------------
+
+/// Runs the self test using the given directory for all files.
+///
+/// Logs the reason and returns an error if the test fails.
+pub fn selftest(base: &Path) -> Result<(), Error> {
+    let cache_dir = base.join("rrdp");
+    let tmp_dir = base.join("tmp");
+    for dir in &[&cache_dir, &tmp_dir] {
+        if let Err(err) = fs::create_dir_all(dir) {
+            error!(
+                "RRDP self test: cannot create directory {}: {}",
+                dir.display(), err
+            );
+            return Err(Error)
+        }
+    }
+
+    let mut repo = MockRepository::start()?;
+    let http = ReqwestClient::with_redirect(
+        tmp_dir, HTTPS_BASE.into(), repo.server.origin()
+    )?;
+    let notify_uri = uri::Https::from_str(
+        &format!("{}notification.xml", HTTPS_BASE)
+    ).map_err(|_| Error)?;
+
+    // Step 1: Create a new server and update it via the snapshot.
+    repo.publish(&[
+        ("a.cer", Some(b"first certificate".as_ref())),
+        ("b.roa", Some(b"some roa".as_ref())),
+    ], false);
+    let server = Server::create(
+        notify_uri.clone(), &cache_dir, Default::default()
+    );
+    check("create", !server.is_broken(), "server directory not created")?;
+    server.update(&http);
+    repo.check("snapshot", &server)?;
+    repo.check_metrics("snapshot", &server, RrdpUpdateKind::Snapshot)?;
+
+    // Step 2: Update the server via two deltas. We don’t serve the new
+    // snapshot, so this can only succeed via the deltas. The deltas are
+    // downloaded concurrently but must be applied in order.
+    repo.publish(&[
+        ("a.cer", Some(b"second certificate".as_ref())),
+        ("c.mft", Some(b"a manifest".as_ref())),
+    ], true);
+    repo.publish(&[
+        ("a.cer", Some(b"third certificate".as_ref())),
+        ("b.roa", None),
+    ], true);
+    let server = Server::existing(
+        notify_uri, server.server_dir().into(), Default::default()
+    );
+    server.update(&http);
+    repo.check("delta", &server)?;
+    repo.check_metrics("delta", &server, RrdpUpdateKind::Delta)?;
+
+    // Step 3: Verify the local copy against its state.
+    let state = match server.verify() {
+        Ok(state) => state,
+        Err(_) => {
+            return check("verify", false, "local copy inconsistent")
+        }
+    };
+    check("verify", state.serial == repo.serial, "unexpected serial")?;
+    info!("RRDP self test succeeded.");
+    Ok(())
+}
+
+/// Checks a condition of a step.
+fn check(step: &str, ok: bool, reason: &str) -> Result<(), Error> {
+    if ok {
+        info!("RRDP self test: step '{}' succeeded.", step);
+        Ok(())
+    }
+    else {
+        error!("RRDP self test failed in step '{}': {}.", step, reason);
+        Err(Error)
+    }
+}
+
+
+//------------ MockRepository ------------------------------------------------
+
+/// A mock RRDP repository.
+pub struct MockRepository {
+    /// The HTTP server publishing the repository.
+    pub server: MockServer,
+
+    /// The session of the repository.
+    session: Uuid,
+
+    /// The current serial number.
+    pub serial: u64,
+
+    /// The current objects by their path relative to `RSYNC_BASE`.
+    objects: BTreeMap<&'static str, &'static [u8]>,
+
+    /// The objects withdrawn in the last update.
+    withdrawn: Vec<&'static str>,
+
+    /// The serial numbers and hashes of all published deltas.
+    deltas: Vec<(u64, String)>,
+}
+
+impl MockRepository {
+    /// Starts a new repository without any content.
+    pub fn start() -> Result<Self, Error> {
+        Ok(MockRepository {
+            server: MockServer::start()?,
+            session: Uuid::new_v4(),
+            serial: 0,
+            objects: BTreeMap::new(),
+            withdrawn: Vec::new(),
+            deltas: Vec::new(),
+        })
+    }
+
+    /// Publishes a new serial with the given changes.
+    ///
+    /// Each change is the path of an object and its new content or `None`
+    /// if the object is to be withdrawn. If `delta` is `true`, a delta
+    /// with the changes is published and the snapshot is not served.
+    /// Otherwise, only the snapshot is served.
+    pub fn publish(
+        &mut self,
+        changes: &[(&'static str, Option<&'static [u8]>)],
+        delta: bool,
+    ) {
+        self.serial += 1;
+        let mut xml = self.header("delta");
+        self.withdrawn.clear();
+        for &(path, data) in changes {
+            let hash = self.objects.get(path).map(|&old| sha256(old));
+            match (data, hash) {
+                (Some(data), Some(hash)) => {
+                    xml.push_str(&format!(
+                        "  <publish uri=\"{}{}\" hash=\"{}\">{}</publish>\n",
+                        RSYNC_BASE, path, hash, base64::encode(data)
+                    ));
+                    self.objects.insert(path, data);
+                }
+                (Some(data), None) => {
+                    xml.push_str(&format!(
+                        "  <publish uri=\"{}{}\">{}</publish>\n",
+                        RSYNC_BASE, path, base64::encode(data)
+                    ));
+                    self.objects.insert(path, data);
+                }
+                (None, Some(hash)) => {
+                    xml.push_str(&format!(
+                        "  <withdraw uri=\"{}{}\" hash=\"{}\"/>\n",
+                        RSYNC_BASE, path, hash
+                    ));
+                    self.objects.remove(path);
+                    self.withdrawn.push(path);
+                }
+                (None, None) => { }
+            }
+        }
+        xml.push_str("</delta>\n");
+        if delta {
+            self.deltas.push((self.serial, sha256(xml.as_bytes())));
+            self.server.serve(
+                &format!("delta-{}.xml", self.serial), xml.into_bytes()
+            );
+        }
+
+        let mut xml = self.header("snapshot");
+        for (path, data) in &self.objects {
+            xml.push_str(&format!(
+                "  <publish uri=\"{}{}\">{}</publish>\n",
+                RSYNC_BASE, path, base64::encode(data)
+            ));
+        }
+        xml.push_str("</snapshot>\n");
+        let snapshot_hash = sha256(xml.as_bytes());
+        if !delta {
+            self.server.serve(
+                &format!("snapshot-{}.xml", self.serial), xml.into_bytes()
+            );
+        }
+
+        let mut xml = self.header("notification");
+        xml.push_str(&format!(
+            "  <snapshot uri=\"{}snapshot-{}.xml\" hash=\"{}\"/>\n",
+            HTTPS_BASE, self.serial, snapshot_hash
+        ));
+        for (serial, hash) in &self.deltas {
+            xml.push_str(&format!(
+                "  <delta serial=\"{}\" uri=\"{}delta-{}.xml\" \
+                 hash=\"{}\"/>\n",
+                serial, HTTPS_BASE, serial, hash
+            ));
+        }
+        xml.push_str("</notification>\n");
+        self.server.serve("notification.xml", xml.into_bytes());
+    }
+
+    /// Returns the opening tag of an RRDP document.
+    fn header(&self, element: &str) -> String {
+        format!(
+            "<{} xmlns=\"http://www.ripe.net/rpki/rrdp\" version=\"1\" \
+             session_id=\"{}\" serial=\"{}\">\n",
+            element, self.session, self.serial
+        )
+    }
+
+    /// Checks that a server provides exactly the current objects.
+    pub fn check(&self, step: &str, server: &Server) -> Result<(), Error> {
+        check(step, !server.is_broken(), "server broken after update")?;
+        for (path, data) in &self.objects {
+            let uri = rsync_uri(path)?;
+            let ok = match server.load_file(&uri) {
+                LoadResult::Found(bytes) => bytes.as_ref() == *data,
+                _ => false
+            };
+            check(step, ok, &format!("wrong content for {}", uri))?;
+        }
+        for path in &self.withdrawn {
+            let uri = rsync_uri(path)?;
+            let ok = server.load_file(&uri).is_not_found();
+            check(step, ok, &format!("withdrawn {} still present", uri))?;
+        }
+        Ok(())
+    }
+
+    /// Checks the metrics of the last update of a server.
+    pub fn check_metrics(
+        &self, step: &str, server: &Server, kind: RrdpUpdateKind
+    ) -> Result<(), Error> {
+        let metrics = match server.metrics() {
+            Some(metrics) => metrics,
+            None => return check(step, false, "no metrics after update")
+        };
+        check(
+            step, metrics.update_kind == Some(kind), "unexpected update kind"
+        )?;
+        check(step, metrics.bytes_downloaded > 0, "no bytes downloaded")?;
+        check(
+            step, metrics.object_count == Some(self.objects.len()),
+            "wrong object count"
+        )
+    }
+}
+
+
+//------------ MockServer ----------------------------------------------------
+
+/// A minimal HTTP server on the loopback interface.
+///
+/// The server answers each GET request with the file published under the
+/// request’s path or a 404 response and then closes the connection. Each
+/// file is served with an ETag and requests with a matching If-None-Match
+/// header get a 304 response. Range requests for the rest of a file
+/// starting at some byte get a 206 response unless an If-Range header
+/// doesn’t match the ETag.
+pub struct MockServer {
+    /// The address the server is listening on.
+    addr: SocketAddr,
+
+    /// The published files by their path without the leading slash.
+    files: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+
+    /// The number of 503 responses still to give for a path.
+    failures: Arc<Mutex<BTreeMap<String, usize>>>,
+
+    /// The number of body bytes after which to cut off a path’s response.
+    truncated: Arc<Mutex<BTreeMap<String, usize>>>,
+
+    /// The paths and first bytes of all range requests received.
+    pub ranges: Arc<Mutex<Vec<(String, usize)>>>,
+
+    /// Set to `true` to stop the server.
+    stop: Arc<AtomicBool>,
+}
+
+impl MockServer {
+    /// Starts the server on a thread of its own.
+    fn start() -> Result<Self, Error> {
+        let listener = TcpListener::bind(
+            SocketAddr::from(([127, 0, 0, 1], 0))
+        ).map_err(|err| {
+            error!("RRDP self test: cannot bind mock server: {}", err);
+            Error
+        })?;
+        let addr = listener.local_addr().map_err(|err| {
+            error!("RRDP self test: cannot bind mock server: {}", err);
+            Error
+        })?;
+        let files = Arc::new(Mutex::new(BTreeMap::new()));
+        let failures = Arc::new(Mutex::new(BTreeMap::new()));
+        let truncated = Arc::new(Mutex::new(BTreeMap::new()));
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let res = MockServer {
+            addr, files, failures, truncated, ranges, stop
+        };
+        let (thread_files, thread_failures, thread_truncated) = (
+            res.files.clone(), res.failures.clone(), res.truncated.clone()
+        );
+        let (thread_ranges, thread_stop) = (
+            res.ranges.clone(), res.stop.clone()
+        );
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_stop.load(Ordering::Relaxed) {
+                    break
+                }
+                if let Ok(stream) = stream {
+                    let _ = Self::respond(
+                        stream, &thread_files, &thread_failures,
+                        &thread_truncated, &thread_ranges,
+                    );
+                }
+            }
+        });
+        Ok(res)
+    }
+
+    /// Returns the origin of the server to redirect requests to.
+    pub fn origin(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    /// Publishes a file under the given path.
+    pub fn serve(&self, path: &str, data: Vec<u8>) {
+        self.files.lock().unwrap().insert(path.into(), data);
+    }
+
+    /// Stops publishing the file under a path and returns its content.
+    pub fn withdraw(&self, path: &str) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().remove(path)
+    }
+
+    /// Answers the next `count` requests for a path with a 503 response.
+    pub fn fail(&self, path: &str, count: usize) {
+        self.failures.lock().unwrap().insert(path.into(), count);
+    }
+
+    /// Cuts off the body of the next response for a path after `len` bytes.
+    pub fn truncate(&self, path: &str, len: usize) {
+        self.truncated.lock().unwrap().insert(path.into(), len);
+    }
+
+    /// Answers a single request.
+    fn respond(
+        mut stream: TcpStream,
+        files: &Mutex<BTreeMap<String, Vec<u8>>>,
+        failures: &Mutex<BTreeMap<String, usize>>,
+        truncated: &Mutex<BTreeMap<String, usize>>,
+        ranges: &Mutex<Vec<(String, usize)>>,
+    ) -> Result<(), io::Error> {
+        let mut reader = BufReader::new(&mut stream);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        // Skip the headers except for If-None-Match, Range, and If-Range.
+        let mut line = String::new();
+        let mut if_none_match = None;
+        let mut range = None;
+        let mut if_range = None;
+        while reader.read_line(&mut line)? > 2 {
+            let lower = line.to_ascii_lowercase();
+            if lower.starts_with("if-none-match:") {
+                if_none_match = Some(
+                    line["if-none-match:".len()..].trim().to_string()
+                );
+            }
+            else if lower.starts_with("range: bytes=") {
+                range = usize::from_str(
+                    line["range: bytes=".len()..].trim().trim_end_matches('-')
+                ).ok();
+            }
+            else if lower.starts_with("if-range:") {
+                if_range = Some(line["if-range:".len()..].trim().to_string());
+            }
+            line.clear();
+        }
+        let path = request.split_whitespace().nth(1).unwrap_or("");
+        let path = path.trim_start_matches('/');
+        if let Some(count) = failures.lock().unwrap().get_mut(path) {
+            if *count > 0 {
+                *count -= 1;
+                return stream.write_all(
+                    b"HTTP/1.1 503 Service Unavailable\r\n\
+                      Content-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+            }
+        }
+        match files.lock().unwrap().get(path) {
+            Some(data) => {
+                let etag = format!("\"{}\"", sha256(data));
+                if if_none_match.as_ref() == Some(&etag) {
+                    return write!(
+                        stream,
+                        "HTTP/1.1 304 Not Modified\r\nETag: {}\r\n\
+                         Content-Length: 0\r\nConnection: close\r\n\r\n",
+                        etag
+                    )
+                }
+                let start = match range {
+                    Some(start) if start < data.len() => {
+                        ranges.lock().unwrap().push((path.into(), start));
+                        if if_range.map(|tag| tag == etag).unwrap_or(true) {
+                            Some(start)
+                        }
+                        else {
+                            None
+                        }
+                    }
+                    _ => None
+                };
+                let body = match start {
+                    Some(start) => {
+                        write!(
+                            stream,
+                            "HTTP/1.1 206 Partial Content\r\nETag: {}\r\n\
+                             Content-Range: bytes {}-{}/{}\r\n\
+                             Content-Length: {}\r\n\
+                             Connection: close\r\n\r\n",
+                            etag, start, data.len() - 1, data.len(),
+                            data.len() - start
+                        )?;
+                        &data[start..]
+                    }
+                    None => {
+                        write!(
+                            stream,
+                            "HTTP/1.1 200 OK\r\nETag: {}\r\n\
+                             Content-Length: {}\r\n\
+                             Connection: close\r\n\r\n",
+                            etag, data.len()
+                        )?;
+                        &data[..]
+                    }
+                };
+                match truncated.lock().unwrap().remove(path) {
+                    Some(len) if len < body.len() => {
+                        stream.write_all(&body[..len])
+                    }
+                    _ => stream.write_all(body)
+                }
+            }
+            None => {
+                stream.write_all(
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\
+                      Connection: close\r\n\r\n"
+                )
+            }
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        // Connect once to wake up the server thread so it sees the flag.
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+
+//------------ Helpers -------------------------------------------------------
+
+/// Returns the rsync URI of an object of the mock repository.
+pub fn rsync_uri(path: &str) -> Result<uri::Rsync, Error> {
+    uri::Rsync::from_str(&format!("{}{}", RSYNC_BASE, path)).map_err(|_| {
+        error!("RRDP self test: invalid object path {}", path);
+        Error
+    })
+}
+
+/// Returns the hex-encoded SHA-256 hash of some data.
+fn sha256(data: &[u8]) -> String {
+    DigestHex::from(digest::digest(&digest::SHA256, data)).to_string()
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn full_cycle() {
+        let tmp = tempfile::tempdir().unwrap();
+        selftest(tmp.path()).unwrap();
+    }
+}
@@ -2,25 +2,50 @@
 //!
 //! This is a private module and exists only for organizational reasons.
 
-use std::{cmp, fs, io};
-use std::io::{BufRead, BufReader, Read, Write};
-use std::path::{Path, PathBuf};
+use std::{cmp, fmt, fs, io};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::convert::Infallible;
+use std::ffi::{OsStr, OsString};
+use std::io::{BufRead, Read, Write};
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Mutex;
-use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::sync::atomic::{AtomicBool, AtomicU8};
 use std::sync::atomic::Ordering::Relaxed;
-use std::time::SystemTime;
+use std::time::{Duration, Instant};
 use bytes::Bytes;
-use log::{info, warn};
+use chrono::DateTime;
+use log::{Level, debug, error, info, log, warn};
 use ring::digest;
 use ring::constant_time::verify_slices_are_equal;
 use rpki::uri;
 use rpki::rrdp::{DigestHex, NotificationFile, UriAndHash};
+use serde::Serialize;
 use uuid::Uuid;
-use crate::metrics::RrdpServerMetrics;
+use crate::config::{Config, DigestAlgorithm, ReplayPolicy};
+use crate::metrics::{RrdpServerMetrics, RrdpUpdateKind};
 use crate::operation::Error;
-use super::http::{DeltaTargets, HttpClient};
-use super::utils::create_unique_dir;
+use super::archive::ArchiveStore;
+use super::backoff::BrokenBackoff;
+use super::cadence::CadenceTracker;
+use super::clock::SharedClock;
+use super::compress;
+use super::dedup::ObjectDedup;
+use super::events::{EventKind, EventLog};
+use super::hashes::ObjectHashes;
+use super::localdigest;
+use super::lru::LoadCache;
+use super::http::{
+    CacheValidators, ChangeKind, DeltaTargets, HttpClient,
+    NotificationResponse, ObjectCheck, RequestError, SnapshotProgress,
+    snapshot_from_file
+};
+use super::stats::ServerStats;
+use super::store::{CacheStore, FsStore, SharedStore};
+use super::timeline::{Timeline, UpdatePath};
+use super::utils::{
+    create_unique_dir, lock_recovered, random_path, sync_tree
+};
 
 
 //------------ Server --------------------------------------------------------
@@ -56,9 +81,54 @@ pub struct Server {
     /// the mutex. If it is still necessary, perform the update, set the flags
     /// and drop the mutex.
     ///
-    /// Because the metrics are only used while updating (and after everything
-    /// is done, anyway), we keep them inside the mutex.
-    mutex: Mutex<RrdpServerMetrics>,
+    /// The mutex is also held by other operations that must not overlap
+    /// with an update, such as removing or exporting the local copy.
+    mutex: Mutex<()>,
+
+    /// Is an update currently being performed?
+    ///
+    /// Unlike holding `mutex`, this is only set by `locked_update`.
+    updating: AtomicBool,
+
+    /// The metrics of the last finished update.
+    ///
+    /// An update works on its own copy and only publishes it here once it
+    /// is done, so the metrics can be read while an update is running.
+    metrics: Mutex<RrdpServerMetrics>,
+
+    /// The options for the server.
+    options: Arc<ServerOptions>,
+
+    /// The object hashes if they have been loaded already.
+    ///
+    /// The inner option is `None` if there is no object hashes file for the
+    /// server.
+    #[allow(clippy::option_option)]
+    object_hashes: Mutex<Option<Option<Arc<ObjectHashes>>>>,
+
+    /// The objects whose hashes have been verified during this run.
+    verified: Mutex<HashSet<uri::Rsync>>,
+
+    /// Has the digest of the local copy been verified during this run?
+    ///
+    /// This is set whenever the digest was found to match the state and
+    /// cleared whenever we change the local copy.
+    digest_verified: AtomicBool,
+
+    /// The objects changed by the update during this run.
+    ///
+    /// This is `None` unless the server has been updated successfully.
+    changes: Mutex<Option<Vec<(uri::Rsync, ChangeKind)>>>,
+
+    /// How the last update during this run was performed.
+    ///
+    /// This is an `UpdateKind` converted into a `u8`.
+    last_update: AtomicU8,
+
+    /// The objects loaded most recently.
+    ///
+    /// This is cleared whenever the local copy changes.
+    load_cache: LoadCache,
 }
 
 
@@ -67,14 +137,24 @@ impl Server {
     fn new(
         notify_uri: uri::Https,
         server_dir: ServerDir,
-        broken: bool
+        broken: bool,
+        options: Arc<ServerOptions>,
     ) -> Self {
         Server {
-            mutex: Mutex::new(RrdpServerMetrics::new(notify_uri.clone())),
+            mutex: Mutex::new(()),
+            updating: AtomicBool::new(false),
+            metrics: Mutex::new(RrdpServerMetrics::new(notify_uri.clone())),
             notify_uri,
             server_dir,
             updated: AtomicBool::new(broken),
             broken: AtomicBool::new(broken),
+            object_hashes: Mutex::new(None),
+            verified: Mutex::new(HashSet::new()),
+            digest_verified: AtomicBool::new(false),
+            changes: Mutex::new(None),
+            last_update: AtomicU8::new(UpdateKind::NotYetRun as u8),
+            load_cache: LoadCache::new(options.load_cache_size),
+            options,
         }
     }
 
@@ -82,8 +162,16 @@ impl Server {
     ///
     /// Assumes that the server directory exists. Marks the server as not
     /// yet updated.
-    pub fn existing(notify_uri: uri::Https, server_dir: PathBuf) -> Self {
-        Self::new(notify_uri, ServerDir::new(server_dir), false)
+    pub fn existing(
+        notify_uri: uri::Https,
+        server_dir: PathBuf,
+        options: Arc<ServerOptions>,
+    ) -> Self {
+        Self::new(
+            notify_uri,
+            ServerDir::new(server_dir, &options),
+            false, options
+        )
     }
 
     /// Creates a new server for a given notify URI.
@@ -94,17 +182,53 @@ impl Server {
     ///
     /// This call will never fail but may leave the server marked as unusable
     /// if something goes wrong.
-    pub fn create(notify_uri: uri::Https, cache_dir: &Path) -> Self {
-        let (server_dir, broken) = match ServerDir::create(cache_dir) {
+    pub fn create(
+        notify_uri: uri::Https,
+        cache_dir: &Path,
+        options: Arc<ServerOptions>,
+    ) -> Self {
+        let (server_dir, broken) = match ServerDir::create(
+            cache_dir, &options
+        ) {
             Ok(server_dir) => (server_dir, false),
             Err(server_dir) => (server_dir, true),
         };
-        Self::new(notify_uri, server_dir, broken)
+        Self::new(notify_uri, server_dir, broken, options)
     }
 
     /// Create a new server that is considered broken.
-    pub fn create_broken(notify_uri: uri::Https) -> Self {
-        Self::new(notify_uri, ServerDir::broken(), true)
+    pub fn create_broken(
+        notify_uri: uri::Https,
+        options: Arc<ServerOptions>,
+    ) -> Self {
+        Self::new(notify_uri, ServerDir::broken(), true, options)
+    }
+
+    /// Creates a new server from an archive written by `export`.
+    ///
+    /// The local copy is written to a new server directory under
+    /// `cache_dir` and the server’s notify URI is taken from the state
+    /// contained in the archive. The import fails and the directory is
+    /// removed again if the digest of the data directory doesn’t match the
+    /// state.
+    pub fn import(
+        cache_dir: &Path,
+        options: Arc<ServerOptions>,
+        reader: impl io::Read,
+    ) -> Result<Self, Error> {
+        let server_dir = ServerDir::create(
+            cache_dir, &options
+        ).map_err(|_| Error)?;
+        match server_dir.import(reader) {
+            Ok(state) => {
+                server_dir.save_manifest();
+                Ok(Self::new(state.notify_uri, server_dir, false, options))
+            }
+            Err(_) => {
+                server_dir.remove();
+                Err(Error)
+            }
+        }
     }
 
     /// Returns a reference to the server directory.
@@ -117,585 +241,7478 @@ impl Server {
         self.updated.load(Relaxed)
     }
 
+    /// Returns whether the server is currently being updated.
+    ///
+    /// This never blocks. Other operations holding the update lock, such
+    /// as exporting the local copy, don’t count as an update.
+    pub fn is_updating(&self) -> bool {
+        self.updating.load(Relaxed)
+    }
+
+    /// Marks the server as current without updating it.
+    ///
+    /// The existing local copy will be used for the rest of the run.
+    pub fn keep_current(&self) {
+        self.updated.store(true, Relaxed)
+    }
+
+    /// Returns the session of the server’s local copy if there is one.
+    pub fn session(&self) -> Option<Uuid> {
+        self.load_state().ok().map(|state| state.session)
+    }
+
+    /// Returns the session and serial number of the server’s local copy.
+    ///
+    /// This only reads the state file. Returns `None` if there is no valid
+    /// state for the server.
+    pub fn current_state(&self) -> Option<(Uuid, u64)> {
+        if self.server_dir.is_placeholder() {
+            return None
+        }
+        self.load_state().ok().map(|state| (state.session, state.serial))
+    }
+
+    /// Returns the objects changed by the update during this run.
+    ///
+    /// For an update via deltas, these are the objects added, modified,
+    /// and withdrawn in the order the deltas were applied. An object
+    /// changed by more than one delta appears more than once. For an update
+    /// via the snapshot, all objects are reported as added. If the local
+    /// copy was current already, the list is empty.
+    ///
+    /// Returns `None` if the server hasn’t been updated successfully
+    /// during this run. In this case, nothing can be said about which
+    /// objects have changed.
+    pub fn changes(&self) -> Option<Vec<(uri::Rsync, ChangeKind)>> {
+        lock_recovered(&self.changes).clone()
+    }
+
+    /// Returns how the last update during this run was performed.
+    ///
+    /// Unlike the metrics, this is available while an update is running
+    /// and then describes the previous update.
+    pub fn last_update_kind(&self) -> UpdateKind {
+        UpdateKind::from_u8(self.last_update.load(Relaxed))
+    }
+
+    /// Records how an update was performed.
+    fn set_update_kind(
+        &self, metrics: &mut RrdpServerMetrics, kind: RrdpUpdateKind
+    ) {
+        metrics.update_kind = Some(kind);
+        self.last_update.store(UpdateKind::from(kind) as u8, Relaxed);
+    }
+
+    /// Returns a suggested delay before updating the server again.
+    ///
+    /// The delay is derived from the interval between serials observed
+    /// across runs, or is the configured refresh interval until one has
+    /// been observed. It includes some random jitter so that servers with
+    /// the same cadence aren’t all updated at once. This is advisory only.
+    pub fn next_update_after(&self) -> Duration {
+        self.options.cadence.next_update_after(
+            &self.notify_uri, self.options.refresh
+        )
+    }
+
     /// Makes sure the server is up-to-date.
     ///
     /// If the server already has been updated, does nothing. Otherwise starts
-    /// an update run.
-    pub fn update(&self, http: &HttpClient) {
+    /// an update run. In a read-only cache, only checks the local copy
+    /// instead.
+    pub fn update(&self, http: &dyn HttpClient) {
         // See if we need to update, get the lock, see if we still need to
         // update.
         if self.updated.load(Relaxed) {
             return
         }
-        let mut metrics = self.mutex.lock().unwrap();
+        let lock_start = self.options.clock.now();
+        let _lock = self.lock_update();
         if self.updated.load(Relaxed) {
             return
         }
-
-        let start_time = SystemTime::now();
-        if self.try_update(http, &mut metrics).is_err() && self.check_broken() {
-            let _ = fs::remove_dir_all(self.server_dir.base());
+        let mut metrics = lock_recovered(&self.metrics).clone();
+        metrics.lock_wait = Some(self.time_since(lock_start));
+        if self.options.read_only {
+            return self.read_only_update(&mut metrics)
         }
-        self.updated.store(true, Relaxed);
-        metrics.duration = SystemTime::now().duration_since(start_time);
+        if self.backing_off() {
+            self.broken.store(true, Relaxed);
+            self.set_update_kind(&mut metrics, RrdpUpdateKind::Failed);
+            *lock_recovered(&self.metrics) = metrics;
+            self.updated.store(true, Relaxed);
+            return
+        }
+        self.locked_update(http, &mut metrics, false)
     }
 
-    /// Performs the actual update.
+    /// Returns whether the server was found broken too recently.
     ///
-    /// Returns an error if the update fails.
-    fn try_update(
-        &self,
-        http: &HttpClient,
-        metrics: &mut RrdpServerMetrics
-    ) -> Result<(), Error> {
-        info!("RRDP {}: Updating server", self.notify_uri);
-        metrics.serial = None;
-        let notify = http.notification_file(
-            &self.notify_uri, &mut metrics.notify_status
-        )?;
-        if self.delta_update(&notify, http, metrics).is_ok() {
-            info!("RRDP {}: Delta update succeeded.", self.notify_uri);
-            return Ok(())
+    /// A server whose local copy had to be removed isn’t tried again
+    /// before the configured backoff time has passed, even if the process
+    /// has been restarted in between.
+    fn backing_off(&self) -> bool {
+        let until = match self.options.backoff.retry_after(&self.notify_uri) {
+            Some(until) => until,
+            None => return false
+        };
+        let now = self.options.clock.system_now();
+        if until <= now {
+            return false
         }
-        self.snapshot_update(&notify, http, metrics)
+        info!(
+            "RRDP {}: server was broken recently. Not retrying for another \
+             {} seconds.",
+            self.notify_uri,
+            until.duration_since(now).unwrap_or_default().as_secs()
+        );
+        true
     }
 
-    /// Try updating via the deltas.
-    fn delta_update(
-        &self,
-        notify: &NotificationFile,
-        http: &HttpClient,
-        metrics: &mut RrdpServerMetrics
-    ) -> Result<(), Error> {
-        let mut state = ServerState::load(self.server_dir.state_path())?;
-        let deltas = match Self::calc_deltas(notify, &state)? {
-            Some(deltas) => deltas,
-            None => {
-                return self.server_dir.check_digest(&state.hash)
+    /// Allows the server to be updated again right away.
+    ///
+    /// This clears the time before which a broken server isn’t retried.
+    /// Updating via `force_snapshot` ignores that time anyway.
+    pub fn clear_backoff(&self) {
+        self.options.backoff.clear(&self.notify_uri)
+    }
+
+    /// Checks the local copy in place of an update in a read-only cache.
+    ///
+    /// Nothing is written. If the local copy is unusable, the server is
+    /// marked as broken for this run.
+    fn read_only_update(&self, metrics: &mut RrdpServerMetrics) {
+        let start_time = self.options.clock.system_now();
+        match self.verify() {
+            Ok(state) => {
+                metrics.serial = Some(state.serial);
+                metrics.object_count = state.object_count;
+                self.set_update_kind(metrics, RrdpUpdateKind::NotNeeded);
+                *lock_recovered(&self.changes) = Some(Vec::new());
             }
-        };
-        let targets = self.collect_delta_targets(
-            &state, notify, deltas, http
-        );
-        let targets = match targets {
-            Ok(targets) => targets,
             Err(_) => {
-                return Err(Error)
+                warn!(
+                    "RRDP {}: local copy in read-only cache is unusable.",
+                    self.notify_uri
+                );
+                self.broken.store(true, Relaxed);
+                metrics.serial = None;
+                metrics.object_count = None;
+                self.set_update_kind(metrics, RrdpUpdateKind::Failed);
             }
-        };
-        self.server_dir.check_digest(&state.hash)?;
-        if targets.apply().is_err() {
-            return Err(Error);
         }
-        state.serial = notify.serial;
-        state.hash = match self.server_dir.digest() {
-            Ok(hash) => hash.into(),
-            Err(_) => {
-                return Err(Error);
-            }
+        metrics.duration = {
+            self.options.clock.system_now().duration_since(start_time)
         };
-        if state.save(self.server_dir.state_path()).is_err() {
-            return Err(Error);
-        }
-        metrics.serial = Some(state.serial);
-        Ok(())
+        *lock_recovered(&self.metrics) = metrics.clone();
+        self.updated.store(true, Relaxed);
     }
 
-    /// Calculates the slice of deltas to follow for updating.
+    /// Updates the server from its snapshot, ignoring any deltas.
     ///
-    /// Returns an error if there is no way to delta update. Returns `Ok(None)`
-    /// if no update is necessary. Returns a slice if a delta update should be
-    /// done.
-    fn calc_deltas<'a>(
-        notify: &'a NotificationFile,
-        state: &ServerState
-    ) -> Result<Option<&'a [(u64, UriAndHash)]>, Error> {
-        if notify.session_id != state.session {
-            info!("New session. Need to get snapshot.");
-            return Err(Error);
+    /// Unlike `update`, this always fetches the notification file and the
+    /// full snapshot, even if the server has been updated during this run
+    /// already. This is the recommended way to recover a server whose
+    /// local copy keeps being flagged by `check_broken`, for instance
+    /// because of a subtly corrupt delta chain. If the update succeeds, the
+    /// server isn’t considered broken any more.
+    pub fn force_snapshot(&self, http: &dyn HttpClient) {
+        let lock_start = self.options.clock.now();
+        let _lock = self.lock_update();
+        let mut metrics = lock_recovered(&self.metrics).clone();
+        metrics.lock_wait = Some(self.time_since(lock_start));
+        if self.options.read_only {
+            info!(
+                "RRDP {}: cache is read-only. Cannot update from snapshot.",
+                self.notify_uri
+            );
+            return
         }
-        info!("Serials: us {}, them {}", state.serial, notify.serial);
-        if notify.serial == state.serial {
-            return Ok(None);
+        if self.server_dir.is_placeholder() {
+            info!(
+                "RRDP {}: no local directory. Cannot update from snapshot.",
+                self.notify_uri
+            );
+            return
         }
+        self.locked_update(http, &mut metrics, true)
+    }
 
-        // If there is no last delta (remember, we have a different
-        // serial than the notification file) or if the last delta’s
-        // serial differs from that noted in the notification file,
-        // bail out.
-        if notify.deltas.last().map(|delta| delta.0) != Some(notify.serial) {
-            info!("Last delta serial differs from current serial.");
+    /// Replaces the local copy with the content of a local snapshot file.
+    ///
+    /// This allows bootstrapping a server without fetching its snapshot,
+    /// e.g., from a pre-populated cache image. The snapshot in the file at
+    /// `path` has to have the given session and serial number. Since there
+    /// is no notification file, the file’s content is trusted as is. The
+    /// objects are written to a new directory under `tmp_dir` which then
+    /// replaces the local copy the same way as for an update via the
+    /// snapshot. The next update can thus continue with the deltas
+    /// following `serial`.
+    pub fn seed_from_snapshot(
+        &self, path: &Path, session: Uuid, serial: u64, tmp_dir: &Path
+    ) -> Result<(), Error> {
+        let _lock = self.lock_update();
+        if self.options.read_only {
+            info!(
+                "RRDP {}: cache is read-only. Cannot seed from snapshot.",
+                self.notify_uri
+            );
             return Err(Error)
         }
-
-        let mut deltas = notify.deltas.as_slice();
-        let serial = match state.serial.checked_add(1) {
-            Some(serial) => serial,
-            None => return Err(Error)
-        };
-        loop {
-            let first = match deltas.first() {
-                Some(first) => first,
-                None => {
-                    info!("Ran out of deltas.");
-                    return Err(Error)
-                }
-            };
-            match first.0.cmp(&serial) {
-                cmp::Ordering::Greater => {
-                    info!("First delta is too new ({})", first.0);
-                    return Err(Error)
-                }
-                cmp::Ordering::Equal => break,
-                cmp::Ordering::Less => deltas = &deltas[1..]
-            }
+        if self.server_dir.is_placeholder() {
+            info!(
+                "RRDP {}: no local directory. Cannot seed from snapshot.",
+                self.notify_uri
+            );
+            return Err(Error)
         }
-        Ok(Some(deltas))
-    }
-
-    /// Performs a delta update in the temporary location.
-    fn collect_delta_targets(
-        &self,
-        state: &ServerState,
-        notify: &NotificationFile,
-        deltas: &[(u64, UriAndHash)],
-        http: &HttpClient
-    ) -> Result<DeltaTargets, Error> {
-        self.server_dir.check_digest(&state.hash)?;
-        let mut targets = DeltaTargets::new(http.tmp_dir())?;
-        for delta in deltas {
-            http.delta(
-                &self.notify_uri, notify, delta, &mut targets,
-                |uri| self.server_dir.uri_path(uri)
-            )?
+        self.server_dir.recover();
+        let tmp_dir = ServerDir::create(
+            tmp_dir, &self.options
+        ).map_err(|_| Error)?;
+        if self.seed_into_tmp(path, session, serial, &tmp_dir).is_err() {
+            tmp_dir.remove();
+            return Err(Error)
         }
-        Ok(targets)
-    }
-
-    /// Try updating via the snapshot.
-    fn snapshot_update(
-        &self,
-        notify: &NotificationFile,
-        http: &HttpClient,
-        metrics: &mut RrdpServerMetrics
-    ) -> Result<(), Error> {
-        info!("RRDP {}: updating from snapshot.", self.notify_uri);
-        let tmp_dir = ServerDir::create(http.tmp_dir()).map_err(|_| Error)?;
-        let state =  match self.snapshot_into_tmp(notify, http, &tmp_dir) {
-            Ok(state) => state,
-            Err(_) => {
-                let _ = fs::remove_dir_all(tmp_dir.base());
-                return Err(Error);
-            }
-        };
         self.move_from_tmp(tmp_dir)?;
-        metrics.serial = Some(state.serial);
+        self.broken.store(false, Relaxed);
+        *lock_recovered(&self.changes) = None;
+        *lock_recovered(&self.object_hashes) = None;
+        lock_recovered(&self.verified).clear();
+        info!(
+            "RRDP {}: seeded from snapshot file '{}' with serial {}.",
+            self.notify_uri, path.display(), serial
+        );
         Ok(())
     }
 
-    fn snapshot_into_tmp(
-        &self,
-        notify: &NotificationFile,
-        http: &HttpClient,
-        tmp_dir: &ServerDir,
-    ) -> Result<ServerState, Error> {
-        http.snapshot(notify, |uri| tmp_dir.uri_path(uri))?;
-        let state = ServerState {
+    /// Writes the content of a local snapshot file into `tmp_dir`.
+    fn seed_into_tmp(
+        &self, path: &Path, session: Uuid, serial: u64, tmp_dir: &ServerDir
+    ) -> Result<(), Error> {
+        let hashes = snapshot_from_file(
+            path, session, serial, |uri| tmp_dir.uri_path(uri),
+            tmp_dir.compress
+        )?;
+        hashes.save(&*tmp_dir.store, &tmp_dir.hashes_path())?;
+        ServerState {
             notify_uri: self.notify_uri().clone(),
-            session: notify.session_id,
-            serial: notify.serial,
+            session,
+            serial,
             hash: tmp_dir.digest()?.into(),
-        };
-        state.save(tmp_dir.state_path())?;
-        Ok(state)
+            object_count: Some(hashes.len()),
+            algorithm: tmp_dir.algorithm,
+            deep_digest: tmp_dir.deep,
+            compressed: tmp_dir.compress,
+            digest_version: DigestVersion::CURRENT,
+            validators: Default::default(),
+            past_sessions: self.past_sessions(session),
+        }.save(&*tmp_dir.store, tmp_dir.state_path())?;
+        tmp_dir.save_manifest();
+        Ok(())
     }
 
-    /// Moves everything back from a temporary directory.
-    fn move_from_tmp(&self, tmp_dir: ServerDir) -> Result<(), Error> {
-        let _ = fs::remove_file(self.server_dir.state_path());
-        let state_res = fs::rename(
-            tmp_dir.state_path(), self.server_dir.state_path()
-        ).map_err(|err| {
-            info!(
-                "Failed to move RRDP state file '{}' from temporary location \
-                '{}': {}.",
-                self.server_dir.state_path().display(),
-                tmp_dir.state_path().display(),
-                err
-            );
-            Error
-        });
-        let _ = fs::remove_dir_all(self.server_dir.data_path());
-        let data_res = fs::rename(
-            tmp_dir.data_path(), self.server_dir.data_path()
-        ).map_err(|err| {
-            info!(
-                "Failed to move RRDP data directory '{}' from temporary \
-                 location '{}': {}.",
-                self.server_dir.data_path().display(),
-                tmp_dir.data_path().display(),
-                err
+    /// Acquires the update lock.
+    ///
+    /// If an earlier update panicked while holding the lock, the lock is
+    /// recovered rather than the panic propagated. This is safe since the
+    /// lock doesn’t protect any data. All the actual state is kept in
+    /// atomics or on disk.
+    fn lock_update(&self) -> MutexGuard<()> {
+        self.mutex.lock().unwrap_or_else(|err| {
+            warn!(
+                "RRDP {}: recovering from an earlier failed update.",
+                self.notify_uri
             );
-            Error
-        });
-        let _ = fs::remove_dir_all(tmp_dir.base());
-        if state_res.is_err() || data_res.is_err() {
-            Err(Error)
-        }
-        else {
-            Ok(())
-        }
+            err.into_inner()
+        })
     }
 
-    /// Checks whether the server in its current state is usable.
-    ///
-    /// For a server to be usable, it has to have a state file that can be
-    /// read and its hash must match the current digest of the directory.
+    /// Performs an update while holding the update lock.
     ///
-    /// Assumes that the server isn’t currently marked broken and sets the
-    /// `broken` flag if anything is fishy.
-    fn check_broken(&self) -> bool {
-        let state = match ServerState::load(self.server_dir.state_path()) {
-            Ok(state) => state,
-            Err(_) => {
-                info!(
-                    "Marking RRDP server '{}' as unusable",
-                    self.notify_uri
+    /// If `snapshot` is `true`, the deltas are skipped. Before starting,
+    /// waits for a permit from the HTTP client so that only a limited
+    /// number of servers update at the same time. The time spent waiting
+    /// doesn’t count towards the update’s duration or deadline. Once done,
+    /// the metrics are published.
+    fn locked_update(
+        &self,
+        http: &dyn HttpClient,
+        metrics: &mut RrdpServerMetrics,
+        snapshot: bool,
+    ) {
+        let _updating = UpdatingFlag::set(&self.updating);
+        let _permit = http.acquire_update();
+        if self.server_dir.recover() {
+            self.invalidate_digest();
+            self.load_cache.clear();
+        }
+        *lock_recovered(&self.changes) = None;
+        let start_time = self.options.clock.system_now();
+        let mut timeline = Timeline::with_clock(
+            self.notify_uri.clone(), self.options.clock.clone()
+        );
+        timeline.set_deadline(
+            self.options.deadlines.get(&self.notify_uri).cloned()
+        );
+        timeline.set_deadline_warning(self.options.deadline_warning);
+        http.set_deadline(&self.notify_uri, timeline.deadline_at());
+        let work_start = self.options.clock.now();
+        let res = if snapshot {
+            self.try_snapshot(http, metrics, &mut timeline)
+        }
+        else {
+            self.try_update(http, metrics, &mut timeline)
+        };
+        http.set_deadline(&self.notify_uri, None);
+        timeline.finish(res.is_ok());
+        metrics.deadline_warning = timeline.deadline_warning();
+        self.set_update_kind(
+            metrics, Self::update_kind(res.is_ok(), &timeline)
+        );
+        metrics.bytes_downloaded = http.take_downloaded(&self.notify_uri);
+        metrics.object_count = if res.is_ok() {
+            self.load_state().ok().and_then(|state| {
+                state.object_count
+            })
+        }
+        else {
+            None
+        };
+        if self.options.log_timeline {
+            info!("RRDP timeline: {}", timeline);
+        }
+        // Only remove the local copy if it is unusable. Otherwise we keep
+        // using it until the server can be updated again.
+        if res.is_err() {
+            if self.check_broken() {
+                self.invalidate_digest();
+                self.server_dir.remove();
+                self.options.backoff.mark(
+                    &self.notify_uri, self.options.clock.system_now()
                 );
-                self.broken.store(true, Relaxed);
-                return true;
             }
-        };
-        let digest = match self.server_dir.digest() {
-            Ok(digest) => digest,
-            Err(_) => {
-                info!(
-                    "Cannot digest RRDP server directory for '{}'. \
-                    Marking as unsable.",
+            else if !self.broken.load(Relaxed) {
+                warn!(
+                    "RRDP {}: update failed. Using the existing local copy.",
                     self.notify_uri
                 );
-                self.broken.store(true, Relaxed);
-                return true;
             }
-        };
-        if verify_slices_are_equal(digest.as_ref(), state.hash.as_ref())
-                                                                   .is_err() {
-            info!(
-                "Hash for RRDP server directory for '{}' doesn’t match. \
-                Marking as unusable.",
-                self.notify_uri
-            );
-            self.broken.store(true, Relaxed);
-            true
         }
         else {
-            false
+            self.broken.store(false, Relaxed);
+            self.options.backoff.clear(&self.notify_uri);
+            if let Some((session, serial)) = self.current_state() {
+                self.options.cadence.update(
+                    &self.notify_uri, session, serial,
+                    self.options.clock.now()
+                );
+            }
         }
+        metrics.work_time = Some(self.time_since(work_start));
+        *lock_recovered(&self.object_hashes) = None;
+        lock_recovered(&self.verified).clear();
+        metrics.duration = {
+            self.options.clock.system_now().duration_since(start_time)
+        };
+        *lock_recovered(&self.metrics) = metrics.clone();
+        self.updated.store(true, Relaxed);
     }
 
-    /// Returns a reference to the server’s notify URI.
-    pub fn notify_uri(&self) -> &uri::Https {
-        &self.notify_uri
+    /// Returns the time passed since `start` according to the clock.
+    fn time_since(&self, start: Instant) -> Duration {
+        self.options.clock.now().saturating_duration_since(start)
     }
 
-    /// Returns whether the server is broken.
-    pub fn is_broken(&self) -> bool {
-        self.broken.load(Relaxed)
-    }
-
-    /// Tries to load a file from this server.
+    /// Returns the level for logging the progress of an update.
     ///
-    /// This assumes that the server is updated already. If there is no file
-    /// corresponding to the URI, returns `None`.
-    #[allow(clippy::verbose_file_reads)]
-    pub fn load_file(&self, uri: &uri::Rsync) -> Result<Option<Bytes>, Error> {
-        if self.broken.load(Relaxed) {
-            return Err(Error)
+    /// If a timeline is logged for each update, it replaces the progress
+    /// messages, so these are only logged at debug level.
+    fn progress_level(&self) -> Level {
+        if self.options.log_timeline {
+            Level::Debug
         }
-        
-        let path = self.server_dir.uri_path(uri);
-        let mut file = match fs::File::open(&path) {
-            Ok(file) => file,
-            Err(err) => {
-                if err.kind() == io::ErrorKind::NotFound {
-                    info!("{} not found in its RRDP repository.", uri);
-                }
-                else {
-                    warn!(
-                        "Failed to open file '{}': {}.",
-                        path.display(), err
-                    );
-                }
-                return Ok(None)
-            }
-        };
-        let mut data = Vec::new();
-        if let Err(err) = file.read_to_end(&mut data) {
-            warn!(
-                "Failed to read file '{}': {}",
-                path.display(), err
-            );
-            return Ok(None)
+        else {
+            Level::Info
         }
-        Ok(Some(data.into()))
     }
 
-    /// Removes the server’s local cache if it hasn’t been used.
-    ///
-    /// Returns whether it indeed removed the cache.
-    pub fn remove_unused(&self) -> bool {
-        if self.updated.load(Relaxed) && !self.broken.load(Relaxed) {
-            return false
+    /// Determines the kind of update performed for the metrics.
+    fn update_kind(ok: bool, timeline: &Timeline) -> RrdpUpdateKind {
+        match (ok, timeline.path()) {
+            (false, _) | (true, None) => RrdpUpdateKind::Failed,
+            (true, Some(UpdatePath::Current)) => RrdpUpdateKind::NotNeeded,
+            (true, Some(UpdatePath::Delta)) => RrdpUpdateKind::Delta,
+            (true, Some(UpdatePath::Snapshot)) => RrdpUpdateKind::Snapshot,
         }
-        let _ = fs::remove_dir_all(self.server_dir.base());
-        true
     }
 
-    /// Return the server metrics if the server was ever updated.
-    pub fn metrics(&self) -> Option<RrdpServerMetrics> {
-        if self.updated.load(Relaxed) {
-            match self.mutex.try_lock() {
-                Ok(metrics) => Some(metrics.clone()),
-                Err(err) => {
-                    panic!("Failed to acquire metrics lock: {}", err);
+    /// Performs the actual update.
+    ///
+    /// Returns an error if the update fails.
+    fn try_update(
+        &self,
+        http: &dyn HttpClient,
+        metrics: &mut RrdpServerMetrics,
+        timeline: &mut Timeline,
+    ) -> Result<(), Error> {
+        log!(
+            self.progress_level(), "RRDP {}: Updating server", self.notify_uri
+        );
+        metrics.serial = None;
+        let state = self.load_state().ok();
+        self.emit(EventKind::UpdateStart, &[]);
+
+        // Only ask whether the notification file has changed if we could
+        // use our local copy as is.
+        let validators = state.as_ref().filter(|state| {
+            state.algorithm == self.options.local_digest
+            && state.deep_digest == self.options.deep_digest
+        }).map(|state| &state.validators).filter(|validators| {
+            !validators.is_empty()
+        });
+        let response = match self.fetch_notification(
+            http, validators, metrics, timeline
+        )? {
+            Some(response) => response,
+            None => {
+                // We checked above that there is a state.
+                let state = state.as_ref().ok_or(Error)?;
+                timeline.set_path(UpdatePath::Current);
+                timeline.set_serial(state.serial);
+                metrics.serial = Some(state.serial);
+                if timeline.step("digest", || {
+                    self.recheck_digest(state)
+                }).is_ok() {
+                    self.upgrade_digest(state);
+                    *lock_recovered(&self.changes) = Some(Vec::new());
+                    return Ok(())
                 }
+                info!(
+                    "RRDP {}: Local copy doesn’t match unchanged \
+                     notification file. Fetching it again.",
+                    self.notify_uri
+                );
+                metrics.serial = None;
+                self.fetch_notification(
+                    http, None, metrics, timeline
+                )?.ok_or(Error)?
             }
+        };
+        let notify = &response.notify;
+        self.accept_notification(notify, state.as_ref(), false)?;
+        // Any failure of the delta update, including a delta request timing
+        // out, falls back to the snapshot.
+        let res = match self.delta_update(
+            notify, state, http, metrics, timeline
+        ) {
+            Ok(()) => {
+                log!(
+                    self.progress_level(),
+                    "RRDP {}: Delta update succeeded.", self.notify_uri
+                );
+                Ok(())
+            }
+            Err(failure) => {
+                if failure != DeltaFailure::NoState {
+                    info!(
+                        "RRDP {}: Delta update failed: {}. \
+                         Falling back to snapshot.",
+                        self.notify_uri, failure
+                    );
+                }
+                timeline.check_deadline()?;
+                self.snapshot_update(notify, http, metrics, timeline)
+            }
+        };
+        if res.is_ok() {
+            self.record_notification(response);
         }
-        else {
-            None
-        }
+        res
     }
-}
-
-
-//------------ ServerDir -----------------------------------------------------
 
-#[derive(Clone, Debug)]
-struct ServerDir {
-    base: PathBuf,
-    state: PathBuf,
-}
+    /// Performs an update via the snapshot only.
+    fn try_snapshot(
+        &self,
+        http: &dyn HttpClient,
+        metrics: &mut RrdpServerMetrics,
+        timeline: &mut Timeline,
+    ) -> Result<(), Error> {
+        debug!("RRDP {}: Forcing snapshot update", self.notify_uri);
+        metrics.serial = None;
 
-impl ServerDir {
-    fn new(base: PathBuf) -> Self {
-        ServerDir {
-            state: base.join("state.txt"),
-            base
+        // The directory is gone if the server was found broken before.
+        let base = self.server_dir.base();
+        if let Err(err) = self.server_dir.store.create_dir_all(base) {
+            info!(
+                "Failed to create RRDP server directory {}: {}.",
+                self.server_dir.base().display(), err
+            );
+            return Err(Error)
         }
+        let response = self.fetch_notification(
+            http, None, metrics, timeline
+        )?.ok_or(Error)?;
+        // A forced snapshot is asked for explicitly, so only the session
+        // pin applies.
+        self.accept_notification(&response.notify, None, false)?;
+        self.snapshot_update(&response.notify, http, metrics, timeline)?;
+        self.record_notification(response);
+        Ok(())
     }
 
-    fn broken() -> Self {
-        ServerDir {
-            base: PathBuf::new(),
-            state: PathBuf::new()
+    /// Checks whether an update from the notification file is acceptable.
+    ///
+    /// Both updates and checks go through here so that they refuse the
+    /// same notification files. If `dry_run` is `true`, the messages say
+    /// that an update would be refused rather than that it is.
+    fn accept_notification(
+        &self,
+        notify: &NotificationFile,
+        state: Option<&ServerState>,
+        dry_run: bool,
+    ) -> Result<(), Error> {
+        let refusal = if dry_run {
+            "Update would be refused."
+        }
+        else {
+            "Refusing update and keeping current data."
+        };
+        self.check_session_pin(notify, refusal)?;
+        if let Some(state) = state {
+            if Self::is_replay(notify, state) {
+                warn!(
+                    "RRDP {}: switching from session {} back to earlier \
+                     session {} with serial {} which isn’t newer than what \
+                     we saw before. This may be a replay of old data.",
+                    self.notify_uri, state.session, notify.session_id,
+                    notify.serial
+                );
+                if self.options.replay_policy == ReplayPolicy::Refuse {
+                    warn!("RRDP {}: {}", self.notify_uri, refusal);
+                    return Err(Error)
+                }
+            }
         }
+        Ok(())
     }
 
-    fn create(cache_dir: &Path) -> Result<Self, Self> {
-        match create_unique_dir(cache_dir) {
-            Ok(path) => Ok(ServerDir::new(path)),
-            Err(_) => Err(ServerDir::broken())
+    /// Checks that the notification file has the pinned session if any.
+    ///
+    /// A publisher resetting its session by accident forces a download of
+    /// the full snapshot. Operators can avoid this by pinning a server to
+    /// its session. Such a server is only ever updated from that session.
+    fn check_session_pin(
+        &self, notify: &NotificationFile, refusal: &str
+    ) -> Result<(), Error> {
+        match self.options.session_pins.get(&self.notify_uri) {
+            Some(pin) if *pin != notify.session_id => {
+                warn!(
+                    "RRDP {}: session {} differs from pinned session {}. {}",
+                    self.notify_uri, notify.session_id, pin, refusal
+                );
+                Err(Error)
+            }
+            _ => Ok(())
         }
-   }
-
-    fn base(&self) -> &Path {
-        &self.base
     }
 
-    fn state_path(&self) -> &Path {
-        &self.state
+    /// Records a notification file after a successful update.
+    fn record_notification(&self, response: NotificationResponse) {
+        if let Some(keep) = self.options.keep_notifications {
+            self.server_dir.store_notification(&response.raw, keep);
+        }
+        self.save_validators(response.validators);
     }
 
-    fn data_path(&self) -> PathBuf {
-        self.base.join("data")
+    /// Fetches the notification file.
+    ///
+    /// If `validators` are given, the request is conditional and `Ok(None)`
+    /// is returned if the file hasn’t changed.
+    fn fetch_notification(
+        &self,
+        http: &dyn HttpClient,
+        validators: Option<&CacheValidators>,
+        metrics: &mut RrdpServerMetrics,
+        timeline: &mut Timeline,
+    ) -> Result<Option<NotificationResponse>, Error> {
+        let notify_status = &mut metrics.notify_status;
+        let notify_uri = &self.notify_uri;
+        timeline.step("notification", || {
+            http.conditional_notification_file(
+                notify_uri, validators, notify_status
+            ).map_err(|err| {
+                if err == RequestError::Timeout {
+                    warn!(
+                        "RRDP {}: notification file timed out. \
+                         Keeping current data.",
+                        notify_uri
+                    );
+                }
+                Error
+            })
+        })
     }
 
-    fn module_path(&self, module: &uri::RsyncModule) -> PathBuf {
-        let mut res = self.data_path();
-        res.push(module.authority());
-        res.push(module.module());
-        res
+    /// Records the validators of the notification file in the state.
+    ///
+    /// The state file is only written if they have changed. Failing to do
+    /// so only means the next request won’t be conditional.
+    fn save_validators(&self, validators: CacheValidators) {
+        let mut state = match self.load_state() {
+            Ok(state) => state,
+            Err(_) => return
+        };
+        if state.validators != validators {
+            state.validators = validators;
+            let _ = self.server_dir.save_state(&state);
+        }
     }
 
-    fn uri_path(&self, uri: &uri::Rsync) -> PathBuf {
-        let mut res = self.module_path(uri.module());
-        res.push(uri.path());
-        res
-    }
+    /// Try updating via the deltas.
+    ///
+    /// Returns the reason if the server can’t be updated this way.
+    fn delta_update(
+        &self,
+        notify: &NotificationFile,
+        state: Option<ServerState>,
+        http: &dyn HttpClient,
+        metrics: &mut RrdpServerMetrics,
+        timeline: &mut Timeline,
+    ) -> Result<(), DeltaFailure> {
+        let mut state = state.ok_or(DeltaFailure::NoState)?;
+        let start_serial = state.serial;
+        let deltas = match self.plan_deltas(notify, &state) {
+            Ok(Some(deltas)) => deltas,
+            Ok(None) => {
+                timeline.set_path(UpdatePath::Current);
+                timeline.set_serial(state.serial);
+                timeline.step("digest", || {
+                    self.recheck_digest(&state)
+                }).map_err(|_| DeltaFailure::DigestMismatch)?;
+                self.upgrade_digest(&state);
+                *lock_recovered(&self.changes) = Some(Vec::new());
+                return Ok(())
+            }
+            Err(DeltaFailure::SerialGap) => {
+                self.emit(EventKind::SerialGap, &[
+                    ("serial", state.serial), ("serverSerial", notify.serial)
+                ]);
+                return Err(DeltaFailure::SerialGap)
+            }
+            Err(failure) => return Err(failure),
+        };
+        timeline.set_path(UpdatePath::Delta);
 
-    /// Determines the digest of a data directory.
-    pub fn digest(&self) -> Result<digest::Digest, Error> {
-        self._digest().map_err(|err| {
-            info!(
-                "Failed to caculate digest for '{}': {}",
-                self.data_path().display(), err
-            );
-            Error
-        })
+        // Without checkpoints, all deltas form one single chunk.
+        let chunk_len = match self.options.delta_checkpoint {
+            Some(len) => len,
+            None => deltas.len()
+        };
+        let mut changes = Vec::new();
+        for chunk in deltas.chunks(cmp::max(chunk_len, 1)) {
+            let targets = self.collect_delta_targets(
+                &state, notify, chunk, http, timeline
+            )?;
+            let serial = match chunk.last() {
+                Some(delta) => delta.0,
+                None => continue
+            };
+            timeline.step("commit", || {
+                self.commit_deltas(&mut state, targets, serial, &mut changes)
+            }).map_err(|_| DeltaFailure::Local)?;
+            if chunk.len() < deltas.len() {
+                debug!(
+                    "RRDP {}: checkpoint at serial {}.",
+                    self.notify_uri, serial
+                );
+            }
+        }
+        timeline.set_serial(state.serial);
+        metrics.serial = Some(state.serial);
+        metrics.deltas_applied = Some(deltas.len());
+        metrics.delta_serials = Some((start_serial, state.serial));
+        *lock_recovered(&self.changes) = Some(changes);
+        self.emit(EventKind::DeltaSuccess, &[
+            ("serial", state.serial), ("deltas", deltas.len() as u64)
+        ]);
+        Ok(())
     }
 
-    fn _digest(&self) -> Result<digest::Digest, io::Error> {
-        // A vec to keep the sorted content of a directory.
-        //
-        // When iterating a directory, we push the directories and regular
-        // files into this vec as pairs. The first item in the pair is the
-        // file name within the parent directory. The second item is a result.
-        // Directories will have `Ok(path)` where `path` is their full path.
-        // Regular files will have `Err(len)` where `len` is their file size.
-        //
-        // After adding, will sort by the file name and then hash the entries.
-        // For each item we hash the name. For files we also hash the size.
-        let mut entries = Vec::new();
-
-        // A stack with the directories we still have to process.
-        //
-        // The paths of directories in `entries` are pushed to the back of this
-        // vec in their sorted order. When we are done with one directory, we
-        // take the last one off the stack and process it. Rince and repeat
-        // until the stack is empty.
-        //
-        // We start with the data directory itself.
-        let mut dirs = vec![self.data_path()];
-
-        // The digest context.
-        let mut context = digest::Context::new(&digest::SHA256);
-
-        while let Some(dir) = dirs.pop() {
-            for entry in dir.read_dir()? {
-                let entry = entry?;
-                let metadata = entry.metadata()?;
-                let name = entry.file_name();
-                if metadata.is_dir() {
-                    entries.push((name, Ok(entry.path())))
-                }
-                else if metadata.is_file() {
-                    entries.push((name, Err(metadata.len())))
-                }
+    /// Applies the collected changes of deltas to the server directory.
+    ///
+    /// Updates the object hashes and the state for the new serial number.
+    /// Afterwards, the directory is consistent again for this serial and
+    /// its digest counts as verified. The changed objects are added to
+    /// `uris`.
+    fn commit_deltas(
+        &self,
+        state: &mut ServerState,
+        targets: DeltaTargets,
+        serial: u64,
+        uris: &mut Vec<(uri::Rsync, ChangeKind)>,
+    ) -> Result<(), Error> {
+        let hashes = match self.server_dir.load_hashes() {
+            Ok(Some(mut hashes)) => {
+                targets.update_hashes(&mut hashes);
+                Some(hashes)
             }
-            entries.sort_by(|left, right| left.0.cmp(&right.0));
-
-            for (name, other) in entries.drain(..) {
-                context.update(name.to_string_lossy().as_bytes());
-                
-                match other {
-                    Ok(path) => dirs.push(path),
-                    Err(len) => context.update(&len.to_ne_bytes()),
-                }
+            _ => None
+        };
+        self.invalidate_digest();
+        let changes = targets.apply(&mut state.object_count, uris);
+        self.load_cache.clear();
+        let changes = match changes {
+            Ok(changes) => changes,
+            Err(_) => return Err(Error)
+        };
+        self.server_dir.commit()?;
+        if self.options.fsync {
+            self.server_dir.sync_changes(&changes)?;
+        }
+        self.server_dir.save_hashes(hashes.as_ref());
+        state.serial = serial;
+        state.digest_version = DigestVersion::CURRENT;
+        state.hash = match self.server_dir.applied_digest(&changes) {
+            Ok(hash) => hash.into(),
+            Err(_) => {
+                return Err(Error);
             }
+        };
+        self.server_dir.save_state(state)?;
+        // The digest was derived from the content of the directory, so the
+        // next chunk doesn’t need to check it again.
+        self.digest_verified.store(true, Relaxed);
+        self.server_dir.save_manifest();
+        if self.options.fsync {
+            self.server_dir.sync_base()?;
         }
-        Ok(context.finish())
+        // Deduplication links files, so it needs them to be local.
+        self.options.dedup.link_files(
+            changes.iter().filter_map(|(path, size)| {
+                size.map(|_| path.as_path())
+            }).filter(|path| self.server_dir.store.is_local(path))
+        );
+        Ok(())
     }
 
-    /// Checks that the digest of the data directory matches the given one.
-    pub fn check_digest(&self, hash: &DigestHex) -> Result<(), Error> {
-        let digest = self.digest()?;
-        verify_slices_are_equal(digest.as_ref(), hash.as_ref()).map_err(|_| {
-            info!(
-                "Mismatch of digest for '{}'. Content must have changed.",
-                self.data_path().display()
-            );
-            Error
-        })
+    /// Checks whether a notification file looks like a replay.
+    ///
+    /// This is the case if the notification file switches back to a session
+    /// the server has used before and has a serial number no higher than
+    /// the last one we have seen for that session. A session we haven’t
+    /// seen before is a legitimate reset no matter its serial number.
+    fn is_replay(notify: &NotificationFile, state: &ServerState) -> bool {
+        notify.session_id != state.session
+            && state.past_sessions.iter().any(|&(session, serial)| {
+                session == notify.session_id && notify.serial <= serial
+            })
     }
-}
-
-
-//------------ ServerState ---------------------------------------------------
-
-#[derive(Clone, Debug)]
-pub struct ServerState {
-    /// The notify URI of the server.
-    pub notify_uri: uri::Https,
-
-    /// The UUID of the current session of this server.
-    pub session: Uuid,
-
-    /// The serial number representing the current state of the server.
-    pub serial: u64,
 
-    /// A hash over the expected local state of the server.
-    pub hash: DigestHex,
-}
+    /// Returns the past sessions to record for a new local copy.
+    ///
+    /// If there is no usable state, no past sessions are known.
+    fn past_sessions(&self, session: Uuid) -> Vec<(Uuid, u64)> {
+        self.load_state().map(|state| {
+            state.past_sessions_for(session)
+        }).unwrap_or_default()
+    }
 
-impl ServerState {
-    pub fn load(path: &Path) -> Result<Self, Error> {
-        Self::_load(path).map_err(|err| {
-            // Not found is mostly normal, don’t complain about that.
-            if err.kind() != io::ErrorKind::NotFound {
+    /// Determines the deltas to follow for updating this server.
+    ///
+    /// This is the same as [`calc_deltas`] except that it refuses to use
+    /// deltas for servers configured to always update via the snapshot
+    /// and if there are more deltas to follow than configured.
+    /// It also refuses if the local copy was digested with a different
+    /// algorithm or digest mode than the configured one so that the copy is
+    /// rebuilt from the snapshot.
+    ///
+    /// [`calc_deltas`]: #method.calc_deltas
+    fn plan_deltas(
+        &self,
+        notify: &NotificationFile,
+        state: &ServerState
+    ) -> Result<Option<Vec<(u64, UriAndHash)>>, DeltaFailure> {
+        if state.algorithm != self.options.local_digest {
+            debug!(
+                "RRDP {}: Local digest algorithm changed from {} to {}. \
+                 Need to get snapshot.",
+                self.notify_uri, state.algorithm, self.options.local_digest
+            );
+            return Err(DeltaFailure::AlgorithmChanged)
+        }
+        if state.deep_digest != self.options.deep_digest {
+            debug!(
+                "RRDP {}: Deep digest setting changed. Need to get snapshot.",
+                self.notify_uri
+            );
+            return Err(DeltaFailure::DigestModeChanged)
+        }
+        if state.compressed != self.options.compress {
+            debug!(
+                "RRDP {}: Object compression changed. Need to get snapshot.",
+                self.notify_uri
+            );
+            return Err(DeltaFailure::StorageChanged)
+        }
+        let deltas = Self::calc_deltas(
+            notify, state, self.progress_level()
+        )?;
+        if deltas.is_some() && self.options.snapshot_only.contains(
+            &self.notify_uri
+        ) {
+            debug!(
+                "RRDP {}: Deltas disabled. Need to get snapshot.",
+                self.notify_uri
+            );
+            return Err(DeltaFailure::Disabled)
+        }
+        if let (Some(deltas), Some(max)) = (&deltas, self.options.max_deltas) {
+            if deltas.len() > max {
                 info!(
-                    "Failed to read state file '{}': {}",
-                    path.display(), err
+                    "RRDP {}: {} deltas exceed the maximum of {}. \
+                     Using snapshot.",
+                    self.notify_uri, deltas.len(), max
                 );
+                return Err(DeltaFailure::TooManyDeltas)
             }
-            Error
-        })
+        }
+        Ok(deltas)
     }
 
-    fn _load(path: &Path) -> Result<Self, io::Error> {
-        let file = BufReader::new(fs::File::open(path)?);
-        let mut lines = file.lines();
-        let res = ServerState {
-            notify_uri: process_line(&mut lines, "notify-uri:")?,
-            session: process_line(&mut lines, "session:")?,
-            serial: process_line(&mut lines, "serial:")?,
-            hash: process_line(&mut lines, "hash:")?,
-        };
-        if lines.next().is_some() {
-            Err(io::Error::new(io::ErrorKind::InvalidData, "invalid data"))
+    /// Calculates the deltas to follow for updating.
+    ///
+    /// Returns an error if there is no way to delta update. Returns `Ok(None)`
+    /// if no update is necessary, even if the notification file doesn’t list
+    /// any deltas. Returns the deltas in order if a delta update should be
+    /// done.
+    ///
+    /// The deltas of the notification file don’t need to be sorted. If they
+    /// aren’t, a warning is logged since this is a bug in the server.
+    /// Duplicate serial numbers, however, make the deltas unusable.
+    ///
+    /// Progress messages are logged at `level`.
+    fn calc_deltas(
+        notify: &NotificationFile,
+        state: &ServerState,
+        level: Level,
+    ) -> Result<Option<Vec<(u64, UriAndHash)>>, DeltaFailure> {
+        if notify.session_id != state.session {
+            log!(level, "New session. Need to get snapshot.");
+            return Err(DeltaFailure::SessionChanged);
         }
-        else {
-            Ok(res)
+        log!(level, "Serials: us {}, them {}", state.serial, notify.serial);
+        if notify.serial == state.serial {
+            return Ok(None);
+        }
+        if notify.serial < state.serial {
+            warn!(
+                "RRDP {}: serial regressed from {} to {} without session \
+                 change. Need to get snapshot.",
+                state.notify_uri, state.serial, notify.serial
+            );
+            return Err(DeltaFailure::SerialRegressed)
         }
-    }
 
-    pub fn save(&self, path: &Path) -> Result<(), Error> {
-        self._save(path).map_err(|err| {
-            info!(
-                "Failed to read write file '{}': {}",
-                path.display(), err
+        // New repositories and those that don’t publish deltas at all list
+        // none. Since our serial differs, only the snapshot will do.
+        if notify.deltas.is_empty() {
+            debug!(
+                "RRDP {}: notification file lists no deltas. \
+                 Need to get snapshot.",
+                state.notify_uri
             );
-            Error
-        })
-    }
+            return Err(DeltaFailure::NoDeltas)
+        }
 
-    fn _save(&self, path: &Path) -> Result<(), io::Error> {
-        let mut file = fs::File::create(path)?;
-        writeln!(
-            file, "notify-uri: {}\nsession: {}\nserial: {}\nhash: {}",
-            self.notify_uri, self.session, self.serial, self.hash
-        )
-    }
+        let mut deltas = notify.deltas.clone();
+        if deltas.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            deltas.sort_by_key(|delta| delta.0);
+            if deltas.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+                warn!(
+                    "RRDP {}: notification file lists deltas with \
+                     duplicate serial numbers.",
+                    state.notify_uri
+                );
+                return Err(DeltaFailure::DuplicateSerial)
+            }
+            warn!(
+                "RRDP {}: notification file lists deltas out of order.",
+                state.notify_uri
+            );
+        }
 
-}
+        // If there is no last delta (remember, we have a different
+        // serial than the notification file) or if the last delta’s
+        // serial differs from that noted in the notification file,
+        // bail out.
+        if deltas.last().map(|delta| delta.0) != Some(notify.serial) {
+            log!(level, "Last delta serial differs from current serial.");
+            return Err(DeltaFailure::SerialGap)
+        }
+
+        // Drop all deltas up to and including our serial. The next one has
+        // to be for the serial following ours.
+        let serial = match state.serial.checked_add(1) {
+            Some(serial) => serial,
+            None => return Err(DeltaFailure::SerialGap)
+        };
+        let start = deltas.iter().position(|delta| delta.0 >= serial);
+        let start = match start {
+            Some(start) => start,
+            None => {
+                log!(level, "Ran out of deltas.");
+                return Err(DeltaFailure::SerialGap)
+            }
+        };
+        if deltas[start].0 != serial {
+            log!(level, "First delta is too new ({})", deltas[start].0);
+            return Err(DeltaFailure::SerialGap)
+        }
+        deltas.drain(..start);
+
+        // Since the deltas are sorted and distinct, they are contiguous if
+        // each serial follows its predecessor.
+        if deltas.windows(2).any(|pair| pair[0].0 + 1 != pair[1].0) {
+            debug!("Gap in the delta serials.");
+            return Err(DeltaFailure::SerialGap)
+        }
+        Ok(Some(deltas))
+    }
+
+    /// Performs a delta update in the temporary location.
+    fn collect_delta_targets(
+        &self,
+        state: &ServerState,
+        notify: &NotificationFile,
+        deltas: &[(u64, UriAndHash)],
+        http: &dyn HttpClient,
+        timeline: &mut Timeline,
+    ) -> Result<DeltaTargets, DeltaFailure> {
+        // After a checkpoint, the digest is known to match already.
+        timeline.step("digest", || {
+            self.recheck_digest(state)
+        }).map_err(|_| DeltaFailure::DigestMismatch)?;
+        let mut targets = DeltaTargets::new(
+            http.tmp_dir(), self.server_dir.compress,
+            self.server_dir.store.clone()
+        ).map_err(|_| DeltaFailure::Local)?;
+
+        // Download the deltas concurrently but process them strictly in
+        // order, as each delta is checked against the result of the
+        // previous ones.
+        let prefetched = timeline.step("prefetch", || {
+            Ok(http.prefetch_deltas(&self.notify_uri, notify, deltas))
+        }).map_err(|_| DeltaFailure::Http)?;
+        for (delta, prefetched) in deltas.iter().zip(prefetched) {
+            timeline.check_deadline().map_err(|_| DeltaFailure::Deadline)?;
+            timeline.delta(delta.0, || {
+                http.delta(
+                    &self.notify_uri, notify, delta, prefetched,
+                    &mut targets, &|uri| self.server_dir.uri_path(uri)
+                )
+            }).map_err(|_| DeltaFailure::Http)?
+        }
+        Ok(targets)
+    }
+
+    /// Try updating via the snapshot.
+    fn snapshot_update(
+        &self,
+        notify: &NotificationFile,
+        http: &dyn HttpClient,
+        metrics: &mut RrdpServerMetrics,
+        timeline: &mut Timeline,
+    ) -> Result<(), Error> {
+        log!(
+            self.progress_level(),
+            "RRDP {}: updating from snapshot.", self.notify_uri
+        );
+        self.emit(EventKind::SnapshotStart, &[("serial", notify.serial)]);
+        timeline.set_path(UpdatePath::Snapshot);
+        let tmp_dir = ServerDir::create(
+            http.tmp_dir(), &self.options
+        ).map_err(|_| Error)?;
+        let (state, hashes) = match self.snapshot_into_tmp(
+            notify, http, &tmp_dir, timeline
+        ) {
+            Ok(res) => res,
+            Err(_) => {
+                tmp_dir.remove();
+                return Err(Error);
+            }
+        };
+        timeline.step("commit", || self.move_from_tmp(tmp_dir))?;
+        timeline.set_serial(state.serial);
+        metrics.serial = Some(state.serial);
+        *lock_recovered(&self.changes) = Some(
+            hashes.uris().map(|uri| (uri.clone(), ChangeKind::Added)).collect()
+        );
+        Ok(())
+    }
+
+    /// Fetches the snapshot into a temporary directory.
+    ///
+    /// The HTTP client checks the snapshot against the hash given in the
+    /// notification file while processing it and fails if they differ. In
+    /// this case, as with any other error, the caller has to remove the
+    /// temporary directory.
+    ///
+    /// Returns the state and object hashes of the new local copy.
+    fn snapshot_into_tmp(
+        &self,
+        notify: &NotificationFile,
+        http: &dyn HttpClient,
+        tmp_dir: &ServerDir,
+        timeline: &mut Timeline,
+    ) -> Result<(ServerState, ObjectHashes), Error> {
+        let notify_uri = &self.notify_uri;
+        let mut progress = |progress: SnapshotProgress| {
+            debug!(
+                "RRDP {}: snapshot progress: {} objects, {} bytes.",
+                notify_uri, progress.objects, progress.bytes
+            );
+        };
+        let hashes = timeline.step("snapshot", || {
+            http.snapshot(
+                &self.notify_uri, notify, &|uri| tmp_dir.uri_path(uri),
+                tmp_dir.compress, &self.options.object_check,
+                Some(&mut progress)
+            )
+        })?;
+        hashes.save(&*tmp_dir.store, &tmp_dir.hashes_path())?;
+        let hash = timeline.step("digest", || tmp_dir.digest())?;
+        let state = ServerState {
+            notify_uri: self.notify_uri().clone(),
+            session: notify.session_id,
+            serial: notify.serial,
+            hash: hash.into(),
+            object_count: Some(hashes.len()),
+            algorithm: tmp_dir.algorithm,
+            deep_digest: tmp_dir.deep,
+            compressed: tmp_dir.compress,
+            digest_version: DigestVersion::CURRENT,
+            validators: Default::default(),
+            past_sessions: self.past_sessions(notify.session_id),
+        };
+        tmp_dir.save_state(&state)?;
+        tmp_dir.save_manifest();
+        Ok((state, hashes))
+    }
+
+    /// Checks that the digest of the local copy matches the state.
+    ///
+    /// A successful check is remembered for `recheck_digest`.
+    fn check_digest(&self, state: &ServerState) -> Result<(), Error> {
+        self.server_dir.check_digest(state)?;
+        self.digest_verified.store(true, Relaxed);
+        Ok(())
+    }
+
+    /// Checks the digest of the local copy unless that happened already.
+    ///
+    /// The check is skipped if the digest has been verified earlier during
+    /// this run and the local copy hasn’t been changed since.
+    fn recheck_digest(&self, state: &ServerState) -> Result<(), Error> {
+        if self.digest_verified.load(Relaxed) {
+            debug!(
+                "RRDP {}: local copy verified already. Skipping digest.",
+                self.notify_uri
+            );
+            return Ok(())
+        }
+        self.check_digest(state)
+    }
+
+    /// Upgrades the state if its digest was calculated by an older version.
+    ///
+    /// This must only be called once the digest has been checked. If the
+    /// upgrade fails, the old state stays in place and the upgrade is
+    /// attempted again during the next update.
+    fn upgrade_digest(&self, state: &ServerState) {
+        if state.digest_version != DigestVersion::CURRENT {
+            let _ = self.server_dir.upgrade_digest(state);
+        }
+    }
+
+    /// Forgets that the digest of the local copy has been verified.
+    ///
+    /// This needs to be called before changing the local copy.
+    fn invalidate_digest(&self) {
+        self.digest_verified.store(false, Relaxed)
+    }
+
+    /// Moves everything back from a temporary directory.
+    ///
+    /// Either both the data directory and the state file are replaced or
+    /// neither is. Objects that didn’t change keep their files and thus
+    /// their modification times.
+    ///
+    /// If the `fsync` option is set, the content of the temporary directory
+    /// is flushed to storage first and the directory of each renamed target
+    /// after it was renamed. If the temporary directory is on a different
+    /// file system, its content is copied instead of renamed and the copy
+    /// is flushed, too.
+    fn move_from_tmp(&self, tmp_dir: ServerDir) -> Result<(), Error> {
+        self.invalidate_digest();
+        self.server_dir.keep_unchanged(&tmp_dir);
+        let fsync = self.options.fsync;
+        if fsync {
+            if let Err(err) = sync_tree(tmp_dir.base()) {
+                info!(
+                    "Failed to flush RRDP temporary directory '{}': {}.",
+                    tmp_dir.base().display(), err
+                );
+                tmp_dir.remove();
+                return Err(Error)
+            }
+        }
+        let store = &self.server_dir.store;
+        let res = self.server_dir.replace_from(&tmp_dir, |from, to| {
+            store.rename(from, to)?;
+            if fsync {
+                if store.is_local(to) && to.is_dir() {
+                    sync_tree(to)?;
+                }
+                else {
+                    store.sync(to)?;
+                }
+                if let Some(parent) = to.parent() {
+                    store.sync(parent)?;
+                }
+            }
+            Ok(())
+        });
+        tmp_dir.remove();
+        self.load_cache.clear();
+        let data_path = self.server_dir.data_path();
+        if res.is_ok() && store.is_local(&data_path) {
+            self.options.dedup.link_tree(&data_path);
+        }
+        res
+    }
+
+    /// Checks whether the server in its current state is usable.
+    ///
+    /// For a server to be usable, it has to have a state file that can be
+    /// read and its hash must match the current digest of the directory.
+    ///
+    /// Assumes that the server isn’t currently marked broken and sets the
+    /// `broken` flag if anything is fishy.
+    ///
+    /// Returns whether the server directory should be removed. If the
+    /// directory can’t be digested because of a transient I/O error, the
+    /// server is marked as broken for this run only and its directory is
+    /// kept. A server that keeps being flagged here can be recovered via
+    /// `force_snapshot`.
+    fn check_broken(&self) -> bool {
+        let remove = self.check_broken_digest(|state| {
+            self.server_dir.try_digest(
+                state.algorithm, state.digest_version, state.deep_digest,
+                state.compressed
+            )
+        });
+        if self.broken.load(Relaxed) {
+            self.emit(EventKind::Broken, &[("removed", remove as u64)]);
+        }
+        remove
+    }
+
+    /// Checks whether the server is broken given the directory’s digest.
+    ///
+    /// The digest is produced by `digest` the way recorded in the server’s
+    /// state.
+    fn check_broken_digest<F>(&self, digest: F) -> bool
+    where
+        F: FnOnce(&ServerState) -> Result<localdigest::Digest, DigestError>
+    {
+        let state = match self.load_state() {
+            Ok(state) => state,
+            Err(_) => {
+                info!(
+                    "Marking RRDP server '{}' as unusable",
+                    self.notify_uri
+                );
+                self.broken.store(true, Relaxed);
+                return true;
+            }
+        };
+        let digest = match digest(&state) {
+            Ok(digest) => digest,
+            Err(DigestError::Transient(err)) => {
+                warn!(
+                    "Transient error while digesting RRDP server directory \
+                     for '{}': {}. Skipping server in this run but keeping \
+                     its data.",
+                    self.notify_uri, err
+                );
+                self.broken.store(true, Relaxed);
+                return false;
+            }
+            Err(DigestError::UnknownVersion(number)) => {
+                info!(
+                    "RRDP server directory for '{}' has unknown digest \
+                     version {}. Skipping server in this run but keeping \
+                     its data.",
+                    self.notify_uri, number
+                );
+                self.broken.store(true, Relaxed);
+                return false;
+            }
+            Err(DigestError::Fatal(_)) => {
+                info!(
+                    "Cannot digest RRDP server directory for '{}'. \
+                    Marking as unsable.",
+                    self.notify_uri
+                );
+                self.broken.store(true, Relaxed);
+                return true;
+            }
+        };
+        if verify_slices_are_equal(digest.as_ref(), state.hash.as_ref())
+                                                                   .is_err() {
+            info!(
+                "Hash for RRDP server directory for '{}' doesn’t match. \
+                Marking as unusable.",
+                self.notify_uri
+            );
+            self.server_dir.log_divergence(&state);
+            self.broken.store(true, Relaxed);
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    /// Loads the server’s state.
+    ///
+    /// A state recorded for a different notify URI is stale and results in
+    /// an error, too.
+    fn load_state(&self) -> Result<ServerState, Error> {
+        let state = self.server_dir.load_state()?;
+        if state.notify_uri != self.notify_uri {
+            info!(
+                "RRDP {}: local copy is for notify URI {}. Ignoring it.",
+                self.notify_uri, state.notify_uri
+            );
+            return Err(Error)
+        }
+        Ok(state)
+    }
+
+    /// Reports an event of this server.
+    fn emit(&self, kind: EventKind, details: &[(&'static str, u64)]) {
+        self.options.events.emit(&self.notify_uri, kind, details)
+    }
+
+    /// Returns a reference to the server’s notify URI.
+    pub fn notify_uri(&self) -> &uri::Https {
+        &self.notify_uri
+    }
+
+    /// Returns whether the server is broken.
+    pub fn is_broken(&self) -> bool {
+        self.broken.load(Relaxed)
+    }
+
+    /// Tries to load a file from this server.
+    ///
+    /// This assumes that the server is updated already. The result tells
+    /// a missing object apart from a failure to read it.
+    ///
+    /// If enabled, recently loaded objects are served from memory.
+    pub fn load_file(&self, uri: &uri::Rsync) -> LoadResult {
+        if !self.broken.load(Relaxed) {
+            if let Some(data) = self.load_cache.get(uri) {
+                return LoadResult::Found(data)
+            }
+        }
+        let res = self.load_with(uri, |path| {
+            compress::read_stored_object(
+                &*self.server_dir.store, path
+            ).map(|data| (data, ()))
+        }).map(|(data, _)| data);
+        if let LoadResult::Found(ref data) = res {
+            self.load_cache.insert(uri, data)
+        }
+        res
+    }
+
+    /// Tries to load a file and its metadata from this server.
+    ///
+    /// This is the same as `load_file` but also returns the metadata of the
+    /// file holding the object, taken from the same open file. For objects
+    /// stored compressed, the length given in the metadata is the length
+    /// of the compressed file. Since the metadata is that of a local file,
+    /// the file is always read from the local file system rather than the
+    /// store. If the store keeps the object elsewhere, e.g., in an
+    /// archive, there is no such metadata and an I/O error is returned.
+    pub fn load_file_with_meta(
+        &self, uri: &uri::Rsync
+    ) -> LoadResult<(Bytes, fs::Metadata)> {
+        if let Ok(path) = self.server_dir.uri_path(uri) {
+            if !self.server_dir.store.is_local(&path) {
+                info!(
+                    "{}: no file metadata available for objects not kept \
+                     in the local file system.",
+                    uri
+                );
+                return LoadResult::IoError
+            }
+        }
+        self.load_with(uri, compress::read_object_with_meta)
+    }
+
+    /// Loads a file using the given operation for reading it.
+    fn load_with<T, F>(
+        &self, uri: &uri::Rsync, op: F
+    ) -> LoadResult<(Bytes, T)>
+    where F: FnOnce(&Path) -> Result<(Vec<u8>, T), io::Error> {
+        if self.broken.load(Relaxed) {
+            return LoadResult::ServerBroken
+        }
+        
+        let path = match self.server_dir.uri_path(uri) {
+            Ok(path) => path,
+            Err(_) => return LoadResult::NotFound
+        };
+        let (data, meta) = match op(&path) {
+            Ok(res) => res,
+            Err(err) => {
+                if err.kind() == io::ErrorKind::NotFound {
+                    info!("{} not found in its RRDP repository.", uri);
+                    return LoadResult::NotFound
+                }
+                warn!(
+                    "Failed to read file '{}': {}.",
+                    path.display(), err
+                );
+                return LoadResult::IoError
+            }
+        };
+        if self.options.verify_on_read
+            && self.verify_object(uri, &data).is_err()
+        {
+            return LoadResult::Corrupt
+        }
+        LoadResult::Found((data.into(), meta))
+    }
+
+    /// Verifies an object against its recorded hash.
+    ///
+    /// Each object is only verified the first time it is read during a
+    /// run. If there are no recorded hashes for the server at all, which
+    /// is the case for caches created by older versions, all objects are
+    /// accepted.
+    fn verify_object(
+        &self,
+        uri: &uri::Rsync,
+        data: &[u8]
+    ) -> Result<(), Error> {
+        if lock_recovered(&self.verified).contains(uri) {
+            return Ok(())
+        }
+        let hashes = match self.object_hashes()? {
+            Some(hashes) => hashes,
+            None => return Ok(())
+        };
+        if !hashes.verify(uri, data) {
+            warn!(
+                "{}: object doesn’t match its hash in RRDP repository {}.",
+                uri, self.notify_uri
+            );
+            return Err(Error)
+        }
+        lock_recovered(&self.verified).insert(uri.clone());
+        Ok(())
+    }
+
+    /// Returns the object hashes, loading them if necessary.
+    fn object_hashes(&self) -> Result<Option<Arc<ObjectHashes>>, Error> {
+        let mut hashes = lock_recovered(&self.object_hashes);
+        if let Some(ref hashes) = *hashes {
+            return Ok(hashes.clone())
+        }
+        let res = self.server_dir.load_hashes()?.map(Arc::new);
+        *hashes = Some(res.clone());
+        Ok(res)
+    }
+
+    /// Returns the rsync URI and size of all objects held by the server.
+    ///
+    /// The list is created from the files in the data directory, not from
+    /// the stored hashes, so it shows what is actually there.
+    pub fn list_objects(&self) -> Result<Vec<(uri::Rsync, u64)>, Error> {
+        if self.broken.load(Relaxed) {
+            return Err(Error)
+        }
+        Ok(self.server_dir.objects()?.into_iter().map(|(uri, _, len)| {
+            (uri, len)
+        }).collect())
+    }
+
+    /// Writes a summary of all objects currently held by the server.
+    ///
+    /// The summary is a JSON object with the server’s notify URI, its
+    /// current session and serial, and an array of all objects with their
+    /// rsync URI, size, and SHA-256 hash so that it can be cross-referenced
+    /// with local exceptions downstream.
+    pub fn export_objects<W: io::Write>(
+        &self,
+        target: &mut W
+    ) -> Result<(), Error> {
+        if self.broken.load(Relaxed) {
+            return Err(Error)
+        }
+        let state = self.load_state()?;
+        let objects = self.server_dir.objects()?;
+        let mut hashes = Vec::with_capacity(objects.len());
+        for (_, path, _) in &objects {
+            let data = compress::read_stored_object(
+                &*self.server_dir.store, path
+            ).map_err(|err| {
+                info!("Failed to read file '{}': {}", path.display(), err);
+                Error
+            })?;
+            hashes.push(DigestHex::from(
+                digest::digest(&digest::SHA256, &data)
+            ));
+        }
+        Self::write_export(&state, &objects, &hashes, target).map_err(|err| {
+            info!(
+                "Failed to write object summary for RRDP server {}: {}",
+                self.notify_uri, err
+            );
+            Error
+        })
+    }
+
+    fn write_export<W: io::Write>(
+        state: &ServerState,
+        objects: &[(uri::Rsync, PathBuf, u64)],
+        hashes: &[DigestHex],
+        target: &mut W
+    ) -> Result<(), io::Error> {
+        let summary = ObjectSummary {
+            notify_uri: state.notify_uri.to_string(),
+            session: state.session.to_string(),
+            serial: state.serial,
+            objects: objects.iter().zip(hashes).map(|((uri, _, size), hash)| {
+                ObjectSummaryEntry {
+                    uri: uri.to_string(),
+                    size: *size,
+                    sha256: hash.to_string(),
+                }
+            }).collect(),
+        };
+        serde_json::to_writer_pretty(&mut *target, &summary)?;
+        writeln!(target)
+    }
+
+    /// Writes the URIs and sizes of all objects currently held by the server.
+    ///
+    /// Writes one line per object with its rsync URI and size separated by
+    /// a space. If `data` is `true`, each line is followed by the content
+    /// of the object.
+    pub fn stream_objects<W: io::Write>(
+        &self,
+        data: bool,
+        target: &mut W
+    ) -> Result<(), Error> {
+        if self.broken.load(Relaxed) {
+            return Err(Error)
+        }
+        for (uri, path, size) in self.server_dir.objects()? {
+            if let Err(err) = writeln!(target, "{} {}", uri, size) {
+                info!("Failed to write object list: {}", err);
+                return Err(Error)
+            }
+            if !data {
+                continue
+            }
+            let data = compress::read_stored_object(
+                &*self.server_dir.store, &path
+            ).map_err(|err| {
+                info!("Failed to read file '{}': {}", path.display(), err);
+                Error
+            })?;
+            // Only ever copy what we announced.
+            if data.len() as u64 != size {
+                info!("File '{}' changed while copying.", path.display());
+                return Err(Error)
+            }
+            target.write_all(&data).map_err(|err| {
+                info!("Failed to copy file '{}': {}", path.display(), err);
+                Error
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Writes the local copy of the server as a tar archive.
+    ///
+    /// The archive contains the state file, the object hashes if there are
+    /// any, and the data directory with its files as stored. These always
+    /// appear as `state.txt`, `hashes.txt`, and `data` in the archive, no
+    /// matter the names configured for the server directory. The entries of
+    /// the data directory appear in the order they are digested in, so the
+    /// same local copy always results in the same archive. Updates have to
+    /// wait until the archive is written.
+    ///
+    /// Use `import` to create a server from the archive.
+    pub fn export(&self, writer: impl io::Write) -> Result<(), Error> {
+        let _lock = self.lock_update();
+        if self.broken.load(Relaxed) {
+            return Err(Error)
+        }
+        self.server_dir.export(writer).map_err(|err| {
+            info!(
+                "Failed to export RRDP server {}: {}", self.notify_uri, err
+            );
+            Error
+        })
+    }
+
+    /// Checks the consistency of the server’s local copy.
+    ///
+    /// Returns the server state if the state file can be read and the
+    /// digest of the data directory matches it. Doesn’t change anything.
+    pub fn verify(&self) -> Result<ServerState, Error> {
+        let state = self.load_state()?;
+        self.check_digest(&state)?;
+        Ok(state)
+    }
+
+    /// Verifies the server’s local copy without accessing the network.
+    ///
+    /// Loads the state file and checks the digest of the data directory
+    /// against it. Other than `check_broken`, this only reports what it
+    /// finds and doesn’t change anything.
+    pub fn verify_local(&self) -> VerifyReport {
+        let state = match self.load_state() {
+            Ok(state) => state,
+            Err(_) => return VerifyReport::default()
+        };
+        VerifyReport {
+            session: Some(state.session),
+            serial: Some(state.serial),
+            object_count: state.object_count,
+            consistent: self.server_dir.check_digest(&state).is_ok(),
+        }
+    }
+
+    /// Diagnoses the health of the server’s local copy.
+    ///
+    /// Verifies the local copy first and, if that succeeds, fetches the
+    /// notification file to compare the copy with what the server
+    /// currently publishes. Doesn’t change anything.
+    pub fn diagnose(&self, http: &dyn HttpClient) -> Diagnosis {
+        self.diagnose_with(|| {
+            let mut status = None;
+            http.notification_file(
+                &self.notify_uri, &mut status
+            ).map(|(notify, _)| notify).map_err(Into::into)
+        })
+    }
+
+    /// Diagnoses the server using the given function to get the
+    /// notification file.
+    fn diagnose_with<F>(&self, fetch: F) -> Diagnosis
+    where F: FnOnce() -> Result<NotificationFile, Error> {
+        let state = match self.verify() {
+            Ok(state) => state,
+            Err(_) => return Diagnosis::Broken
+        };
+        match fetch() {
+            Ok(notify) => Diagnosis::classify(&state, &notify),
+            Err(_) => Diagnosis::Unreachable { serial: state.serial }
+        }
+    }
+
+    /// Checks what updating the server would do.
+    ///
+    /// Performs the same steps as an update, i.e., fetches the notification
+    /// file, checks that it is acceptable, determines the deltas to follow,
+    /// and fetches and verifies them or, if that fails, the snapshot, but
+    /// doesn’t change the local copy.
+    /// Everything fetched is processed in the temporary directory and
+    /// removed again. Deltas may be kept in the delta cache, though.
+    pub fn check(&self, http: &dyn HttpClient) -> CheckReport {
+        let mut report = CheckReport::default();
+        let mut status = None;
+        let notify = match http.notification_file(
+            &self.notify_uri, &mut status
+        ) {
+            Ok((notify, _)) => notify,
+            Err(_) => return report
+        };
+        report.serial = Some(notify.serial);
+        let state = self.load_state().ok();
+        if self.accept_notification(&notify, state.as_ref(), true).is_err() {
+            report.refused = true;
+            return report
+        }
+        match self.check_deltas(&notify, state, http) {
+            Ok(path) => {
+                report.path = Some(path);
+                return report
+            }
+            Err(failure) => report.delta_failure = Some(failure)
+        }
+        if self.check_snapshot(&notify, http).is_ok() {
+            report.path = Some(UpdatePath::Snapshot)
+        }
+        else {
+            report.snapshot_failed = true
+        }
+        report
+    }
+
+    /// Checks whether the server could be updated via deltas.
+    ///
+    /// Returns the update path that would be taken.
+    fn check_deltas(
+        &self,
+        notify: &NotificationFile,
+        state: Option<ServerState>,
+        http: &dyn HttpClient,
+    ) -> Result<UpdatePath, DeltaFailure> {
+        let state = state.ok_or(DeltaFailure::NoState)?;
+        let deltas = self.plan_deltas(notify, &state)?;
+        self.server_dir.check_digest(&state).map_err(|_| {
+            DeltaFailure::DigestMismatch
+        })?;
+        let deltas = match deltas {
+            Some(deltas) => deltas,
+            None => return Ok(UpdatePath::Current)
+        };
+        let mut targets = DeltaTargets::new(
+            http.tmp_dir(), self.server_dir.compress,
+            self.server_dir.store.clone()
+        ).map_err(|_| DeltaFailure::Local)?;
+        for delta in &deltas {
+            http.delta(
+                &self.notify_uri, notify, delta, None, &mut targets,
+                &|uri| self.server_dir.uri_path(uri)
+            ).map_err(|_| DeltaFailure::Http)?;
+        }
+
+        // Dropping the targets without applying them removes everything.
+        Ok(UpdatePath::Delta)
+    }
+
+    /// Checks whether the server could be updated via the snapshot.
+    fn check_snapshot(
+        &self, notify: &NotificationFile, http: &dyn HttpClient
+    ) -> Result<(), Error> {
+        let tmp_dir = ServerDir::create(
+            http.tmp_dir(), &self.options
+        ).map_err(|_| Error)?;
+        let mut timeline = Timeline::with_clock(
+            self.notify_uri.clone(), self.options.clock.clone()
+        );
+        let res = self.snapshot_into_tmp(
+            notify, http, &tmp_dir, &mut timeline
+        );
+        tmp_dir.remove();
+        res.map(|_| ())
+    }
+
+    /// Returns the statistics of the server’s local copy.
+    ///
+    /// The state must have been loaded from the server’s directory. The
+    /// time of the last change is taken from the state file which is only
+    /// written when the local copy changes.
+    pub fn stats(&self, state: ServerState) -> Result<ServerStats, Error> {
+        let objects = self.server_dir.objects()?;
+        let last_change = fs::metadata(
+            self.server_dir.state_path()
+        ).and_then(|meta| meta.modified()).ok().map(DateTime::from);
+        Ok(ServerStats {
+            notify_uri: state.notify_uri,
+            path: self.server_dir.base().into(),
+            session: state.session,
+            serial: state.serial,
+            objects: objects.len(),
+            bytes: objects.iter().map(|item| item.2).sum(),
+            last_change,
+        })
+    }
+
+    /// Removes the server’s local cache if it hasn’t been used.
+    ///
+    /// If the server is currently being updated or the cache is read-only,
+    /// the cache is kept. Returns whether it indeed removed the cache.
+    pub fn remove_unused(&self) -> bool {
+        if self.options.read_only {
+            return false
+        }
+        // Holding the update lock keeps an update from starting while we
+        // remove the directory.
+        let _lock = match self.mutex.try_lock() {
+            Ok(lock) => lock,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                debug!(
+                    "RRDP {}: update in progress. Not removing.",
+                    self.notify_uri
+                );
+                return false
+            }
+        };
+        if self.updated.load(Relaxed) && !self.broken.load(Relaxed) {
+            return false
+        }
+        self.invalidate_digest();
+        self.server_dir.remove();
+        true
+    }
+
+    /// Return the server metrics if the server was ever updated.
+    ///
+    /// These are the metrics of the last finished update. This never waits
+    /// for an update in progress.
+    pub fn metrics(&self) -> Option<RrdpServerMetrics> {
+        if self.updated.load(Relaxed) {
+            Some(lock_recovered(&self.metrics).clone())
+        }
+        else {
+            None
+        }
+    }
+}
+
+
+//------------ UpdatingFlag --------------------------------------------------
+
+/// A guard setting a flag while an update is running.
+///
+/// The flag is cleared again when the guard is dropped, so it is cleared no
+/// matter how the update returns.
+struct UpdatingFlag<'a>(&'a AtomicBool);
+
+impl<'a> UpdatingFlag<'a> {
+    /// Sets the flag and returns a guard clearing it again.
+    fn set(flag: &'a AtomicBool) -> Self {
+        flag.store(true, Relaxed);
+        UpdatingFlag(flag)
+    }
+}
+
+impl<'a> Drop for UpdatingFlag<'a> {
+    fn drop(&mut self) {
+        self.0.store(false, Relaxed)
+    }
+}
+
+
+//------------ LoadResult ----------------------------------------------------
+
+/// The outcome of loading an object from a server.
+#[derive(Clone, Debug)]
+pub enum LoadResult<T = Bytes> {
+    /// The object was found.
+    Found(T),
+
+    /// The server doesn’t have the object.
+    NotFound,
+
+    /// The server is unusable, so nothing can be loaded from it.
+    ServerBroken,
+
+    /// Reading the object failed.
+    ///
+    /// This may be a transient error, so the object may well exist.
+    IoError,
+
+    /// The object doesn’t match its recorded hash or that couldn’t be
+    /// checked.
+    Corrupt,
+}
+
+impl<T> LoadResult<T> {
+    /// Converts the object if it was found.
+    pub fn map<U, F: FnOnce(T) -> U>(self, op: F) -> LoadResult<U> {
+        match self {
+            LoadResult::Found(value) => LoadResult::Found(op(value)),
+            LoadResult::NotFound => LoadResult::NotFound,
+            LoadResult::ServerBroken => LoadResult::ServerBroken,
+            LoadResult::IoError => LoadResult::IoError,
+            LoadResult::Corrupt => LoadResult::Corrupt,
+        }
+    }
+
+    /// Returns the object if it was found.
+    pub fn found(self) -> Option<T> {
+        match self {
+            LoadResult::Found(value) => Some(value),
+            _ => None
+        }
+    }
+
+    /// Returns whether the object is known to not exist.
+    pub fn is_not_found(&self) -> bool {
+        matches!(*self, LoadResult::NotFound)
+    }
+}
+
+
+//------------ UpdateKind ----------------------------------------------------
+
+/// How the last update of a server was performed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UpdateKind {
+    /// The server hasn’t been updated during this run yet.
+    NotYetRun = 0,
+
+    /// The server was updated via deltas.
+    Delta = 1,
+
+    /// The server was updated via the snapshot.
+    Snapshot = 2,
+
+    /// The local copy was current already.
+    NotNeeded = 3,
+
+    /// The update failed.
+    Failed = 4,
+}
+
+impl UpdateKind {
+    /// Converts the value stored in an atomic back.
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => UpdateKind::Delta,
+            2 => UpdateKind::Snapshot,
+            3 => UpdateKind::NotNeeded,
+            4 => UpdateKind::Failed,
+            _ => UpdateKind::NotYetRun,
+        }
+    }
+}
+
+impl From<RrdpUpdateKind> for UpdateKind {
+    fn from(kind: RrdpUpdateKind) -> Self {
+        match kind {
+            RrdpUpdateKind::Delta => UpdateKind::Delta,
+            RrdpUpdateKind::Snapshot => UpdateKind::Snapshot,
+            RrdpUpdateKind::NotNeeded => UpdateKind::NotNeeded,
+            RrdpUpdateKind::Failed => UpdateKind::Failed,
+        }
+    }
+}
+
+
+//------------ ServerDir -----------------------------------------------------
+
+#[derive(Debug)]
+struct ServerDir {
+    base: PathBuf,
+    state: PathBuf,
+
+    /// The path of the data directory.
+    data: PathBuf,
+
+    /// The path the data directory is moved to while being replaced.
+    old_data: PathBuf,
+
+    /// The path of the object hashes.
+    hashes: PathBuf,
+
+    /// The path of the manifest.
+    manifest: PathBuf,
+
+    /// The path of the latest stored notification file.
+    notification: PathBuf,
+
+    /// The algorithm for digests of the data directory.
+    algorithm: DigestAlgorithm,
+
+    /// Whether digests of the data directory cover the file content.
+    deep: bool,
+
+    /// Whether to store objects compressed.
+    compress: bool,
+
+    /// The store holding the data directory.
+    store: SharedStore,
+
+    /// The content of the data directory when it was last digested.
+    ///
+    /// This allows deriving the digest after applying deltas without
+    /// walking the directory again at the cost of keeping an entry for
+    /// each file in memory. Walks that don’t need it, such as the one in
+    /// `recover`, use `_data_digest` instead.
+    tree: Mutex<Option<DigestTree>>,
+}
+
+impl ServerDir {
+    /// Creates a server directory at `base` using the given options.
+    ///
+    /// The names of the state file and data directory are taken from the
+    /// options, too.
+    fn new(base: PathBuf, options: &ServerOptions) -> Self {
+        ServerDir {
+            state: options.dir_names.state_path(&base),
+            data: options.dir_names.data_path(&base),
+            old_data: options.dir_names.old_data_path(&base),
+            hashes: options.dir_names.hashes_path(&base),
+            manifest: options.dir_names.manifest_path(&base),
+            notification: base.join(&options.dir_names.notification),
+            base,
+            algorithm: options.local_digest,
+            deep: options.deep_digest,
+            compress: options.compress,
+            store: options.store.clone(),
+            tree: Mutex::new(None),
+        }
+    }
+
+    fn broken() -> Self {
+        ServerDir {
+            base: PathBuf::new(),
+            state: PathBuf::new(),
+            data: PathBuf::new(),
+            old_data: PathBuf::new(),
+            hashes: PathBuf::new(),
+            manifest: PathBuf::new(),
+            notification: PathBuf::new(),
+            algorithm: Default::default(),
+            deep: false,
+            compress: false,
+            store: Default::default(),
+            tree: Mutex::new(None),
+        }
+    }
+
+    /// Returns whether this is the placeholder of a broken server.
+    ///
+    /// The placeholder doesn’t have a location, so nothing can be stored.
+    fn is_placeholder(&self) -> bool {
+        self.base.as_os_str().is_empty()
+    }
+
+    /// Creates a new server directory under `cache_dir`.
+    ///
+    /// If that fails, logs the reason and returns a broken directory. Since
+    /// servers are loaded afresh for each run, creating is tried again in
+    /// the next run.
+    fn create(
+        cache_dir: &Path, options: &ServerOptions
+    ) -> Result<Self, Self> {
+        match create_unique_dir(cache_dir) {
+            Ok(path) => Ok(ServerDir::new(path, options)),
+            Err(err) => {
+                warn!(
+                    "Failed to create RRDP server directory under {}: {}",
+                    cache_dir.display(), err
+                );
+                Err(ServerDir::broken())
+            }
+        }
+    }
+
+    fn base(&self) -> &Path {
+        &self.base
+    }
+
+    fn state_path(&self) -> &Path {
+        &self.state
+    }
+
+    fn data_path(&self) -> PathBuf {
+        self.data.clone()
+    }
+
+    fn hashes_path(&self) -> PathBuf {
+        self.hashes.clone()
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.manifest.clone()
+    }
+
+    /// Loads the state file from the store.
+    fn load_state(&self) -> Result<ServerState, Error> {
+        ServerState::load(&*self.store, self.state_path())
+    }
+
+    /// Saves the state file to the store.
+    fn save_state(&self, state: &ServerState) -> Result<(), Error> {
+        state.save(&*self.store, self.state_path())
+    }
+
+    /// Loads the object hashes from the store if there are any.
+    fn load_hashes(&self) -> Result<Option<ObjectHashes>, Error> {
+        ObjectHashes::load(&*self.store, &self.hashes_path())
+    }
+
+    /// Removes the server directory and all its content.
+    fn remove(&self) {
+        let _ = self.store.remove_dir_all(&self.base);
+    }
+
+    /// Flushes the base directory to storage.
+    ///
+    /// This makes replacing the state file durable.
+    fn sync_base(&self) -> Result<(), Error> {
+        self.store.sync(&self.base).map_err(|err| {
+            info!(
+                "Failed to flush RRDP server directory '{}': {}.",
+                self.base.display(), err
+            );
+            Error
+        })
+    }
+
+    /// Completes the changes applied from deltas via the store.
+    fn commit(&self) -> Result<(), Error> {
+        self.store.commit(&self.data_path()).map_err(|err| {
+            info!(
+                "Failed to commit changes to RRDP data directory '{}': {}.",
+                self.data_path().display(), err
+            );
+            Error
+        })
+    }
+
+    /// Flushes the changes applied from deltas to storage.
+    ///
+    /// Each written file and the directory of each written or removed file
+    /// is flushed.
+    fn sync_changes(
+        &self, changes: &[(PathBuf, Option<u64>)]
+    ) -> Result<(), Error> {
+        let mut dirs = HashSet::new();
+        for (path, size) in changes {
+            let res = if size.is_some() {
+                self.store.sync(path)
+            }
+            else {
+                Ok(())
+            };
+            if let Err(err) = res {
+                info!(
+                    "Failed to flush RRDP object '{}': {}.",
+                    path.display(), err
+                );
+                return Err(Error)
+            }
+            if let Some(parent) = path.parent() {
+                dirs.insert(parent);
+            }
+        }
+        for dir in dirs {
+            if let Err(err) = self.store.sync(dir) {
+                info!(
+                    "Failed to flush RRDP data directory '{}': {}.",
+                    dir.display(), err
+                );
+                return Err(Error)
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the path of a stored notification file.
+    ///
+    /// The latest file has index 0, older files have increasing indexes.
+    fn notification_path(&self, idx: usize) -> PathBuf {
+        if idx == 0 {
+            self.notification.clone()
+        }
+        else {
+            let mut path = self.notification.clone().into_os_string();
+            path.push(format!(".{}", idx));
+            path.into()
+        }
+    }
+
+    /// Stores the raw content of a notification file.
+    ///
+    /// Keeps up to `keep` files, including the new one, dropping the
+    /// oldest. The files live outside the data directory, so they are not
+    /// part of the digest. Failing to store the file isn’t fatal.
+    fn store_notification(&self, data: &[u8], keep: usize) {
+        if keep == 0 {
+            return
+        }
+        let _ = self.store.remove_file(&self.notification_path(keep));
+        for idx in (1..keep).rev() {
+            let _ = self.store.rename(
+                &self.notification_path(idx - 1),
+                &self.notification_path(idx)
+            );
+        }
+        if let Err(err) = self.store.write(&self.notification_path(0), data) {
+            info!(
+                "Failed to write notification file '{}': {}",
+                self.notification_path(0).display(), err
+            );
+        }
+    }
+
+    fn old_data_path(&self) -> PathBuf {
+        self.old_data.clone()
+    }
+
+    /// Carries unchanged files over from the current data directory.
+    ///
+    /// Each file in the data directory of `other` that is identical to the
+    /// file at the same place in this directory is replaced with a link to
+    /// the latter. This way, objects that didn’t change keep their
+    /// modification time when `other` replaces this directory. Files that
+    /// can’t be compared or linked are left as they are.
+    ///
+    /// Since this needs hard links, it only happens if both data
+    /// directories are kept in the local file system.
+    fn keep_unchanged(&self, other: &ServerDir) {
+        let data = self.data_path();
+        let other_data = other.data_path();
+        if !self.store.is_local(&data) || !other.store.is_local(&other_data) {
+            return
+        }
+        let mut dirs = vec![other_data.clone()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue
+            };
+            for entry in entries.filter_map(Result::ok) {
+                let path = entry.path();
+                match entry.file_type() {
+                    Ok(file_type) if file_type.is_dir() => {
+                        dirs.push(path);
+                        continue
+                    }
+                    Ok(file_type) if file_type.is_file() => { }
+                    _ => continue
+                }
+                let current = match path.strip_prefix(&other_data) {
+                    Ok(relative) => data.join(relative),
+                    Err(_) => continue
+                };
+                if Self::same_content(&current, &path) {
+                    let link = random_path(other.base());
+                    if fs::hard_link(&current, &link).is_ok()
+                        && fs::rename(&link, &path).is_err()
+                    {
+                        let _ = fs::remove_file(&link);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns whether two files exist and have the same content.
+    fn same_content(left: &Path, right: &Path) -> bool {
+        let same_len = match (fs::metadata(left), fs::metadata(right)) {
+            (Ok(left), Ok(right)) => {
+                left.is_file() && left.len() == right.len()
+            }
+            _ => false
+        };
+        same_len && match (fs::read(left), fs::read(right)) {
+            (Ok(left), Ok(right)) => left == right,
+            _ => false
+        }
+    }
+
+    /// Replaces the content of the directory with that of another one.
+    ///
+    /// The current data directory is first moved out of the way, then the
+    /// new data directory, the object hashes, the manifest, and finally the
+    /// new state file are moved in. If any of these steps fail, the
+    /// previous steps are undone so that the directory is left in its
+    /// original state. All renaming is done via `rename`.
+    ///
+    /// Because the object hashes and the manifest are optional, failing to
+    /// move them only removes them. They are moved before the state file so
+    /// that they are never older than the state. If moving the state file
+    /// fails, they are removed rather than restored.
+    ///
+    /// If the process dies half way through, the old data directory is
+    /// left behind. The next update will sort this out via `recover`.
+    fn replace_from<F>(
+        &self, other: &ServerDir, mut rename: F
+    ) -> Result<(), Error>
+    where F: FnMut(&Path, &Path) -> Result<(), io::Error> {
+        *lock_recovered(&self.tree) = None;
+        let data = self.data_path();
+        let old_data = self.old_data_path();
+        let _ = self.store.remove_dir_all(&old_data);
+        let have_old = match rename(&data, &old_data) {
+            Ok(()) => true,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => {
+                info!(
+                    "Failed to move RRDP data directory '{}' out of the \
+                     way: {}.",
+                    data.display(), err
+                );
+                return Err(Error)
+            }
+        };
+        let restore_old = |rename: &mut F| {
+            if have_old {
+                if let Err(err) = rename(&old_data, &data) {
+                    info!(
+                        "Failed to restore RRDP data directory '{}': {}.",
+                        data.display(), err
+                    );
+                }
+            }
+        };
+        if let Err(err) = rename(&other.data_path(), &data) {
+            info!(
+                "Failed to move RRDP data directory '{}' from temporary \
+                 location '{}': {}.",
+                data.display(), other.data_path().display(), err
+            );
+            restore_old(&mut rename);
+            return Err(Error)
+        }
+
+        let _ = self.store.remove_file(&self.hashes_path());
+        if let Err(err) = rename(&other.hashes_path(), &self.hashes_path()) {
+            info!(
+                "Failed to move RRDP object hashes file '{}' from temporary \
+                 location '{}': {}.",
+                self.hashes_path().display(),
+                other.hashes_path().display(),
+                err
+            );
+        }
+
+        let _ = self.store.remove_file(&self.manifest_path());
+        if let Err(err) = rename(
+            &other.manifest_path(), &self.manifest_path()
+        ) {
+            if err.kind() != io::ErrorKind::NotFound {
+                info!(
+                    "Failed to move RRDP manifest file '{}' from temporary \
+                     location '{}': {}.",
+                    self.manifest_path().display(),
+                    other.manifest_path().display(),
+                    err
+                );
+            }
+        }
+
+        // Renaming a file replaces an existing target, so the old state
+        // file stays in place if this fails. The object hashes and manifest
+        // are for the new data, though, so they have to go.
+        if let Err(err) = rename(other.state_path(), self.state_path()) {
+            info!(
+                "Failed to move RRDP state file '{}' from temporary \
+                 location '{}': {}.",
+                self.state_path().display(), other.state_path().display(),
+                err
+            );
+            let _ = self.store.remove_file(&self.hashes_path());
+            let _ = self.store.remove_file(&self.manifest_path());
+            if let Err(err) = rename(&data, &other.data_path()) {
+                info!(
+                    "Failed to move back RRDP data directory '{}': {}.",
+                    data.display(), err
+                );
+                let _ = self.store.remove_dir_all(&data);
+            }
+            restore_old(&mut rename);
+            return Err(Error)
+        }
+        let _ = self.store.remove_dir_all(&old_data);
+        Ok(())
+    }
+
+    /// Recovers from an interrupted `replace_from`.
+    ///
+    /// If the old data directory is still around, replacing was interrupted.
+    /// If the data directory matches the state file, the state file has
+    /// been replaced, too, and the old data directory is simply removed.
+    /// If instead the old data directory matches, it is moved back in
+    /// place and the object hashes, which may already be those of the new
+    /// data, are removed. Otherwise nothing is done and the server will be
+    /// found broken. Returns whether the data directory has been replaced.
+    fn recover(&self) -> bool {
+        let old_data = self.old_data_path();
+        if !self.store.is_dir(&old_data) {
+            return false
+        }
+        let state = match self.load_state() {
+            Ok(state) => state,
+            Err(_) => return false
+        };
+        let matches_state = |path: &Path| {
+            match _data_digest(
+                &*self.store, path, state.algorithm, state.digest_version,
+                state.deep_digest, state.compressed
+            ) {
+                Ok(digest) => {
+                    verify_slices_are_equal(
+                        digest.as_ref(), state.hash.as_ref()
+                    ).is_ok()
+                }
+                Err(_) => false
+            }
+        };
+        let data = self.data_path();
+        if self.store.is_dir(&data) && matches_state(&data) {
+            info!(
+                "Removing left-over RRDP data directory '{}'.",
+                old_data.display()
+            );
+            let _ = self.store.remove_dir_all(&old_data);
+            false
+        }
+        else if matches_state(&old_data) {
+            info!(
+                "Restoring RRDP data directory '{}' after an interrupted \
+                 update.",
+                data.display()
+            );
+            *lock_recovered(&self.tree) = None;
+            let _ = self.store.remove_file(&self.hashes_path());
+            let _ = self.store.remove_dir_all(&data);
+            if let Err(err) = self.store.rename(&old_data, &data) {
+                info!(
+                    "Failed to restore RRDP data directory '{}': {}.",
+                    data.display(), err
+                );
+            }
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    /// Saves the object hashes after an update.
+    ///
+    /// If there are no hashes or saving fails, removes the hashes file
+    /// instead so that we don’t keep outdated hashes around.
+    fn save_hashes(&self, hashes: Option<&ObjectHashes>) {
+        let path = self.hashes_path();
+        if let Some(hashes) = hashes {
+            if hashes.save(&*self.store, &path).is_ok() {
+                return
+            }
+        }
+        let _ = self.store.remove_file(&path);
+    }
+
+    /// Saves the content of the data directory as the manifest.
+    ///
+    /// The content is the one remembered when the directory was last
+    /// digested, so this needs to happen right after the state was saved.
+    /// Because the manifest is optional, it is removed if the content isn’t
+    /// known or saving fails.
+    fn save_manifest(&self) {
+        let path = self.manifest_path();
+        if let Some(ref tree) = *lock_recovered(&self.tree) {
+            match tree.save_manifest(&*self.store, &path) {
+                Ok(()) => return,
+                Err(err) => {
+                    info!(
+                        "Failed to write RRDP manifest file '{}': {}",
+                        path.display(), err
+                    );
+                }
+            }
+        }
+        let _ = self.store.remove_file(&path);
+    }
+
+    /// Loads the manifest if it matches the given state.
+    ///
+    /// Returns the content of the data directory listed in the manifest and
+    /// its digest. Returns `None` if there is no manifest or if it doesn’t
+    /// match the state, e.g., because the process died before the manifest
+    /// for a new state was written.
+    fn load_manifest(
+        &self, state: &ServerState
+    ) -> Option<(DigestTree, localdigest::Digest)> {
+        if !state.digest_version.is_known() {
+            return None
+        }
+        let path = self.manifest_path();
+        let tree = match DigestTree::load_manifest(&*self.store, &path) {
+            Ok(tree) => tree,
+            Err(err) => {
+                if err.kind() != io::ErrorKind::NotFound {
+                    info!(
+                        "Failed to read RRDP manifest file '{}': {}",
+                        path.display(), err
+                    );
+                }
+                return None
+            }
+        };
+        let digest = tree.digest(state.algorithm, state.digest_version);
+        if verify_slices_are_equal(
+            digest.as_ref(), state.hash.as_ref()
+        ).is_err() {
+            debug!(
+                "RRDP manifest file '{}' is stale. Ignoring it.",
+                path.display()
+            );
+            return None
+        }
+        Some((tree, digest))
+    }
+
+    /// The name of the state file in an archive.
+    ///
+    /// The names in an archive are always the same no matter the names
+    /// used in the server directory, so that an archive can be imported
+    /// into a cache configured differently.
+    const ARCHIVE_STATE: &'static str = "state.txt";
+
+    /// The name of the object hashes file in an archive.
+    const ARCHIVE_HASHES: &'static str = "hashes.txt";
+
+    /// The name of the data directory in an archive.
+    const ARCHIVE_DATA: &'static str = "data";
+
+    fn export(&self, writer: impl io::Write) -> Result<(), io::Error> {
+        let state = self.store.read(self.state_path())?;
+        let data_path = self.data_path();
+        let tree = DigestTree::load(&*self.store, &data_path, false)?;
+        let mut tar = tar::Builder::new(writer);
+        Self::append_archive_file(&mut tar, Self::ARCHIVE_STATE, &state)?;
+        match self.store.read(&self.hashes_path()) {
+            Ok(hashes) => {
+                Self::append_archive_file(
+                    &mut tar, Self::ARCHIVE_HASHES, &hashes
+                )?
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => { }
+            Err(err) => return Err(err)
+        }
+        Self::append_archive_dir(&mut tar, Self::ARCHIVE_DATA)?;
+        tree.for_each_entry(|path, size| {
+            let name = Self::archive_name(path)?;
+            if size.is_some() {
+                let data = self.store.read(&data_path.join(path))?;
+                Self::append_archive_file(&mut tar, &name, &data)
+            }
+            else {
+                Self::append_archive_dir(&mut tar, &name)
+            }
+        })?;
+        tar.into_inner()?.flush()
+    }
+
+    /// Adds a regular file to an archive.
+    ///
+    /// All metadata other than the size is fixed, so the same content
+    /// always results in the same archive.
+    fn append_archive_file<W: io::Write>(
+        tar: &mut tar::Builder<W>, name: &str, data: &[u8]
+    ) -> Result<(), io::Error> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        tar.append_data(&mut header, name, data)
+    }
+
+    /// Adds a directory to an archive.
+    fn append_archive_dir<W: io::Write>(
+        tar: &mut tar::Builder<W>, name: &str
+    ) -> Result<(), io::Error> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        tar.append_data(&mut header, name, io::empty())
+    }
+
+    /// Returns the name in an archive for a path in the data directory.
+    ///
+    /// Names are separated by slashes and have to be valid UTF-8.
+    fn archive_name(path: &Path) -> Result<String, io::Error> {
+        let mut res = String::from(Self::ARCHIVE_DATA);
+        for component in path.components() {
+            let component = match component {
+                Component::Normal(component) => component.to_str(),
+                _ => None
+            };
+            match component {
+                Some(component) => {
+                    res.push('/');
+                    res.push_str(component);
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("cannot archive path '{}'", path.display())
+                    ))
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Writes the content of an archive created by `export`.
+    ///
+    /// The directory is expected to be empty. Returns the state from the
+    /// archive if the digest of the data directory matches it.
+    fn import(&self, reader: impl io::Read) -> Result<ServerState, Error> {
+        self._import(reader).map_err(|err| {
+            info!(
+                "Failed to import RRDP archive into '{}': {}",
+                self.base.display(), err
+            );
+            Error
+        })?;
+        let state = self.load_state()?;
+        self.check_digest(&state)?;
+        Ok(state)
+    }
+
+    /// Writes the entries of an archive into the directory.
+    ///
+    /// The state file and object hashes are written under the names used
+    /// by this directory. Entries other than regular files and directories
+    /// are skipped.
+    fn _import(&self, reader: impl io::Read) -> Result<(), io::Error> {
+        let data_path = self.data_path();
+        let mut tar = tar::Archive::new(reader);
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            let kind = entry.header().entry_type();
+            let name = match entry.path()?.to_str() {
+                Some(name) => name.trim_end_matches('/').to_string(),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData, "invalid entry name"
+                    ))
+                }
+            };
+            if kind.is_dir() {
+                self.store.create_dir_all(
+                    &Self::archive_path(&data_path, &name)?
+                )?;
+                continue
+            }
+            else if !kind.is_file() {
+                continue
+            }
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            if name == Self::ARCHIVE_STATE {
+                self.store.write(self.state_path(), &data)?
+            }
+            else if name == Self::ARCHIVE_HASHES {
+                self.store.write(&self.hashes_path(), &data)?
+            }
+            else {
+                let path = Self::archive_path(&data_path, &name)?;
+                if let Some(parent) = path.parent() {
+                    self.store.create_dir_all(parent)?;
+                }
+                self.store.write(&path, &data)?
+            }
+        }
+        self.store.create_dir_all(&data_path)?;
+        self.store.commit(&data_path)
+    }
+
+    /// Returns the path in the data directory for a name in an archive.
+    ///
+    /// Only names of the data directory and of entries below it are
+    /// accepted, so an archive can’t write anywhere else.
+    fn archive_path(
+        data_path: &Path, name: &str
+    ) -> Result<PathBuf, io::Error> {
+        let mut components = name.split('/');
+        let mut res = data_path.to_path_buf();
+        let mut valid = components.next() == Some(Self::ARCHIVE_DATA);
+        for component in components {
+            if component.is_empty() || component == "."
+                || component == ".."
+                || Path::new(component).components().count() != 1
+            {
+                valid = false;
+                break
+            }
+            res.push(component);
+        }
+        if valid {
+            Ok(res)
+        }
+        else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid entry '{}'", name)
+            ))
+        }
+    }
+
+    fn module_path(&self, module: &uri::RsyncModule) -> PathBuf {
+        let mut res = self.data_path();
+        res.push(module.authority());
+        res.push(module.module());
+        res
+    }
+
+    /// Returns the path of the local file for an rsync URI.
+    ///
+    /// The URI comes from the repository and can’t be trusted. If any part
+    /// of it isn’t a plain path component, such as `..` or an absolute
+    /// path, the file would end up outside the data directory and the URI
+    /// is rejected.
+    fn uri_path(&self, uri: &uri::Rsync) -> Result<PathBuf, Error> {
+        let module = uri.module();
+        let parts = [module.authority(), module.module(), uri.path()];
+        let safe = parts.iter().all(|part| {
+            Path::new(part).components().all(|component| {
+                matches!(component, Component::Normal(_))
+            })
+        });
+        if !safe {
+            warn!(
+                "RRDP: rejecting URI {} pointing outside of the repository \
+                 directory.",
+                uri
+            );
+            return Err(Error)
+        }
+        let mut res = self.module_path(module);
+        res.push(uri.path());
+        Ok(res)
+    }
+
+    /// Returns all objects present in the data directory.
+    ///
+    /// For each object, returns its rsync URI, its local path, and its size.
+    /// The size of compressed objects is their uncompressed size. Within
+    /// each directory, the objects are sorted by name the same way as for
+    /// the digest.
+    fn objects(&self) -> Result<Vec<(uri::Rsync, PathBuf, u64)>, Error> {
+        self._objects().map_err(|err| {
+            info!(
+                "Failed to list objects in '{}': {}",
+                self.data_path().display(), err
+            );
+            Error
+        })
+    }
+
+    fn _objects(&self) -> Result<Vec<(uri::Rsync, PathBuf, u64)>, io::Error> {
+        let data_path = self.data_path();
+        let mut res = Vec::new();
+        let mut entries = Vec::new();
+        let mut dirs = vec![data_path.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in self.store.read_dir(&dir)? {
+                let path = dir.join(&entry.name);
+                match entry.size {
+                    None => entries.push((entry.name, path, None)),
+                    Some(size) => {
+                        let len = compress::stored_object_size(
+                            &*self.store, &path, size
+                        )?;
+                        entries.push((entry.name, path, Some(len)))
+                    }
+                }
+            }
+            entries.sort_by(|left, right| left.0.cmp(&right.0));
+
+            for (_, path, len) in entries.drain(..) {
+                match len {
+                    None => dirs.push(path),
+                    Some(len) => match Self::path_uri(&data_path, &path) {
+                        Some(uri) => res.push((uri, path, len)),
+                        None => {
+                            info!(
+                                "Skipping unexpected file '{}'.",
+                                path.display()
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Reconstructs the rsync URI for a path within the data directory.
+    ///
+    /// This is the reverse of `uri_path`.
+    fn path_uri(data_path: &Path, path: &Path) -> Option<uri::Rsync> {
+        let mut components = path.strip_prefix(data_path).ok()?.iter();
+        let authority = components.next()?.to_str()?;
+        let module = components.next()?.to_str()?;
+        let mut res = format!("rsync://{}/{}/", authority, module);
+        let mut first = true;
+        for item in components {
+            if !first {
+                res.push('/')
+            }
+            else {
+                first = false
+            }
+            res.push_str(item.to_str()?);
+        }
+        uri::Rsync::from_str(&res).ok()
+    }
+
+    /// Determines the digest of a data directory.
+    ///
+    /// See [`data_digest`] for how the digest is calculated.
+    ///
+    /// [`data_digest`]: fn.data_digest.html
+    pub fn digest(&self) -> Result<localdigest::Digest, Error> {
+        self.try_digest(
+            self.algorithm, DigestVersion::CURRENT, self.deep, self.compress
+        ).map_err(|_| Error)
+    }
+
+    /// Determines the digest of a data directory keeping the error.
+    ///
+    /// The digest is calculated using the given algorithm and version,
+    /// over the file content if `deep` is `true`, and for compressed objects
+    /// if `compressed` is `true`. These may differ from the directory’s own
+    /// for checking older state. Remembers the content of the directory
+    /// for `applied_digest`.
+    fn try_digest(
+        &self,
+        algorithm: DigestAlgorithm,
+        version: DigestVersion,
+        deep: bool,
+        compressed: bool,
+    ) -> Result<localdigest::Digest, DigestError> {
+        if let DigestVersion::Unknown(number) = version {
+            info!(
+                "Cannot digest RRDP data directory '{}': unknown digest \
+                 version {}.",
+                self.data_path().display(), number
+            );
+            return Err(DigestError::UnknownVersion(number))
+        }
+        let data_path = self.data_path();
+        self.digest_tree(compressed, |tree| {
+            if deep {
+                tree.deep_digest(
+                    &*self.store, &data_path, algorithm, version
+                )
+            }
+            else {
+                Ok(tree.digest(algorithm, version))
+            }
+        })
+    }
+
+    /// Walks the data directory and determines a digest from its content.
+    ///
+    /// Remembers the content for `applied_digest`.
+    fn digest_tree<F>(
+        &self, compressed: bool, op: F
+    ) -> Result<localdigest::Digest, DigestError>
+    where F: FnOnce(&DigestTree) -> Result<localdigest::Digest, io::Error> {
+        let mut cached = lock_recovered(&self.tree);
+        let res = DigestTree::load(
+            &*self.store, &self.data_path(), compressed
+        ).and_then(|tree| {
+            let digest = op(&tree)?;
+            Ok((tree, digest))
+        });
+        match res {
+            Ok((tree, digest)) => {
+                *cached = Some(tree);
+                Ok(digest)
+            }
+            Err(err) => {
+                *cached = None;
+                info!(
+                    "Failed to caculate digest for '{}': {}",
+                    self.data_path().display(), err
+                );
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Determines the digest of the data directory after known changes.
+    ///
+    /// The changes are the paths of files that have been written with
+    /// their new size or removed with a size of `None`. If the directory
+    /// has been digested before, the digest is derived from the content
+    /// remembered then and the changes without walking the directory
+    /// again. Otherwise, and always for a deep digest which needs the
+    /// content of all files, the whole directory is digested.
+    pub fn applied_digest(
+        &self, changes: &[(PathBuf, Option<u64>)]
+    ) -> Result<localdigest::Digest, Error> {
+        if self.deep {
+            return self.digest()
+        }
+        let data_path = self.data_path();
+        {
+            let mut cached = lock_recovered(&self.tree);
+            if let Some(mut tree) = cached.take() {
+                let ok = changes.iter().all(|(path, size)| {
+                    match path.strip_prefix(&data_path) {
+                        Ok(path) => tree.update(path, *size),
+                        Err(_) => false
+                    }
+                });
+                if ok {
+                    let digest = tree.digest(
+                        self.algorithm, DigestVersion::CURRENT
+                    );
+                    *cached = Some(tree);
+                    return Ok(digest)
+                }
+            }
+        }
+        self.digest()
+    }
+
+    /// Checks that the digest of the data directory matches the state.
+    ///
+    /// The digest is calculated the way recorded in the state. If it
+    /// doesn’t match, the files that diverge are logged.
+    pub fn check_digest(&self, state: &ServerState) -> Result<(), Error> {
+        let digest = self.try_digest(
+            state.algorithm, state.digest_version, state.deep_digest,
+            state.compressed
+        ).map_err(|_| Error)?;
+        verify_slices_are_equal(
+            digest.as_ref(), state.hash.as_ref()
+        ).map_err(|_| {
+            info!(
+                "Mismatch of digest for '{}'. Content must have changed.",
+                self.data_path().display()
+            );
+            self.log_divergence(state);
+            Error
+        })
+    }
+
+    /// Rewrites the state with a digest of the current version.
+    ///
+    /// Everything else about the digest stays as recorded in the state.
+    /// This is meant to be used right after the digest has been checked, so
+    /// that the state of an older version is upgraded once rather than the
+    /// local copy being considered broken.
+    pub fn upgrade_digest(&self, state: &ServerState) -> Result<(), Error> {
+        let digest = self.try_digest(
+            state.algorithm, DigestVersion::CURRENT, state.deep_digest,
+            state.compressed
+        ).map_err(|_| Error)?;
+        debug!(
+            "RRDP data directory '{}': upgrading digest from version {} \
+             to {}.",
+            self.data_path().display(), state.digest_version.to_number(),
+            DigestVersion::CURRENT.to_number()
+        );
+        ServerState {
+            hash: digest.into(),
+            digest_version: DigestVersion::CURRENT,
+            .. state.clone()
+        }.save(&*self.store, self.state_path())
+    }
+
+    /// Logs which files of the data directory diverge from the state.
+    ///
+    /// This is meant to be used after the digest didn’t match the state.
+    /// If there is a manifest for the state, the first difference to it is
+    /// logged. Since the manifest records the size of each file, this finds
+    /// files that were changed, too. Otherwise, the directory is compared
+    /// against the object hashes. These don’t record the size of files, so
+    /// only missing and unexpected files are found unless the state uses a
+    /// deep digest in which case the content of all files is checked, too.
+    /// This is slow but only happens if something is wrong already.
+    pub fn log_divergence(&self, state: &ServerState) {
+        if !state.deep_digest && self.log_manifest_difference(state) {
+            return
+        }
+        let differences = match self.divergence(state) {
+            Ok(differences) => differences,
+            Err(_) => return
+        };
+        match differences.first() {
+            Some(difference) => {
+                info!(
+                    "RRDP data directory '{}' differs from its object \
+                     hashes: {}. {} file(s) diverge in total.",
+                    self.data_path().display(), difference,
+                    differences.len()
+                );
+            }
+            None => {
+                info!(
+                    "RRDP data directory '{}' matches its object hashes. \
+                     Cannot tell which file has changed.",
+                    self.data_path().display()
+                );
+            }
+        }
+    }
+
+    /// Logs the first difference between the data directory and manifest.
+    ///
+    /// Returns whether there was a manifest for the state to compare with.
+    fn log_manifest_difference(&self, state: &ServerState) -> bool {
+        let manifest = match self.load_manifest(state) {
+            Some((manifest, _)) => manifest,
+            None => return false
+        };
+        let tree = match DigestTree::load(
+            &*self.store, &self.data_path(), state.compressed
+        ) {
+            Ok(tree) => tree,
+            Err(_) => return false
+        };
+        match manifest.difference(&tree) {
+            Some(difference) => {
+                info!(
+                    "RRDP data directory '{}' differs from its \
+                     manifest: {}.",
+                    self.data_path().display(), difference
+                );
+            }
+            None => {
+                info!(
+                    "RRDP data directory '{}' matches its manifest. \
+                     Cannot tell which file has changed.",
+                    self.data_path().display()
+                );
+            }
+        }
+        true
+    }
+
+    /// Returns the differences between the data directory and the hashes.
+    ///
+    /// The differences are sorted by path, with unexpected files last.
+    fn divergence(
+        &self, state: &ServerState
+    ) -> Result<Vec<TreeDifference>, Error> {
+        let hashes = match self.load_hashes()? {
+            Some(hashes) => hashes,
+            None => {
+                info!(
+                    "No object hashes to compare RRDP data directory '{}' \
+                     against.",
+                    self.data_path().display()
+                );
+                return Err(Error)
+            }
+        };
+        let data_path = self.data_path();
+        let mut found = DigestTree::load(
+            &*self.store, &data_path, state.compressed
+        ).map_err(|err| {
+            info!(
+                "Failed to walk RRDP data directory '{}': {}",
+                data_path.display(), err
+            );
+            Error
+        })?.files();
+        let mut expected: Vec<_> = hashes.uris().filter_map(|uri| {
+            let path = self.uri_path(uri).ok()?;
+            Some((path.strip_prefix(&data_path).ok()?.to_path_buf(), uri))
+        }).collect();
+        expected.sort_by(|left, right| left.0.cmp(&right.0));
+
+        let mut res = Vec::new();
+        for (path, uri) in expected {
+            if !found.remove(&path) {
+                res.push(TreeDifference::Missing(path));
+                continue
+            }
+            if !state.deep_digest {
+                continue
+            }
+            let file_path = data_path.join(&path);
+            let data = compress::read_stored_object(
+                &*self.store, &file_path
+            ).map_err(|err| {
+                info!(
+                    "Failed to read RRDP object file '{}': {}",
+                    file_path.display(), err
+                );
+                Error
+            })?;
+            if !hashes.verify(uri, &data) {
+                res.push(TreeDifference::Content(path))
+            }
+        }
+        res.extend(found.into_iter().map(TreeDifference::Unexpected));
+        Ok(res)
+    }
+}
+
+
+//------------ Data Directory Digest -----------------------------------------
+
+/// Calculates the digest of the data directory of an RRDP server.
+///
+/// The digest is used to detect changes to the local copy of a server made
+/// behind our back. It only covers the names and sizes of the files, not
+/// their content. It is calculated as follows using the given algorithm,
+/// SHA-256 by default.
+///
+/// Starting with the data directory itself, the entries of a directory are
+/// sorted by their file name, compared byte by byte. Entries that are
+/// neither directories nor regular files are ignored. For each entry in
+/// this order, the file name, lossily converted to UTF-8, is added to the
+/// digest context, followed by the file size as an eight byte unsigned
+/// integer in little-endian byte order for regular files. Each directory is
+/// pushed onto a stack. Once all entries of the directory are done, the
+/// directory on top of the stack is taken off and processed in the same
+/// way, i.e., directories are visited depth first, starting with the last
+/// one in sort order. The digest is finished once the stack is empty.
+///
+/// For instance, a data directory containing a file
+/// `example.net/repo/a.cer` of 11 bytes and a file
+/// `example.net/repo/sub/b.roa` of 3 bytes has the digest
+/// `a4d742f594f2513f95c841a13d7cc676720f5ffc8e17a200e6eb7a492cbfad70` when
+/// using SHA-256.
+///
+/// If objects are stored compressed, the size of their uncompressed data is
+/// used instead of the file size, so the digest doesn’t change with the way
+/// objects are stored. This function is for uncompressed objects only.
+///
+/// If `deep` is `true`, the digest also covers the content of the files:
+/// after the size of each regular file, the digest of its content using
+/// the same algorithm is added. For compressed objects, this is the digest
+/// of the uncompressed data.
+pub fn data_digest(
+    path: &Path, algorithm: DigestAlgorithm, deep: bool
+) -> Result<DigestHex, Error> {
+    match _data_digest(
+        &FsStore, path, algorithm, DigestVersion::CURRENT, deep, false
+    ) {
+        Ok(digest) => Ok(digest.into()),
+        Err(err) => {
+            error!(
+                "Failed to caculate digest for '{}': {}",
+                path.display(), err
+            );
+            Err(Error)
+        }
+    }
+}
+
+/// Calculates the digest of the data directory at `path` in `store`.
+///
+/// Unlike loading a `DigestTree`, this walks the directory without
+/// remembering its content. Only the entries of the directory currently
+/// digested and the paths of the directories still to visit are kept in
+/// memory, so a repository with a huge number of files only needs as much
+/// memory as its largest directory.
+fn _data_digest(
+    store: &dyn CacheStore,
+    path: &Path,
+    algorithm: DigestAlgorithm,
+    version: DigestVersion,
+    deep: bool,
+    compressed: bool,
+) -> Result<localdigest::Digest, io::Error> {
+    if !version.is_known() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData, "unknown digest version"
+        ))
+    }
+    let mut dirs = vec![path.to_path_buf()];
+    let mut context = localdigest::Context::new(algorithm);
+    while let Some(dir) = dirs.pop() {
+        let mut entries = store.read_dir(&dir)?;
+        entries.sort_unstable_by(|left, right| left.name.cmp(&right.name));
+        for entry in entries {
+            context.update(entry.name.to_string_lossy().as_bytes());
+            let size = match entry.size {
+                Some(size) => size,
+                None => {
+                    dirs.push(dir.join(&entry.name));
+                    continue
+                }
+            };
+            let size = if compressed {
+                compress::stored_object_size(
+                    store, &dir.join(&entry.name), size
+                )?
+            }
+            else {
+                size
+            };
+            context.update(&version.size_bytes(size));
+            if deep {
+                let data = compress::read_stored_object(
+                    store, &dir.join(&entry.name)
+                )?;
+                context.update(
+                    localdigest::Digest::of(algorithm, &data).as_ref()
+                );
+            }
+        }
+    }
+    Ok(context.finish())
+}
+
+
+//------------ DigestVersion -------------------------------------------------
+
+/// The way the digest of a data directory is calculated.
+///
+/// The version is recorded in the state file so that local copies digested
+/// by older versions can still be checked.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DigestVersion {
+    /// Version 1 adds file sizes in host byte order.
+    ///
+    /// This makes the digest differ between little-endian and big-endian
+    /// systems.
+    HostOrder,
+
+    /// Version 2 adds file sizes in little-endian byte order.
+    LittleEndian,
+
+    /// A version we don’t know, presumably written by a newer version.
+    ///
+    /// We can’t calculate such a digest, so the local copy can’t be checked
+    /// and has to be replaced by the snapshot.
+    Unknown(u8),
+}
+
+impl DigestVersion {
+    /// The version used for all new digests.
+    pub const CURRENT: Self = DigestVersion::LittleEndian;
+
+    /// Returns the version for the number recorded in the state file.
+    pub fn from_number(number: u8) -> Self {
+        match number {
+            1 => DigestVersion::HostOrder,
+            2 => DigestVersion::LittleEndian,
+            _ => DigestVersion::Unknown(number)
+        }
+    }
+
+    /// Returns the number of the version recorded in the state file.
+    pub fn to_number(self) -> u8 {
+        match self {
+            DigestVersion::HostOrder => 1,
+            DigestVersion::LittleEndian => 2,
+            DigestVersion::Unknown(number) => number,
+        }
+    }
+
+    /// Returns whether we know how to calculate a digest of this version.
+    pub fn is_known(self) -> bool {
+        !matches!(self, DigestVersion::Unknown(_))
+    }
+
+    /// Returns the representation of a file size in the digest.
+    fn size_bytes(self, size: u64) -> [u8; 8] {
+        match self {
+            DigestVersion::HostOrder => size.to_ne_bytes(),
+            DigestVersion::LittleEndian => size.to_le_bytes(),
+            // Digests of unknown versions are refused before we get here.
+            DigestVersion::Unknown(_) => size.to_le_bytes(),
+        }
+    }
+}
+
+impl Default for DigestVersion {
+    fn default() -> Self {
+        DigestVersion::CURRENT
+    }
+}
+
+
+//------------ DigestTree ----------------------------------------------------
+
+/// The names and sizes of all entries of a data directory.
+///
+/// This is everything that goes into the digest of the directory. Keeping
+/// it around allows calculating the digest again after known changes
+/// without walking the whole directory.
+#[derive(Clone, Debug, Default)]
+struct DigestTree {
+    /// The entries of each directory.
+    ///
+    /// The keys are the paths of the directories relative to the data
+    /// directory, with the data directory itself as the empty path. The
+    /// entries map the file name to the size for regular files and to
+    /// `None` for directories. Because the maps are ordered by file name,
+    /// they already are in the order needed for the digest.
+    dirs: BTreeMap<PathBuf, BTreeMap<OsString, Option<u64>>>,
+}
+
+impl DigestTree {
+    /// Loads the tree by walking the data directory at `path` in `store`.
+    ///
+    /// Entries that are neither directories nor regular files are ignored.
+    /// If `compressed` is `true`, the uncompressed size of objects is used.
+    fn load(
+        store: &dyn CacheStore, path: &Path, compressed: bool
+    ) -> Result<Self, io::Error> {
+        let mut res = DigestTree::default();
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = BTreeMap::new();
+            let dir_path = path.join(&dir);
+            for entry in store.read_dir(&dir_path)? {
+                let size = match entry.size {
+                    Some(size) if compressed => {
+                        Some(compress::stored_object_size(
+                            store, &dir_path.join(&entry.name), size
+                        )?)
+                    }
+                    Some(size) => Some(size),
+                    None => {
+                        dirs.push(dir.join(&entry.name));
+                        None
+                    }
+                };
+                entries.insert(entry.name, size);
+            }
+            res.dirs.insert(dir, entries);
+        }
+        Ok(res)
+    }
+
+    /// Calculates the digest as described for `data_digest`.
+    fn digest(
+        &self, algorithm: DigestAlgorithm, version: DigestVersion
+    ) -> localdigest::Digest {
+        match self.digest_files(
+            algorithm, version, |_, _| Ok::<_, Infallible>(())
+        ) {
+            Ok(digest) => digest,
+            Err(err) => match err { }
+        }
+    }
+
+    /// Calculates the deep digest as described for `data_digest`.
+    ///
+    /// The content of the files is read from the data directory at `path`
+    /// in `store`.
+    fn deep_digest(
+        &self,
+        store: &dyn CacheStore,
+        path: &Path,
+        algorithm: DigestAlgorithm,
+        version: DigestVersion,
+    ) -> Result<localdigest::Digest, io::Error> {
+        self.digest_files(algorithm, version, |file, context| {
+            let data = compress::read_stored_object(store, &path.join(file))?;
+            context.update(
+                localdigest::Digest::of(algorithm, &data).as_ref()
+            );
+            Ok(())
+        })
+    }
+
+    /// Calculates a digest, adding to it for each file via `op`.
+    ///
+    /// The operation is called with the path of a regular file relative to
+    /// the data directory right after its size has been added.
+    fn digest_files<F, E>(
+        &self, algorithm: DigestAlgorithm, version: DigestVersion, mut op: F
+    ) -> Result<localdigest::Digest, E>
+    where F: FnMut(&Path, &mut localdigest::Context) -> Result<(), E> {
+        // A stack with the directories we still have to process.
+        //
+        // The directories of an entry are pushed in their sorted order. When
+        // we are done with one directory, we take the last one off the stack
+        // and process it. Rince and repeat until the stack is empty.
+        let mut dirs = vec![PathBuf::new()];
+        let mut context = localdigest::Context::new(algorithm);
+        while let Some(dir) = dirs.pop() {
+            let entries = match self.dirs.get(&dir) {
+                Some(entries) => entries,
+                None => continue
+            };
+            for (name, size) in entries {
+                context.update(name.to_string_lossy().as_bytes());
+                match *size {
+                    Some(size) => {
+                        context.update(&version.size_bytes(size));
+                        op(&dir.join(name), &mut context)?;
+                    }
+                    None => dirs.push(dir.join(name)),
+                }
+            }
+        }
+        Ok(context.finish())
+    }
+
+    /// Calls `op` for each entry in the order entries are digested in.
+    ///
+    /// The operation is called with the path of the entry relative to the
+    /// data directory and its size or `None` for a directory. Directories
+    /// are visited when they appear as an entry, before any of their own
+    /// entries.
+    fn for_each_entry<F, E>(&self, mut op: F) -> Result<(), E>
+    where F: FnMut(&Path, Option<u64>) -> Result<(), E> {
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match self.dirs.get(&dir) {
+                Some(entries) => entries,
+                None => continue
+            };
+            for (name, size) in entries {
+                let path = dir.join(name);
+                op(&path, *size)?;
+                if size.is_none() {
+                    dirs.push(path)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a change to a file.
+    ///
+    /// The path is relative to the data directory. If `size` is `None`,
+    /// the file has been removed. Otherwise it has been written with the
+    /// given size, creating any missing parent directories.
+    ///
+    /// Returns `false` if the change cannot be represented, e.g., because
+    /// a directory was replaced by a file. The tree should not be used
+    /// anymore in this case.
+    fn update(&mut self, path: &Path, size: Option<u64>) -> bool {
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return false
+        };
+        let size = match size {
+            Some(size) => size,
+            None => {
+                if let Some(entries) = self.dirs.get_mut(parent) {
+                    entries.remove(name);
+                }
+                return true
+            }
+        };
+        let mut dir = PathBuf::new();
+        for component in parent.components() {
+            let component = match component {
+                Component::Normal(component) => component,
+                _ => return false
+            };
+            let entries = self.dirs.entry(dir.clone()).or_default();
+            if let Some(Some(_)) = entries.insert(component.into(), None) {
+                return false
+            }
+            dir.push(component);
+        }
+        let entries = self.dirs.entry(dir).or_default();
+        !matches!(entries.insert(name.into(), Some(size)), Some(None))
+    }
+
+    /// Loads a tree from the manifest file at `path`.
+    ///
+    /// The manifest contains one line for each entry of the tree. The line
+    /// starts with the size of a regular file or a `-` for a directory,
+    /// followed by a single space and the path of the entry relative to the
+    /// data directory with its components separated by slashes.
+    fn load_manifest(
+        store: &dyn CacheStore, path: &Path
+    ) -> Result<Self, io::Error> {
+        let invalid = || {
+            io::Error::new(io::ErrorKind::InvalidData, "invalid data")
+        };
+        let mut res = DigestTree::default();
+        res.dirs.insert(PathBuf::new(), BTreeMap::new());
+        for line in store.read(path)?.as_slice().lines() {
+            let line = line?;
+            let (size, entry) = split_text_line(&line)?;
+            let mut entry_path = PathBuf::new();
+            for component in entry.split('/') {
+                if component.is_empty() || component == "."
+                    || component == ".."
+                {
+                    return Err(invalid())
+                }
+                entry_path.push(component);
+            }
+            if size == "-" {
+                let (parent, name) = match (
+                    entry_path.parent(), entry_path.file_name()
+                ) {
+                    (Some(parent), Some(name)) => (parent, name),
+                    _ => return Err(invalid())
+                };
+                if let Some(Some(_)) = res.dirs.entry(
+                    parent.into()
+                ).or_default().insert(name.into(), None) {
+                    return Err(invalid())
+                }
+                res.dirs.entry(entry_path).or_default();
+            }
+            else {
+                let size = u64::from_str(size).map_err(|_| invalid())?;
+                if !res.update(&entry_path, Some(size)) {
+                    return Err(invalid())
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Saves the tree to the manifest file at `path`.
+    ///
+    /// Fails if a file name isn’t valid UTF-8 or can’t be represented in
+    /// the manifest.
+    fn save_manifest(
+        &self, store: &dyn CacheStore, path: &Path
+    ) -> Result<(), io::Error> {
+        write_atomic(store, path, |file| {
+            for (dir, entries) in &self.dirs {
+                let mut dir_path = String::new();
+                for component in dir.components() {
+                    dir_path.push_str(manifest_name(component.as_os_str())?);
+                    dir_path.push('/');
+                }
+                for (name, size) in entries {
+                    let name = manifest_name(name)?;
+                    match *size {
+                        Some(size) => {
+                            writeln!(file, "{} {}{}", size, dir_path, name)?
+                        }
+                        None => writeln!(file, "- {}{}", dir_path, name)?,
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns the paths of all regular files in the tree.
+    fn files(&self) -> BTreeSet<PathBuf> {
+        self.dirs.iter().flat_map(|(dir, entries)| {
+            entries.iter().filter(|(_, size)| size.is_some()).map(
+                move |(name, _)| dir.join(name)
+            )
+        }).collect()
+    }
+
+    /// Returns the first difference between this and the `actual` tree.
+    fn difference(&self, actual: &DigestTree) -> Option<TreeDifference> {
+        let empty = BTreeMap::new();
+        for (dir, entries) in &self.dirs {
+            let actual_entries = actual.dirs.get(dir).unwrap_or(&empty);
+            for (name, size) in entries {
+                let path = dir.join(name);
+                match (*size, actual_entries.get(name)) {
+                    (_, None) => return Some(TreeDifference::Missing(path)),
+                    (Some(expected), Some(&Some(found))) => {
+                        if expected != found {
+                            return Some(TreeDifference::Size {
+                                path, expected, found
+                            })
+                        }
+                    }
+                    (None, Some(&None)) => { }
+                    _ => return Some(TreeDifference::Kind(path))
+                }
+            }
+        }
+        for (dir, entries) in &actual.dirs {
+            let expected = self.dirs.get(dir).unwrap_or(&empty);
+            for name in entries.keys() {
+                if !expected.contains_key(name) {
+                    return Some(TreeDifference::Unexpected(dir.join(name)))
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Returns a file name for use in a manifest.
+fn manifest_name(name: &OsStr) -> Result<&str, io::Error> {
+    match name.to_str() {
+        Some(name) if !name.contains(|ch| ch == '/' || ch == '\n') => {
+            Ok(name)
+        }
+        _ => {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file name not allowed in manifest"
+            ))
+        }
+    }
+}
+
+
+//------------ TreeDifference ------------------------------------------------
+
+/// A difference between the expected and actual content of a directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum TreeDifference {
+    /// An expected entry is missing.
+    Missing(PathBuf),
+
+    /// There is an entry that isn’t expected.
+    Unexpected(PathBuf),
+
+    /// A file has a different size.
+    Size {
+        path: PathBuf,
+        expected: u64,
+        found: u64,
+    },
+
+    /// A file has become a directory or vice versa.
+    Kind(PathBuf),
+
+    /// The content of a file doesn’t match its hash.
+    Content(PathBuf),
+}
+
+impl fmt::Display for TreeDifference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TreeDifference::Missing(ref path) => {
+                write!(f, "'{}' is missing", path.display())
+            }
+            TreeDifference::Unexpected(ref path) => {
+                write!(f, "'{}' is unexpected", path.display())
+            }
+            TreeDifference::Size { ref path, expected, found } => {
+                write!(
+                    f, "'{}' has size {} instead of {}",
+                    path.display(), found, expected
+                )
+            }
+            TreeDifference::Kind(ref path) => {
+                write!(
+                    f, "'{}' changed between file and directory",
+                    path.display()
+                )
+            }
+            TreeDifference::Content(ref path) => {
+                write!(f, "'{}' has changed content", path.display())
+            }
+        }
+    }
+}
+
+
+//------------ DigestError ---------------------------------------------------
+
+/// Calculating the digest of a data directory failed.
+#[derive(Debug)]
+pub enum DigestError {
+    /// A transient I/O error. The directory itself may well be fine.
+    Transient(io::Error),
+
+    /// The digest version of the state is unknown.
+    ///
+    /// The directory may well be fine but we can’t tell.
+    UnknownVersion(u8),
+
+    /// Any other error.
+    Fatal(io::Error),
+}
+
+impl DigestError {
+    /// Returns whether an I/O error is likely to go away by itself.
+    fn is_transient(err: &io::Error) -> bool {
+        matches!(
+            err.kind(),
+            io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+        ) || Self::is_transient_os_error(err)
+    }
+
+    #[cfg(unix)]
+    fn is_transient_os_error(err: &io::Error) -> bool {
+        match err.raw_os_error() {
+            Some(code) => code == libc::EIO || code == libc::ETIMEDOUT,
+            None => false
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn is_transient_os_error(_err: &io::Error) -> bool {
+        false
+    }
+}
+
+impl From<io::Error> for DigestError {
+    fn from(err: io::Error) -> Self {
+        if Self::is_transient(&err) {
+            DigestError::Transient(err)
+        }
+        else {
+            DigestError::Fatal(err)
+        }
+    }
+}
+
+impl fmt::Display for DigestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DigestError::Transient(ref err) => {
+                write!(f, "transient error: {}", err)
+            }
+            DigestError::UnknownVersion(number) => {
+                write!(f, "unknown digest version {}", number)
+            }
+            DigestError::Fatal(ref err) => err.fmt(f)
+        }
+    }
+}
+
+
+//------------ DeltaFailure --------------------------------------------------
+
+/// The reason why a server could not be updated via deltas.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeltaFailure {
+    /// There is no usable local state for the server.
+    NoState,
+
+    /// The server has started a new session.
+    SessionChanged,
+
+    /// The notification file doesn’t list any deltas.
+    NoDeltas,
+
+    /// The deltas of the server don’t lead from our serial to the current.
+    SerialGap,
+
+    /// The server’s serial is lower than ours within the same session.
+    SerialRegressed,
+
+    /// The notification file lists more than one delta for a serial.
+    DuplicateSerial,
+
+    /// Deltas are disabled for the server.
+    Disabled,
+
+    /// There are more deltas to follow than allowed.
+    TooManyDeltas,
+
+    /// The local copy doesn’t match its recorded digest.
+    DigestMismatch,
+
+    /// The local copy was digested with a different algorithm.
+    AlgorithmChanged,
+
+    /// The local copy was digested with a different deep digest setting.
+    DigestModeChanged,
+
+    /// The compression of stored objects has changed.
+    StorageChanged,
+
+    /// Fetching or processing a delta failed.
+    Http,
+
+    /// The deadline for updating the server has passed.
+    Deadline,
+
+    /// Updating the local copy failed.
+    Local,
+}
+
+impl fmt::Display for DeltaFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            DeltaFailure::NoState => "no local state",
+            DeltaFailure::SessionChanged => "session changed",
+            DeltaFailure::NoDeltas => "no deltas listed",
+            DeltaFailure::SerialGap => "no deltas from our serial",
+            DeltaFailure::SerialRegressed => "serial regressed",
+            DeltaFailure::DuplicateSerial => "duplicate delta serials",
+            DeltaFailure::Disabled => "deltas disabled",
+            DeltaFailure::TooManyDeltas => "too many deltas",
+            DeltaFailure::DigestMismatch => "local copy doesn’t match digest",
+            DeltaFailure::AlgorithmChanged => "local digest algorithm changed",
+            DeltaFailure::DigestModeChanged => "deep digest setting changed",
+            DeltaFailure::StorageChanged => "object compression changed",
+            DeltaFailure::Http => "fetching delta failed",
+            DeltaFailure::Deadline => "deadline exceeded",
+            DeltaFailure::Local => "local error while applying deltas",
+        })
+    }
+}
+
+
+//------------ CheckReport ---------------------------------------------------
+
+/// What updating a server would do.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CheckReport {
+    /// The server’s serial number if the notification file was fetched.
+    pub serial: Option<u64>,
+
+    /// Whether the notification file would be refused.
+    ///
+    /// This happens if it doesn’t have the pinned session or looks like a
+    /// replay that is refused. Nothing else is checked then.
+    pub refused: bool,
+
+    /// The update path that would be chosen if updating would succeed.
+    pub path: Option<UpdatePath>,
+
+    /// Why the deltas can’t be used if they were considered and can’t.
+    pub delta_failure: Option<DeltaFailure>,
+
+    /// Whether the snapshot was tried and found unusable.
+    pub snapshot_failed: bool,
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let serial = match self.serial {
+            Some(serial) => serial,
+            None => return f.write_str("notification file unavailable")
+        };
+        if self.refused {
+            return write!(f, "update to serial {} would be refused", serial)
+        }
+        match self.path {
+            Some(UpdatePath::Current) => {
+                write!(f, "up to date at serial {}", serial)?
+            }
+            Some(UpdatePath::Delta) => {
+                write!(f, "delta update to serial {}", serial)?
+            }
+            Some(UpdatePath::Snapshot) => {
+                write!(f, "snapshot update to serial {}", serial)?
+            }
+            None => write!(f, "update to serial {} would fail", serial)?,
+        }
+        match self.delta_failure {
+            None | Some(DeltaFailure::NoState) => { }
+            Some(failure) => write!(f, ", deltas unusable: {}", failure)?,
+        }
+        if self.snapshot_failed {
+            f.write_str(", snapshot unusable")?
+        }
+        Ok(())
+    }
+}
+
+
+//------------ VerifyReport --------------------------------------------------
+
+/// The consistency of the local copy of a server.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct VerifyReport {
+    /// The session of the local copy if the state file could be read.
+    pub session: Option<Uuid>,
+
+    /// The serial number of the local copy if the state could be read.
+    pub serial: Option<u64>,
+
+    /// The number of objects if the state file could be read.
+    ///
+    /// This is zero for state files of older versions.
+    pub object_count: Option<usize>,
+
+    /// Whether the data directory matches the state.
+    pub consistent: bool,
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (session, serial) = match (self.session, self.serial) {
+            (Some(session), Some(serial)) => (session, serial),
+            _ => return f.write_str("state file unavailable")
+        };
+        write!(
+            f, "{} at serial {} of session {}",
+            if self.consistent { "consistent" } else { "inconsistent" },
+            serial, session
+        )?;
+        match self.object_count {
+            Some(count) => write!(f, ", {} objects", count),
+            None => f.write_str(", object count unknown")
+        }
+    }
+}
+
+
+//------------ Diagnosis -----------------------------------------------------
+
+/// The health of the local copy of an RRDP server.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Diagnosis {
+    /// The local copy is current.
+    UpToDate {
+        serial: u64
+    },
+
+    /// The local copy is behind the server.
+    Behind {
+        /// Our serial number.
+        serial: u64,
+
+        /// The server’s serial number.
+        current: u64,
+
+        /// Whether the copy can be updated via the available deltas.
+        deltas: bool,
+    },
+
+    /// The local copy is ahead of the server within the same session.
+    Ahead {
+        /// Our serial number.
+        serial: u64,
+
+        /// The server’s serial number.
+        current: u64,
+    },
+
+    /// The server has moved on to a different session.
+    StaleSession {
+        /// Our session.
+        session: Uuid,
+
+        /// The server’s session.
+        current: Uuid,
+    },
+
+    /// The notification file could not be fetched.
+    Unreachable {
+        serial: u64
+    },
+
+    /// The local copy is broken.
+    Broken,
+}
+
+impl Diagnosis {
+    /// Classifies a consistent local copy against a notification file.
+    fn classify(state: &ServerState, notify: &NotificationFile) -> Self {
+        if notify.session_id != state.session {
+            return Diagnosis::StaleSession {
+                session: state.session,
+                current: notify.session_id,
+            }
+        }
+        match notify.serial.cmp(&state.serial) {
+            cmp::Ordering::Equal => {
+                Diagnosis::UpToDate { serial: state.serial }
+            }
+            cmp::Ordering::Greater => {
+                Diagnosis::Behind {
+                    serial: state.serial,
+                    current: notify.serial,
+                    deltas: Server::calc_deltas(
+                        notify, state, Level::Debug
+                    ).is_ok(),
+                }
+            }
+            cmp::Ordering::Less => {
+                Diagnosis::Ahead {
+                    serial: state.serial,
+                    current: notify.serial,
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Diagnosis::UpToDate { serial } => {
+                write!(f, "up to date at serial {}", serial)
+            }
+            Diagnosis::Behind { serial, current, deltas } => {
+                write!(
+                    f, "behind at serial {}, server at serial {}, {}",
+                    serial, current,
+                    if deltas { "deltas available" }
+                    else { "snapshot needed" }
+                )
+            }
+            Diagnosis::Ahead { serial, current } => {
+                write!(
+                    f, "ahead at serial {}, server at serial {}",
+                    serial, current
+                )
+            }
+            Diagnosis::StaleSession { session, current } => {
+                write!(
+                    f, "stale session {}, server at session {}",
+                    session, current
+                )
+            }
+            Diagnosis::Unreachable { serial } => {
+                write!(
+                    f, "notification file unavailable, local copy at \
+                        serial {}",
+                    serial
+                )
+            }
+            Diagnosis::Broken => f.write_str("broken")
+        }
+    }
+}
+
+
+//------------ ServerOptions -------------------------------------------------
+
+/// The options for the local cache of RRDP servers.
+#[derive(Clone, Debug, Default)]
+pub struct ServerOptions {
+    /// Whether to verify objects against their hash when first read.
+    pub verify_on_read: bool,
+
+    /// Whether to log a summary of each update as a single record.
+    pub log_timeline: bool,
+
+    /// What to do when a notification file looks like a replay.
+    pub replay_policy: ReplayPolicy,
+
+    /// The digest algorithm for checking the local copy.
+    pub local_digest: DigestAlgorithm,
+
+    /// Whether the digest of the local copy covers the file content.
+    pub deep_digest: bool,
+
+    /// Whether to store objects compressed.
+    pub compress: bool,
+
+    /// Whether the local copies must never be changed.
+    ///
+    /// Updates only check the local copy and unused copies are kept.
+    pub read_only: bool,
+
+    /// Whether to flush changes to the local copies to storage.
+    ///
+    /// This makes sure the recorded state of a server always matches the
+    /// data present after a crash.
+    pub fsync: bool,
+
+    /// The number of deltas to apply before committing a checkpoint.
+    ///
+    /// If this is `None`, all deltas are applied at once.
+    pub delta_checkpoint: Option<usize>,
+
+    /// The number of deltas above which to update via the snapshot.
+    ///
+    /// If this is `None`, there is no limit.
+    pub max_deltas: Option<usize>,
+
+    /// The number of raw notification files to keep.
+    ///
+    /// If this is `None`, notification files are not stored.
+    pub keep_notifications: Option<usize>,
+
+    /// The maximum duration of an update for specific servers.
+    ///
+    /// The keys are notification URIs.
+    pub deadlines: HashMap<uri::Https, Duration>,
+
+    /// The notification URIs of servers never to update via deltas.
+    pub snapshot_only: HashSet<uri::Https>,
+
+    /// The sessions servers are pinned to.
+    ///
+    /// The keys are notification URIs.
+    pub session_pins: HashMap<uri::Https, Uuid>,
+
+    /// The fraction of a deadline after which to warn of a slow update.
+    pub deadline_warning: Option<f64>,
+
+    /// The clock to take all times from.
+    pub clock: SharedClock,
+
+    /// The store for the local copies of the servers.
+    pub store: SharedStore,
+
+    /// The observed interval between serials of all servers.
+    pub cadence: Arc<CadenceTracker>,
+
+    /// The delay suggested for the next update if there is no interval.
+    pub refresh: Duration,
+
+    /// The times before which broken servers aren’t tried again.
+    pub backoff: BrokenBackoff,
+
+    /// Where to report structured events of updates.
+    pub events: EventLog,
+
+    /// Where to share identical objects between servers.
+    pub dedup: ObjectDedup,
+
+    /// The maximum size of the recently loaded objects kept per server.
+    ///
+    /// If this is `None`, objects are always read from the local copy.
+    pub load_cache_size: Option<u64>,
+
+    /// The check objects of a snapshot have to pass before being written.
+    pub object_check: ObjectCheck,
+
+    /// The names of the entries of each server directory.
+    pub dir_names: ServerDirNames,
+}
+
+impl ServerOptions {
+    /// Creates the options from the configuration.
+    pub fn from_config(config: &Config) -> Self {
+        ServerOptions {
+            verify_on_read: config.rrdp_verify_on_read,
+            log_timeline: config.rrdp_log_timeline,
+            replay_policy: config.rrdp_replay_policy,
+            local_digest: config.rrdp_local_digest,
+            deep_digest: config.rrdp_deep_digest,
+            compress: config.rrdp_compress,
+            read_only: config.rrdp_read_only,
+            fsync: config.rrdp_fsync,
+            delta_checkpoint: config.rrdp_delta_checkpoint,
+            max_deltas: config.rrdp_max_deltas,
+            keep_notifications: config.rrdp_keep_notifications,
+            deadlines: config.rrdp_repository_timeouts.iter().filter_map(
+                |(uri, timeouts)| {
+                    timeouts.deadline.map(|deadline| (uri.clone(), deadline))
+                }
+            ).collect(),
+            snapshot_only: config.rrdp_snapshot_only.clone(),
+            session_pins: config.rrdp_session_pins.clone(),
+            deadline_warning: Some(config.rrdp_deadline_warning),
+            clock: Default::default(),
+            store: if config.rrdp_archive {
+                SharedStore::new(ArchiveStore::new(
+                    config.cache_dir.clone(), config.rrdp_fsync
+                ))
+            }
+            else {
+                Default::default()
+            },
+            cadence: Default::default(),
+            refresh: config.refresh,
+            backoff: BrokenBackoff::from_config(config),
+            events: EventLog::new(config.rrdp_log_events),
+            dedup: ObjectDedup::from_config(config),
+            load_cache_size: config.rrdp_load_cache,
+            object_check: Default::default(),
+            dir_names: config.rrdp_dir_names.clone(),
+        }
+    }
+}
+
+
+//------------ ServerDirNames ------------------------------------------------
+
+/// The names of the entries of a server directory.
+///
+/// By default, the state file is called `state.txt`, the data directory
+/// `data`, the object hashes `hashes.txt`, the manifest `manifest.txt`,
+/// and stored notification files `notification.xml` which is what all
+/// existing caches use. Other names allow keeping the cache in a directory
+/// shared with other tools. Use `check` to make sure the names don’t
+/// collide.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServerDirNames {
+    /// The file name of the state file.
+    pub state: OsString,
+
+    /// The file name of the data directory.
+    ///
+    /// While the data directory is being replaced, the old one is kept
+    /// under this name with `.old` appended.
+    pub data: OsString,
+
+    /// The file name of the object hashes.
+    pub hashes: OsString,
+
+    /// The file name of the manifest.
+    pub manifest: OsString,
+
+    /// The file name of the latest stored notification file.
+    ///
+    /// Older notification files are kept under this name with a dot and
+    /// their index appended.
+    pub notification: OsString,
+}
+
+impl ServerDirNames {
+    /// Returns the path of the state file in the server directory `base`.
+    pub fn state_path(&self, base: &Path) -> PathBuf {
+        base.join(&self.state)
+    }
+
+    /// Returns the path of the data directory in the server directory.
+    pub fn data_path(&self, base: &Path) -> PathBuf {
+        base.join(&self.data)
+    }
+
+    /// Returns the path of the old data directory in the server directory.
+    fn old_data_path(&self, base: &Path) -> PathBuf {
+        base.join(self.old_data())
+    }
+
+    /// Returns the path of the object hashes in the server directory.
+    pub fn hashes_path(&self, base: &Path) -> PathBuf {
+        base.join(&self.hashes)
+    }
+
+    /// Returns the path of the manifest in the server directory.
+    fn manifest_path(&self, base: &Path) -> PathBuf {
+        base.join(&self.manifest)
+    }
+
+    /// Returns the name of the old data directory.
+    fn old_data(&self) -> OsString {
+        let mut name = self.data.clone();
+        name.push(".old");
+        name
+    }
+
+    /// Checks that the names can be used for a server directory.
+    ///
+    /// Each name has to be a single, normal path component and no two
+    /// entries of the directory may end up with the same name. This
+    /// includes the old data directory and older notification files.
+    /// Logs the reason and returns an error otherwise.
+    pub fn check(&self) -> Result<(), Error> {
+        let names = [
+            ("state file", self.state.clone()),
+            ("data directory", self.data.clone()),
+            ("object hashes", self.hashes.clone()),
+            ("manifest", self.manifest.clone()),
+            ("notification file", self.notification.clone()),
+            ("old data directory", self.old_data()),
+        ];
+        for &(what, ref name) in &names {
+            let mut components = Path::new(name).components();
+            let valid = match (components.next(), components.next()) {
+                (Some(Component::Normal(item)), None) => item == name,
+                _ => false
+            };
+            if !valid {
+                error!(
+                    "Invalid name '{}' for the RRDP {}.",
+                    name.to_string_lossy(), what
+                );
+                return Err(Error)
+            }
+        }
+        for (idx, &(what, ref name)) in names.iter().enumerate() {
+            for &(other, ref other_name) in &names[idx + 1..] {
+                if name == other_name {
+                    error!(
+                        "The RRDP {} and {} both are named '{}'.",
+                        what, other, name.to_string_lossy()
+                    );
+                    return Err(Error)
+                }
+            }
+            if *name != self.notification && self.is_old_notification(name) {
+                error!(
+                    "The name '{}' of the RRDP {} is used for older \
+                     notification files.",
+                    name.to_string_lossy(), what
+                );
+                return Err(Error)
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether `name` is the name of an older notification file.
+    fn is_old_notification(&self, name: &OsStr) -> bool {
+        let (name, notification) = match (
+            name.to_str(), self.notification.to_str()
+        ) {
+            (Some(name), Some(notification)) => (name, notification),
+            _ => return false
+        };
+        if !name.starts_with(notification) {
+            return false
+        }
+        let suffix = &name[notification.len()..];
+        suffix.len() > 1 && suffix.starts_with('.')
+            && suffix[1..].bytes().all(|ch| ch.is_ascii_digit())
+    }
+}
+
+impl Default for ServerDirNames {
+    fn default() -> Self {
+        ServerDirNames {
+            state: "state.txt".into(),
+            data: "data".into(),
+            hashes: "hashes.txt".into(),
+            manifest: "manifest.txt".into(),
+            notification: "notification.xml".into(),
+        }
+    }
+}
+
+
+//------------ ObjectSummary -------------------------------------------------
+
+/// The summary of all objects of a server written by `export_objects`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectSummary {
+    notify_uri: String,
+    session: String,
+    serial: u64,
+    objects: Vec<ObjectSummaryEntry>,
+}
+
+/// A single object in an `ObjectSummary`.
+#[derive(Serialize)]
+struct ObjectSummaryEntry {
+    uri: String,
+    size: u64,
+    sha256: String,
+}
+
+
+//------------ ServerState ---------------------------------------------------
+
+#[derive(Clone, Debug)]
+pub struct ServerState {
+    /// The notify URI of the server.
+    pub notify_uri: uri::Https,
+
+    /// The UUID of the current session of this server.
+    pub session: Uuid,
+
+    /// The serial number representing the current state of the server.
+    pub serial: u64,
+
+    /// A hash over the expected local state of the server.
+    pub hash: DigestHex,
+
+    /// The number of objects in the local copy of the server.
+    ///
+    /// This is `None` if the count isn’t known. This is the case for state
+    /// files written by older versions and if a delta couldn’t tell whether
+    /// an object was added. The count is known again after the next update
+    /// via the snapshot.
+    pub object_count: Option<usize>,
+
+    /// The algorithm used for `hash`.
+    ///
+    /// State files written by older versions don’t contain the algorithm.
+    /// These always used SHA-256.
+    pub algorithm: DigestAlgorithm,
+
+    /// Whether `hash` covers the content of the files.
+    ///
+    /// This is only recorded in the state file if it is `true`.
+    pub deep_digest: bool,
+
+    /// Whether the objects are stored compressed.
+    ///
+    /// This is only recorded in the state file if it is `true`.
+    pub compressed: bool,
+
+    /// The way `hash` was calculated.
+    ///
+    /// State files written by older versions don’t contain the version.
+    /// These always used `DigestVersion::HostOrder`.
+    pub digest_version: DigestVersion,
+
+    /// The HTTP validators of the notification file last used.
+    pub validators: CacheValidators,
+
+    /// The sessions the server used before the current one.
+    ///
+    /// Each session is given with the last serial number we have seen for
+    /// it. The most recent session comes first and only
+    /// `MAX_PAST_SESSIONS` are kept.
+    pub past_sessions: Vec<(Uuid, u64)>,
+}
+
+impl ServerState {
+    /// The maximum number of past sessions recorded.
+    pub const MAX_PAST_SESSIONS: usize = 16;
+
+    /// Returns the past sessions for a copy of the server with `session`.
+    ///
+    /// If the session changes, our current session becomes the most recent
+    /// past session.
+    pub fn past_sessions_for(&self, session: Uuid) -> Vec<(Uuid, u64)> {
+        if session == self.session {
+            return self.past_sessions.clone()
+        }
+        let mut res = vec![(self.session, self.serial)];
+        res.extend(self.past_sessions.iter().filter(|item| {
+            item.0 != self.session && item.0 != session
+        }));
+        res.truncate(Self::MAX_PAST_SESSIONS);
+        res
+    }
+
+    /// Loads the state from the file at `path` in `store`.
+    pub fn load(store: &dyn CacheStore, path: &Path) -> Result<Self, Error> {
+        Self::_load(store, path).map_err(|err| {
+            // Not found is mostly normal, don’t complain about that.
+            if err.kind() != io::ErrorKind::NotFound {
+                info!(
+                    "Failed to read state file '{}': {}",
+                    path.display(), err
+                );
+            }
+            Error
+        })
+    }
+
+    fn _load(store: &dyn CacheStore, path: &Path) -> Result<Self, io::Error> {
+        let data = store.read(path)?;
+        let mut lines = data.as_slice().lines();
+        let notify_uri = process_line(&mut lines, "notify-uri:")?;
+        let session = process_line(&mut lines, "session:")?;
+        let serial = process_line(&mut lines, "serial:")?;
+        let hash = process_line(&mut lines, "hash:")?;
+
+        // Everything after the hash is optional. The object count is left
+        // out if it isn’t known.
+        let mut res = ServerState {
+            notify_uri, session, serial, hash,
+            object_count: None,
+            algorithm: DigestAlgorithm::Sha256,
+            deep_digest: false,
+            compressed: false,
+            digest_version: DigestVersion::HostOrder,
+            validators: CacheValidators::default(),
+            past_sessions: Vec::new(),
+        };
+        while let Some(line) = next_line(&mut lines) {
+            let line = line?;
+            match split_state_line(&line) {
+                ("objects:", _) => {
+                    res.object_count = Some(parse_line(&line, "objects:")?)
+                }
+                ("algorithm:", _) => {
+                    res.algorithm = parse_line(&line, "algorithm:")?
+                }
+                ("deep-digest:", "yes") => res.deep_digest = true,
+                ("compressed:", "yes") => res.compressed = true,
+                // An unknown version is kept so that the local copy is
+                // replaced rather than considered broken.
+                ("digest-version:", value) => {
+                    res.digest_version = u8::from_str(value).map(
+                        DigestVersion::from_number
+                    ).map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "invalid digest version"
+                        )
+                    })?
+                }
+                ("etag:", value) => {
+                    res.validators.etag = Some(value.into())
+                }
+                ("last-modified:", value) => {
+                    res.validators.last_modified = Some(value.into())
+                }
+                ("past-session:", value) => {
+                    res.past_sessions.push(parse_past_session(value)?)
+                }
+                // Keys we don’t know may have been added by a newer
+                // version, so we skip them, even if they have no value.
+                (key, _) if key.ends_with(':') => { }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData, "invalid data"
+                    ))
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Saves the state to the file at `path` in `store`.
+    pub fn save(
+        &self, store: &dyn CacheStore, path: &Path
+    ) -> Result<(), Error> {
+        self._save(store, path).map_err(|err| {
+            info!(
+                "Failed to read write file '{}': {}",
+                path.display(), err
+            );
+            Error
+        })
+    }
+
+    fn _save(
+        &self, store: &dyn CacheStore, path: &Path
+    ) -> Result<(), io::Error> {
+        write_atomic(store, path, |file| {
+            writeln!(
+                file,
+                "notify-uri: {}\nsession: {}\nserial: {}\nhash: {}",
+                self.notify_uri, self.session, self.serial, self.hash,
+            )?;
+            if let Some(count) = self.object_count {
+                writeln!(file, "objects: {}", count)?;
+            }
+            writeln!(file, "algorithm: {}", self.algorithm)?;
+            if self.deep_digest {
+                writeln!(file, "deep-digest: yes")?;
+            }
+            if self.compressed {
+                writeln!(file, "compressed: yes")?;
+            }
+            writeln!(
+                file, "digest-version: {}", self.digest_version.to_number()
+            )?;
+            if let Some(ref etag) = self.validators.etag {
+                writeln!(file, "etag: {}", etag)?;
+            }
+            if let Some(ref last_modified) = self.validators.last_modified {
+                writeln!(file, "last-modified: {}", last_modified)?;
+            }
+            for (session, serial) in &self.past_sessions {
+                writeln!(file, "past-session: {} {}", session, serial)?;
+            }
+            Ok(())
+        })
+    }
+
+}
+
+/// Atomically replaces the file at `path` in `store` with what `op` writes.
+///
+/// The content is written to a uniquely named temporary file in the same
+/// directory which is then renamed to `path`. If anything goes wrong, the
+/// temporary file is removed and the previous file stays untouched.
+fn write_atomic<F>(
+    store: &dyn CacheStore, path: &Path, op: F
+) -> Result<(), io::Error>
+where F: FnOnce(&mut Vec<u8>) -> Result<(), io::Error> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new(".")
+    };
+    let mut data = Vec::new();
+    op(&mut data)?;
+    let tmp = random_path(dir);
+    let res = store.write(&tmp, &data).and_then(|_| {
+        store.sync(&tmp)
+    }).and_then(|_| {
+        store.rename(&tmp, path)
+    });
+    if res.is_err() {
+        let _ = store.remove_file(&tmp);
+    }
+    res
+}
+
+/// Returns the next line that isn’t blank.
+///
+/// A state file may have been edited by hand, so blank lines are skipped
+/// wherever they appear.
+fn next_line<B: io::BufRead>(
+    lines: &mut io::Lines<B>
+) -> Option<Result<String, io::Error>> {
+    loop {
+        match lines.next()? {
+            Ok(line) if line.trim().is_empty() => continue,
+            res => return Some(res)
+        }
+    }
+}
+
+fn process_line<B: io::BufRead, T: FromStr>(
+    lines: &mut io::Lines<B>, expected_key: &str
+) -> Result<T, io::Error> {
+    let line = next_line(lines).ok_or_else(||
+        io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected EOF")
+    )??;
+    parse_line(&line, expected_key)
+}
+
+/// Splits a line into its key and the rest of the line as its value.
+///
+/// Other than with `parse_line`, the value may contain white space.
+fn split_text_line(line: &str) -> Result<(&str, &str), io::Error> {
+    match line.find(' ') {
+        Some(pos) if pos + 1 < line.len() => {
+            Ok((&line[..pos], &line[pos + 1..]))
+        }
+        _ => {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "invalid data"))
+        }
+    }
+}
+
+/// Splits a line of a state file into its key and value.
+///
+/// Since state files may have been edited by hand, white space around the
+/// key and the value is ignored and they may be separated by any amount
+/// of white space. A line with only a key has an empty value.
+fn split_state_line(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.find(char::is_whitespace) {
+        Some(pos) => (&line[..pos], line[pos..].trim_start()),
+        None => (line, "")
+    }
+}
+
+/// Parses the value of a past session line: a session and a serial.
+fn parse_past_session(value: &str) -> Result<(Uuid, u64), io::Error> {
+    let mut value = value.split_whitespace();
+    match (value.next(), value.next(), value.next()) {
+        (Some(session), Some(serial), None) => {
+            match (Uuid::from_str(session), u64::from_str(serial)) {
+                (Ok(session), Ok(serial)) => Ok((session, serial)),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData, "bad value"
+                ))
+            }
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid data"))
+    }
+}
+
+fn parse_line<T: FromStr>(
+    line: &str, expected_key: &str
+) -> Result<T, io::Error> {
+    let mut line = line.split_whitespace();
+    let key = line.next().ok_or_else(||
+        io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected EOF")
+    )?;
+    if key != expected_key {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid data"
+        ))
+    }
+    let value = line.next().ok_or_else(||
+        io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected EOF")
+    )?;
+    if line.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid data"
+        ))
+    }
+    match T::from_str(value) {
+        Ok(value) => Ok(value),
+        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "bad value"))
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use filetime::{FileTime, set_file_mtime};
+    use crate::rrdp::clock::MockClock;
+    use crate::rrdp::events::Event;
+    use crate::rrdp::http::ReqwestClient;
+    use crate::rrdp::mock::{
+        MockClient, RecordingStore, SEED_OBJECTS, header, https_uri,
+        seed_server, sha256
+    };
+
+    fn notify_uri() -> uri::Https {
+        uri::Https::from_str("https://example.net/notification.xml").unwrap()
+    }
+
+    fn rsync_uri(path: &str) -> uri::Rsync {
+        uri::Rsync::from_str(
+            &format!("rsync://example.net/repo/{}", path)
+        ).unwrap()
+    }
+
+    /// Creates a server directory with a few objects and a matching state.
+    fn seed_server_dir(base: &Path) -> ServerDir {
+        seed_server(base, "example.net", 12, SEED_OBJECTS);
+        ServerDir::new(base.into(), &Default::default())
+    }
+
+    /// Creates a notification file with the given deltas.
+    fn notification(
+        session: &str, serial: u64, deltas: &[u64]
+    ) -> NotificationFile {
+        let mut xml = format!(
+            "<notification xmlns=\"http://www.ripe.net/rpki/rrdp\" \
+             version=\"1\" session_id=\"{}\" serial=\"{}\">\n\
+             <snapshot uri=\"https://example.net/snapshot.xml\" \
+             hash=\"2a51c2b6e1fa1d6c4e7d4d0bb2e9e0a6\
+             3d6e9b2d0e73f6b1d29cd563d1fe1b61\"/>\n",
+            session, serial
+        );
+        for serial in deltas {
+            xml.push_str(&format!(
+                "<delta serial=\"{}\" \
+                 uri=\"https://example.net/delta-{}.xml\" \
+                 hash=\"2a51c2b6e1fa1d6c4e7d4d0bb2e9e0a6\
+                 3d6e9b2d0e73f6b1d29cd563d1fe1b61\"/>\n",
+                serial, serial
+            ));
+        }
+        xml.push_str("</notification>\n");
+        ReqwestClient::parse_notification(xml.as_bytes()).unwrap()
+    }
+
+    /// Calculates the deltas to apply without logging progress.
+    fn calc_deltas(
+        notify: &NotificationFile, state: &ServerState
+    ) -> Result<Option<Vec<(u64, UriAndHash)>>, DeltaFailure> {
+        Server::calc_deltas(notify, state, Level::Debug)
+    }
+
+    fn sha256_hex(data: &[u8]) -> String {
+        digest::digest(&digest::SHA256, data).as_ref().iter().map(|ch| {
+            format!("{:02x}", ch)
+        }).collect()
+    }
+
+    #[test]
+    fn export_objects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        let mut export = Vec::new();
+        server.export_objects(&mut export).unwrap();
+        let export: serde_json::Value = serde_json::from_slice(
+            &export
+        ).unwrap();
+        assert_eq!(
+            export["notifyUri"], "https://example.net/notification.xml"
+        );
+        assert_eq!(export["serial"], 12);
+        let objects = export["objects"].as_array().unwrap();
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[0]["uri"], "rsync://example.net/repo/a.cer");
+        assert_eq!(objects[0]["size"], 11);
+        assert_eq!(objects[0]["sha256"], sha256_hex(b"certificate"));
+        assert_eq!(objects[1]["uri"], "rsync://example.net/repo/sub/b.roa");
+        assert_eq!(objects[1]["size"], 3);
+        assert_eq!(objects[1]["sha256"], sha256_hex(b"roa"));
+    }
+
+    #[test]
+    fn list_objects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        assert_eq!(
+            server.list_objects().unwrap(),
+            vec![(rsync_uri("a.cer"), 11), (rsync_uri("sub/b.roa"), 3)]
+        );
+    }
+
+    #[test]
+    fn load_file_with_meta() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        let (data, meta) = server.load_file_with_meta(
+            &rsync_uri("a.cer")
+        ).found().unwrap();
+        assert_eq!(data.as_ref(), b"certificate");
+        assert!(meta.is_file());
+        assert_eq!(meta.len(), 11);
+        assert!(
+            server.load_file_with_meta(&rsync_uri("c.cer")).is_not_found()
+        );
+    }
+
+    #[test]
+    fn load_file_failures() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        assert!(server.load_file(&rsync_uri("c.cer")).is_not_found());
+
+        // A directory in place of the object can’t be read.
+        fs::create_dir(dir.uri_path(&rsync_uri("c.cer")).unwrap()).unwrap();
+        assert!(matches!(
+            server.load_file(&rsync_uri("c.cer")), LoadResult::IoError
+        ));
+
+        let server = Server::create_broken(notify_uri(), Default::default());
+        assert!(matches!(
+            server.load_file(&rsync_uri("a.cer")), LoadResult::ServerBroken
+        ));
+    }
+
+    #[test]
+    fn escaping_uri_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let module = dir.data_path().join("example.net").join("repo");
+        assert_eq!(
+            dir.uri_path(&rsync_uri("sub/b.roa")).unwrap(),
+            module.join("sub").join("b.roa")
+        );
+
+        // Parent directory components.
+        assert!(dir.uri_path(&rsync_uri("../a.cer")).is_err());
+        assert!(dir.uri_path(&rsync_uri("sub/../../../a.cer")).is_err());
+        assert!(dir.uri_path(&rsync_uri("./a.cer")).is_err());
+        assert!(dir.uri_path(
+            &uri::Rsync::from_str("rsync://example.net/../a.cer").unwrap()
+        ).is_err());
+
+        // Absolute paths.
+        assert!(dir.uri_path(&rsync_uri("/etc/passwd")).is_err());
+
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        fs::write(tmp.path().join("a.cer"), b"outside").unwrap();
+        assert!(server.load_file(&rsync_uri("../../../a.cer")).is_not_found());
+    }
+
+    #[test]
+    fn verify_on_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+
+        // Same size so the directory digest doesn’t notice.
+        fs::write(
+            dir.uri_path(&rsync_uri("a.cer")).unwrap(), b"CERTIFICATE"
+        ).unwrap();
+
+        let server = Server::existing(
+            notify_uri(), dir.base().into(),
+            Arc::new(ServerOptions {
+                verify_on_read: true,
+                .. Default::default()
+            })
+        );
+        assert!(matches!(
+            server.load_file(&rsync_uri("a.cer")), LoadResult::Corrupt
+        ));
+        assert!(server.verified.lock().unwrap().is_empty());
+        assert!(server.load_file(&rsync_uri("sub/b.roa")).found().is_some());
+        assert!(
+            server.verified.lock().unwrap().contains(&rsync_uri("sub/b.roa"))
+        );
+        assert!(
+            !server.verified.lock().unwrap().contains(&rsync_uri("a.cer"))
+        );
+    }
+
+    #[test]
+    fn delta_keeps_hashes() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let store = RecordingStore::default();
+        let options = Arc::new(ServerOptions {
+            store: SharedStore::new(store.clone()),
+            verify_on_read: true,
+            .. Default::default()
+        });
+        let session = Uuid::new_v4();
+        client.publish(
+            session, 1,
+            &[("a.cer", b"first".as_ref()), ("b.roa", b"roa".as_ref())], &[]
+        );
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, options.clone()
+        );
+        server.update(&client);
+
+        // A delta changing one object reads it to check its old hash but
+        // doesn’t read the untouched one to update the object hashes.
+        let hash = client.publish_delta(
+            session, 2, "a.cer", b"first", b"second certificate"
+        );
+        client.publish(
+            session, 2,
+            &[
+                ("a.cer", b"second certificate".as_ref()),
+                ("b.roa", b"roa".as_ref())
+            ],
+            &[(2, hash)]
+        );
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            options
+        );
+        store.take_reads();
+        server.update(&client);
+        assert_eq!(server.last_update_kind(), UpdateKind::Delta);
+        let module = server.server_dir().join("data/example.net/repo");
+        let reads = store.take_reads();
+        assert!(reads.contains(&module.join("a.cer")));
+        assert!(!reads.contains(&module.join("b.roa")));
+
+        // Both objects still match their hashes.
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"second certificate"
+        );
+        assert_eq!(
+            server.load_file(&rsync_uri("b.roa")).found().unwrap().as_ref(),
+            b"roa"
+        );
+    }
+
+    #[test]
+    fn applied_digest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let module = dir.data_path().join("example.net").join("repo");
+
+        // Change the directory and report the changes.
+        fs::write(module.join("a.cer"), b"new certificate").unwrap();
+        fs::remove_file(module.join("sub").join("b.roa")).unwrap();
+        let new_dir = module.join("new").join("dir");
+        fs::create_dir_all(&new_dir).unwrap();
+        fs::write(new_dir.join("c.mft"), b"manifest").unwrap();
+        let changes = vec![
+            (module.join("a.cer"), Some(15)),
+            (module.join("sub").join("b.roa"), None),
+            (new_dir.join("c.mft"), Some(8)),
+        ];
+        let digest = dir.applied_digest(&changes).unwrap();
+        assert_eq!(
+            digest.as_ref(),
+            data_digest(
+                &dir.data_path(), Default::default(), false
+            ).unwrap().as_ref()
+        );
+
+        // Changes not reported aren’t seen since the directory isn’t
+        // walked again.
+        fs::write(module.join("d.roa"), b"roa").unwrap();
+        assert_eq!(
+            dir.applied_digest(&[]).unwrap().as_ref(), digest.as_ref()
+        );
+        assert_ne!(dir.digest().unwrap().as_ref(), digest.as_ref());
+
+        // Without a previous digest, the directory is walked.
+        let dir = ServerDir::new(tmp.path().into(), &Default::default());
+        assert_eq!(
+            dir.applied_digest(&[]).unwrap().as_ref(),
+            dir.digest().unwrap().as_ref()
+        );
+    }
+
+    #[test]
+    fn manifest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let module = PathBuf::from("example.net").join("repo");
+        let state = dir.load_state().unwrap();
+        fs::create_dir_all(dir.data_path().join("empty")).unwrap();
+        ServerState {
+            hash: dir.digest().unwrap().into(),
+            .. state
+        }.save(&FsStore, dir.state_path()).unwrap();
+        let state = dir.load_state().unwrap();
+        dir.save_manifest();
+
+        // The manifest round-trips and matches the state.
+        let (tree, digest) = dir.load_manifest(&state).unwrap();
+        assert_eq!(digest.as_ref(), state.hash.as_ref());
+        let actual = DigestTree::load(
+            &FsStore, &dir.data_path(), false
+        ).unwrap();
+        assert_eq!(tree.difference(&actual), None);
+        dir.check_digest(&state).unwrap();
+
+        // Differences are found.
+        fs::write(
+            dir.data_path().join(&module).join("a.cer"), b"certificate!"
+        ).unwrap();
+        fs::write(dir.data_path().join(&module).join("c.roa"), b"").unwrap();
+        fs::remove_dir(dir.data_path().join("empty")).unwrap();
+        let actual = DigestTree::load(
+            &FsStore, &dir.data_path(), false
+        ).unwrap();
+        assert_eq!(
+            tree.difference(&actual),
+            Some(TreeDifference::Missing("empty".into()))
+        );
+        fs::create_dir_all(dir.data_path().join("empty")).unwrap();
+        let actual = DigestTree::load(
+            &FsStore, &dir.data_path(), false
+        ).unwrap();
+        assert_eq!(
+            tree.difference(&actual),
+            Some(TreeDifference::Size {
+                path: module.join("a.cer"), expected: 11, found: 12
+            })
+        );
+        fs::write(
+            dir.data_path().join(&module).join("a.cer"), b"certificate"
+        ).unwrap();
+        let actual = DigestTree::load(
+            &FsStore, &dir.data_path(), false
+        ).unwrap();
+        assert_eq!(
+            tree.difference(&actual),
+            Some(TreeDifference::Unexpected(module.join("c.roa")))
+        );
+        assert!(dir.check_digest(&state).is_err());
+
+        // A manifest for another state is ignored.
+        fs::remove_file(dir.data_path().join(&module).join("c.roa")).unwrap();
+        let state = ServerState {
+            hash: DigestHex::from(digest::digest(&digest::SHA256, b"")),
+            .. state
+        };
+        assert!(dir.load_manifest(&state).is_none());
+    }
+
+    #[test]
+    fn divergence() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let module = PathBuf::from("example.net").join("repo");
+        let state = dir.load_state().unwrap();
+        assert!(dir.divergence(&state).unwrap().is_empty());
+
+        // Without a deep digest, changed content goes unnoticed.
+        fs::write(
+            dir.data_path().join(&module).join("a.cer"), b"certifikat"
+        ).unwrap();
+        fs::write(dir.data_path().join(&module).join("c.roa"), b"").unwrap();
+        fs::remove_file(
+            dir.data_path().join(&module).join("sub").join("b.roa")
+        ).unwrap();
+        assert_eq!(
+            dir.divergence(&state).unwrap(),
+            vec![
+                TreeDifference::Missing(module.join("sub").join("b.roa")),
+                TreeDifference::Unexpected(module.join("c.roa")),
+            ]
+        );
+
+        let state = ServerState { deep_digest: true, .. state };
+        assert_eq!(
+            dir.divergence(&state).unwrap(),
+            vec![
+                TreeDifference::Content(module.join("a.cer")),
+                TreeDifference::Missing(module.join("sub").join("b.roa")),
+                TreeDifference::Unexpected(module.join("c.roa")),
+            ]
+        );
+
+        fs::remove_file(dir.hashes_path()).unwrap();
+        assert!(dir.divergence(&state).is_err());
+    }
+
+    #[test]
+    fn replace_from_rolls_back() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(&tmp.path().join("server"));
+        let state = fs::read(dir.state_path()).unwrap();
+        let new_dir = ServerDir::new(
+            tmp.path().join("tmp"), &Default::default()
+        );
+        let new_data = new_dir.data_path().join("example.net").join("repo");
+        fs::create_dir_all(&new_data).unwrap();
+        fs::write(new_data.join("c.cer"), b"new").unwrap();
+        fs::write(new_dir.state_path(), b"new state").unwrap();
+
+        // Fail the rename of the state file.
+        let state_path = dir.state_path().to_path_buf();
+        assert!(dir.replace_from(&new_dir, |from, to| {
+            if to == state_path.as_path() {
+                Err(io::Error::new(io::ErrorKind::Other, "injected"))
+            }
+            else {
+                fs::rename(from, to)
+            }
+        }).is_err());
+
+        assert_eq!(fs::read(dir.state_path()).unwrap(), state);
+        assert!(dir.uri_path(&rsync_uri("a.cer")).unwrap().exists());
+        assert!(!dir.uri_path(&rsync_uri("c.cer")).unwrap().exists());
+        assert!(!dir.old_data_path().exists());
+        assert!(!dir.hashes_path().exists());
+        assert!(new_data.join("c.cer").exists());
+        dir.check_digest(
+            &dir.load_state().unwrap()
+        ).unwrap();
+
+        // Now let it succeed.
+        dir.replace_from(&new_dir, |from, to| fs::rename(from, to)).unwrap();
+        assert_eq!(fs::read(dir.state_path()).unwrap(), b"new state");
+        assert!(dir.uri_path(&rsync_uri("c.cer")).unwrap().exists());
+        assert!(!dir.uri_path(&rsync_uri("a.cer")).unwrap().exists());
+        assert!(!dir.old_data_path().exists());
+    }
+
+    #[test]
+    fn replace_from_interrupted() {
+        use std::panic;
+
+        // Interrupt before each of the renames in turn. Moving the state
+        // file is the last one, so we always end up with the old content
+        // and never with the new object hashes.
+        for step in 0..5 {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = seed_server_dir(&tmp.path().join("server"));
+            seed_server(
+                &tmp.path().join("tmp"), "example.net", 13,
+                &[SEED_OBJECTS, &[("c.cer", b"new".as_ref())]].concat()
+            );
+            let new_dir = ServerDir::new(
+                tmp.path().join("tmp"), &Default::default()
+            );
+
+            let mut count = 0;
+            assert!(panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                dir.replace_from(&new_dir, |from, to| {
+                    if count == step {
+                        panic!("killed")
+                    }
+                    count += 1;
+                    fs::rename(from, to)
+                })
+            })).is_err());
+
+            dir.recover();
+            let state = dir.load_state().unwrap();
+            dir.check_digest(&state).unwrap();
+            assert!(!dir.old_data_path().exists());
+            assert_eq!(state.serial, 12);
+            assert!(!dir.uri_path(&rsync_uri("c.cer")).unwrap().exists());
+            assert!(dir.uri_path(&rsync_uri("a.cer")).unwrap().exists());
+            if dir.hashes_path().exists() {
+                assert_eq!(dir.divergence(&state).unwrap(), Vec::new());
+            }
+
+            // The server can be used again.
+            let server = Server::existing(
+                notify_uri(), dir.base().into(), Default::default()
+            );
+            assert!(!server.check_broken());
+        }
+
+        // Interrupted after moving the state file but before removing the
+        // old data directory.
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        fs::create_dir_all(dir.old_data_path().join("example.net")).unwrap();
+        dir.recover();
+        assert!(!dir.old_data_path().exists());
+        dir.check_digest(&dir.load_state().unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn state_object_count() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let state = dir.load_state().unwrap();
+        assert_eq!(state.object_count, Some(2));
+
+        // State files of older versions have no object count.
+        let hash = state.hash.to_string();
+        fs::write(
+            dir.state_path(),
+            format!(
+                "notify-uri: {}\nsession: {}\nserial: 12\nhash: {}\n",
+                notify_uri(), Uuid::nil(), hash
+            )
+        ).unwrap();
+        let state = dir.load_state().unwrap();
+        assert_eq!(state.serial, 12);
+        assert_eq!(state.object_count, None);
+
+        // Nor the algorithm, which then is SHA-256.
+        assert_eq!(state.algorithm, DigestAlgorithm::Sha256);
+
+        // An unknown count is left out and stays unknown.
+        dir.save_state(&state).unwrap();
+        let state = dir.load_state().unwrap();
+        assert_eq!(state.object_count, None);
+        assert_eq!(state.algorithm, DigestAlgorithm::Sha256);
+
+        // Nor the digest version, which then is the first one.
+        assert_eq!(state.digest_version, DigestVersion::HostOrder);
+
+        // Unknown keys are skipped wherever they appear after the hash,
+        // even without a value.
+        fs::write(
+            dir.state_path(),
+            format!(
+                "notify-uri: {}\nsession: {}\nserial: 12\nhash: {}\n\
+                 other: 2\nobjects: 2\nempty:\nalgorithm: fnv1a\n\
+                 more: a b c\n",
+                notify_uri(), Uuid::nil(), hash
+            )
+        ).unwrap();
+        let state = dir.load_state().unwrap();
+        assert_eq!(state.object_count, Some(2));
+        assert_eq!(state.algorithm, DigestAlgorithm::Fnv1a);
+
+        // But lines that aren’t a key are still rejected.
+        fs::write(
+            dir.state_path(),
+            format!(
+                "notify-uri: {}\nsession: {}\nserial: 12\nhash: {}\n\
+                 garbage\n",
+                notify_uri(), Uuid::nil(), hash
+            )
+        ).unwrap();
+        assert!(dir.load_state().is_err());
+    }
+
+    #[test]
+    fn verify_local() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        let report = server.verify_local();
+        assert_eq!(
+            report,
+            VerifyReport {
+                session: Some(Uuid::nil()),
+                serial: Some(12),
+                object_count: Some(2),
+                consistent: true,
+            }
+        );
+        assert_eq!(
+            report.to_string(),
+            format!(
+                "consistent at serial 12 of session {}, 2 objects",
+                Uuid::nil()
+            )
+        );
+
+        // A changed file is reported but nothing is removed.
+        let path = dir.data_path().join("example.net/repo/a.cer");
+        fs::write(&path, b"changed").unwrap();
+        let report = server.verify_local();
+        assert_eq!(report.serial, Some(12));
+        assert!(!report.consistent);
+        assert!(path.exists());
+        assert!(!server.broken.load(Relaxed));
+
+        // Without a state file, there is nothing to report.
+        fs::remove_file(dir.state_path()).unwrap();
+        assert_eq!(server.verify_local(), VerifyReport::default());
+        assert_eq!(
+            VerifyReport::default().to_string(), "state file unavailable"
+        );
+    }
+
+    #[test]
+    fn digest_version() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let state = dir.load_state().unwrap();
+        assert_eq!(state.digest_version, DigestVersion::CURRENT);
+
+        // A state with a digest of the old version still checks out.
+        let tree = DigestTree::load(
+            &FsStore, &dir.data_path(), false
+        ).unwrap();
+        ServerState {
+            hash: tree.digest(
+                DigestAlgorithm::Sha256, DigestVersion::HostOrder
+            ).into(),
+            digest_version: DigestVersion::HostOrder,
+            .. state
+        }.save(&FsStore, dir.state_path()).unwrap();
+        let state = dir.load_state().unwrap();
+        assert_eq!(state.digest_version, DigestVersion::HostOrder);
+        dir.check_digest(&state).unwrap();
+
+        // Upgrading switches to the current version.
+        dir.upgrade_digest(&state).unwrap();
+        let state = dir.load_state().unwrap();
+        assert_eq!(state.digest_version, DigestVersion::CURRENT);
+        assert_eq!(state.hash.as_ref(), dir.digest().unwrap().as_ref());
+        dir.check_digest(&state).unwrap();
+
+        // An unknown version can’t be checked but the local copy is kept
+        // and replaced by the snapshot during the next update.
+        let data = fs::read_to_string(dir.state_path()).unwrap().replace(
+            "digest-version: 2", "digest-version: 3"
+        );
+        fs::write(dir.state_path(), &data).unwrap();
+        let state = dir.load_state().unwrap();
+        assert_eq!(state.digest_version, DigestVersion::Unknown(3));
+        assert!(dir.check_digest(&state).is_err());
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        assert!(!server.check_broken());
+        assert!(dir.data_path().exists());
+
+        let (_client_tmp, _, client) = MockClient::setup();
+        client.publish(Uuid::nil(), 12, SEED_OBJECTS, &[]);
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        server.update(&client);
+        assert!(!server.is_broken());
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Snapshot)
+        );
+        let state = dir.load_state().unwrap();
+        assert_eq!(state.digest_version, DigestVersion::CURRENT);
+        dir.check_digest(&state).unwrap();
+    }
+
+    #[test]
+    fn state_validators() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let state = dir.load_state().unwrap();
+        assert!(state.validators.is_empty());
+
+        ServerState {
+            validators: CacheValidators {
+                etag: Some("W/\"some tag\"".into()),
+                last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".into()),
+            },
+            .. state.clone()
+        }.save(&FsStore, dir.state_path()).unwrap();
+        let loaded = dir.load_state().unwrap();
+        assert_eq!(loaded.validators.etag.unwrap(), "W/\"some tag\"");
+        assert_eq!(
+            loaded.validators.last_modified.unwrap(),
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        );
+
+        // Either may be missing.
+        ServerState {
+            validators: CacheValidators {
+                etag: None,
+                last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".into()),
+            },
+            .. state
+        }.save(&FsStore, dir.state_path()).unwrap();
+        let loaded = dir.load_state().unwrap();
+        assert!(loaded.validators.etag.is_none());
+        assert!(loaded.validators.last_modified.is_some());
+
+        // Unknown keys are ignored, but other lines are rejected.
+        let mut data = fs::read_to_string(dir.state_path()).unwrap();
+        data.push_str("other: value\n");
+        fs::write(dir.state_path(), &data).unwrap();
+        let loaded = dir.load_state().unwrap();
+        assert_eq!(loaded.serial, 12);
+        assert!(loaded.validators.last_modified.is_some());
+        data.push_str("garbage\n");
+        fs::write(dir.state_path(), &data).unwrap();
+        assert!(dir.load_state().is_err());
+    }
+
+    #[test]
+    fn state_irregular_white_space() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let state = dir.load_state().unwrap();
+        let hash = state.hash.to_string();
+
+        // Blank lines and extra white space as left by an editor.
+        fs::write(
+            dir.state_path(),
+            format!(
+                "\n  notify-uri:  {}  \n\nsession:\t{}\nserial: 12 \n\
+                 \n   \nhash: {}\n objects:   2\nalgorithm: sha256\n\n\
+                 etag:   W/\"some tag\"  \n\n",
+                notify_uri(), Uuid::nil(), hash
+            )
+        ).unwrap();
+        let loaded = dir.load_state().unwrap();
+        assert_eq!(loaded.notify_uri, notify_uri());
+        assert_eq!(loaded.serial, 12);
+        assert_eq!(loaded.object_count, Some(2));
+        assert_eq!(loaded.validators.etag.unwrap(), "W/\"some tag\"");
+
+        // A keyed field with more than one value is still rejected.
+        fs::write(
+            dir.state_path(),
+            format!(
+                "notify-uri: {}\nsession: {}\nserial: 12 13\nhash: {}\n",
+                notify_uri(), Uuid::nil(), hash
+            )
+        ).unwrap();
+        assert!(dir.load_state().is_err());
+    }
+
+    #[test]
+    fn state_partial_write() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let before = fs::read(dir.state_path()).unwrap();
+        let entries = || fs::read_dir(dir.base()).unwrap().count();
+        let entry_count = entries();
+
+        // Writing fails half way through as if we were killed.
+        assert!(write_atomic(&FsStore, dir.state_path(), |file| {
+            file.write_all(b"notify-uri: https://exa")?;
+            Err(io::Error::new(io::ErrorKind::Other, "killed"))
+        }).is_err());
+        assert_eq!(fs::read(dir.state_path()).unwrap(), before);
+        let state = dir.load_state().unwrap();
+        assert_eq!(state.serial, 12);
+
+        // The temporary file is gone, too.
+        assert_eq!(entries(), entry_count);
+
+        // A complete write replaces the file.
+        let mut state = state;
+        state.serial = 13;
+        dir.save_state(&state).unwrap();
+        assert_eq!(dir.load_state().unwrap().serial, 13);
+    }
+
+    #[test]
+    fn replayed_session() {
+        fn notify(session: &str, serial: u64) -> NotificationFile {
+            notification(session, serial, &[])
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let mut state = dir.load_state().unwrap();
+        state.session = Uuid::from_str(
+            "9df4b597-af9e-4dca-bdda-719cce2c4e28"
+        ).unwrap();
+        let current = "9df4b597-af9e-4dca-bdda-719cce2c4e28";
+        let old = "3e6ac3a5-4e9c-4427-a1b8-26fc3d9a5e6f";
+        let new = "b0e6d2a7-3c2e-4a5b-8f4e-2d9b7c6a1e3f";
+        state.past_sessions = vec![(Uuid::from_str(old).unwrap(), 8)];
+
+        // A session seen before with a serial up to the last one seen: a
+        // replay.
+        assert!(Server::is_replay(&notify(old, 3), &state));
+        assert!(Server::is_replay(&notify(old, 8), &state));
+
+        // Newer serials, new sessions, or the same session are fine.
+        assert!(!Server::is_replay(&notify(old, 9), &state));
+        assert!(!Server::is_replay(&notify(new, 3), &state));
+        assert!(!Server::is_replay(&notify(current, 3), &state));
+    }
+
+    #[test]
+    fn past_sessions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let state = dir.load_state().unwrap();
+        assert!(state.past_sessions.is_empty());
+        let first = state.session;
+        let second = Uuid::new_v4();
+
+        // Staying in the session keeps the list, changing adds to it.
+        assert!(state.past_sessions_for(first).is_empty());
+        let state = ServerState {
+            session: second, serial: 1,
+            past_sessions: state.past_sessions_for(second),
+            .. state
+        };
+        assert_eq!(state.past_sessions, vec![(first, 12)]);
+
+        // Returning to an old session removes it from the list.
+        assert_eq!(state.past_sessions_for(first), vec![(second, 1)]);
+
+        // The list is saved and loaded again.
+        dir.save_state(&state).unwrap();
+        let loaded = dir.load_state().unwrap();
+        assert_eq!(loaded.past_sessions, vec![(first, 12)]);
+
+        // Only a limited number of past sessions is kept.
+        let mut state = loaded;
+        for serial in 0..2 * ServerState::MAX_PAST_SESSIONS as u64 {
+            let session = Uuid::new_v4();
+            state = ServerState {
+                session, serial,
+                past_sessions: state.past_sessions_for(session),
+                .. state
+            };
+        }
+        assert_eq!(
+            state.past_sessions.len(), ServerState::MAX_PAST_SESSIONS
+        );
+    }
+
+    #[test]
+    fn delta_checkpoints() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(&tmp.path().join("server"));
+        let server = Server::existing(
+            notify_uri(), dir.base().into(),
+            Arc::new(ServerOptions {
+                delta_checkpoint: Some(3),
+                .. Default::default()
+            })
+        );
+        let mut state = dir.load_state().unwrap();
+
+        // Go through deltas 13 to 20 in chunks of three, but crash while
+        // processing the last chunk.
+        let serials: Vec<u64> = (13..21).collect();
+        for chunk in serials.chunks(3) {
+            let mut targets = DeltaTargets::new(
+                tmp.path(), false, Default::default()
+            ).unwrap();
+            for serial in chunk {
+                let uri = rsync_uri(&format!("d{}.cer", serial));
+                let path = dir.uri_path(&uri).unwrap();
+                targets.publish(uri, path, b"delta".to_vec()).unwrap();
+            }
+            if chunk.len() < 3 {
+                break
+            }
+            server.commit_deltas(
+                &mut state, targets, *chunk.last().unwrap(), &mut Vec::new()
+            ).unwrap();
+        }
+        drop(server);
+
+        // We should be at the last checkpoint with a usable directory.
+        let state = dir.load_state().unwrap();
+        assert_eq!(state.serial, 18);
+        dir.check_digest(&state).unwrap();
+        assert!(dir.uri_path(&rsync_uri("d18.cer")).unwrap().exists());
+        assert!(!dir.uri_path(&rsync_uri("d19.cer")).unwrap().exists());
+        let hashes = dir.load_hashes().unwrap().unwrap();
+        assert!(hashes.verify(&rsync_uri("d18.cer"), b"delta"));
+
+        // And resume from there.
+        let notify = notification(
+            &Uuid::nil().to_string(), 20, &serials
+        );
+        let deltas = calc_deltas(&notify, &state).unwrap().unwrap();
+        assert_eq!(
+            deltas.iter().map(|delta| delta.0).collect::<Vec<_>>(),
+            vec![19, 20]
+        );
+    }
+
+    #[test]
+    fn delta_checkpoint_walks() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let store = RecordingStore::default();
+        let options = Arc::new(ServerOptions {
+            store: SharedStore::new(store.clone()),
+            delta_checkpoint: Some(1),
+            .. Default::default()
+        });
+        let session = Uuid::new_v4();
+        let content: [&[u8]; 4] = [b"first", b"second", b"third", b"fourth"];
+        client.publish(session, 1, &[("a.cer", content[0])], &[]);
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, options.clone()
+        );
+        server.update(&client);
+
+        // Apply three deltas with a checkpoint after each.
+        let mut deltas = Vec::new();
+        for serial in 2..5 {
+            let i = serial as usize - 1;
+            deltas.push((serial, client.publish_delta(
+                session, serial, "a.cer", content[i - 1], content[i]
+            )));
+        }
+        client.publish(session, 4, &[("a.cer", content[3])], &deltas);
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            options
+        );
+        store.take_listed();
+        server.update(&client);
+        let data = server.server_dir().join("data");
+        let walks = store.take_listed().into_iter().filter(|path| {
+            *path == data
+        }).count();
+        assert_eq!(server.metrics().unwrap().deltas_applied, Some(3));
+        assert_eq!(server.current_state().unwrap(), (session, 4));
+        assert!(server.verify().is_ok());
+
+        // Only the first chunk checks the digest. The digest after applying
+        // a chunk is derived from the changes and trusted by the next one,
+        // so the data directory is walked once only.
+        assert_eq!(walks, 1);
+    }
+
+    #[test]
+    fn snapshot_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let state = dir.load_state().unwrap();
+        let nil = Uuid::nil().to_string();
+        let behind = notification(&nil, 14, &[13, 14]);
+        let current = notification(&nil, 12, &[12]);
+
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        assert_eq!(
+            server.plan_deltas(&behind, &state).unwrap().unwrap().len(), 2
+        );
+
+        let server = Server::existing(
+            notify_uri(), dir.base().into(),
+            Arc::new(ServerOptions {
+                snapshot_only: Some(notify_uri()).into_iter().collect(),
+                .. Default::default()
+            })
+        );
+        assert!(matches!(
+            server.plan_deltas(&behind, &state),
+            Err(DeltaFailure::Disabled)
+        ));
+
+        // An unchanged server is still current.
+        assert!(server.plan_deltas(&current, &state).unwrap().is_none());
+    }
+
+    #[test]
+    fn max_deltas() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let state = dir.load_state().unwrap();
+        let nil = Uuid::nil().to_string();
+        let server = Server::existing(
+            notify_uri(), dir.base().into(),
+            Arc::new(ServerOptions {
+                max_deltas: Some(2),
+                .. Default::default()
+            })
+        );
+
+        // Older deltas don’t count, only those we would have to follow.
+        assert_eq!(
+            server.plan_deltas(
+                &notification(&nil, 14, &[11, 12, 13, 14]), &state
+            ).unwrap().unwrap().len(),
+            2
+        );
+        assert!(matches!(
+            server.plan_deltas(&notification(&nil, 15, &[13, 14, 15]), &state),
+            Err(DeltaFailure::TooManyDeltas)
+        ));
+        assert!(
+            server.plan_deltas(&notification(&nil, 12, &[12]), &state)
+                .unwrap().is_none()
+        );
+    }
+
+    #[test]
+    fn compressed_objects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(&tmp.path().join("plain"));
+        let state = dir.load_state().unwrap();
+        assert!(!state.compressed);
+
+        // The same objects stored compressed have the same digest.
+        let packed = ServerDir::new(
+            tmp.path().join("packed"),
+            &ServerOptions { compress: true, .. Default::default() }
+        );
+        let module = packed.data_path().join("example.net").join("repo");
+        fs::create_dir_all(module.join("sub")).unwrap();
+        compress::create_object(
+            &module.join("a.cer"), b"certificate", true
+        ).unwrap();
+        compress::create_object(
+            &module.join("sub").join("b.roa"), b"roa", true
+        ).unwrap();
+        assert_eq!(packed.digest().unwrap().as_ref(), state.hash.as_ref());
+        ServerState {
+            compressed: true,
+            .. state.clone()
+        }.save(&FsStore, packed.state_path()).unwrap();
+
+        let server = Server::existing(
+            notify_uri(), packed.base().into(),
+            Arc::new(ServerOptions {
+                compress: true,
+                .. Default::default()
+            })
+        );
+        assert!(server.verify().is_ok());
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"certificate"
+        );
+        assert_eq!(
+            server.list_objects().unwrap(),
+            vec![(rsync_uri("a.cer"), 11), (rsync_uri("sub/b.roa"), 3)]
+        );
+        let mut stream = Vec::new();
+        server.stream_objects(true, &mut stream).unwrap();
+        assert!(stream.ends_with(b"sub/b.roa 3\nroa"));
+
+        // Changing the storage forces a snapshot.
+        let state = packed.load_state().unwrap();
+        assert!(state.compressed);
+        let nil = Uuid::nil().to_string();
+        let server = Server::existing(
+            notify_uri(), packed.base().into(), Default::default()
+        );
+        assert!(server.verify().is_ok());
+        assert!(matches!(
+            server.plan_deltas(&notification(&nil, 12, &[12]), &state),
+            Err(DeltaFailure::StorageChanged)
+        ));
+    }
+
+    #[test]
+    fn local_digest_algorithm() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let state = dir.load_state().unwrap();
+        assert_eq!(state.algorithm, DigestAlgorithm::Sha256);
+        let nil = Uuid::nil().to_string();
+        let behind = notification(&nil, 14, &[13, 14]);
+        let current = notification(&nil, 12, &[12]);
+
+        // A different algorithm still verifies the old state ...
+        let server = Server::existing(
+            notify_uri(), dir.base().into(),
+            Arc::new(ServerOptions {
+                local_digest: DigestAlgorithm::Fnv1a,
+                .. Default::default()
+            })
+        );
+        assert!(server.verify().is_ok());
+        assert!(!server.check_broken());
+        assert_ne!(
+            server.server_dir.digest().unwrap().as_ref(),
+            state.hash.as_ref()
+        );
+
+        // ... but forces a snapshot even if the server is current.
+        assert!(matches!(
+            server.plan_deltas(&behind, &state),
+            Err(DeltaFailure::AlgorithmChanged)
+        ));
+        assert!(matches!(
+            server.plan_deltas(&current, &state),
+            Err(DeltaFailure::AlgorithmChanged)
+        ));
+
+        // The algorithm is written to and read from the state file.
+        ServerState {
+            hash: server.server_dir.digest().unwrap().into(),
+            algorithm: DigestAlgorithm::Fnv1a,
+            .. state
+        }.save(&FsStore, dir.state_path()).unwrap();
+        let state = dir.load_state().unwrap();
+        assert_eq!(state.algorithm, DigestAlgorithm::Fnv1a);
+        assert!(server.verify().is_ok());
+        assert!(server.plan_deltas(&current, &state).unwrap().is_none());
+    }
+
+    #[test]
+    fn custom_store() {
+        use crate::rrdp::store::StoreEntry;
+
+        /// A file system store recording the paths it reads.
+        #[derive(Debug, Default)]
+        struct RecordingStore(Arc<Mutex<Vec<PathBuf>>>);
+
+        impl CacheStore for RecordingStore {
+            fn read(&self, path: &Path) -> Result<Vec<u8>, io::Error> {
+                self.0.lock().unwrap().push(path.into());
+                FsStore.read(path)
+            }
+
+            fn read_prefix(
+                &self, path: &Path, len: usize
+            ) -> Result<Vec<u8>, io::Error> {
+                FsStore.read_prefix(path, len)
+            }
+
+            fn write(
+                &self, path: &Path, data: &[u8]
+            ) -> Result<(), io::Error> {
+                FsStore.write(path, data)
+            }
+
+            fn read_dir(
+                &self, path: &Path
+            ) -> Result<Vec<StoreEntry>, io::Error> {
+                self.0.lock().unwrap().push(path.into());
+                FsStore.read_dir(path)
+            }
+
+            fn is_dir(&self, path: &Path) -> bool {
+                FsStore.is_dir(path)
+            }
+
+            fn is_local(&self, path: &Path) -> bool {
+                FsStore.is_local(path)
+            }
+
+            fn rename(
+                &self, from: &Path, to: &Path
+            ) -> Result<(), io::Error> {
+                FsStore.rename(from, to)
+            }
+
+            fn remove_file(&self, path: &Path) -> Result<(), io::Error> {
+                FsStore.remove_file(path)
+            }
+
+            fn remove_dir_all(&self, path: &Path) -> Result<(), io::Error> {
+                FsStore.remove_dir_all(path)
+            }
+
+            fn create_dir_all(&self, path: &Path) -> Result<(), io::Error> {
+                FsStore.create_dir_all(path)
+            }
+
+            fn sync(&self, path: &Path) -> Result<(), io::Error> {
+                FsStore.sync(path)
+            }
+
+            fn commit(&self, path: &Path) -> Result<(), io::Error> {
+                FsStore.commit(path)
+            }
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let paths = Arc::new(Mutex::new(Vec::new()));
+        let server = Server::existing(
+            notify_uri(), dir.base().into(),
+            Arc::new(ServerOptions {
+                store: SharedStore::new(RecordingStore(paths.clone())),
+                .. Default::default()
+            })
+        );
+        let path = dir.uri_path(&rsync_uri("a.cer")).unwrap();
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"certificate"
+        );
+        assert_eq!(*paths.lock().unwrap(), vec![path]);
+
+        // The state file is read and the data directory walked for the
+        // digest via the store.
+        paths.lock().unwrap().clear();
+        let state = server.load_state().unwrap();
+        server.server_dir.check_digest(&state).unwrap();
+        let paths = paths.lock().unwrap();
+        assert!(paths.contains(&dir.state_path().into()));
+        assert!(paths.contains(&dir.data_path()));
+    }
+
+    #[test]
+    fn deep_digest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let state = dir.load_state().unwrap();
+        assert!(!state.deep_digest);
+        let deep = ServerDir::new(
+            tmp.path().into(),
+            &ServerOptions { deep_digest: true, .. Default::default() }
+        );
+        let digest = deep.digest().unwrap();
+        assert_ne!(digest.as_ref(), state.hash.as_ref());
+        assert_eq!(
+            data_digest(&dir.data_path(), Default::default(), true)
+                .unwrap().as_ref(),
+            digest.as_ref()
+        );
+
+        // Changed content of the same size is only seen by the deep digest.
+        let path = dir.uri_path(&rsync_uri("a.cer")).unwrap();
+        fs::write(&path, b"CERTIFICATE").unwrap();
+        assert_eq!(dir.digest().unwrap().as_ref(), state.hash.as_ref());
+        assert_ne!(deep.digest().unwrap().as_ref(), digest.as_ref());
+        assert_ne!(
+            deep.applied_digest(&[(path.clone(), Some(11))]).unwrap().as_ref(),
+            digest.as_ref()
+        );
+        fs::write(&path, b"certificate").unwrap();
+
+        // The setting is written to and read from the state file and the
+        // check uses the recorded setting.
+        ServerState {
+            hash: digest.into(),
+            deep_digest: true,
+            .. state.clone()
+        }.save(&FsStore, dir.state_path()).unwrap();
+        let deep_state = dir.load_state().unwrap();
+        assert!(deep_state.deep_digest);
+        assert!(dir.check_digest(&deep_state).is_ok());
+        fs::write(&path, b"CERTIFICATE").unwrap();
+        assert!(dir.check_digest(&deep_state).is_err());
+        fs::write(&path, b"certificate").unwrap();
+
+        // Changing the setting forces a snapshot.
+        let nil = Uuid::nil().to_string();
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        assert!(server.verify().is_ok());
+        assert!(matches!(
+            server.plan_deltas(&notification(&nil, 12, &[12]), &deep_state),
+            Err(DeltaFailure::DigestModeChanged)
+        ));
+        let server = Server::existing(
+            notify_uri(), dir.base().into(),
+            Arc::new(ServerOptions {
+                deep_digest: true,
+                .. Default::default()
+            })
+        );
+        assert!(server.plan_deltas(
+            &notification(&nil, 12, &[12]), &deep_state
+        ).unwrap().is_none());
+        assert!(matches!(
+            server.plan_deltas(&notification(&nil, 12, &[12]), &state),
+            Err(DeltaFailure::DigestModeChanged)
+        ));
+    }
+
+    #[test]
+    fn delta_failures() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let state = dir.load_state().unwrap();
+        let nil = Uuid::nil().to_string();
+        let other = "3e6ac3a5-4e9c-4427-a1b8-26fc3d9a5e6f";
+
+        assert!(matches!(
+            calc_deltas(&notification(other, 14, &[13, 14]), &state),
+            Err(DeltaFailure::SessionChanged)
+        ));
+
+        // The last delta isn’t for the current serial.
+        assert!(matches!(
+            calc_deltas(&notification(&nil, 14, &[13]), &state),
+            Err(DeltaFailure::SerialGap)
+        ));
+
+        // The delta following our serial is missing.
+        assert!(matches!(
+            calc_deltas(&notification(&nil, 15, &[14, 15]), &state),
+            Err(DeltaFailure::SerialGap)
+        ));
+
+        // The serial went backwards without a new session.
+        assert!(matches!(
+            calc_deltas(&notification(&nil, 11, &[10, 11]), &state),
+            Err(DeltaFailure::SerialRegressed)
+        ));
+        assert!(matches!(
+            calc_deltas(&notification(&nil, 3, &[]), &state),
+            Err(DeltaFailure::SerialRegressed)
+        ));
+    }
+
+    #[test]
+    fn no_deltas() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let state = dir.load_state().unwrap();
+        let nil = Uuid::nil().to_string();
+
+        // Our serial is current, so nothing needs doing.
+        assert!(matches!(
+            calc_deltas(&notification(&nil, 12, &[]), &state),
+            Ok(None)
+        ));
+
+        // A newer serial can only be had via the snapshot.
+        assert!(matches!(
+            calc_deltas(&notification(&nil, 13, &[]), &state),
+            Err(DeltaFailure::NoDeltas)
+        ));
+    }
+
+    #[test]
+    fn unsorted_deltas() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let state = dir.load_state().unwrap();
+        let nil = Uuid::nil().to_string();
+        let serials = |notify: &NotificationFile| {
+            calc_deltas(notify, &state).map(|deltas| {
+                deltas.unwrap().iter().map(|delta| delta.0).collect::<Vec<_>>()
+            })
+        };
+
+        // Out of order deltas are sorted.
+        assert_eq!(
+            serials(&notification(&nil, 15, &[15, 13, 11, 14, 12])),
+            Ok(vec![13, 14, 15])
+        );
+
+        // Duplicate serials are rejected even if the deltas are sorted.
+        assert_eq!(
+            serials(&notification(&nil, 15, &[13, 14, 14, 15])),
+            Err(DeltaFailure::DuplicateSerial)
+        );
+        assert_eq!(
+            serials(&notification(&nil, 15, &[14, 13, 15, 13])),
+            Err(DeltaFailure::DuplicateSerial)
+        );
+
+        // A missing delta in the middle is a gap, sorted or not.
+        assert_eq!(
+            serials(&notification(&nil, 16, &[13, 14, 16])),
+            Err(DeltaFailure::SerialGap)
+        );
+        assert_eq!(
+            serials(&notification(&nil, 16, &[16, 13, 15])),
+            Err(DeltaFailure::SerialGap)
+        );
+    }
+
+    #[test]
+    fn transient_digest_error() {
+        assert!(matches!(
+            DigestError::from(io::Error::from(io::ErrorKind::TimedOut)),
+            DigestError::Transient(_)
+        ));
+        assert!(matches!(
+            DigestError::from(io::Error::from(io::ErrorKind::NotFound)),
+            DigestError::Fatal(_)
+        ));
+        #[cfg(unix)]
+        assert!(matches!(
+            DigestError::from(io::Error::from_raw_os_error(libc::EIO)),
+            DigestError::Transient(_)
+        ));
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+
+        // A transient error skips the server but keeps its data.
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        assert!(!server.check_broken_digest(|_| Err(DigestError::Transient(
+            io::Error::from(io::ErrorKind::TimedOut)
+        ))));
+        assert!(server.is_broken());
+        assert!(dir.uri_path(&rsync_uri("a.cer")).unwrap().exists());
+
+        // The next run can use the data again.
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        assert!(!server.check_broken());
+        assert!(!server.is_broken());
+
+        // Any other error marks the data for removal.
+        assert!(server.check_broken_digest(|_| Err(DigestError::Fatal(
+            io::Error::from(io::ErrorKind::InvalidData)
+        ))));
+    }
+
+    #[test]
+    fn notify_uri_changed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        assert!(server.load_state().is_ok());
+        assert!(!server.check_broken());
+
+        let server = Server::existing(
+            uri::Https::from_str("https://example.org/notification.xml")
+                .unwrap(),
+            dir.base().into(), Default::default()
+        );
+        assert!(server.load_state().is_err());
+        assert!(server.check_broken());
+        assert!(server.is_broken());
+    }
+
+    #[test]
+    fn failed_update() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tmp_dir = tmp.path().join("tmp");
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let http = ReqwestClient::with_redirect(
+            tmp_dir, "https://example.net/".into(),
+            "http://127.0.0.1:1/".into()
+        ).unwrap();
+
+        // A consistent local copy survives a failed update.
+        let dir = seed_server_dir(&tmp.path().join("good"));
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        server.update(&http);
+        assert!(server.is_current());
+        assert!(!server.is_broken());
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"certificate"
+        );
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Failed)
+        );
+        assert_eq!(server.last_update_kind(), UpdateKind::Failed);
+
+        // A corrupt one is removed.
+        let dir = seed_server_dir(&tmp.path().join("bad"));
+        fs::write(
+            dir.uri_path(&rsync_uri("a.cer")).unwrap(), b"cert"
+        ).unwrap();
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        server.update(&http);
+        assert!(server.is_broken());
+        assert!(!dir.base().exists());
+    }
+
+    #[test]
+    fn broken_backoff() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tmp_dir = tmp.path().join("tmp");
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let http = MockClient::new(tmp_dir);
+        let clock = MockClock::new();
+        let options = Arc::new(ServerOptions {
+            clock: clock.shared(),
+            backoff: BrokenBackoff::new(
+                tmp.path().join("backoff"), Duration::from_secs(3600)
+            ),
+            .. Default::default()
+        });
+
+        // A corrupt local copy is removed and not retried for a while.
+        let dir = seed_server_dir(&tmp.path().join("server"));
+        fs::write(
+            dir.uri_path(&rsync_uri("a.cer")).unwrap(), b"cert"
+        ).unwrap();
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), options.clone()
+        );
+        server.update(&http);
+        assert!(server.is_broken());
+        assert!(!dir.base().exists());
+        assert_eq!(http.take_requests(), vec![notify_uri()]);
+
+        let server = Server::create(notify_uri(), tmp.path(), options.clone());
+        server.update(&http);
+        assert!(server.is_broken());
+        assert!(server.is_current());
+        assert!(http.take_requests().is_empty());
+
+        // Clearing the backoff allows retrying right away.
+        server.clear_backoff();
+        let server = Server::create(notify_uri(), tmp.path(), options.clone());
+        server.update(&http);
+        assert_eq!(http.take_requests(), vec![notify_uri()]);
+
+        // The failed retry started the backoff again. It ends eventually.
+        clock.advance(Duration::from_secs(3599));
+        let server = Server::create(notify_uri(), tmp.path(), options.clone());
+        server.update(&http);
+        assert!(http.take_requests().is_empty());
+        clock.advance(Duration::from_secs(1));
+        let server = Server::create(notify_uri(), tmp.path(), options);
+        server.update(&http);
+        assert_eq!(http.take_requests(), vec![notify_uri()]);
+    }
+
+    #[test]
+    fn current_state() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(&tmp.path().join("server"));
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        assert_eq!(server.current_state(), Some((Uuid::nil(), 12)));
+
+        // A state for another server isn’t ours.
+        let server = Server::existing(
+            uri::Https::from_str("https://example.net/other.xml").unwrap(),
+            dir.base().into(), Default::default()
+        );
+        assert_eq!(server.current_state(), None);
+
+        let server = Server::existing(
+            notify_uri(), tmp.path().join("missing"), Default::default()
+        );
+        assert_eq!(server.current_state(), None);
+        let server = Server::create_broken(notify_uri(), Default::default());
+        assert_eq!(server.current_state(), None);
+    }
+
+    #[test]
+    fn read_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tmp_dir = tmp.path().join("tmp");
+        let http = ReqwestClient::with_redirect(
+            tmp_dir.clone(), "https://example.net/".into(),
+            "http://127.0.0.1:1/".into()
+        ).unwrap();
+        let options = Arc::new(ServerOptions {
+            read_only: true,
+            .. Default::default()
+        });
+
+        // A consistent copy is used as is and never removed.
+        let dir = seed_server_dir(&tmp.path().join("good"));
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), options.clone()
+        );
+        assert_eq!(server.last_update_kind(), UpdateKind::NotYetRun);
+        server.update(&http);
+        assert!(server.is_current());
+        assert!(!server.is_broken());
+        let metrics = server.metrics().unwrap();
+        assert_eq!(metrics.update_kind, Some(RrdpUpdateKind::NotNeeded));
+        assert_eq!(server.last_update_kind(), UpdateKind::NotNeeded);
+        assert_eq!(metrics.serial, Some(12));
+        assert!(server.load_file(&rsync_uri("a.cer")).found().is_some());
+        assert!(!server.remove_unused());
+        assert!(dir.state_path().exists());
+
+        // A corrupt one is broken but stays in place.
+        let dir = seed_server_dir(&tmp.path().join("bad"));
+        fs::write(
+            dir.uri_path(&rsync_uri("a.cer")).unwrap(), b"cert"
+        ).unwrap();
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), options
+        );
+        server.update(&http);
+        server.force_snapshot(&http);
+        assert!(server.is_broken());
+        assert!(!server.remove_unused());
+        assert!(dir.uri_path(&rsync_uri("a.cer")).unwrap().exists());
+        assert!(!tmp_dir.exists());
+    }
+
+    #[test]
+    fn poisoned_update_lock() {
+        use std::panic;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let tmp_dir = tmp.path().join("tmp");
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let http = ReqwestClient::with_redirect(
+            tmp_dir, "https://example.net/".into(),
+            "http://127.0.0.1:1/".into()
+        ).unwrap();
+        let dir = seed_server_dir(&tmp.path().join("server"));
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        assert!(panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _lock = server.mutex.lock().unwrap();
+            panic!("killed")
+        })).is_err());
+        assert!(server.mutex.is_poisoned());
+
+        // The update still happens and the local copy stays usable.
+        server.update(&http);
+        assert!(server.is_current());
+        assert!(!server.is_broken());
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Failed)
+        );
+        assert!(server.load_file(&rsync_uri("a.cer")).found().is_some());
+    }
+
+    #[test]
+    fn remove_during_update() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path().join("server");
+        let dir = seed_server_dir(&base);
+        let server = Server::existing(
+            notify_uri(), base.clone(), Default::default()
+        );
+
+        // An update in progress keeps the directory.
+        {
+            let _lock = server.mutex.lock().unwrap();
+            assert!(!server.remove_unused());
+            assert!(dir.state_path().exists());
+        }
+        assert!(server.remove_unused());
+        assert!(!dir.base().exists());
+
+        // Race updates against removal. Updating fails since there is no
+        // server but leaves the data in place. The directory must either
+        // be gone or intact afterwards.
+        let tmp_dir = tmp.path().join("tmp");
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let http = ReqwestClient::with_redirect(
+            tmp_dir, "https://example.net/".into(),
+            "http://127.0.0.1:1/".into()
+        ).unwrap();
+        for _ in 0..20 {
+            let dir = seed_server_dir(&base);
+            let server = Server::existing(
+                notify_uri(), base.clone(), Default::default()
+            );
+            let removed = crossbeam_utils::thread::scope(|scope| {
+                let updater = scope.spawn(|_| server.update(&http));
+                let removed = (0..100).any(|_| server.remove_unused());
+                updater.join().unwrap();
+                removed
+            }).unwrap();
+            if removed {
+                assert!(!dir.base().exists());
+            }
+            else {
+                assert!(server.verify().is_ok());
+            }
+            let _ = fs::remove_dir_all(dir.base());
+        }
+    }
+
+    #[test]
+    fn is_updating() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        assert!(!server.is_updating());
+
+        // Others holding the update lock don’t count as an update.
+        {
+            let _lock = server.mutex.lock().unwrap();
+            assert!(!server.is_updating());
+            assert!(server.metrics().is_none());
+        }
+
+        // An update does and the flag is cleared however it ends.
+        {
+            let _updating = UpdatingFlag::set(&server.updating);
+            assert!(server.is_updating());
+        }
+        assert!(!server.is_updating());
+        let (_tmp, _, client) = MockClient::setup();
+        server.update(&client);
+        assert!(!server.is_updating());
+    }
+
+    #[test]
+    fn metrics_while_locked() {
+        use std::sync::Barrier;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        server.keep_current();
+
+        // Somebody else holding the update lock, for instance a running
+        // update, doesn’t make us wait. The holder only lets go once we
+        // have the metrics.
+        let barrier = Barrier::new(2);
+        let metrics = crossbeam_utils::thread::scope(|scope| {
+            let holder = scope.spawn(|_| {
+                let _lock = server.mutex.lock().unwrap();
+                barrier.wait();
+                barrier.wait();
+            });
+            barrier.wait();
+            let metrics = server.metrics();
+            barrier.wait();
+            holder.join().unwrap();
+            metrics
+        }).unwrap();
+        assert!(metrics.is_some());
+
+        // Also while racing removal of the unused directory.
+        crossbeam_utils::thread::scope(|scope| {
+            let remover = scope.spawn(|_| server.remove_unused());
+            for _ in 0..100 {
+                assert!(server.metrics().is_some());
+            }
+            remover.join().unwrap();
+        }).unwrap();
+    }
+
+    #[test]
+    fn digest_large_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let module = tmp.path().join("example.net").join("repo");
+        fs::create_dir_all(module.join("sub")).unwrap();
+        for i in 0..2000usize {
+            fs::write(
+                module.join(format!("{}.cer", i)), vec![0x30; i % 7]
+            ).unwrap();
+        }
+        fs::write(module.join("sub").join("b.roa"), b"roa").unwrap();
+
+        // Calculate the digest the naive way: collect, sort, process.
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(b"example.net");
+        context.update(b"repo");
+        let mut names: Vec<_> = (0..2000usize).map(|i| {
+            (format!("{}.cer", i), Some(i % 7))
+        }).collect();
+        names.push(("sub".into(), None));
+        names.sort();
+        for (name, size) in names {
+            context.update(name.as_bytes());
+            if let Some(size) = size {
+                context.update(&(size as u64).to_le_bytes());
+            }
+        }
+        context.update(b"b.roa");
+        context.update(&3u64.to_le_bytes());
+        let naive = context.finish();
+
+        // The walk gives the same digest as the naive way and the tree.
+        let walked = _data_digest(
+            &FsStore, tmp.path(), DigestAlgorithm::Sha256,
+            DigestVersion::CURRENT, false, false
+        ).unwrap();
+        assert_eq!(walked.as_ref(), naive.as_ref());
+        let tree = DigestTree::load(&FsStore, tmp.path(), false).unwrap();
+        assert_eq!(
+            tree.digest(
+                DigestAlgorithm::Sha256, DigestVersion::CURRENT
+            ).as_ref(),
+            naive.as_ref()
+        );
+
+        // So does the deep digest.
+        assert_eq!(
+            _data_digest(
+                &FsStore, tmp.path(), DigestAlgorithm::Sha256,
+                DigestVersion::CURRENT, true, false
+            ).unwrap().as_ref(),
+            tree.deep_digest(
+                &FsStore, tmp.path(), DigestAlgorithm::Sha256,
+                DigestVersion::CURRENT
+            ).unwrap().as_ref()
+        );
+    }
+
+    #[test]
+    fn data_digest_reference() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let digest = data_digest(
+            &dir.data_path(), Default::default(), false
+        ).unwrap();
+        assert_eq!(
+            digest.to_string(),
+            DigestHex::from(dir.digest().unwrap()).to_string()
+        );
 
-fn process_line<B: io::BufRead, T: FromStr>(
-    lines: &mut io::Lines<B>, expected_key: &str
-) -> Result<T, io::Error> {
-    let line = lines.next().ok_or_else(||
-        io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected EOF")
-    )??;
-    let mut line = line.split_whitespace();
-    let key = line.next().ok_or_else(||
-        io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected EOF")
-    )?;
-    if key != expected_key {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid data"
-        ))
+        // The digest is the same on all systems.
+        assert_eq!(
+            digest.to_string(),
+            "a4d742f594f2513f95c841a13d7cc676720f5ffc8e17a200e6eb7a492cbfad70"
+        );
     }
-    let value = line.next().ok_or_else(||
-        io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected EOF")
-    )?;
-    if line.next().is_some() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid data"
-        ))
+
+    #[test]
+    fn store_notification() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let digest = DigestHex::from(dir.digest().unwrap()).to_string();
+
+        for serial in 1..5 {
+            dir.store_notification(
+                format!("notification {}", serial).as_bytes(), 2
+            );
+        }
+        assert_eq!(
+            fs::read(tmp.path().join("notification.xml")).unwrap(),
+            b"notification 4"
+        );
+        assert_eq!(
+            fs::read(tmp.path().join("notification.xml.1")).unwrap(),
+            b"notification 3"
+        );
+        assert!(!tmp.path().join("notification.xml.2").exists());
+        assert_eq!(
+            DigestHex::from(dir.digest().unwrap()).to_string(), digest
+        );
     }
-    match T::from_str(value) {
-        Ok(value) => Ok(value),
-        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "bad value"))
+
+    #[test]
+    fn diagnose() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = seed_server_dir(tmp.path());
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        let nil = Uuid::nil().to_string();
+
+        assert_eq!(
+            server.diagnose_with(|| Ok(notification(&nil, 12, &[12]))),
+            Diagnosis::UpToDate { serial: 12 }
+        );
+        assert_eq!(
+            server.diagnose_with(|| Ok(notification(&nil, 14, &[13, 14]))),
+            Diagnosis::Behind { serial: 12, current: 14, deltas: true }
+        );
+        assert_eq!(
+            server.diagnose_with(|| Ok(notification(&nil, 14, &[14]))),
+            Diagnosis::Behind { serial: 12, current: 14, deltas: false }
+        );
+        assert_eq!(
+            server.diagnose_with(|| Ok(notification(
+                "9df4b597-af9e-4dca-bdda-719cce2c4e28", 12, &[]
+            ))),
+            Diagnosis::StaleSession {
+                session: Uuid::nil(),
+                current: Uuid::from_str(
+                    "9df4b597-af9e-4dca-bdda-719cce2c4e28"
+                ).unwrap()
+            }
+        );
+        assert_eq!(
+            server.diagnose_with(|| Err(Error)),
+            Diagnosis::Unreachable { serial: 12 }
+        );
+
+        // Corrupt the local copy. We must not even fetch the notification.
+        fs::write(
+            dir.uri_path(&rsync_uri("a.cer")).unwrap(), b"short"
+        ).unwrap();
+        assert_eq!(
+            server.diagnose_with(|| panic!("fetched notification")),
+            Diagnosis::Broken
+        );
+    }
+
+    #[test]
+    fn skip_verified_digest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tmp_dir = tmp.path().join("tmp");
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let http = ReqwestClient::with_redirect(
+            tmp_dir, "https://example.net/".into(),
+            "http://127.0.0.1:1/".into()
+        ).unwrap();
+        let dir = seed_server_dir(&tmp.path().join("server"));
+        let server = Server::existing(
+            notify_uri(), dir.base().into(), Default::default()
+        );
+        let state = server.verify().unwrap();
+        let notify = notification(&Uuid::nil().to_string(), 12, &[]);
+        let update = || {
+            server.delta_update(
+                &notify, Some(state.clone()), &http,
+                &mut RrdpServerMetrics::new(notify_uri()),
+                &mut Timeline::new(notify_uri())
+            )
+        };
+
+        // Sneak in a change. The unchanged serial trusts the earlier check.
+        fs::write(
+            dir.uri_path(&rsync_uri("a.cer")).unwrap(), b"cert"
+        ).unwrap();
+        assert_eq!(update(), Ok(()));
+
+        // Changing the local copy ourselves forgets about the check.
+        server.invalidate_digest();
+        assert_eq!(update(), Err(DeltaFailure::DigestMismatch));
+        assert!(server.verify().is_err());
+        assert_eq!(update(), Err(DeltaFailure::DigestMismatch));
+    }
+
+    #[test]
+    fn snapshot_and_delta() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let session = Uuid::new_v4();
+        client.publish(session, 1, &[("a.cer", b"first".as_ref())], &[]);
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, Default::default()
+        );
+        server.update(&client);
+        assert!(!server.is_broken());
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"first"
+        );
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Snapshot)
+        );
+        assert_eq!(server.metrics().unwrap().deltas_applied, None);
+
+        // The snapshot is published but only the delta is fetched.
+        client.take_requests();
+        let hash = client.publish_delta(
+            session, 2, "a.cer", b"first", b"second"
+        );
+        client.publish(
+            session, 2, &[("a.cer", b"second".as_ref())],
+            &[(2, hash)]
+        );
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            Default::default()
+        );
+        server.update(&client);
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"second"
+        );
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Delta)
+        );
+        assert_eq!(server.metrics().unwrap().deltas_applied, Some(1));
+        assert_eq!(server.metrics().unwrap().delta_serials, Some((1, 2)));
+        assert!(server.metrics().unwrap().lock_wait.is_some());
+        assert!(server.metrics().unwrap().work_time.is_some());
+        assert_eq!(
+            client.take_requests(),
+            vec![https_uri("notification.xml"), https_uri("delta-2.xml")]
+        );
+    }
+
+    #[test]
+    fn load_cache() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let options = Arc::new(ServerOptions {
+            load_cache_size: Some(1024),
+            .. Default::default()
+        });
+        let session = Uuid::new_v4();
+        client.publish(session, 1, &[("a.cer", b"first".as_ref())], &[]);
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, options
+        );
+        server.update(&client);
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"first"
+        );
+
+        // Loading again is served from memory.
+        let data = server.server_dir().join("data");
+        let moved = server.server_dir().join("moved");
+        fs::rename(&data, &moved).unwrap();
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"first"
+        );
+        fs::rename(&moved, &data).unwrap();
+
+        // Updating the local copy drops the cached objects.
+        client.publish(session, 2, &[("a.cer", b"second".as_ref())], &[]);
+        server.force_snapshot(&client);
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"second"
+        );
+    }
+
+    #[test]
+    fn export_import() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let session = Uuid::new_v4();
+        client.publish(
+            session, 1,
+            &[("a.cer", b"first".as_ref()), ("sub/b.roa", b"roa".as_ref())],
+            &[]
+        );
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, Default::default()
+        );
+        server.update(&client);
+        let mut archive = Vec::new();
+        server.export(&mut archive).unwrap();
+        let mut again = Vec::new();
+        server.export(&mut again).unwrap();
+        assert_eq!(archive, again);
+
+        let imported = Server::import(
+            &cache_dir, Default::default(), archive.as_slice()
+        ).unwrap();
+        assert_ne!(imported.server_dir(), server.server_dir());
+        assert_eq!(imported.notify_uri(), &https_uri("notification.xml"));
+        assert_eq!(imported.current_state().unwrap(), (session, 1));
+        assert_eq!(
+            imported.load_file(
+                &rsync_uri("sub/b.roa")
+            ).found().unwrap().as_ref(),
+            b"roa"
+        );
+
+        // The imported copy continues with the deltas.
+        client.take_requests();
+        let hash = client.publish_delta(
+            session, 2, "a.cer", b"first", b"second"
+        );
+        client.publish(
+            session, 2,
+            &[("a.cer", b"second".as_ref()), ("sub/b.roa", b"roa".as_ref())],
+            &[(2, hash)]
+        );
+        imported.update(&client);
+        assert_eq!(
+            client.take_requests(),
+            vec![https_uri("notification.xml"), https_uri("delta-2.xml")]
+        );
+
+        // An archive missing an object is rejected and leaves nothing.
+        let mut reader = tar::Archive::new(archive.as_slice());
+        let mut tampered = tar::Builder::new(Vec::new());
+        for entry in reader.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().into_owned();
+            if path.ends_with("b.roa") {
+                continue
+            }
+            let mut header = entry.header().clone();
+            tampered.append_data(&mut header, &path, &mut entry).unwrap();
+        }
+        let tampered = tampered.into_inner().unwrap();
+        let count = fs::read_dir(&cache_dir).unwrap().count();
+        assert!(Server::import(
+            &cache_dir, Default::default(), tampered.as_slice()
+        ).is_err());
+        assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), count);
+    }
+
+    #[test]
+    fn object_check() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let options = Arc::new(ServerOptions {
+            object_check: ObjectCheck::new(|_, data| {
+                !data.starts_with(b"garbage")
+            }),
+            .. Default::default()
+        });
+        let session = Uuid::new_v4();
+        client.publish(session, 1, &[("a.cer", b"first".as_ref())], &[]);
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, options.clone()
+        );
+        server.update(&client);
+        assert!(!server.is_broken());
+
+        // A snapshot with a rejected object leaves the local copy alone.
+        client.publish(
+            session, 2,
+            &[("a.cer", b"second".as_ref()), ("b.roa", b"garbage".as_ref())],
+            &[]
+        );
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            options
+        );
+        server.force_snapshot(&client);
+        assert_eq!(server.current_state().unwrap(), (session, 1));
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"first"
+        );
+        assert_eq!(fs::read_dir(client.tmp_dir()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn check_dir_names() {
+        assert!(ServerDirNames::default().check().is_ok());
+        let check = |names: ServerDirNames| names.check().is_ok();
+        let names = ServerDirNames::default;
+
+        // Names must be single components.
+        assert!(!check(ServerDirNames { state: "".into(), .. names() }));
+        assert!(!check(ServerDirNames { state: "..".into(), .. names() }));
+        assert!(!check(ServerDirNames { data: "a/data".into(), .. names() }));
+
+        // Names must not collide, including with derived names.
+        assert!(!check(ServerDirNames {
+            manifest: "hashes.txt".into(), .. names()
+        }));
+        assert!(!check(ServerDirNames {
+            state: "data.old".into(), .. names()
+        }));
+        assert!(!check(ServerDirNames {
+            hashes: "notification.xml.3".into(), .. names()
+        }));
+        assert!(check(ServerDirNames {
+            hashes: "notification.xml.old".into(), .. names()
+        }));
+    }
+
+    #[test]
+    fn dir_names() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let options = Arc::new(ServerOptions {
+            dir_names: ServerDirNames {
+                state: "rrdp-state".into(),
+                data: "rrdp-data".into(),
+                hashes: "rrdp-hashes".into(),
+                manifest: "rrdp-manifest".into(),
+                notification: "rrdp-notification".into(),
+            },
+            keep_notifications: Some(2),
+            .. Default::default()
+        });
+        options.dir_names.check().unwrap();
+        let session = Uuid::new_v4();
+        client.publish(session, 1, &[("a.cer", b"first".as_ref())], &[]);
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, options.clone()
+        );
+        server.update(&client);
+        let base = server.server_dir().to_path_buf();
+        let mut names: Vec<_> = fs::read_dir(&base).unwrap().map(|entry| {
+            entry.unwrap().file_name().into_string().unwrap()
+        }).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "rrdp-data", "rrdp-hashes", "rrdp-manifest",
+                "rrdp-notification", "rrdp-state"
+            ]
+        );
+
+        // Updating via the snapshot replaces the renamed data directory.
+        client.publish(session, 2, &[("a.cer", b"second".as_ref())], &[]);
+        let server = Server::existing(
+            https_uri("notification.xml"), base.clone(), options.clone()
+        );
+        server.force_snapshot(&client);
+        assert_eq!(server.current_state().unwrap(), (session, 2));
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"second"
+        );
+        assert!(!base.join("rrdp-data.old").exists());
+        assert!(!base.join("data").exists());
+
+        // Archives always use the default names, so they can be imported
+        // into a cache using different names and back again.
+        let mut archive = Vec::new();
+        server.export(&mut archive).unwrap();
+        let imported = Server::import(
+            &cache_dir, Default::default(), archive.as_slice()
+        ).unwrap();
+        assert!(imported.server_dir().join("state.txt").is_file());
+        assert_eq!(imported.current_state().unwrap(), (session, 2));
+        let mut again = Vec::new();
+        imported.export(&mut again).unwrap();
+        assert_eq!(archive, again);
+        let imported = Server::import(
+            &cache_dir, options, again.as_slice()
+        ).unwrap();
+        assert!(imported.server_dir().join("rrdp-state").is_file());
+        assert_eq!(
+            imported.load_file(
+                &rsync_uri("a.cer")
+            ).found().unwrap().as_ref(),
+            b"second"
+        );
+    }
+
+    #[test]
+    fn unchanged_mtimes() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let session = Uuid::new_v4();
+        let objects = [
+            ("a.cer", b"first".as_ref()), ("b.roa", b"roa".as_ref())
+        ];
+        client.publish(session, 1, &objects, &[]);
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, Default::default()
+        );
+        server.update(&client);
+        let mtime = |server: &Server, path: &str| {
+            server.load_file_with_meta(
+                &rsync_uri(path)
+            ).found().unwrap().1.modified().unwrap()
+        };
+        // Backdate the file so that rewriting it would show.
+        let b_time = FileTime::from_unix_time(3600, 0);
+        set_file_mtime(
+            server.server_dir().join("data/example.net/repo/b.roa"), b_time
+        ).unwrap();
+        let b_time = mtime(&server, "b.roa");
+        assert_eq!(FileTime::from_system_time(b_time).unix_seconds(), 3600);
+
+        // A delta republishing an object unchanged leaves it alone.
+        let hash = client.publish_delta(session, 2, "b.roa", b"roa", b"roa");
+        client.publish(session, 2, &objects, &[(2, hash)]);
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            Default::default()
+        );
+        server.update(&client);
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Delta)
+        );
+        assert_eq!(mtime(&server, "b.roa"), b_time);
+
+        // So does a snapshot for objects that didn’t change.
+        client.publish(
+            Uuid::new_v4(), 1,
+            &[("a.cer", b"second".as_ref()), ("b.roa", b"roa".as_ref())],
+            &[]
+        );
+        server.force_snapshot(&client);
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"second"
+        );
+        assert_eq!(mtime(&server, "b.roa"), b_time);
+    }
+
+    #[test]
+    fn forged_delta() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let session = Uuid::new_v4();
+        client.publish(session, 1, &[("a.cer", b"first".as_ref())], &[]);
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, Default::default()
+        );
+        server.update(&client);
+
+        // A delta not matching its hash falls back to the snapshot.
+        client.publish_delta(session, 2, "a.cer", b"first", b"forged");
+        let hash = sha256(b"something else");
+        client.publish(
+            session, 2, &[("a.cer", b"second".as_ref())],
+            &[(2, hash)]
+        );
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            Default::default()
+        );
+        server.update(&client);
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"second"
+        );
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Snapshot)
+        );
+    }
+
+    #[test]
+    fn withdraw_hash_mismatch() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let session = Uuid::new_v4();
+        client.publish(
+            session, 1,
+            &[("a.cer", b"first".as_ref()), ("b.roa", b"roa".as_ref())], &[]
+        );
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, Default::default()
+        );
+        server.update(&client);
+
+        // A delta withdrawing an object with a different hash falls back
+        // to the snapshot.
+        let mut xml = header("delta", session, 2);
+        xml.push_str(&format!(
+            "  <withdraw uri=\"{}\" hash=\"{}\"/>\n",
+            rsync_uri("b.roa"), sha256(b"other roa")
+        ));
+        xml.push_str("</delta>\n");
+        let hash = sha256(xml.as_bytes());
+        client.serve(&https_uri("delta-2.xml"), xml.into_bytes());
+        client.publish(
+            session, 2, &[("a.cer", b"first".as_ref())],
+            &[(2, hash)]
+        );
+        client.take_requests();
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            Default::default()
+        );
+        server.update(&client);
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Snapshot)
+        );
+        assert_eq!(
+            client.take_requests(),
+            vec![
+                https_uri("notification.xml"), https_uri("delta-2.xml"),
+                https_uri("snapshot-2.xml"),
+            ]
+        );
+        assert!(server.load_file(&rsync_uri("b.roa")).is_not_found());
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"first"
+        );
+    }
+
+    #[test]
+    fn snapshot_hash_mismatch() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let session = Uuid::new_v4();
+        client.publish(session, 1, &[("a.cer", b"first".as_ref())], &[]);
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, Default::default()
+        );
+        server.update(&client);
+
+        // Serve a snapshot that doesn’t match the advertised hash.
+        client.publish(session, 2, &[("a.cer", b"second".as_ref())], &[]);
+        let snapshot_uri = https_uri("snapshot-2.xml");
+        let forged = String::from_utf8(
+            client.published(&snapshot_uri).unwrap()
+        ).unwrap().replace(
+            &base64::encode(b"second"), &base64::encode(b"forged")
+        );
+        client.serve(&snapshot_uri, forged.into_bytes());
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            Default::default()
+        );
+        server.update(&client);
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"first"
+        );
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Failed)
+        );
+
+        // Nothing is left behind in the temporary directory.
+        assert_eq!(client.tmp_dir().read_dir().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn session_pin() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let session = Uuid::new_v4();
+        let mut options = ServerOptions::default();
+        options.session_pins.insert(https_uri("notification.xml"), session);
+        let options = Arc::new(options);
+        client.publish(session, 1, &[("a.cer", b"first".as_ref())], &[]);
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, options.clone()
+        );
+        server.update(&client);
+        assert!(!server.is_broken());
+
+        // A new session is refused without fetching the snapshot.
+        client.take_requests();
+        client.publish(
+            Uuid::new_v4(), 1, &[("a.cer", b"second".as_ref())], &[]
+        );
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            options
+        );
+        server.update(&client);
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"first"
+        );
+        assert_eq!(
+            client.take_requests(), vec![https_uri("notification.xml")]
+        );
+
+        // Checking the server refuses the new session, too.
+        assert!(server.check(&client).refused);
+
+        // Without the pin, the new session is accepted.
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            Default::default()
+        );
+        server.update(&client);
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"second"
+        );
+    }
+
+    #[test]
+    fn replay_refused() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let mut options = ServerOptions::default();
+        options.replay_policy = ReplayPolicy::Refuse;
+        let options = Arc::new(options);
+        let server = |dir: &Path| Server::existing(
+            https_uri("notification.xml"), dir.into(), options.clone()
+        );
+        let first = Uuid::new_v4();
+        client.publish(first, 10, &[("a.cer", b"first".as_ref())], &[]);
+        let created = Server::create(
+            https_uri("notification.xml"), &cache_dir, options.clone()
+        );
+        created.update(&client);
+        assert!(!created.is_broken());
+        let dir = created.server_dir().to_path_buf();
+
+        // A genuine reset to a new session with a lower serial is
+        // accepted.
+        let second = Uuid::new_v4();
+        client.publish(second, 1, &[("a.cer", b"second".as_ref())], &[]);
+        let updated = server(&dir);
+        updated.update(&client);
+        assert_eq!(
+            updated.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"second"
+        );
+
+        // Going back to the first session is refused as a replay. Checking
+        // the server refuses it, too.
+        client.publish(first, 10, &[("a.cer", b"first".as_ref())], &[]);
+        let updated = server(&dir);
+        client.take_requests();
+        let report = updated.check(&client);
+        assert!(report.refused);
+        assert_eq!(report.path, None);
+        assert_eq!(
+            client.take_requests(), vec![https_uri("notification.xml")]
+        );
+        updated.update(&client);
+        assert_eq!(
+            updated.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"second"
+        );
+
+        // The first session moving on past what we have seen is fine.
+        client.publish(first, 11, &[("a.cer", b"third".as_ref())], &[]);
+        let updated = server(&dir);
+        updated.update(&client);
+        assert_eq!(
+            updated.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"third"
+        );
+    }
+
+    #[test]
+    fn events() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut options = ServerOptions::default();
+        let target = events.clone();
+        options.events.set_listener(Arc::new(move |event: &Event| {
+            target.lock().unwrap().push((event.kind, event.details.to_vec()))
+        }));
+        let options = Arc::new(options);
+        let session = Uuid::new_v4();
+        client.publish(session, 1, &[("a.cer", b"first".as_ref())], &[]);
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, options.clone()
+        );
+        server.update(&client);
+
+        // Only the delta for serial 3 is listed, so there is a gap.
+        let hash = client.publish_delta(
+            session, 3, "a.cer", b"first", b"third"
+        );
+        client.publish(
+            session, 3, &[("a.cer", b"third".as_ref())],
+            &[(3, hash)]
+        );
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            options
+        );
+        server.update(&client);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                (EventKind::UpdateStart, vec![]),
+                (EventKind::SnapshotStart, vec![("serial", 1)]),
+                (EventKind::UpdateStart, vec![]),
+                (
+                    EventKind::SerialGap,
+                    vec![("serial", 1), ("serverSerial", 3)]
+                ),
+                (EventKind::SnapshotStart, vec![("serial", 3)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn notification_not_modified() {
+        let (_tmp, cache_dir, mut client) = MockClient::setup();
+        client.enable_etags();
+        let session = Uuid::new_v4();
+        client.publish(session, 1, &[("a.cer", b"first".as_ref())], &[]);
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, Default::default()
+        );
+        server.update(&client);
+
+        // Nothing has changed, so the server answers with a 304.
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            Default::default()
+        );
+        server.update(&client);
+        let metrics = server.metrics().unwrap();
+        assert_eq!(metrics.notify_status.unwrap().as_u16(), 304);
+        assert_eq!(metrics.update_kind, Some(RrdpUpdateKind::NotNeeded));
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"first"
+        );
+
+        // A changed local copy is noticed and the notification file
+        // fetched again in full.
+        fs::write(
+            server.server_dir().join("data").join("stray.txt"), b"stray"
+        ).unwrap();
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            Default::default()
+        );
+        server.update(&client);
+        let metrics = server.metrics().unwrap();
+        assert_eq!(metrics.notify_status.unwrap().as_u16(), 200);
+        assert_eq!(metrics.update_kind, Some(RrdpUpdateKind::Snapshot));
+        assert!(!server.is_broken());
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"first"
+        );
+    }
+
+    #[test]
+    fn reported_changes() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let session = Uuid::new_v4();
+        client.publish(
+            session, 1,
+            &[("a.cer", b"first".as_ref()), ("b.roa", b"roa".as_ref())], &[]
+        );
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, Default::default()
+        );
+        assert!(server.changes().is_none());
+        server.update(&client);
+
+        // A snapshot reports all objects as added.
+        assert_eq!(server.last_update_kind(), UpdateKind::Snapshot);
+        let mut changes = server.changes().unwrap();
+        changes.sort_by_key(|change| change.0.to_string());
+        assert_eq!(
+            changes,
+            vec![
+                (rsync_uri("a.cer"), ChangeKind::Added),
+                (rsync_uri("b.roa"), ChangeKind::Added),
+            ]
+        );
+
+        // Deltas report each change in order.
+        let hash = client.publish_changes(session, 2, &[
+            ("a.cer", Some(b"first".as_ref()), Some(b"second".as_ref())),
+            ("b.roa", Some(b"roa".as_ref()), None),
+            ("c.mft", None, Some(b"manifest".as_ref())),
+        ]);
+        client.publish(
+            session, 2,
+            &[("a.cer", b"second".as_ref()), ("c.mft", b"manifest".as_ref())],
+            &[(2, hash)]
+        );
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            Default::default()
+        );
+        server.update(&client);
+        assert_eq!(server.last_update_kind(), UpdateKind::Delta);
+        assert_eq!(
+            server.changes().unwrap(),
+            vec![
+                (rsync_uri("a.cer"), ChangeKind::Modified),
+                (rsync_uri("b.roa"), ChangeKind::Withdrawn),
+                (rsync_uri("c.mft"), ChangeKind::Added),
+            ]
+        );
+
+        // Objects republished with identical content aren’t reported.
+        let hash = client.publish_changes(session, 3, &[
+            ("a.cer", Some(b"second".as_ref()), Some(b"second".as_ref())),
+            ("c.mft", Some(b"manifest".as_ref()), Some(b"new".as_ref())),
+        ]);
+        client.publish(
+            session, 3,
+            &[("a.cer", b"second".as_ref()), ("c.mft", b"new".as_ref())],
+            &[(3, hash)]
+        );
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            Default::default()
+        );
+        server.update(&client);
+        assert_eq!(server.last_update_kind(), UpdateKind::Delta);
+        assert_eq!(
+            server.changes().unwrap(),
+            vec![(rsync_uri("c.mft"), ChangeKind::Modified)]
+        );
+        assert_eq!(
+            server.load_file(&rsync_uri("c.mft")).found().unwrap().as_ref(),
+            b"new"
+        );
+
+        // An unchanged server reports nothing.
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            Default::default()
+        );
+        server.update(&client);
+        assert_eq!(server.changes(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn check_only() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let session = Uuid::new_v4();
+        client.publish(session, 1, &[("a.cer", b"first".as_ref())], &[]);
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, Default::default()
+        );
+        server.update(&client);
+        assert_eq!(server.check(&client).path, Some(UpdatePath::Current));
+
+        // A delta update would be chosen but nothing changes.
+        let hash = client.publish_delta(
+            session, 2, "a.cer", b"first", b"second"
+        );
+        client.publish(
+            session, 2, &[("a.cer", b"second".as_ref())], &[(2, hash)]
+        );
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            Default::default()
+        );
+        let report = server.check(&client);
+        assert_eq!(report.serial, Some(2));
+        assert_eq!(report.path, Some(UpdatePath::Delta));
+        assert_eq!(report.delta_failure, None);
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"first"
+        );
+        assert!(server.verify().is_ok());
+        assert_eq!(fs::read_dir(client.tmp_dir()).unwrap().count(), 0);
+
+        // A forged delta is reported and so is a broken snapshot.
+        let delta = https_uri("delta-2.xml");
+        let forged = String::from_utf8(
+            client.published(&delta).unwrap()
+        ).unwrap().replace(
+            &base64::encode(b"second"), &base64::encode(b"forged")
+        );
+        client.serve(&delta, forged.into_bytes());
+        client.serve(&https_uri("snapshot-2.xml"), b"garbage".to_vec());
+        let report = server.check(&client);
+        assert_eq!(report.path, None);
+        assert_eq!(report.delta_failure, Some(DeltaFailure::Http));
+        assert!(report.snapshot_failed);
+        assert_eq!(fs::read_dir(client.tmp_dir()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn compressed_cycle() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let options = Arc::new(ServerOptions {
+            compress: true,
+            .. Default::default()
+        });
+        let session = Uuid::new_v4();
+        client.publish(
+            session, 1,
+            &[("a.cer", b"first".as_ref()), ("b.roa", b"roa".as_ref())], &[]
+        );
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, options.clone()
+        );
+        server.update(&client);
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Snapshot)
+        );
+        assert_eq!(
+            server.load_file(&rsync_uri("b.roa")).found().unwrap().as_ref(),
+            b"roa"
+        );
+
+        // Deltas check the hashes of the compressed objects they replace.
+        let hash = client.publish_changes(session, 2, &[
+            ("a.cer", Some(b"first".as_ref()), Some(b"second".as_ref())),
+            ("b.roa", Some(b"roa".as_ref()), None),
+        ]);
+        client.publish(
+            session, 2, &[("a.cer", b"second".as_ref())], &[(2, hash)]
+        );
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            options
+        );
+        server.update(&client);
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Delta)
+        );
+        assert_eq!(server.metrics().unwrap().object_count, Some(1));
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"second"
+        );
+        assert!(server.load_file(&rsync_uri("b.roa")).is_not_found());
+        assert!(server.verify().unwrap().compressed);
     }
-}
 
+    #[test]
+    fn fsync_cycle() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let options = Arc::new(ServerOptions {
+            fsync: true,
+            .. Default::default()
+        });
+        let session = Uuid::new_v4();
+        client.publish(
+            session, 1,
+            &[("a.cer", b"first".as_ref()), ("sub/b.roa", b"roa".as_ref())],
+            &[]
+        );
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, options.clone()
+        );
+        server.update(&client);
+        assert_eq!(
+            server.load_file(
+                &rsync_uri("sub/b.roa")
+            ).found().unwrap().as_ref(),
+            b"roa"
+        );
+
+        let hash = client.publish_changes(session, 2, &[
+            ("a.cer", Some(b"first".as_ref()), Some(b"second".as_ref())),
+            ("sub/b.roa", Some(b"roa".as_ref()), None),
+            ("sub/c.mft", None, Some(b"manifest".as_ref())),
+        ]);
+        client.publish(
+            session, 2,
+            &[
+                ("a.cer", b"second".as_ref()),
+                ("sub/c.mft", b"manifest".as_ref())
+            ],
+            &[(2, hash)]
+        );
+        let server = Server::existing(
+            https_uri("notification.xml"), server.server_dir().into(),
+            options
+        );
+        server.update(&client);
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Delta)
+        );
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"second"
+        );
+        assert!(server.load_file(&rsync_uri("sub/b.roa")).is_not_found());
+        assert_eq!(
+            server.load_file(
+                &rsync_uri("sub/c.mft")
+            ).found().unwrap().as_ref(),
+            b"manifest"
+        );
+    }
+
+    #[test]
+    fn seed_from_snapshot() {
+        let (tmp, cache_dir, client) = MockClient::setup();
+        let session = Uuid::new_v4();
+        client.publish(
+            session, 1,
+            &[("a.cer", b"first".as_ref()), ("sub/b.roa", b"roa".as_ref())],
+            &[]
+        );
+        let snapshot = tmp.path().join("snapshot.xml");
+        fs::write(
+            &snapshot, client.published(&https_uri("snapshot-1.xml")).unwrap()
+        ).unwrap();
+
+        // The session and serial have to match the snapshot.
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, Default::default()
+        );
+        assert!(server.seed_from_snapshot(
+            &snapshot, Uuid::new_v4(), 1, client.tmp_dir()
+        ).is_err());
+        assert!(server.seed_from_snapshot(
+            &snapshot, session, 2, client.tmp_dir()
+        ).is_err());
+        assert_eq!(server.current_state(), None);
+
+        server.seed_from_snapshot(
+            &snapshot, session, 1, client.tmp_dir()
+        ).unwrap();
+        assert_eq!(server.current_state(), Some((session, 1)));
+        assert_eq!(
+            server.load_file(
+                &rsync_uri("sub/b.roa")
+            ).found().unwrap().as_ref(),
+            b"roa"
+        );
+
+        // The seeded copy catches up via the deltas.
+        let hash = client.publish_delta(
+            session, 2, "a.cer", b"first", b"second"
+        );
+        client.publish(
+            session, 2,
+            &[("a.cer", b"second".as_ref()), ("sub/b.roa", b"roa".as_ref())],
+            &[(2, hash)]
+        );
+        server.update(&client);
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Delta)
+        );
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"second"
+        );
+    }
+
+    #[test]
+    fn force_snapshot() {
+        let (_tmp, cache_dir, client) = MockClient::setup();
+        let session = Uuid::new_v4();
+        client.publish(session, 1, &[("a.cer", b"first".as_ref())], &[]);
+        let server = Server::create(
+            https_uri("notification.xml"), &cache_dir, Default::default()
+        );
+        server.update(&client);
+
+        // Damage the local copy behind the server’s back. The forced
+        // snapshot repairs it even though the server is current already.
+        fs::write(
+            server.server_dir().join("data").join("stray.txt"), b"stray"
+        ).unwrap();
+        assert!(server.verify().is_err());
+        server.force_snapshot(&client);
+        assert_eq!(
+            server.metrics().unwrap().update_kind,
+            Some(RrdpUpdateKind::Snapshot)
+        );
+        assert_eq!(
+            server.load_file(&rsync_uri("a.cer")).found().unwrap().as_ref(),
+            b"first"
+        );
+        assert!(server.verify().is_ok());
+
+        // A broken server without a directory stays broken.
+        let server = Server::create_broken(
+            https_uri("notification.xml"), Default::default()
+        );
+        server.force_snapshot(&client);
+        assert!(server.is_broken());
+    }
+}
@@ -0,0 +1,271 @@
+//! Backing off from RRDP servers that change their session too often.
+//!
+//! This is a private module for organizational purposes.
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use rpki::uri;
+use uuid::Uuid;
+use crate::config::Config;
+use super::clock::SharedClock;
+
+
+//------------ SessionPolicy -------------------------------------------------
+
+/// The policy for servers that change their session frequently.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SessionPolicy {
+    /// The number of session changes tolerated within the window.
+    pub max_changes: usize,
+
+    /// The window over which session changes are counted.
+    pub window: Duration,
+}
+
+impl SessionPolicy {
+    /// Returns the minimum time between updates while backing off.
+    ///
+    /// This is chosen so that a server that keeps changing its session
+    /// will not cause more than the tolerated number of snapshots per
+    /// window. A number of changes too large for the division to be
+    /// meaningful is capped.
+    pub fn interval(&self) -> Duration {
+        self.window / u32::try_from(
+            cmp::max(self.max_changes, 1)
+        ).unwrap_or(::std::u32::MAX)
+    }
+}
+
+
+//------------ SessionTracker ------------------------------------------------
+
+/// Tracks session changes of servers across validation runs.
+#[derive(Debug)]
+pub struct SessionTracker {
+    /// The policy to apply or `None` if session changes are ignored.
+    policy: Option<SessionPolicy>,
+
+    /// The history of each server.
+    servers: Mutex<HashMap<uri::Https, SessionHistory>>,
+
+    /// The clock to take the current time from.
+    clock: SharedClock,
+}
+
+/// The session history of a single server.
+#[derive(Debug)]
+struct SessionHistory {
+    /// The session seen in the last update.
+    session: Uuid,
+
+    /// The times of session changes within the window.
+    changes: VecDeque<Instant>,
+
+    /// The time of the last update.
+    last_update: Instant,
+
+    /// Whether we are currently backing off.
+    backing_off: bool,
+}
+
+impl SessionTracker {
+    /// Creates a new tracker using the given policy.
+    pub fn new(policy: Option<SessionPolicy>, clock: SharedClock) -> Self {
+        SessionTracker {
+            policy,
+            servers: Default::default(),
+            clock,
+        }
+    }
+
+    /// Creates a new tracker from the configuration.
+    pub fn from_config(config: &Config, clock: SharedClock) -> Self {
+        Self::new(
+            config.rrdp_session_changes.map(|max_changes| {
+                SessionPolicy {
+                    max_changes,
+                    window: config.rrdp_session_window,
+                }
+            }),
+            clock
+        )
+    }
+
+    /// Returns whether an update of the server should be skipped now.
+    pub fn should_skip(&self, notify_uri: &uri::Https) -> bool {
+        let policy = match self.policy {
+            Some(policy) => policy,
+            None => return false
+        };
+        let now = self.clock.now();
+        let mut servers = self.servers.lock().unwrap();
+        let history = match servers.get_mut(notify_uri) {
+            Some(history) => history,
+            None => return false
+        };
+        history.expire(policy.window, now);
+        if history.changes.len() <= policy.max_changes {
+            return false
+        }
+        if now.duration_since(history.last_update) >= policy.interval() {
+            return false
+        }
+        info!(
+            "RRDP {}: backing off after frequent session changes. \
+             Keeping current data.",
+            notify_uri
+        );
+        true
+    }
+
+    /// Records the session of a server after an update.
+    pub fn update(&self, notify_uri: &uri::Https, session: Option<Uuid>) {
+        let policy = match self.policy {
+            Some(policy) => policy,
+            None => return
+        };
+        let session = match session {
+            Some(session) => session,
+            None => return
+        };
+        let now = self.clock.now();
+        let mut servers = self.servers.lock().unwrap();
+        let history = servers.entry(notify_uri.clone()).or_insert_with(|| {
+            SessionHistory {
+                session,
+                changes: VecDeque::new(),
+                last_update: now,
+                backing_off: false,
+            }
+        });
+        if history.session != session {
+            history.session = session;
+            history.changes.push_back(now);
+        }
+        history.last_update = now;
+        history.expire(policy.window, now);
+        let backing_off = history.changes.len() > policy.max_changes;
+        if backing_off && !history.backing_off {
+            warn!(
+                "RRDP {}: session changed {} times in the last {} seconds. \
+                 Updating at most every {} seconds.",
+                notify_uri, history.changes.len(), policy.window.as_secs(),
+                policy.interval().as_secs()
+            );
+        }
+        else if !backing_off && history.backing_off {
+            info!(
+                "RRDP {}: session has settled. Resuming regular updates.",
+                notify_uri
+            );
+        }
+        history.backing_off = backing_off;
+    }
+}
+
+impl SessionHistory {
+    /// Forgets about session changes that have left the window.
+    fn expire(&mut self, window: Duration, now: Instant) {
+        while let Some(time) = self.changes.front() {
+            if now.duration_since(*time) < window {
+                break
+            }
+            self.changes.pop_front();
+        }
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::str::FromStr;
+    use crate::rrdp::clock::MockClock;
+
+    #[test]
+    fn interval() {
+        let policy = |max_changes| SessionPolicy {
+            max_changes, window: Duration::from_secs(3600)
+        };
+        assert_eq!(policy(0).interval(), Duration::from_secs(3600));
+        assert_eq!(policy(4).interval(), Duration::from_secs(900));
+        assert!(
+            policy(usize::max_value()).interval() > Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn backoff() {
+        let uri = uri::Https::from_str(
+            "https://example.net/notification.xml"
+        ).unwrap();
+        let clock = MockClock::new();
+        let tracker = SessionTracker::new(
+            Some(SessionPolicy {
+                max_changes: 2,
+                window: Duration::from_secs(3600),
+            }),
+            clock.shared()
+        );
+
+        // Moves the clock to the given number of seconds after the start.
+        let elapsed = Cell::new(0);
+        let at = |secs: u64| {
+            clock.advance(Duration::from_secs(secs - elapsed.replace(secs)))
+        };
+
+        // A stable session never backs off.
+        let session = Uuid::new_v4();
+        tracker.update(&uri, Some(session));
+        at(600);
+        tracker.update(&uri, Some(session));
+        at(601);
+        assert!(!tracker.should_skip(&uri));
+
+        // Two changes are still tolerated, the third one isn’t.
+        at(700);
+        tracker.update(&uri, Some(Uuid::new_v4()));
+        at(800);
+        tracker.update(&uri, Some(Uuid::new_v4()));
+        at(801);
+        assert!(!tracker.should_skip(&uri));
+        at(900);
+        tracker.update(&uri, Some(Uuid::new_v4()));
+        at(901);
+        assert!(tracker.should_skip(&uri));
+
+        // Backing off allows one update every half hour.
+        at(900 + 1799);
+        assert!(tracker.should_skip(&uri));
+        at(900 + 1800);
+        assert!(!tracker.should_skip(&uri));
+        tracker.update(&uri, Some(Uuid::new_v4()));
+        at(2701);
+        assert!(tracker.should_skip(&uri));
+
+        // Once enough changes have left the window, regular updates resume.
+        at(700 + 3599);
+        assert!(tracker.should_skip(&uri));
+        at(800 + 3600);
+        assert!(!tracker.should_skip(&uri));
+    }
+
+    #[test]
+    fn no_policy() {
+        let uri = uri::Https::from_str(
+            "https://example.net/notification.xml"
+        ).unwrap();
+        let tracker = SessionTracker::new(None, Default::default());
+        for _ in 0..10 {
+            tracker.update(&uri, Some(Uuid::new_v4()));
+        }
+        assert!(!tracker.should_skip(&uri));
+    }
+}
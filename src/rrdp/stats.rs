@@ -0,0 +1,286 @@
+//! Statistics of the RRDP servers in the local cache.
+//!
+//! This is a private module for organizational purposes.
+//!
+//! Only what the local copies themselves record is available here, since
+//! the statistics are collected from the cache directory without running
+//! any updates. Values observed during updates, such as the bandwidth used
+//! or how often a server publishes new data, are only kept while running
+//! and are available through the metrics instead.
+
+use std::{fs, io};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{error, info};
+use rpki::uri;
+use serde_json::json;
+use uuid::Uuid;
+use crate::operation::Error;
+use super::server::{Server, ServerOptions, ServerState};
+
+
+//------------ ServerStats ---------------------------------------------------
+
+/// The statistics of the local copy of a single RRDP server.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServerStats {
+    /// The notification URI of the server.
+    pub notify_uri: uri::Https,
+
+    /// The directory of the server in the cache.
+    pub path: PathBuf,
+
+    /// The session of the local copy.
+    pub session: Uuid,
+
+    /// The serial number of the local copy.
+    pub serial: u64,
+
+    /// The number of objects in the local copy.
+    pub objects: usize,
+
+    /// The combined size of all objects in bytes.
+    pub bytes: u64,
+
+    /// The time the local copy was last changed if known.
+    pub last_change: Option<DateTime<Utc>>,
+}
+
+impl ServerStats {
+    /// Collects the statistics of all servers in a cache directory.
+    ///
+    /// Servers whose state cannot be read are skipped. The result is
+    /// ordered by the server directory.
+    pub fn collect(
+        cache_dir: &Path, options: &Arc<ServerOptions>
+    ) -> Result<Vec<Self>, Error> {
+        let dir = match fs::read_dir(cache_dir) {
+            Ok(dir) => dir,
+            Err(err) => {
+                error!(
+                    "Fatal: Cannot open RRDP cache dir '{}': {}",
+                    cache_dir.display(), err
+                );
+                return Err(Error)
+            }
+        };
+        let mut res = Vec::new();
+        for entry in dir {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    error!(
+                        "Fatal: Cannot iterate over RRDP cache dir '{}': {}",
+                        cache_dir.display(), err
+                    );
+                    return Err(Error)
+                }
+            };
+            if !entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                continue
+            }
+            let path = entry.path();
+            let stats = ServerState::load(
+                &*options.store, &options.dir_names.state_path(&path)
+            ).and_then(|state| {
+                Server::existing(
+                    state.notify_uri.clone(), path.clone(), options.clone()
+                ).stats(state)
+            });
+            match stats {
+                Ok(stats) => res.push(stats),
+                Err(_) => {
+                    info!(
+                        "Skipping unreadable RRDP server directory '{}'.",
+                        path.display()
+                    );
+                }
+            }
+        }
+        res.sort_by(|left, right| left.path.cmp(&right.path));
+        Ok(res)
+    }
+}
+
+
+//------------ StatsFormat ---------------------------------------------------
+
+/// The output format for server statistics.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StatsFormat {
+    /// Comma separated values with a header line.
+    Csv,
+
+    /// A JSON object with an array of servers.
+    Json,
+}
+
+impl StatsFormat {
+    /// The names of the available formats.
+    pub const VALUES: &'static [&'static str] = &["csv", "json"];
+
+    /// Writes the statistics of all servers in this format.
+    pub fn write<W: io::Write>(
+        self, stats: &[ServerStats], target: &mut W
+    ) -> Result<(), io::Error> {
+        match self {
+            StatsFormat::Csv => Self::write_csv(stats, target),
+            StatsFormat::Json => Self::write_json(stats, target),
+        }
+    }
+
+    fn write_csv<W: io::Write>(
+        stats: &[ServerStats], target: &mut W
+    ) -> Result<(), io::Error> {
+        writeln!(
+            target, "Notify URI,Session,Serial,Objects,Bytes,Last Change"
+        )?;
+        for item in stats {
+            writeln!(
+                target, "{},{},{},{},{},{}",
+                csv_field(item.notify_uri.as_str()), item.session,
+                item.serial, item.objects, item.bytes,
+                item.last_change.map(format_time).unwrap_or_default()
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_json<W: io::Write>(
+        stats: &[ServerStats], target: &mut W
+    ) -> Result<(), io::Error> {
+        let servers: Vec<_> = stats.iter().map(|item| {
+            json!({
+                "notifyUri": item.notify_uri.as_str(),
+                "session": item.session.to_string(),
+                "serial": item.serial,
+                "objects": item.objects,
+                "bytes": item.bytes,
+                "lastChange": item.last_change.map(format_time),
+            })
+        }).collect();
+        serde_json::to_writer_pretty(
+            &mut *target, &json!({ "servers": servers })
+        )?;
+        writeln!(target)
+    }
+}
+
+impl FromStr for StatsFormat {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "csv" => Ok(StatsFormat::Csv),
+            "json" => Ok(StatsFormat::Json),
+            _ => {
+                error!("Unknown statistics format: {}", value);
+                Err(Error)
+            }
+        }
+    }
+}
+
+impl Default for StatsFormat {
+    fn default() -> Self {
+        StatsFormat::Csv
+    }
+}
+
+
+//------------ Helper Functions ----------------------------------------------
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Returns a value as a CSV field.
+///
+/// The value is quoted if it contains a comma, a quote, or a line break.
+/// Quotes inside the value are doubled.
+fn csv_field(value: &str) -> Cow<str> {
+    if value.contains(|ch| matches!(ch, ',' | '"' | '\r' | '\n')) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    }
+    else {
+        Cow::Borrowed(value)
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rrdp::mock::seed_server;
+
+    #[test]
+    fn export() {
+        let tmp = tempfile::tempdir().unwrap();
+        seed_server(
+            &tmp.path().join("a.example.net"), "a.example.net", 12,
+            &[("0.roa", b"roa"), ("1.roa", b"other")]
+        );
+        seed_server(
+            &tmp.path().join("b.example.net"), "b.example.net", 7,
+            &[("0.roa", b"certificate")]
+        );
+        fs::create_dir(tmp.path().join("broken")).unwrap();
+
+        let stats = ServerStats::collect(
+            tmp.path(), &Default::default()
+        ).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].serial, 12);
+        assert_eq!(stats[0].objects, 2);
+        assert_eq!(stats[0].bytes, 8);
+        assert_eq!(stats[1].objects, 1);
+        assert_eq!(stats[1].bytes, 11);
+        assert!(stats.iter().all(|item| item.last_change.is_some()));
+
+        let mut csv = Vec::new();
+        StatsFormat::Csv.write(&stats, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0], "Notify URI,Session,Serial,Objects,Bytes,Last Change"
+        );
+        let fields: Vec<_> = lines[1].split(',').collect();
+        assert_eq!(
+            &fields[..5],
+            &[
+                "https://a.example.net/notification.xml",
+                "00000000-0000-0000-0000-000000000000", "12", "2", "8"
+            ]
+        );
+        assert!(fields[5].ends_with('Z'));
+
+        let mut json = Vec::new();
+        StatsFormat::Json.write(&stats, &mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        let servers = json["servers"].as_array().unwrap();
+        assert_eq!(servers.len(), 2);
+        assert_eq!(
+            servers[1]["notifyUri"], "https://b.example.net/notification.xml"
+        );
+        assert_eq!(servers[1]["serial"], 7);
+        assert_eq!(servers[1]["objects"], 1);
+        assert_eq!(servers[1]["bytes"], 11);
+        assert!(servers[1]["lastChange"].is_string());
+    }
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("https://example.net/"), "https://example.net/");
+        assert_eq!(
+            csv_field("https://example.net/a,b"), "\"https://example.net/a,b\""
+        );
+        assert_eq!(csv_field("a\"b"), "\"a\"\"b\"");
+        assert_eq!(csv_field("a\nb"), "\"a\nb\"");
+    }
+}
@@ -0,0 +1,249 @@
+//! Access to the storage of the local copies of RRDP servers.
+//!
+//! This is a private module for organizational purposes.
+//!
+//! A server directory doesn’t access the objects of its local copy
+//! directly but through a `CacheStore`. This allows keeping the local
+//! copies somewhere other than in a plain directory. The default store is
+//! the local file system.
+//!
+//! Locations are given as paths below the RRDP cache directory. Everything
+//! kept in a server directory goes through the store: the data directory,
+//! the state file, the object hashes, the manifest, and kept notification
+//! files, as well as exporting and importing a server. Only the temporary
+//! directory the HTTP client writes new objects to is always a plain
+//! directory. Its content reaches the store when it is moved into place
+//! via `rename`.
+//!
+//! Keeping unchanged objects from a snapshot and deduplicating objects
+//! between servers both rely on hard links. They only happen for paths the
+//! store reports as local through `is_local`.
+
+use std::{fmt, fs, io};
+use std::ffi::OsString;
+use std::io::Read;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+use super::utils::{move_path, sync_dir};
+
+
+//------------ CacheStore ----------------------------------------------------
+
+/// The storage of the local copies of RRDP servers.
+pub trait CacheStore: fmt::Debug + Send + Sync {
+    /// Returns the content of the file at the given path.
+    fn read(&self, path: &Path) -> Result<Vec<u8>, io::Error>;
+
+    /// Returns the first `len` bytes of the file at the given path.
+    ///
+    /// If the file is shorter, returns its entire content.
+    fn read_prefix(
+        &self, path: &Path, len: usize
+    ) -> Result<Vec<u8>, io::Error>;
+
+    /// Writes a file at the given path, replacing an existing file.
+    fn write(&self, path: &Path, data: &[u8]) -> Result<(), io::Error>;
+
+    /// Returns the entries of the directory at the given path.
+    ///
+    /// Entries that are neither directories nor regular files are left
+    /// out. The entries can be returned in any order.
+    fn read_dir(&self, path: &Path) -> Result<Vec<StoreEntry>, io::Error>;
+
+    /// Returns whether there is a directory at the given path.
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// Returns whether the given path is kept in the local file system.
+    ///
+    /// Only then is there a local file whose metadata can be inspected.
+    fn is_local(&self, path: &Path) -> bool;
+
+    /// Moves a file or directory, replacing an existing file.
+    ///
+    /// The target has to appear atomically.
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), io::Error>;
+
+    /// Removes the file at the given path.
+    fn remove_file(&self, path: &Path) -> Result<(), io::Error>;
+
+    /// Removes the directory at the given path and all its content.
+    fn remove_dir_all(&self, path: &Path) -> Result<(), io::Error>;
+
+    /// Creates a directory and all its missing parents.
+    fn create_dir_all(&self, path: &Path) -> Result<(), io::Error>;
+
+    /// Flushes the file or directory at the given path to storage.
+    fn sync(&self, path: &Path) -> Result<(), io::Error>;
+
+    /// Completes the changes made to the directory at the given path.
+    ///
+    /// A store may keep changes in memory until this is called. Changes
+    /// that haven’t been completed may be lost.
+    fn commit(&self, path: &Path) -> Result<(), io::Error>;
+}
+
+
+//------------ StoreEntry ----------------------------------------------------
+
+/// An entry of a directory in a store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StoreEntry {
+    /// The file name of the entry.
+    pub name: OsString,
+
+    /// The size of a regular file or `None` for a directory.
+    pub size: Option<u64>,
+}
+
+
+//------------ FsStore -------------------------------------------------------
+
+/// The local file system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FsStore;
+
+impl CacheStore for FsStore {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, io::Error> {
+        fs::read(path)
+    }
+
+    fn read_prefix(
+        &self, path: &Path, len: usize
+    ) -> Result<Vec<u8>, io::Error> {
+        let mut res = Vec::with_capacity(len);
+        fs::File::open(path)?.take(len as u64).read_to_end(&mut res)?;
+        Ok(res)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> Result<(), io::Error> {
+        fs::write(path, data)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<StoreEntry>, io::Error> {
+        let mut res = Vec::new();
+        for entry in path.read_dir()? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                res.push(StoreEntry { name: entry.file_name(), size: None })
+            }
+            else if metadata.is_file() {
+                res.push(StoreEntry {
+                    name: entry.file_name(), size: Some(metadata.len())
+                })
+            }
+        }
+        Ok(res)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn is_local(&self, _path: &Path) -> bool {
+        true
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), io::Error> {
+        move_path(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), io::Error> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<(), io::Error> {
+        fs::remove_dir_all(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), io::Error> {
+        fs::create_dir_all(path)
+    }
+
+    fn sync(&self, path: &Path) -> Result<(), io::Error> {
+        if path.is_dir() {
+            sync_dir(path)
+        }
+        else {
+            fs::File::open(path)?.sync_all()
+        }
+    }
+
+    fn commit(&self, _path: &Path) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+
+//------------ SharedStore ---------------------------------------------------
+
+/// A store that can be shared between all servers.
+///
+/// This is the local file system by default.
+#[derive(Clone, Debug)]
+pub struct SharedStore(Arc<dyn CacheStore>);
+
+impl SharedStore {
+    /// Creates a shared store from the given store.
+    pub fn new<S: CacheStore + 'static>(store: S) -> Self {
+        SharedStore(Arc::new(store))
+    }
+}
+
+impl Default for SharedStore {
+    fn default() -> Self {
+        Self::new(FsStore)
+    }
+}
+
+impl Deref for SharedStore {
+    type Target = dyn CacheStore;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fs_store() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = SharedStore::default();
+        let dir = tmp.path().join("dir");
+        fs::create_dir(&dir).unwrap();
+        let file = dir.join("file");
+        store.write(&file, b"some data").unwrap();
+        assert_eq!(store.read(&file).unwrap(), b"some data");
+        assert_eq!(store.read_prefix(&file, 4).unwrap(), b"some");
+        assert_eq!(store.read_prefix(&file, 100).unwrap(), b"some data");
+
+        fs::create_dir(dir.join("sub")).unwrap();
+        let mut entries = store.read_dir(&dir).unwrap();
+        entries.sort_by(|left, right| left.name.cmp(&right.name));
+        assert_eq!(
+            entries,
+            vec![
+                StoreEntry { name: "file".into(), size: Some(9) },
+                StoreEntry { name: "sub".into(), size: None },
+            ]
+        );
+        assert!(store.is_dir(&dir));
+        assert!(!store.is_dir(&file));
+        assert!(store.is_local(&file));
+
+        let moved = tmp.path().join("moved");
+        store.rename(&dir, &moved).unwrap();
+        assert!(!dir.exists());
+        store.remove_file(&moved.join("file")).unwrap();
+        assert!(!moved.join("file").exists());
+        store.remove_dir_all(&moved).unwrap();
+        assert!(!moved.exists());
+    }
+}
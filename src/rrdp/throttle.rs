@@ -0,0 +1,206 @@
+//! Throttling RRDP updates under high system load.
+//!
+//! This is a private module for organizational purposes.
+
+use std::{fmt, fs, thread};
+use std::time::{Duration, Instant};
+use log::{debug, info};
+
+
+///----------- Configuration Constants ---------------------------------------
+
+/// How long to wait between checking whether the load has dropped.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The maximum time to pause a single update.
+///
+/// After this time, the update proceeds even if the load is still high so
+/// that a busy host doesn’t stop updates altogether.
+const MAX_PAUSE: Duration = Duration::from_secs(60);
+
+
+//------------ LoadSignal ----------------------------------------------------
+
+/// A source for the current system load.
+pub trait LoadSignal: fmt::Debug + Send + Sync {
+    /// Returns the current load or `None` if it isn’t available.
+    fn load(&self) -> Option<f64>;
+}
+
+
+//------------ LoadAverage ---------------------------------------------------
+
+/// The one minute load average of the system.
+///
+/// This is only available on systems that provide `/proc/loadavg`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoadAverage;
+
+impl LoadSignal for LoadAverage {
+    fn load(&self) -> Option<f64> {
+        let content = fs::read_to_string("/proc/loadavg").ok()?;
+        content.split_whitespace().next()?.parse().ok()
+    }
+}
+
+
+//------------ LoadThrottle --------------------------------------------------
+
+/// Pauses updates while the system load is above a threshold.
+#[derive(Debug)]
+pub struct LoadThrottle {
+    /// The load above which updates are paused.
+    ///
+    /// If this is `None`, updates are never paused.
+    threshold: Option<f64>,
+
+    /// The source of the current load.
+    signal: Box<dyn LoadSignal>,
+
+    /// How long to wait between checks.
+    interval: Duration,
+
+    /// The maximum duration of a single pause.
+    max_pause: Duration,
+}
+
+impl LoadThrottle {
+    /// Creates a new throttle using the system load average.
+    pub fn new(threshold: Option<f64>) -> Self {
+        Self::with_signal(
+            threshold, Box::new(LoadAverage), CHECK_INTERVAL, MAX_PAUSE
+        )
+    }
+
+    /// Creates a new throttle with an explicit load signal.
+    pub fn with_signal(
+        threshold: Option<f64>,
+        signal: Box<dyn LoadSignal>,
+        interval: Duration,
+        max_pause: Duration,
+    ) -> Self {
+        LoadThrottle { threshold, signal, interval, max_pause }
+    }
+
+    /// Waits until the load has dropped below the threshold.
+    ///
+    /// Returns whether the update had to be paused.
+    pub fn wait(&self) -> bool {
+        let threshold = match self.threshold {
+            Some(threshold) => threshold,
+            None => return false
+        };
+        let start = Instant::now();
+        let mut paused = false;
+        loop {
+            let load = match self.signal.load() {
+                Some(load) => load,
+                None => return paused
+            };
+            if load <= threshold {
+                if paused {
+                    debug!("System load dropped to {}. Resuming.", load);
+                }
+                return paused
+            }
+            if start.elapsed() >= self.max_pause {
+                info!(
+                    "System load still at {} after {} seconds. \
+                     Resuming anyway.",
+                    load, self.max_pause.as_secs()
+                );
+                return paused
+            }
+            if !paused {
+                info!(
+                    "System load at {} exceeds {}. Pausing RRDP updates.",
+                    load, threshold
+                );
+                paused = true;
+            }
+            thread::sleep(self.interval);
+        }
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// A load signal that returns a given sequence of values.
+    #[derive(Debug)]
+    struct Sequence {
+        values: Mutex<Vec<f64>>,
+        checks: Arc<Mutex<usize>>,
+    }
+
+    impl Sequence {
+        fn new(values: &[f64]) -> (Box<Self>, Arc<Mutex<usize>>) {
+            let checks = Arc::new(Mutex::new(0));
+            let mut values = values.to_vec();
+            values.reverse();
+            (
+                Box::new(Sequence {
+                    values: Mutex::new(values),
+                    checks: checks.clone()
+                }),
+                checks
+            )
+        }
+    }
+
+    impl LoadSignal for Sequence {
+        fn load(&self) -> Option<f64> {
+            *self.checks.lock().unwrap() += 1;
+            self.values.lock().unwrap().pop()
+        }
+    }
+
+    fn throttle(threshold: Option<f64>, values: &[f64])
+        -> (LoadThrottle, Arc<Mutex<usize>>)
+    {
+        let (signal, checks) = Sequence::new(values);
+        (
+            LoadThrottle::with_signal(
+                threshold, signal,
+                Duration::from_millis(1), Duration::from_secs(10)
+            ),
+            checks
+        )
+    }
+
+    #[test]
+    fn pauses_above_threshold() {
+        let (throttle, checks) = throttle(Some(2.0), &[4.0, 3.0, 1.5, 5.0]);
+        assert!(throttle.wait());
+        assert_eq!(*checks.lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn no_pause_below_threshold() {
+        let (throttle, checks) = throttle(Some(2.0), &[1.0]);
+        assert!(!throttle.wait());
+        assert_eq!(*checks.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn no_threshold() {
+        let (throttle, checks) = throttle(None, &[10.0]);
+        assert!(!throttle.wait());
+        assert_eq!(*checks.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn max_pause() {
+        let (signal, _) = Sequence::new(&[10.0; 100]);
+        let throttle = LoadThrottle::with_signal(
+            Some(2.0), signal,
+            Duration::from_millis(1), Duration::from_millis(0)
+        );
+        assert!(!throttle.wait());
+    }
+}
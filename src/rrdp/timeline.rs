@@ -0,0 +1,358 @@
+//! A record of all the steps taken while updating an RRDP server.
+//!
+//! This is a private module for organizational purposes.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+use log::warn;
+use rpki::uri;
+use serde_json::json;
+use crate::operation::Error;
+use super::clock::SharedClock;
+
+
+//------------ Timeline ------------------------------------------------------
+
+/// The timeline of a single server update.
+///
+/// The timeline collects the duration and result of each step of the
+/// update so that it can be logged as a single record once the update has
+/// finished. The `Display` implementation produces this record as a
+/// single line of JSON.
+#[derive(Clone, Debug)]
+pub struct Timeline {
+    /// The notification URI of the server.
+    notify_uri: uri::Https,
+
+    /// The clock to take all times from.
+    clock: SharedClock,
+
+    /// The time the update started.
+    start: Instant,
+
+    /// The steps taken so far.
+    steps: Vec<Step>,
+
+    /// The update path chosen, if we got that far.
+    path: Option<UpdatePath>,
+
+    /// The serial number we ended up with.
+    serial: Option<u64>,
+
+    /// The overall duration and outcome once finished.
+    outcome: Option<(Duration, bool)>,
+
+    /// The maximum duration of the update.
+    deadline: Option<Duration>,
+
+    /// The fraction of the deadline after which to warn.
+    warning: Option<f64>,
+
+    /// The step during which the warning threshold was crossed.
+    warned: Option<&'static str>,
+}
+
+impl Timeline {
+    /// Starts a new timeline for the given server.
+    pub fn new(notify_uri: uri::Https) -> Self {
+        Self::with_clock(notify_uri, SharedClock::default())
+    }
+
+    /// Starts a new timeline using the given clock.
+    pub fn with_clock(notify_uri: uri::Https, clock: SharedClock) -> Self {
+        Timeline {
+            notify_uri,
+            start: clock.now(),
+            clock,
+            steps: Vec::new(),
+            path: None,
+            serial: None,
+            outcome: None,
+            deadline: None,
+            warning: None,
+            warned: None,
+        }
+    }
+
+    /// Sets the maximum duration of the update.
+    pub fn set_deadline(&mut self, deadline: Option<Duration>) {
+        self.deadline = deadline
+    }
+
+    /// Returns the time by which the update has to end if there is one.
+    pub fn deadline_at(&self) -> Option<Instant> {
+        self.deadline.map(|deadline| self.start + deadline)
+    }
+
+    /// Sets the fraction of the deadline after which to warn.
+    ///
+    /// The warning is issued at most once when a step finishes after this
+    /// share of the deadline has passed.
+    pub fn set_deadline_warning(&mut self, fraction: Option<f64>) {
+        self.warning = fraction
+    }
+
+    /// Returns the step during which the warning threshold was crossed.
+    pub fn deadline_warning(&self) -> Option<&'static str> {
+        self.warned
+    }
+
+    /// Checks that the deadline of the update hasn’t passed yet.
+    pub fn check_deadline(&self) -> Result<(), Error> {
+        match self.deadline {
+            Some(deadline) if self.elapsed() >= deadline => {
+                warn!(
+                    "RRDP {}: update exceeded deadline of {} seconds.",
+                    self.notify_uri, deadline.as_secs()
+                );
+                Err(Error)
+            }
+            _ => Ok(())
+        }
+    }
+
+    /// Performs and records a step.
+    pub fn step<T, F>(&mut self, name: &'static str, op: F) -> Result<T, Error>
+    where F: FnOnce() -> Result<T, Error> {
+        self.timed(name, None, op)
+    }
+
+    /// Performs and records the step of processing a delta.
+    pub fn delta<T, F>(&mut self, serial: u64, op: F) -> Result<T, Error>
+    where F: FnOnce() -> Result<T, Error> {
+        self.timed("delta", Some(serial), op)
+    }
+
+    fn timed<T, F>(
+        &mut self, name: &'static str, serial: Option<u64>, op: F
+    ) -> Result<T, Error>
+    where F: FnOnce() -> Result<T, Error> {
+        let start = self.clock.now();
+        let res = op();
+        self.steps.push(Step {
+            name,
+            serial,
+            duration: self.clock.now().duration_since(start),
+            ok: res.is_ok(),
+        });
+        self.check_warning(name);
+        res
+    }
+
+    /// Warns if the update is getting close to its deadline.
+    fn check_warning(&mut self, step: &'static str) {
+        if self.warned.is_some() {
+            return
+        }
+        let (deadline, fraction) = match (self.deadline, self.warning) {
+            (Some(deadline), Some(fraction)) => (deadline, fraction),
+            _ => return
+        };
+        if self.elapsed() >= deadline.mul_f64(fraction) {
+            warn!(
+                "RRDP {}: update has used {:.0}% of its deadline of {} \
+                 seconds during step '{}'.",
+                self.notify_uri, fraction * 100., deadline.as_secs(), step
+            );
+            self.warned = Some(step)
+        }
+    }
+
+    /// Records the update path chosen.
+    pub fn set_path(&mut self, path: UpdatePath) {
+        self.path = Some(path)
+    }
+
+    /// Returns the update path chosen if there was one.
+    pub fn path(&self) -> Option<UpdatePath> {
+        self.path
+    }
+
+    /// Records the serial number of the new state.
+    pub fn set_serial(&mut self, serial: u64) {
+        self.serial = Some(serial)
+    }
+
+    /// Finishes the timeline with the given overall outcome.
+    pub fn finish(&mut self, ok: bool) {
+        self.outcome = Some((self.elapsed(), ok))
+    }
+
+    /// Returns the time since the update started.
+    fn elapsed(&self) -> Duration {
+        self.clock.now().duration_since(self.start)
+    }
+}
+
+impl fmt::Display for Timeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let steps: Vec<_> = self.steps.iter().map(|step| {
+            let mut res = json!({
+                "step": step.name,
+                "duration": seconds(step.duration),
+                "ok": step.ok,
+            });
+            if let Some(serial) = step.serial {
+                res["serial"] = serial.into();
+            }
+            res
+        }).collect();
+        let record = json!({
+            "notifyUri": self.notify_uri.to_string(),
+            "outcome": self.outcome.map(|(_, ok)| {
+                if ok { "success" } else { "failure" }
+            }),
+            "duration": self.outcome.map(|(duration, _)| seconds(duration)),
+            "path": self.path.map(|path| path.to_string()),
+            "serial": self.serial,
+            "deadlineWarning": self.warned,
+            "steps": steps,
+        });
+        write!(f, "{}", record)
+    }
+}
+
+/// Returns a duration in seconds rounded to milliseconds.
+fn seconds(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1000.).round() / 1000.
+}
+
+
+//------------ Step ----------------------------------------------------------
+
+/// A single step of an update.
+#[derive(Clone, Debug)]
+struct Step {
+    /// The name of the step.
+    name: &'static str,
+
+    /// The serial number of the delta for delta steps.
+    serial: Option<u64>,
+
+    /// How long the step took.
+    duration: Duration,
+
+    /// Whether the step succeeded.
+    ok: bool,
+}
+
+
+//------------ UpdatePath ----------------------------------------------------
+
+/// The way a server was updated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UpdatePath {
+    /// The local copy was already current.
+    Current,
+
+    /// The server was updated via deltas.
+    Delta,
+
+    /// The server was updated via the snapshot.
+    Snapshot,
+}
+
+impl fmt::Display for UpdatePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            UpdatePath::Current => "current",
+            UpdatePath::Delta => "delta",
+            UpdatePath::Snapshot => "snapshot",
+        })
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+    use crate::rrdp::clock::MockClock;
+
+    #[test]
+    fn single_record() {
+        let mut timeline = Timeline::new(
+            uri::Https::from_str("https://example.net/notify.xml").unwrap()
+        );
+        timeline.step("notification", || Ok(())).unwrap();
+        timeline.delta(12, || Ok(())).unwrap();
+        assert!(timeline.delta(13, || Err::<(), _>(Error)).is_err());
+        timeline.set_path(UpdatePath::Snapshot);
+        timeline.step("snapshot", || Ok(())).unwrap();
+        timeline.set_serial(13);
+        timeline.finish(true);
+
+        let record = timeline.to_string();
+        assert!(!record.contains('\n'));
+        let json: serde_json::Value = serde_json::from_str(&record).unwrap();
+        assert_eq!(json["notifyUri"], "https://example.net/notify.xml");
+        assert_eq!(json["outcome"], "success");
+        assert_eq!(json["path"], "snapshot");
+        assert_eq!(json["serial"], 13);
+        let steps = json["steps"].as_array().unwrap();
+        assert_eq!(steps.len(), 4);
+        assert_eq!(steps[0]["step"], "notification");
+        assert_eq!(steps[2]["serial"], 13);
+        assert_eq!(steps[2]["ok"], false);
+        assert_eq!(steps[3]["ok"], true);
+    }
+
+    #[test]
+    fn unfinished_record() {
+        let timeline = Timeline::new(
+            uri::Https::from_str("https://example.net/notify.xml").unwrap()
+        );
+        let json: serde_json::Value = serde_json::from_str(
+            &timeline.to_string()
+        ).unwrap();
+        assert!(json["outcome"].is_null());
+        assert!(json["steps"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn deadline() {
+        let mut timeline = Timeline::new(
+            uri::Https::from_str("https://example.net/notify.xml").unwrap()
+        );
+        assert!(timeline.check_deadline().is_ok());
+        timeline.set_deadline(Some(Duration::from_secs(3600)));
+        assert!(timeline.check_deadline().is_ok());
+        timeline.set_deadline(Some(Duration::from_secs(0)));
+        assert!(timeline.check_deadline().is_err());
+    }
+
+    #[test]
+    fn deadline_warning() {
+        let clock = MockClock::new();
+        let mut timeline = Timeline::with_clock(
+            uri::Https::from_str("https://example.net/notify.xml").unwrap(),
+            clock.shared()
+        );
+        timeline.set_deadline(Some(Duration::from_secs(10)));
+        timeline.set_deadline_warning(Some(0.8));
+        timeline.step("notification", || {
+            clock.advance(Duration::from_secs(2));
+            Ok(())
+        }).unwrap();
+        assert_eq!(timeline.deadline_warning(), None);
+
+        // A slow snapshot crosses the threshold but not the deadline.
+        timeline.step("snapshot", || {
+            clock.advance(Duration::from_secs(7));
+            Ok(())
+        }).unwrap();
+        assert!(timeline.check_deadline().is_ok());
+        assert_eq!(timeline.deadline_warning(), Some("snapshot"));
+
+        // Only the first step is recorded.
+        timeline.step("digest", || Ok(())).unwrap();
+        assert_eq!(timeline.deadline_warning(), Some("snapshot"));
+        let json: serde_json::Value = serde_json::from_str(
+            &timeline.to_string()
+        ).unwrap();
+        assert_eq!(json["deadlineWarning"], "snapshot");
+        assert_eq!(json["steps"][1]["duration"], 7.0);
+    }
+}
@@ -2,36 +2,31 @@
 //!
 //! This is a private module here only for organizional purposes.
 
-use std::io;
+use std::{fmt, fs, io};
 use std::fs::{File, create_dir_all};
 use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use log::info;
 use rand::random;
 use crate::operation::Error;
 
 
 /// Creates a new directory under the given path with a unique name.
-pub fn create_unique_dir(path: &Path) -> Result<PathBuf, Error> {
+///
+/// Doesn’t log anything but leaves it to the caller to report the error.
+pub fn create_unique_dir(path: &Path) -> Result<PathBuf, UniqueDirError> {
     for _ in 0..100 {
         let target = random_path(path);
         match create_dir_all(&target) {
             Ok(()) => return Ok(target),
             Err(err) => {
                 if err.kind() != io::ErrorKind::AlreadyExists {
-                    info!(
-                        "Failed to create unique directory under {}: {}",
-                        path.display(), err
-                    );
-                    return Err(Error);
+                    return Err(err.into())
                 }
             }
         }
     }
-    info!(
-        "Failed to create unique directory under {}: tried a hundred times.",
-        path.display()
-    );
-    Err(Error)
+    Err(UniqueDirError::Exhausted)
 }
 
 /// Creates a new file under the given path with a unique name.
@@ -63,3 +58,248 @@ pub fn random_path(path: &Path) -> PathBuf {
     path.join(format!("{}", random::<u32>()))
 }
 
+/// Acquires a mutex even if a thread panicked while holding it.
+///
+/// This is for mutexes that only protect caches or bookkeeping that stay
+/// usable after a panic in the middle of an update.
+pub fn lock_recovered<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Flushes a directory to storage.
+///
+/// This makes renaming and removing files in the directory durable. On
+/// systems other than Unix, directories can’t be opened and nothing is done.
+#[cfg(unix)]
+pub fn sync_dir(path: &Path) -> Result<(), io::Error> {
+    File::open(path)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_path: &Path) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Moves a file or directory.
+///
+/// Renaming fails if `from` and `to` are on different file systems, for
+/// instance if the temporary directory lives elsewhere. In this case, the
+/// file or directory is copied instead. See `copy_into_place` for details.
+pub fn move_path(from: &Path, to: &Path) -> Result<(), io::Error> {
+    match fs::rename(from, to) {
+        Err(ref err) if is_cross_device(err) => copy_into_place(from, to),
+        res => res
+    }
+}
+
+/// Copies a file or directory to `to` and removes the original.
+///
+/// The copy is made at a temporary location next to `to` and then renamed,
+/// so that `to` appears atomically just like with `fs::rename`. If copying
+/// fails, the copy is removed again and the original is kept. Failing to
+/// remove the original afterwards is ignored.
+fn copy_into_place(from: &Path, to: &Path) -> Result<(), io::Error> {
+    let tmp = random_path(to.parent().unwrap_or_else(|| Path::new("")));
+    if let Err(err) = copy_path(from, &tmp).and_then(|_| {
+        fs::rename(&tmp, to)
+    }) {
+        let _ = remove_path(&tmp);
+        return Err(err)
+    }
+    let _ = remove_path(from);
+    Ok(())
+}
+
+/// Recursively copies a file or directory.
+fn copy_path(from: &Path, to: &Path) -> Result<(), io::Error> {
+    if fs::symlink_metadata(from)?.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_path(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        Ok(())
+    }
+    else {
+        fs::copy(from, to).map(|_| ())
+    }
+}
+
+/// Removes a file or a directory with all its content.
+fn remove_path(path: &Path) -> Result<(), io::Error> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    }
+    else {
+        fs::remove_file(path)
+    }
+}
+
+/// Returns whether an error was caused by renaming across file systems.
+#[cfg(unix)]
+fn is_cross_device(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EXDEV)
+}
+
+#[cfg(not(unix))]
+fn is_cross_device(_err: &io::Error) -> bool {
+    false
+}
+
+/// Flushes all files and directories under and including `path` to storage.
+pub fn sync_tree(path: &Path) -> Result<(), io::Error> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            sync_tree(&entry.path())?;
+        }
+        else {
+            File::open(entry.path())?.sync_all()?;
+        }
+    }
+    sync_dir(path)
+}
+
+
+
+//------------ UniqueDirError ------------------------------------------------
+
+/// Creating a unique directory failed.
+#[derive(Debug)]
+pub enum UniqueDirError {
+    /// All the names tried already existed.
+    Exhausted,
+
+    /// We are not allowed to create the directory.
+    PermissionDenied(io::Error),
+
+    /// There is no space left on the device.
+    NoSpace(io::Error),
+
+    /// Any other I/O error.
+    Io(io::Error),
+}
+
+impl UniqueDirError {
+    #[cfg(unix)]
+    fn is_no_space(err: &io::Error) -> bool {
+        err.raw_os_error() == Some(libc::ENOSPC)
+    }
+
+    #[cfg(not(unix))]
+    fn is_no_space(_err: &io::Error) -> bool {
+        false
+    }
+}
+
+impl From<io::Error> for UniqueDirError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::PermissionDenied {
+            UniqueDirError::PermissionDenied(err)
+        }
+        else if Self::is_no_space(&err) {
+            UniqueDirError::NoSpace(err)
+        }
+        else {
+            UniqueDirError::Io(err)
+        }
+    }
+}
+
+impl fmt::Display for UniqueDirError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UniqueDirError::Exhausted => {
+                f.write_str("no unused name found after a hundred tries")
+            }
+            UniqueDirError::PermissionDenied(ref err) => {
+                write!(f, "permission denied: {}", err)
+            }
+            UniqueDirError::NoSpace(ref err) => {
+                write!(f, "no space left: {}", err)
+            }
+            UniqueDirError::Io(ref err) => err.fmt(f)
+        }
+    }
+}
+
+
+//============ Tests =========================================================
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unique_dir_error() {
+        assert!(matches!(
+            UniqueDirError::from(
+                io::Error::from(io::ErrorKind::PermissionDenied)
+            ),
+            UniqueDirError::PermissionDenied(_)
+        ));
+        #[cfg(unix)]
+        assert!(matches!(
+            UniqueDirError::from(io::Error::from_raw_os_error(libc::ENOSPC)),
+            UniqueDirError::NoSpace(_)
+        ));
+        assert!(matches!(
+            UniqueDirError::from(io::Error::from(io::ErrorKind::NotFound)),
+            UniqueDirError::Io(_)
+        ));
+    }
+
+    #[test]
+    fn create_unique_dir_in_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("file");
+        File::create(&file).unwrap();
+        assert!(create_unique_dir(&file).is_err());
+        assert!(create_unique_dir(tmp.path()).unwrap().is_dir());
+    }
+
+    #[test]
+    fn move_and_copy_into_place() {
+        let tmp = tempfile::tempdir().unwrap();
+        create_dir_all(tmp.path().join("a/b")).unwrap();
+        fs::write(tmp.path().join("a/b/c"), b"object").unwrap();
+        fs::write(tmp.path().join("a/d"), b"other").unwrap();
+
+        move_path(&tmp.path().join("a"), &tmp.path().join("e")).unwrap();
+        assert!(!tmp.path().join("a").exists());
+        assert_eq!(fs::read(tmp.path().join("e/b/c")).unwrap(), b"object");
+
+        // Copying keeps the content and only leaves the target.
+        create_dir_all(tmp.path().join("f")).unwrap();
+        copy_into_place(&tmp.path().join("e"), &tmp.path().join("f/g"))
+            .unwrap();
+        assert!(!tmp.path().join("e").exists());
+        assert_eq!(fs::read(tmp.path().join("f/g/b/c")).unwrap(), b"object");
+        assert_eq!(fs::read(tmp.path().join("f/g/d")).unwrap(), b"other");
+        assert_eq!(fs::read_dir(tmp.path().join("f")).unwrap().count(), 1);
+        copy_into_place(
+            &tmp.path().join("f/g/d"), &tmp.path().join("f/g/b/c")
+        ).unwrap();
+        assert_eq!(fs::read(tmp.path().join("f/g/b/c")).unwrap(), b"other");
+        assert!(!tmp.path().join("f/g/d").exists());
+
+        // A failed copy keeps the original.
+        assert!(copy_into_place(
+            &tmp.path().join("f/g"), &tmp.path().join("missing/h")
+        ).is_err());
+        assert!(tmp.path().join("f/g/b/c").exists());
+        #[cfg(unix)]
+        assert!(is_cross_device(&io::Error::from_raw_os_error(libc::EXDEV)));
+    }
+
+    #[test]
+    fn sync_tree_and_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        create_dir_all(tmp.path().join("a/b")).unwrap();
+        fs::write(tmp.path().join("a/b/c"), b"object").unwrap();
+        fs::write(tmp.path().join("d"), b"object").unwrap();
+        sync_tree(tmp.path()).unwrap();
+        sync_dir(&tmp.path().join("a")).unwrap();
+        assert!(sync_tree(&tmp.path().join("missing")).is_err());
+    }
+}